@@ -349,6 +349,251 @@ impl ApiClient {
         Ok(response.json().await?)
     }
     
+    pub async fn redeem_promo_code(&self, code: &str) -> Result<RedeemPromoCodeResponse> {
+        let url = format!("{}/v1/credits/redeem", self.base_url);
+        
+        let response = self.client
+            .post(&url)
+            .headers(self.headers()?)
+            .json(&serde_json::json!({ "code": code }))
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to redeem promo code: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to redeem promo code: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    pub async fn list_api_keys(&self, include_revoked: bool) -> Result<ApiKeysResponse> {
+        let url = format!("{}/v1/keys?include_revoked={}", self.base_url, include_revoked);
+        
+        let response = self.client
+            .get(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to list API keys: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to list API keys: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    pub async fn create_api_key(&self, name: &str, scopes: &[String], expires_in_days: Option<u32>) -> Result<ApiKeySecretResponse> {
+        let url = format!("{}/v1/keys", self.base_url);
+        
+        let response = self.client
+            .post(&url)
+            .headers(self.headers()?)
+            .json(&serde_json::json!({
+                "name": name,
+                "scopes": scopes,
+                "expires_in_days": expires_in_days,
+            }))
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to create API key: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to create API key: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    pub async fn rotate_api_key(&self, key_id: &str) -> Result<ApiKeySecretResponse> {
+        let url = format!("{}/v1/keys/{}/rotate", self.base_url, key_id);
+        
+        let response = self.client
+            .post(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to rotate API key: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to rotate API key: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    pub async fn revoke_api_key(&self, key_id: &str) -> Result<()> {
+        let url = format!("{}/v1/keys/{}", self.base_url, key_id);
+        
+        let response = self.client
+            .delete(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to revoke API key: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to revoke API key: {} - {}", status, text);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    pub async fn list_sessions(&self) -> Result<SessionsResponse> {
+        let url = format!("{}/v1/sessions", self.base_url);
+        
+        let response = self.client
+            .get(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to list sessions: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to list sessions: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    pub async fn revoke_session(&self, session_id: &str) -> Result<()> {
+        let url = format!("{}/v1/sessions/{}", self.base_url, session_id);
+        
+        let response = self.client
+            .delete(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to revoke session: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to revoke session: {} - {}", status, text);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    pub async fn revoke_all_sessions(&self, keep_current: bool) -> Result<RevokeAllSessionsResponse> {
+        let url = format!("{}/v1/sessions?keep_current={}", self.base_url, keep_current);
+        
+        let response = self.client
+            .delete(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to sign out everywhere: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to sign out everywhere: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
+    /// Streams the ledger export into `out` as it arrives. Returns the bytes written.
+    pub async fn export_credit_transactions(
+        &self,
+        format: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+        types: Option<&str>,
+        out: &mut impl std::io::Write,
+    ) -> Result<u64> {
+        let mut url = format!("{}/v1/credits/transactions/export?format={}", self.base_url, format);
+        for (key, value) in [("from", from), ("to", to), ("type", types)] {
+            if let Some(value) = value {
+                url.push_str(&format!("&{}={}", key, urlencoding::encode(value)));
+            }
+        }
+
+        let mut response = self.client
+            .get(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to export credit transactions: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to export credit transactions: {} - {}", status, text);
+            }
+        }
+
+        let mut written = 0u64;
+        while let Some(chunk) = response.chunk().await? {
+            out.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        out.flush()?;
+        Ok(written)
+    }
+
+    pub async fn get_referral_stats(&self) -> Result<ReferralStats> {
+        let url = format!("{}/v1/credits/referral", self.base_url);
+        
+        let response = self.client
+            .get(&url)
+            .headers(self.headers()?)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
+                anyhow::bail!("Failed to get referral stats: {}", error.error.message);
+            } else {
+                anyhow::bail!("Failed to get referral stats: {} - {}", status, text);
+            }
+        }
+        
+        Ok(response.json().await?)
+    }
+    
     pub async fn purchase_credits_crypto(&self, pack_id: &str, currency: &str) -> Result<CryptoPurchaseResponse> {
         let url = format!("{}/v1/credits/purchase", self.base_url);
         
@@ -609,6 +854,30 @@ pub struct CreditBalance {
     pub balance: i32,
     #[allow(dead_code)]
     pub currency: String,
+    #[serde(default)]
+    pub breakdown: Option<CreditBreakdown>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreditBreakdown {
+    pub paid: i32,
+    pub promotional: i32,
+    #[serde(default)]
+    pub subscription: i32,
+    pub buckets: Vec<CreditBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreditBucket {
+    #[allow(dead_code)]
+    pub id: String,
+    pub source: String,
+    #[allow(dead_code)]
+    pub original_amount: i32,
+    pub remaining: i32,
+    pub expires_at: Option<String>,
+    #[allow(dead_code)]
+    pub created_at: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -668,6 +937,77 @@ pub struct CreditEstimateResponse {
     pub note: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RedeemPromoCodeResponse {
+    pub code: String,
+    pub credits_granted: i32,
+    pub balance: i32,
+    pub expires_at: Option<String>,
+    pub pack_discount_percent: Option<i32>,
+    pub discount_pack_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReferralStats {
+    pub code: String,
+    pub enabled: bool,
+    pub referrer_credits: u32,
+    pub referee_credits: u32,
+    pub referred: i64,
+    pub pending: i64,
+    pub rewarded: i64,
+    pub credits_earned: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+    pub created_at: String,
+    #[serde(default)]
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeysResponse {
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeySecretResponse {
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub provider: String,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip_country: Option<String>,
+    pub created_at: String,
+    pub last_seen_at: String,
+    #[serde(default)]
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionsResponse {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeAllSessionsResponse {
+    pub revoked: usize,
+    pub account_key_revoked: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ErrorResponse {
     pub error: ErrorDetail,
@@ -678,6 +1018,8 @@ pub struct ErrorDetail {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
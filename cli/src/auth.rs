@@ -53,7 +53,7 @@ async fn authenticate_provider(api_url: &str, provider: &str) -> Result<()> {
     println!("Starting {} authentication...\n", provider);
     
     let device_response = client
-        .post(&format!("{}/v1/auth/device/code", api_url))
+        .post(format!("{}/v1/auth/device/code", api_url))
         .json(&DeviceCodeRequest {
             client_type: "cli".to_string(),
             provider: provider.to_string(),
@@ -97,7 +97,9 @@ async fn authenticate_provider(api_url: &str, provider: &str) -> Result<()> {
         spinner_idx = (spinner_idx + 1) % spinner_chars.len();
         
         let token_response = client
-            .post(&format!("{}/v1/auth/device/token", api_url))
+            .post(format!("{}/v1/auth/device/token", api_url))
+            .header("X-Device-Name", format!("pixie CLI on {}", std::env::consts::OS))
+            .header("X-Device-Platform", "cli")
             .json(&DeviceTokenRequest {
                 device_code: device_data.device_code.clone(),
                 client_type: "cli".to_string(),
@@ -26,19 +26,116 @@ EXAMPLES:
         action: AdminCreditsAction,
     },
     
-    #[command(about = "Grant admin privileges to a user
+    #[command(about = "Set a user's admin roles
     
 Example:
-  pixie admin grant --user-id <id>", long_about = "Grant admin privileges to a user.
+  pixie admin grant --user-id <id> --role support --reason \"New support hire\"", long_about = "Set a user's admin roles in this app, replacing any they had.
+Only app owners (and platform admins) can do this.
 
-NOTE: This command is not yet implemented. To grant admin access,
-update the database directly.
+ROLES:
+  support    Look up users, grant up to 500 credits per request (2,000 per 24h)
+  finance    Stats, exports, ledger, webhooks, promo codes, wallet freezes
+  moderator  Image reports, suspensions and bans
+  owner      Everything, including roles
 
-EXAMPLE:
-  pixie admin grant --user-id 123e4567-e89b-12d3-a456-426614174000")]
+Pass --role none to remove a user's admin access. A reason is required and
+goes into the audit log.
+
+EXAMPLES:
+  pixie admin grant --user-id 123e4567-e89b-12d3-a456-426614174000 --role support --reason \"New support hire\"
+  pixie admin grant --user-id 123e4567-e89b-12d3-a456-426614174000 --role finance,moderator --reason \"Taking over billing\"
+  pixie admin grant --user-id 123e4567-e89b-12d3-a456-426614174000 --role none --reason \"Left the team\"")]
     Grant {
-        #[arg(long, help = "User ID to grant admin privileges")]
+        #[arg(long, help = "User ID to set roles for")]
         user_id: String,
+
+        #[arg(long = "role", value_delimiter = ',', required = true, help = "Comma-separated roles, or 'none'")]
+        roles: Vec<String>,
+
+        #[arg(long, help = "Why the roles are changing (recorded in the audit log)")]
+        reason: String,
+    },
+
+    #[command(about = "List the app's admins and your own roles")]
+    Roles,
+
+    #[command(about = "Show the admin audit log
+    
+Example:
+  pixie admin audit --action credits.", long_about = "Show the app's admin audit log, newest first. Owners only.
+
+--action matches a prefix, so 'credits.' shows every grant and gift.
+
+EXAMPLES:
+  pixie admin audit
+  pixie admin audit --actor 123e4567-e89b-12d3-a456-426614174000
+  pixie admin audit --action roles.set --since 2026-01-01T00:00:00Z
+  pixie admin audit --target-id 123e4567-e89b-12d3-a456-426614174000 --page 2")]
+    Audit {
+        #[arg(long, help = "Only actions by this admin user ID")]
+        actor: Option<String>,
+
+        #[arg(long, help = "Action or action prefix, e.g. credits.adjust or credits.")]
+        action: Option<String>,
+
+        #[arg(long, help = "Only actions on this user, code, event or image ID")]
+        target_id: Option<String>,
+
+        #[arg(long, help = "Only actions at or after this RFC 3339 time")]
+        since: Option<String>,
+
+        #[arg(long, default_value = "1", help = "Page number")]
+        page: u32,
+    },
+
+    #[command(about = "Suspend a user
+    
+Example:
+  pixie admin suspend --user-id <id> --days 7 --reason \"Spam\"", long_about = "Suspend a user. Every request they make is refused until the
+suspension ends or is lifted with 'pixie admin reinstate'. Their sign-ins
+are kept. Moderators and owners only.
+
+EXAMPLES:
+  pixie admin suspend --user-id 123e4567-e89b-12d3-a456-426614174000 --days 7 --reason \"Spam in the gallery\"
+  pixie admin suspend --user-id 123e4567-e89b-12d3-a456-426614174000 --reason \"Under review\"")]
+    Suspend {
+        #[arg(long, help = "User ID to suspend")]
+        user_id: String,
+
+        #[arg(long, help = "Days until the suspension ends (default: until lifted)")]
+        days: Option<u32>,
+
+        #[arg(long, help = "Why (shown to the user and recorded in the audit log)")]
+        reason: String,
+    },
+
+    #[command(about = "Ban a user
+    
+Example:
+  pixie admin ban --user-id <id> --reason \"Repeated abuse\"", long_about = "Ban a user. Their requests are refused, their sign-ins are revoked
+and the device they registered from can't create a new account.
+Moderators and owners only.
+
+EXAMPLES:
+  pixie admin ban --user-id 123e4567-e89b-12d3-a456-426614174000 --reason \"Repeated abuse\"")]
+    Ban {
+        #[arg(long, help = "User ID to ban")]
+        user_id: String,
+
+        #[arg(long, help = "Why (shown to the user and recorded in the audit log)")]
+        reason: String,
+    },
+
+    #[command(about = "Lift a suspension or ban
+    
+Example:
+  pixie admin reinstate --user-id <id> --reason \"Appeal accepted\"")]
+    Reinstate {
+        #[arg(long, help = "User ID to reinstate")]
+        user_id: String,
+
+        #[arg(long, help = "Why (recorded in the audit log)")]
+        reason: String,
     },
 }
 
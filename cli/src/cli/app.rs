@@ -1,5 +1,5 @@
 use clap::{Parser, Subcommand};
-use super::{AuthProvider, GalleryAction, CreditsAction, AdminAction};
+use super::{AuthProvider, GalleryAction, CreditsAction, AdminAction, KeysAction, SessionsAction};
 
 #[derive(Parser)]
 #[command(name = "pixie")]
@@ -433,6 +433,48 @@ CREDIT COSTS:
         action: Option<CreditsAction>,
     },
     
+    #[command(about = "Manage named API keys for scripts
+
+Examples:
+  pixie keys list
+  pixie keys create ci --scope images:write", long_about = "Create, rotate and revoke named API keys.
+
+Signing in gives this CLI a full-access key tied to its session (see
+'pixie sessions'). For scripts and CI, create separate keys limited to the
+scopes they need, optionally expiring. Rotating or revoking one key doesn't
+affect your sign-ins or any other key.
+
+EXAMPLES:
+  pixie keys list                                        # Show your keys
+  pixie keys create ci --scope images:write              # Key for CI image jobs
+  pixie keys create reports --scope credits:read,usage:read --expires-in 90
+  pixie keys rotate <key-id>                             # New secret, same key
+  pixie keys revoke <key-id>                             # Disable a key")]
+    Keys {
+        #[command(subcommand)]
+        action: KeysAction,
+    },
+    
+    #[command(about = "See where you're signed in and sign devices out
+
+Examples:
+  pixie sessions list
+  pixie sessions revoke <session-id>", long_about = "List the devices signed in to your account and sign them out.
+
+Every sign-in (this CLI, the apps, the web) is a session with its own
+credentials. Revoking a session signs that device out; keys created with
+'pixie keys' are not affected.
+
+EXAMPLES:
+  pixie sessions list                     # Signed-in devices
+  pixie sessions revoke <session-id>      # Sign one device out
+  pixie sessions revoke-all               # Sign out everywhere else
+  pixie sessions revoke-all --include-this  # ...and here too")]
+    Sessions {
+        #[command(subcommand)]
+        action: SessionsAction,
+    },
+    
     #[command(about = "Check API health status
 
 Example:
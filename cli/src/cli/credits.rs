@@ -15,13 +15,32 @@ Shows:
   - Date and time
   - Balance after transaction
 
+With --export, downloads your full ledger to a file instead. The format follows
+the file extension: .jsonl (or .ndjson) writes JSON Lines, anything else CSV.
+--from, --to and --type narrow the export.
+
 EXAMPLES:
   pixie credits history                 # Show last 10 transactions
   pixie credits history --limit 50      # Show last 50 transactions
-  pixie credits history -l 100          # Show last 100 transactions")]
+  pixie credits history -l 100          # Show last 100 transactions
+  pixie credits history --export ledger.csv
+  pixie credits history --export 2025.jsonl --from 2025-01-01 --to 2025-12-31
+  pixie credits history --export purchases.csv --type purchase,refund_clawback")]
     History {
         #[arg(short, long, default_value = "10", help = "Number of transactions to show")]
         limit: usize,
+
+        #[arg(long, value_name = "FILE", help = "Export the full history to a CSV or JSONL file")]
+        export: Option<std::path::PathBuf>,
+
+        #[arg(long, requires = "export", help = "Export entries from this date (YYYY-MM-DD)")]
+        from: Option<String>,
+
+        #[arg(long, requires = "export", help = "Export entries up to and including this date (YYYY-MM-DD)")]
+        to: Option<String>,
+
+        #[arg(long = "type", requires = "export", help = "Comma-separated transaction types to export")]
+        types: Option<String>,
     },
     
     #[command(about = "Show available credit packs
@@ -66,6 +85,38 @@ EXAMPLES:
         edit: bool,
     },
     
+    #[command(about = "Redeem a promo code for credits
+
+Example:
+  pixie credits redeem WELCOME50", long_about = "Redeem a promo code.
+
+Codes can add promotional credits to your balance, give a discount on a
+credit pack at card checkout, or both. Promotional credits are spent before
+purchased credits and may expire - check 'pixie credits' for the breakdown.
+
+Codes are case-insensitive and can usually be redeemed once per account.
+
+EXAMPLES:
+  pixie credits redeem WELCOME50
+  pixie credits redeem spring-promo")]
+    Redeem {
+        #[arg(help = "Promo code to redeem")]
+        code: String,
+    },
+    
+    #[command(about = "Show your referral code and rewards
+
+Example:
+  pixie credits referral", long_about = "Show your referral code and how many people have signed up with it.
+
+When referrals are enabled for the app, a new user who signs up with your code
+and completes their first purchase earns you (and usually them) bonus credits.
+Rewards are promotional credits and may expire.
+
+EXAMPLES:
+  pixie credits referral")]
+    Referral,
+    
     #[command(about = "Buy credits with card or cryptocurrency
     
 Examples:
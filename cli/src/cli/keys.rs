@@ -0,0 +1,84 @@
+use clap::Subcommand;
+
+#[derive(Subcommand)]
+pub enum KeysAction {
+    #[command(about = "List your API keys
+
+Examples:
+  pixie keys list
+  pixie keys list --all", long_about = "List your named API keys.
+
+Shows each key's id, name, scopes, expiry and when it was last used.
+Secrets are never shown again after a key is created or rotated.
+Keys handed out by signing in belong to sessions and are managed with
+'pixie sessions' instead.
+
+EXAMPLES:
+  pixie keys list          # Active keys
+  pixie keys list --all    # Include revoked keys")]
+    List {
+        #[arg(long, help = "Include revoked keys")]
+        all: bool,
+    },
+
+    #[command(about = "Create a named, scoped API key
+
+Examples:
+  pixie keys create ci --scope images:write
+  pixie keys create reporting --scope credits:read,usage:read --expires-in 90", long_about = "Create a named API key limited to the given scopes.
+
+The secret is printed once; store it somewhere safe. A request outside the
+key's scopes is rejected with 403. Use '*' for a key with full access.
+
+SCOPES:
+  images:write    Generate and edit images
+  gallery:read    Browse images
+  gallery:write   Delete images, change visibility
+  credits:read    Balance, history, packs, estimates
+  credits:write   Purchases, transfers, redeeming codes
+  chat:write      Chat completions
+  realtime:write  Realtime translation sessions
+  usage:read      Usage statistics
+  orgs:read       View organisations
+  orgs:write      Manage organisations
+  admin           Admin endpoints (admins only)
+
+EXAMPLES:
+  pixie keys create ci --scope images:write
+  pixie keys create dashboard --scope credits:read,usage:read --expires-in 30")]
+    Create {
+        #[arg(help = "A name to recognise the key by")]
+        name: String,
+
+        #[arg(short, long = "scope", value_delimiter = ',', required = true, help = "Comma-separated scopes")]
+        scopes: Vec<String>,
+
+        #[arg(long, value_name = "DAYS", help = "Expire the key after this many days")]
+        expires_in: Option<u32>,
+    },
+
+    #[command(about = "Replace a key's secret
+
+Example:
+  pixie keys rotate <key-id>", long_about = "Issue a new secret for a key. The old secret stops working immediately;
+the key's name, scopes and expiry stay the same.
+
+EXAMPLE:
+  pixie keys rotate 123e4567-e89b-12d3-a456-426614174000")]
+    Rotate {
+        #[arg(help = "Key id (see pixie keys list)")]
+        id: String,
+    },
+
+    #[command(about = "Revoke a key
+
+Example:
+  pixie keys revoke <key-id>", long_about = "Revoke a key. Requests using it fail from now on.
+
+EXAMPLE:
+  pixie keys revoke 123e4567-e89b-12d3-a456-426614174000")]
+    Revoke {
+        #[arg(help = "Key id (see pixie keys list)")]
+        id: String,
+    },
+}
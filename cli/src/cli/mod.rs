@@ -3,9 +3,13 @@ pub mod auth;
 pub mod gallery;
 pub mod credits;
 pub mod admin;
+pub mod keys;
+pub mod sessions;
 
 pub use app::{Cli, Commands};
 pub use auth::AuthProvider;
 pub use gallery::{GalleryAction, VisibilityState};
 pub use credits::CreditsAction;
-pub use admin::{AdminAction, AdminCreditsAction};
\ No newline at end of file
+pub use admin::{AdminAction, AdminCreditsAction};
+pub use keys::KeysAction;
+pub use sessions::SessionsAction;
\ No newline at end of file
@@ -0,0 +1,46 @@
+use clap::Subcommand;
+
+#[derive(Subcommand)]
+pub enum SessionsAction {
+    #[command(about = "List signed-in devices
+
+Example:
+  pixie sessions list", long_about = "List the devices signed in to your account.
+
+Shows each session's id, how it signed in, the device name and platform it
+reported, the country it signed in from and when it was last used. The
+session this CLI uses is marked.
+
+EXAMPLE:
+  pixie sessions list")]
+    List,
+
+    #[command(about = "Sign one device out
+
+Example:
+  pixie sessions revoke <session-id>", long_about = "Revoke a session. The device's credentials stop working immediately.
+
+EXAMPLE:
+  pixie sessions revoke 123e4567-e89b-12d3-a456-426614174000")]
+    Revoke {
+        #[arg(help = "Session id (see pixie sessions list)")]
+        id: String,
+    },
+
+    #[command(about = "Sign out everywhere
+
+Examples:
+  pixie sessions revoke-all
+  pixie sessions revoke-all --include-this", long_about = "Revoke every session except this CLI's. With --include-this, this CLI
+is signed out too and its stored credentials are removed.
+
+Keys created with 'pixie keys' keep working.
+
+EXAMPLES:
+  pixie sessions revoke-all                  # Everywhere else
+  pixie sessions revoke-all --include-this   # Everywhere, including here")]
+    RevokeAll {
+        #[arg(long, help = "Also sign this CLI out")]
+        include_this: bool,
+    },
+}
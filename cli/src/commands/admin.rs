@@ -46,7 +46,7 @@ pub async fn adjust_credits(
     }
     
     let response = reqwest::Client::new()
-        .post(&format!("{}/v1/admin/credits/adjust", api_url))
+        .post(format!("{}/v1/admin/credits/adjust", api_url))
         .headers(headers)
         .json(&request_body)
         .send()
@@ -86,7 +86,7 @@ pub async fn system_stats(api_url: &str) -> Result<()> {
     }
     
     let response = reqwest::Client::new()
-        .get(&format!("{}/v1/admin/credits/stats", api_url))
+        .get(format!("{}/v1/admin/credits/stats", api_url))
         .headers(headers)
         .send()
         .await?;
@@ -141,15 +141,223 @@ pub async fn system_stats(api_url: &str) -> Result<()> {
     Ok(())
 }
 
+fn auth_headers(config: &Config) -> Result<reqwest::header::HeaderMap> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(api_key) = &config.api_key {
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+        );
+    }
+    Ok(headers)
+}
+
 pub async fn grant_admin(
-    _api_url: &str,
+    api_url: &str,
     user_id: &str,
+    roles: &[String],
+    reason: &str,
 ) -> Result<()> {
-    let _config = Config::load()?;
+    let config = Config::load()?;
+    if !config.is_authenticated() {
+        return Err(anyhow::anyhow!(
+            "Not authenticated. Run {} to authenticate",
+            "pixie auth github".cyan()
+        ));
+    }
+    
+    let roles: Vec<&String> = roles.iter().filter(|r| r.as_str() != "none").collect();
+    let response = reqwest::Client::new()
+        .put(format!("{}/v1/admin/roles/{}", api_url, user_id))
+        .headers(auth_headers(&config)?)
+        .json(&json!({ "roles": roles, "reason": reason }))
+        .send()
+        .await?;
     
-    println!("{}", "❌ Grant admin not yet implemented".red());
-    println!("   To grant admin access, update the database directly:");
-    println!("   UPDATE users SET is_admin = TRUE WHERE id = '{}';", user_id);
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(anyhow::anyhow!("Failed to set roles: {}", error));
+    }
+    
+    let result: serde_json::Value = response.json().await?;
+    let roles: Vec<&str> = result["roles"]
+        .as_array()
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    if roles.is_empty() {
+        println!("{} {} no longer has admin access", "✓".green(), user_id.cyan());
+    } else {
+        println!("{} {} now has roles: {}", "✓".green(), user_id.cyan(), roles.join(", ").bold());
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn list_roles(api_url: &str) -> Result<()> {
+    let config = Config::load()?;
+    if !config.is_authenticated() {
+        return Err(anyhow::anyhow!(
+            "Not authenticated. Run {} to authenticate",
+            "pixie auth github".cyan()
+        ));
+    }
+    
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("{}/v1/admin/me", api_url))
+        .headers(auth_headers(&config)?)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(anyhow::anyhow!("Failed to get your admin roles: {}", error));
+    }
+    let me: serde_json::Value = response.json().await?;
+    let join = |v: &serde_json::Value| -> String {
+        v.as_array()
+            .map(|a| a.iter().filter_map(|r| r.as_str()).collect::<Vec<_>>().join(", "))
+            .unwrap_or_default()
+    };
+    
+    println!("\n{}", "🔐 Admin Roles".bold().magenta());
+    println!("{}", "═".repeat(60).magenta());
+    if me["platform_admin"].as_bool().unwrap_or(false) {
+        println!("  You:   {}", "platform admin".bold());
+    } else {
+        println!("  You:   {}", join(&me["roles"]).bold());
+    }
+    println!("  Can:   {}", join(&me["permissions"]));
+    
+    let response = client
+        .get(format!("{}/v1/admin/roles", api_url))
+        .headers(auth_headers(&config)?)
+        .send()
+        .await?;
+    if response.status().is_success() {
+        let result: serde_json::Value = response.json().await?;
+        println!();
+        for admin in result["admins"].as_array().into_iter().flatten() {
+            println!("  {} {}  {}",
+                admin["user_id"].as_str().unwrap_or("").cyan(),
+                admin["email"].as_str().unwrap_or(""),
+                join(&admin["roles"]).bold()
+            );
+        }
+    }
+    println!("{}", "═".repeat(60).magenta());
+    
+    Ok(())
+}
+/// Filters for `audit_log`; unset ones match everything.
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<String>,
+}
+
+pub async fn audit_log(api_url: &str, filter: &AuditFilter, page: u32) -> Result<()> {
+    let config = Config::load()?;
+    if !config.is_authenticated() {
+        return Err(anyhow::anyhow!(
+            "Not authenticated. Run {} to authenticate",
+            "pixie auth github".cyan()
+        ));
+    }
+    
+    let mut query = vec![("page", page.to_string())];
+    for (name, value) in [
+        ("actor", &filter.actor),
+        ("action", &filter.action),
+        ("target_id", &filter.target_id),
+        ("from", &filter.since),
+    ] {
+        if let Some(value) = value {
+            query.push((name, value.clone()));
+        }
+    }
+    let response = reqwest::Client::new()
+        .get(format!("{}/v1/admin/audit-log", api_url))
+        .headers(auth_headers(&config)?)
+        .query(&query)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(anyhow::anyhow!("Failed to get the audit log: {}", error));
+    }
+    let result: serde_json::Value = response.json().await?;
+    let entries = result["entries"].as_array().cloned().unwrap_or_default();
+    
+    println!("\n{}", "📜 Admin Audit Log".bold().magenta());
+    println!("{}", "═".repeat(80).magenta());
+    if entries.is_empty() {
+        println!("  {}", "No matching entries".dimmed());
+    }
+    for entry in &entries {
+        let target = match (entry["target_type"].as_str(), entry["target_id"].as_str()) {
+            (Some(kind), Some(id)) => format!("{} {}", kind, id),
+            _ => String::new(),
+        };
+        println!("  {} {} {}",
+            entry["created_at"].as_str().unwrap_or("").dimmed(),
+            entry["action"].as_str().unwrap_or("").bold(),
+            target.cyan()
+        );
+        println!("    by {}{}",
+            entry["actor_user_id"].as_str().unwrap_or(""),
+            if entry["platform_admin"].as_bool().unwrap_or(false) { " (platform admin)" } else { "" }
+        );
+        if let Some(reason) = entry["reason"].as_str() {
+            println!("    reason: {}", reason);
+        }
+        if !entry["before"].is_null() {
+            println!("    before: {}", entry["before"].to_string().dimmed());
+        }
+        if !entry["after"].is_null() {
+            println!("    after:  {}", entry["after"].to_string().dimmed());
+        }
+    }
+    println!("{}", "═".repeat(80).magenta());
+    if entries.len() as u64 == result["per_page"].as_u64().unwrap_or(0) {
+        println!("  {}", format!("More entries: --page {}", page + 1).dimmed());
+    }
+    
+    Ok(())
+}
+
+pub async fn set_user_status(
+    api_url: &str,
+    user_id: &str,
+    status: &str,
+    days: Option<u32>,
+    reason: &str,
+) -> Result<()> {
+    let config = Config::load()?;
+    if !config.is_authenticated() {
+        return Err(anyhow::anyhow!(
+            "Not authenticated. Run {} to authenticate",
+            "pixie auth github".cyan()
+        ));
+    }
+    
+    let response = reqwest::Client::new()
+        .put(format!("{}/v1/admin/users/{}/status", api_url, user_id))
+        .headers(auth_headers(&config)?)
+        .json(&json!({ "status": status, "reason": reason, "expires_in_days": days }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(anyhow::anyhow!("Failed to set user status: {}", error));
+    }
+    
+    let result: serde_json::Value = response.json().await?;
+    match (status, result["expires_at"].as_str()) {
+        ("active", _) => println!("{} {} reinstated", "✓".green(), user_id.cyan()),
+        ("suspended", Some(until)) => println!("{} {} suspended until {}", "✓".green(), user_id.cyan(), until),
+        _ => println!("{} {} {}", "✓".green(), user_id.cyan(), status.bold()),
+    }
+    
+    Ok(())
+}
@@ -48,6 +48,33 @@ pub async fn show_balance(api_url: &str) -> Result<()> {
         );
     }
     
+    if let Some(breakdown) = balance.breakdown.as_ref().filter(|b| b.promotional > 0 || b.subscription > 0) {
+        println!();
+        if breakdown.subscription > 0 {
+            println!("  {} paid, {} promotional, {} subscription allowance",
+                breakdown.paid.to_string().cyan(),
+                breakdown.promotional.to_string().magenta(),
+                breakdown.subscription.to_string().blue()
+            );
+        } else {
+            println!("  {} paid, {} promotional",
+                breakdown.paid.to_string().cyan(),
+                breakdown.promotional.to_string().magenta()
+            );
+        }
+        for bucket in &breakdown.buckets {
+            let expiry = bucket.expires_at.as_deref()
+                .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+                .map(|e| format!("expires {}", e.format("%Y-%m-%d")))
+                .unwrap_or_else(|| "never expires".to_string());
+            println!("  • {} {} credits ({})",
+                bucket.remaining.to_string().magenta(),
+                bucket.source.replace('_', " "),
+                expiry.dimmed()
+            );
+        }
+    }
+    
     println!();
     
     // Show what they can do with their balance
@@ -116,6 +143,8 @@ pub async fn show_history(api_url: &str, limit: usize) -> Result<()> {
                 "refund" => "Refund".cyan(),
                 "bonus" => "Bonus".yellow(),
                 "admin_adjustment" => "Adjustment".blue(),
+                "transfer_out" => "Sent".magenta(),
+                "transfer_in" => "Received".green(),
                 _ => transaction.transaction_type.normal(),
             };
             
@@ -142,6 +171,120 @@ pub async fn show_history(api_url: &str, limit: usize) -> Result<()> {
     Ok(())
 }
 
+pub async fn export_history(
+    api_url: &str,
+    path: &std::path::Path,
+    from: Option<&str>,
+    to: Option<&str>,
+    types: Option<&str>,
+) -> Result<()> {
+    let config = Config::load()?;
+    
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+    
+    let format = match path.extension().and_then(|e| e.to_str()) {
+        Some("jsonl") | Some("ndjson") => "jsonl",
+        _ => "csv",
+    };
+    
+    let client = ApiClient::new(api_url)?;
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    let written = match client.export_credit_transactions(format, from, to, types, &mut file).await {
+        Ok(n) => n,
+        Err(e) => {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            return Err(e);
+        }
+    };
+    
+    println!(
+        "{} Exported credit history to {} ({} bytes, {})",
+        "✓".green(),
+        path.display().to_string().bold(),
+        written,
+        format.to_uppercase()
+    );
+    
+    Ok(())
+}
+
+pub async fn redeem_code(api_url: &str, code: &str) -> Result<()> {
+    let config = Config::load()?;
+    
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+    
+    let client = ApiClient::new(api_url)?;
+    let result = client.redeem_promo_code(code).await?;
+    
+    println!();
+    if result.credits_granted > 0 {
+        println!("{} Redeemed {}: {} credits added",
+            "✅".green(),
+            result.code.bold(),
+            result.credits_granted.to_string().green().bold()
+        );
+        if let Some(expires) = result.expires_at.as_deref()
+            .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+        {
+            println!("   {}", format!("Promotional credits expire {}", expires.format("%Y-%m-%d")).dimmed());
+        }
+        println!("   New balance: {} credits", result.balance.to_string().cyan());
+    }
+    if let Some(percent) = result.pack_discount_percent {
+        let applies_to = result.discount_pack_id
+            .map(|p| format!("the {} pack", p))
+            .unwrap_or_else(|| "any pack".to_string());
+        println!("{} {} gives {}% off {} at card checkout",
+            "🏷️".cyan(),
+            result.code.bold(),
+            percent.to_string().green().bold(),
+            applies_to
+        );
+    }
+    println!();
+    
+    Ok(())
+}
+
+pub async fn show_referral(api_url: &str) -> Result<()> {
+    let config = Config::load()?;
+    
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+    
+    let client = ApiClient::new(api_url)?;
+    let stats = client.get_referral_stats().await?;
+    
+    println!();
+    println!("{} Your referral code: {}", "🎁".cyan(), stats.code.bold().green());
+    if stats.enabled {
+        println!("   Friends who sign up with it and make their first purchase earn you {} credits",
+            stats.referrer_credits.to_string().green().bold());
+        if stats.referee_credits > 0 {
+            println!("   and get {} credits themselves", stats.referee_credits.to_string().green().bold());
+        }
+    } else {
+        println!("   {}", "Referral rewards are not currently enabled for this app".dimmed());
+    }
+    println!();
+    println!("   Signed up:      {}", stats.referred.to_string().cyan());
+    println!("   Pending:        {}", stats.pending.to_string().yellow());
+    println!("   Rewarded:       {}", stats.rewarded.to_string().green());
+    println!("   Credits earned: {}", stats.credits_earned.to_string().green().bold());
+    println!();
+    
+    Ok(())
+}
+
 pub async fn show_packs(api_url: &str) -> Result<()> {
     let client = ApiClient::new(api_url)?;
     let response = client.get_credit_packs().await?;
@@ -643,11 +786,9 @@ pub async fn buy_credits(
                         } else if let Some(payment_status) = status.payment_status {
                             // Show progress steps as they happen
                             match payment_status.as_str() {
-                                "waiting" => {
-                                    if !waiting_shown {
-                                        waiting_shown = true;
-                                        pb.set_message("Waiting for payment to be sent...");
-                                    }
+                                "waiting" if !waiting_shown => {
+                                    waiting_shown = true;
+                                    pb.set_message("Waiting for payment to be sent...");
                                 },
                                 "confirming" => {
                                     if !waiting_shown {
@@ -16,12 +16,12 @@ pub async fn list_all(api_url: &str, page: usize, limit: usize) -> Result<()> {
     println!("Total images: {} | Page {} of {}", 
         response.total,
         response.page,
-        (response.total + response.per_page - 1) / response.per_page
+        response.total.div_ceil(response.per_page)
     );
     println!();
     
     for image in &response.images {
-        display_image_summary(&image);
+        display_image_summary(image);
     }
     
     if response.images.is_empty() {
@@ -53,12 +53,12 @@ pub async fn list_mine(api_url: &str, page: usize, limit: usize) -> Result<()> {
     println!("Total images: {} | Page {} of {}", 
         response.total,
         response.page,
-        (response.total + response.per_page - 1) / response.per_page
+        response.total.div_ceil(response.per_page)
     );
     println!();
     
     for image in &response.images {
-        display_image_summary(&image);
+        display_image_summary(image);
     }
     
     if response.images.is_empty() {
@@ -9,6 +9,7 @@ use crate::config::Config;
 use crate::commands::utils::{check_credits_and_estimate, show_credits_used};
 use crate::commands::parse_size_alias;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle(
     api_url: &str,
     prompt: &str,
@@ -49,7 +50,7 @@ pub async fn handle(
         println!("  Quality:    {}", quality.to_uppercase().yellow());
         println!("  Size:       {} {}", 
             size.green(), 
-            if size != &actual_size { format!("({})", actual_size).dimmed() } else { "".dimmed() }
+            if size != actual_size { format!("({})", actual_size).dimmed() } else { "".dimmed() }
         );
     }
     
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use colored::*;
+use crate::{api::{ApiClient, ApiKeySecretResponse}, config::Config};
+
+fn date_of(timestamp: &str) -> &str {
+    timestamp.split('T').next().unwrap_or(timestamp)
+}
+
+pub async fn list(api_url: &str, all: bool) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    let response = client.list_api_keys(all).await?;
+
+    println!();
+    println!("{}", "🔑 API Keys".bold().blue());
+    println!("{}", "═".repeat(80).blue());
+
+    if response.keys.is_empty() {
+        println!("  {}", "No API keys yet. Create one with: pixie keys create <name> --scope images:write".dimmed());
+    } else {
+        for key in &response.keys {
+            let status = if key.revoked_at.is_some() {
+                "revoked".red()
+            } else if key.current {
+                "in use".green()
+            } else {
+                "active".green()
+            };
+            println!("  {} {} [{}]", key.name.bold(), format!("{}…", key.key_prefix).dimmed(), status);
+            println!("    {:<10} {}", "id".dimmed(), key.id);
+            println!("    {:<10} {}", "scopes".dimmed(), key.scopes.join(", ").cyan());
+            println!("    {:<10} {}", "created".dimmed(), date_of(&key.created_at));
+            println!("    {:<10} {}", "expires".dimmed(), key.expires_at.as_deref().map(date_of).unwrap_or("never"));
+            println!("    {:<10} {}", "last used".dimmed(), key.last_used_at.as_deref().map(date_of).unwrap_or("never"));
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn create(api_url: &str, name: &str, scopes: &[String], expires_in: Option<u32>) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    let created = client.create_api_key(name, scopes, expires_in).await?;
+    print_secret("Created", &created);
+
+    Ok(())
+}
+
+pub async fn rotate(api_url: &str, id: &str) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    let rotated = client.rotate_api_key(id).await?;
+    print_secret("Rotated", &rotated);
+    println!("   {}", "The previous secret no longer works.".dimmed());
+    println!();
+
+    Ok(())
+}
+
+pub async fn revoke(api_url: &str, id: &str) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    client.revoke_api_key(id).await?;
+    println!("{} Key {} revoked", "✓".green(), id.cyan());
+
+    Ok(())
+}
+
+fn print_secret(verb: &str, response: &ApiKeySecretResponse) {
+    println!();
+    println!("{} {} key {} ({})",
+        "✅".green(),
+        verb,
+        response.key.name.bold(),
+        response.key.scopes.join(", ").cyan()
+    );
+    println!();
+    println!("   {}", response.secret.bold().yellow());
+    println!();
+    println!("   {}", "This secret is shown only once. Store it somewhere safe.".dimmed());
+    if let Some(expires) = response.key.expires_at.as_deref() {
+        println!("   {}", format!("Expires {}", date_of(expires)).dimmed());
+    }
+    println!();
+}
@@ -5,6 +5,8 @@ pub mod usage;
 pub mod credits;
 pub mod utils;
 pub mod admin;
+pub mod keys;
+pub mod sessions;
 
 // Size alias mapping
 pub fn parse_size_alias(size: &str) -> String {
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use colored::*;
+use crate::{api::ApiClient, config::{self, Config}};
+
+fn date_of(timestamp: &str) -> &str {
+    timestamp.split('T').next().unwrap_or(timestamp)
+}
+
+pub async fn list(api_url: &str) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    let response = client.list_sessions().await?;
+
+    println!();
+    println!("{}", "💻 Sessions".bold().blue());
+    println!("{}", "═".repeat(80).blue());
+
+    if response.sessions.is_empty() {
+        println!("  {}", "No active sessions.".dimmed());
+    } else {
+        for session in &response.sessions {
+            let name = session.device_name.as_deref().unwrap_or("Unnamed device");
+            let marker = if session.current { " [this device]".green().to_string() } else { String::new() };
+            println!("  {}{}", name.bold(), marker);
+            println!("    {:<10} {}", "id".dimmed(), session.id);
+            println!("    {:<10} {} on {}", "signed in".dimmed(), session.provider, date_of(&session.created_at));
+            if let Some(platform) = session.platform.as_deref() {
+                println!("    {:<10} {}", "platform".dimmed(), platform);
+            }
+            if let Some(country) = session.ip_country.as_deref() {
+                println!("    {:<10} {}", "country".dimmed(), country);
+            }
+            println!("    {:<10} {}", "last seen".dimmed(), date_of(&session.last_seen_at));
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn revoke(api_url: &str, id: &str) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    client.revoke_session(id).await?;
+    println!("{} Session {} signed out", "✓".green(), id.cyan());
+
+    Ok(())
+}
+
+pub async fn revoke_all(api_url: &str, include_this: bool) -> Result<()> {
+    let config = Config::load()?;
+
+    if !config.is_authenticated() {
+        println!("{}", "You need to authenticate first. Run: pixie auth github".yellow());
+        return Ok(());
+    }
+
+    let client = ApiClient::new(api_url)?;
+    let response = client.revoke_all_sessions(!include_this).await?;
+    println!("{} Signed out {} session(s)", "✓".green(), response.revoked);
+    if response.account_key_revoked {
+        println!("   {}", "Your account key was revoked too.".dimmed());
+    }
+    if include_this {
+        config::logout()?;
+        println!("   {}", "This CLI is signed out. Run 'pixie auth' to sign in again.".dimmed());
+    }
+
+    Ok(())
+}
@@ -35,6 +35,9 @@ fn format_api_error(error: ErrorResponse, operation: &str) -> String {
     
     message.push_str(&format!("\n{} {} failed\n", icon, operation.bold()));
     message.push_str(&format!("   {}\n", error.error.message.red()));
+    if let Some(request_id) = &error.error.request_id {
+        message.push_str(&format!("   Request ID: {} (quote it when reporting this)\n", request_id.dimmed()));
+    }
     
     // Add helpful suggestions based on error code
     match error.error.code.as_deref() {
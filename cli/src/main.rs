@@ -10,7 +10,7 @@ mod commands;
 mod cli;
 mod error_handler;
 
-use cli::{Cli, Commands, AuthProvider, GalleryAction, CreditsAction, AdminAction, AdminCreditsAction};
+use cli::{Cli, Commands, AuthProvider, GalleryAction, CreditsAction, AdminAction, AdminCreditsAction, KeysAction, SessionsAction};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -97,8 +97,13 @@ async fn main() -> Result<()> {
                 None => {
                     commands::credits::show_balance(&api_url).await?;
                 }
-                Some(CreditsAction::History { limit }) => {
-                    commands::credits::show_history(&api_url, limit).await?;
+                Some(CreditsAction::History { limit, export, from, to, types }) => {
+                    match export {
+                        Some(path) => {
+                            commands::credits::export_history(&api_url, &path, from.as_deref(), to.as_deref(), types.as_deref()).await?;
+                        }
+                        None => commands::credits::show_history(&api_url, limit).await?,
+                    }
                 }
                 Some(CreditsAction::Packs) => {
                     commands::credits::show_packs(&api_url).await?;
@@ -106,12 +111,49 @@ async fn main() -> Result<()> {
                 Some(CreditsAction::Estimate { quality, size, number, edit }) => {
                     commands::credits::estimate_cost(&api_url, quality.as_deref(), size.as_deref(), number, edit).await?;
                 }
+                Some(CreditsAction::Redeem { code }) => {
+                    commands::credits::redeem_code(&api_url, &code).await?;
+                }
+                Some(CreditsAction::Referral) => {
+                    commands::credits::show_referral(&api_url).await?;
+                }
                 Some(CreditsAction::Buy { pack, crypto }) => {
                     commands::credits::buy_credits(&api_url, pack.as_deref(), crypto.as_deref()).await?;
                 }
             }
         }
         
+        Commands::Keys { action } => {
+            match action {
+                KeysAction::List { all } => {
+                    commands::keys::list(&api_url, all).await?;
+                }
+                KeysAction::Create { name, scopes, expires_in } => {
+                    commands::keys::create(&api_url, &name, &scopes, expires_in).await?;
+                }
+                KeysAction::Rotate { id } => {
+                    commands::keys::rotate(&api_url, &id).await?;
+                }
+                KeysAction::Revoke { id } => {
+                    commands::keys::revoke(&api_url, &id).await?;
+                }
+            }
+        }
+        
+        Commands::Sessions { action } => {
+            match action {
+                SessionsAction::List => {
+                    commands::sessions::list(&api_url).await?;
+                }
+                SessionsAction::Revoke { id } => {
+                    commands::sessions::revoke(&api_url, &id).await?;
+                }
+                SessionsAction::RevokeAll { include_this } => {
+                    commands::sessions::revoke_all(&api_url, include_this).await?;
+                }
+            }
+        }
+        
         Commands::Admin { action } => {
             match action {
                 AdminAction::Stats => {
@@ -124,8 +166,24 @@ async fn main() -> Result<()> {
                         }
                     }
                 }
-                AdminAction::Grant { user_id } => {
-                    commands::admin::grant_admin(&api_url, &user_id).await?;
+                AdminAction::Grant { user_id, roles, reason } => {
+                    commands::admin::grant_admin(&api_url, &user_id, &roles, &reason).await?;
+                }
+                AdminAction::Roles => {
+                    commands::admin::list_roles(&api_url).await?;
+                }
+                AdminAction::Audit { actor, action, target_id, since, page } => {
+                    let filter = commands::admin::AuditFilter { actor, action, target_id, since };
+                    commands::admin::audit_log(&api_url, &filter, page).await?;
+                }
+                AdminAction::Suspend { user_id, days, reason } => {
+                    commands::admin::set_user_status(&api_url, &user_id, "suspended", days, &reason).await?;
+                }
+                AdminAction::Ban { user_id, reason } => {
+                    commands::admin::set_user_status(&api_url, &user_id, "banned", None, &reason).await?;
+                }
+                AdminAction::Reinstate { user_id, reason } => {
+                    commands::admin::set_user_status(&api_url, &user_id, "active", None, &reason).await?;
                 }
             }
         }
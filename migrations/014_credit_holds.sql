@@ -0,0 +1,28 @@
+-- 014: escrowed credit holds. Metered image calls used to check the balance up
+-- front and only deduct after the provider call and R2 writes, so a failed
+-- deduct left the user with free images. A hold now escrows the estimate
+-- atomically before any provider work (held_credits is subtracted from what can
+-- be spent, the ledger is untouched), the actual cost is captured as a normal
+-- 'spend' at the end, and the remainder is released. Holds that are never
+-- captured or released (worker killed mid-request) are expired by the cron.
+-- A capture records what it owes (captured_amount and the ledger text) before
+-- settling, so a hold whose capture failed part-way is charged by the cron
+-- rather than released.
+ALTER TABLE user_credits ADD COLUMN held_credits INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS credit_holds (
+    id              TEXT PRIMARY KEY,
+    app_id          TEXT NOT NULL,
+    user_id         TEXT NOT NULL,
+    capability      TEXT NOT NULL,
+    amount          INTEGER NOT NULL,
+    captured_amount INTEGER,
+    capture_description  TEXT,
+    capture_reference_id TEXT,
+    status          TEXT NOT NULL DEFAULT 'held' CHECK (status IN ('held', 'captured', 'released', 'expired')),
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at      TIMESTAMP NOT NULL,
+    settled_at      TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_credit_holds_user ON credit_holds(app_id, user_id, status);
+CREATE INDEX IF NOT EXISTS idx_credit_holds_expiry ON credit_holds(status, expires_at);
@@ -0,0 +1,56 @@
+-- 015: expiring promotional credit buckets. Welcome bonuses and admin grants used
+-- to land in the same undifferentiated balance as purchased credits and never
+-- expired. Non-purchased credits now also open a bucket (source, amount, expiry);
+-- spending drains the soonest-expiring bucket first, and the cron expires any
+-- leftover with an 'expire' ledger entry. Whatever the buckets don't cover is
+-- paid credit. Existing balances are not backfilled: everything granted before
+-- this migration is treated as paid and never expires.
+--
+-- The ledger CHECK has to admit the new 'expire' type; SQLite can't alter a
+-- CHECK, so rebuild (create-copy-drop-rename) with foreign_keys OFF as in 005.
+PRAGMA foreign_keys=OFF;
+
+CREATE TABLE credit_transactions_new (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    type TEXT NOT NULL CHECK (type IN ('purchase', 'spend', 'refund', 'bonus', 'admin_adjustment', 'expire')),
+    amount INTEGER NOT NULL,
+    balance_after INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    reference_id TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    app_id TEXT NOT NULL DEFAULT 'pixie',
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+INSERT INTO credit_transactions_new (id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id)
+    SELECT id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id FROM credit_transactions;
+
+DROP TABLE credit_transactions;
+ALTER TABLE credit_transactions_new RENAME TO credit_transactions;
+
+CREATE INDEX idx_credit_transactions_user_id ON credit_transactions(user_id);
+CREATE INDEX idx_credit_transactions_created_at ON credit_transactions(created_at);
+CREATE INDEX idx_credit_transactions_app_user ON credit_transactions(app_id, user_id, created_at);
+
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+CREATE TABLE IF NOT EXISTS credit_buckets (
+    id              TEXT PRIMARY KEY,
+    app_id          TEXT NOT NULL,
+    user_id         TEXT NOT NULL,
+    source          TEXT NOT NULL,
+    original_amount INTEGER NOT NULL,
+    remaining       INTEGER NOT NULL,
+    expired_amount  INTEGER NOT NULL DEFAULT 0,
+    reference_id    TEXT,
+    expires_at      TIMESTAMP,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_credit_buckets_user ON credit_buckets(app_id, user_id, remaining);
+CREATE INDEX IF NOT EXISTS idx_credit_buckets_expiry ON credit_buckets(expires_at, remaining);
+
+-- Lifetime of the welcome bonus (and other promo grants without their own
+-- expiry). NULL = never expires, so existing tenants are unchanged.
+ALTER TABLE apps ADD COLUMN promo_credit_ttl_days INTEGER;
@@ -0,0 +1,40 @@
+-- 016: per-app promo codes, so marketing can hand out credits without an admin
+-- running `admin credits adjust` per person. A code can grant credits (as a
+-- promotional bucket, see 015), discount a credit pack at card checkout, or
+-- both. Codes are stored upper-cased; redemption is case-insensitive.
+CREATE TABLE IF NOT EXISTS promo_codes (
+    app_id                TEXT NOT NULL,
+    code                  TEXT NOT NULL,
+    credits               INTEGER NOT NULL DEFAULT 0,
+    -- NULL = unlimited total redemptions.
+    max_redemptions       INTEGER,
+    per_user_limit        INTEGER NOT NULL DEFAULT 1,
+    redemption_count      INTEGER NOT NULL DEFAULT 0,
+    valid_from            TIMESTAMP,
+    valid_until           TIMESTAMP,
+    -- Optional percent off a pack at checkout; NULL discount_pack_id = any pack.
+    pack_discount_percent INTEGER CHECK (pack_discount_percent IS NULL OR (pack_discount_percent > 0 AND pack_discount_percent < 100)),
+    discount_pack_id      TEXT,
+    -- Lifetime of the granted credits; NULL falls back to apps.promo_credit_ttl_days.
+    credit_ttl_days       INTEGER,
+    enabled               INTEGER NOT NULL DEFAULT 1,
+    created_by            TEXT,
+    created_at            TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (app_id, code)
+);
+
+CREATE TABLE IF NOT EXISTS promo_redemptions (
+    id              TEXT PRIMARY KEY,
+    app_id          TEXT NOT NULL,
+    code            TEXT NOT NULL,
+    user_id         TEXT NOT NULL,
+    credits_granted INTEGER NOT NULL DEFAULT 0,
+    -- Set when the redemption was a pack discount applied to a checkout.
+    purchase_id     TEXT,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_promo_redemptions_user ON promo_redemptions(app_id, code, user_id);
+
+-- The discount code a checkout was priced with; claimed when the purchase
+-- completes, so an abandoned checkout doesn't use it up.
+ALTER TABLE credit_purchases ADD COLUMN promo_code TEXT;
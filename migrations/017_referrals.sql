@@ -0,0 +1,40 @@
+-- 017: two-sided referral program. Every user can get a per-app referral code
+-- (created on first request for it); a new account may attach someone's code at
+-- signup (anonymous register, OAuth, native token auth or device flow). Nothing
+-- is granted at signup: both sides are rewarded with 'bonus' credits only when
+-- the referee completes their first purchase, so throwaway accounts can't farm
+-- credits. Disabled (and zero-reward) unless the tenant opts in. If that
+-- purchase is later refunded or disputed, both rewards are clawed back and the
+-- referral is 'reversed' for good; a dispute the merchant wins re-grants them.
+ALTER TABLE apps ADD COLUMN referral_enabled INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE apps ADD COLUMN referral_referrer_credits INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE apps ADD COLUMN referral_referee_credits INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS referral_codes (
+    app_id     TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    code       TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (app_id, user_id)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_referral_codes_code ON referral_codes(app_id, code);
+
+CREATE TABLE IF NOT EXISTS referrals (
+    id               TEXT PRIMARY KEY,
+    app_id           TEXT NOT NULL,
+    referrer_id      TEXT NOT NULL,
+    referee_id       TEXT NOT NULL,
+    code             TEXT NOT NULL,
+    status           TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'rewarded', 'reversed')),
+    -- Amounts actually granted, recorded at reward time.
+    referrer_credits INTEGER NOT NULL DEFAULT 0,
+    referee_credits  INTEGER NOT NULL DEFAULT 0,
+    -- The referee purchase that triggered the reward.
+    purchase_id      TEXT,
+    created_at       TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    rewarded_at      TIMESTAMP,
+    reversed_at      TIMESTAMP
+);
+-- A user can be referred at most once per app.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_referrals_referee ON referrals(app_id, referee_id);
+CREATE INDEX IF NOT EXISTS idx_referrals_referrer ON referrals(app_id, referrer_id, status);
@@ -0,0 +1,42 @@
+-- 018: Stripe subscriptions with a monthly credit allowance. Plans are seeded per
+-- tenant like credit_packs. Each paid invoice (invoice.paid) records a completed
+-- credit_purchases row (payment_id = invoice id) and grants the plan's allowance
+-- as a 'purchase' ledger entry plus a credit bucket with source 'subscription'
+-- (see 015) that lapses shortly after the billing period ends. At renewal,
+-- unused allowance up to rollover_cap is carried into the new period and the rest
+-- lapses with an 'expire' entry. The grant only lands if the invoice has no
+-- purchase row yet, which makes webhook redelivery a no-op.
+-- customer.subscription.updated/deleted keep status and period in sync.
+CREATE TABLE IF NOT EXISTS subscription_plans (
+    app_id          TEXT NOT NULL,
+    plan_id         TEXT NOT NULL,
+    name            TEXT NOT NULL,
+    description     TEXT NOT NULL DEFAULT '',
+    stripe_price_id TEXT NOT NULL,
+    price_usd_cents INTEGER NOT NULL,
+    monthly_credits INTEGER NOT NULL,
+    -- Max unused allowance carried into the next period; 0 = use it or lose it.
+    rollover_cap    INTEGER NOT NULL DEFAULT 0,
+    enabled         INTEGER NOT NULL DEFAULT 1,
+    sort_order      INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (app_id, plan_id)
+);
+
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id                     TEXT PRIMARY KEY,
+    app_id                 TEXT NOT NULL,
+    user_id                TEXT NOT NULL,
+    plan_id                TEXT NOT NULL,
+    stripe_subscription_id TEXT NOT NULL,
+    stripe_customer_id     TEXT,
+    -- Mirrors the Stripe subscription status (active, past_due, canceled, ...).
+    status                 TEXT NOT NULL,
+    current_period_start   TIMESTAMP,
+    current_period_end     TIMESTAMP,
+    cancel_at_period_end   INTEGER NOT NULL DEFAULT 0,
+    created_at             TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at             TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_subscriptions_stripe ON subscriptions(stripe_subscription_id);
+CREATE INDEX IF NOT EXISTS idx_subscriptions_user ON subscriptions(app_id, user_id, status);
+CREATE INDEX IF NOT EXISTS idx_credit_buckets_reference ON credit_buckets(reference_id);
@@ -0,0 +1,47 @@
+-- 019: refunds, disputes and chargebacks. A refunded or disputed purchase
+-- (Stripe charge.refunded / charge.dispute.created, RevenueCat customer-support
+-- cancellations, NOWPayments 'refunded') is moved to status 'refunded' and its
+-- credits are taken back with a negative 'refund_clawback' ledger entry whose
+-- reference_id is the credit_purchases row. The balance may go negative; under
+-- the tenant's 'freeze' policy the wallet is also frozen so it can't spend again
+-- (even after topping up) until an admin clears frozen_at. A dispute the
+-- merchant wins restores the credits.
+--
+-- The ledger CHECK has to admit 'refund_clawback'; rebuild as in 015.
+PRAGMA foreign_keys=OFF;
+
+CREATE TABLE credit_transactions_new (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    type TEXT NOT NULL CHECK (type IN ('purchase', 'spend', 'refund', 'bonus', 'admin_adjustment', 'expire', 'refund_clawback')),
+    amount INTEGER NOT NULL,
+    balance_after INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    reference_id TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    app_id TEXT NOT NULL DEFAULT 'pixie',
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+INSERT INTO credit_transactions_new (id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id)
+    SELECT id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id FROM credit_transactions;
+
+DROP TABLE credit_transactions;
+ALTER TABLE credit_transactions_new RENAME TO credit_transactions;
+
+CREATE INDEX idx_credit_transactions_user_id ON credit_transactions(user_id);
+CREATE INDEX idx_credit_transactions_created_at ON credit_transactions(created_at);
+CREATE INDEX idx_credit_transactions_app_user ON credit_transactions(app_id, user_id, created_at);
+
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+ALTER TABLE apps ADD COLUMN refund_policy TEXT NOT NULL DEFAULT 'negative_balance'
+    CHECK (refund_policy IN ('negative_balance', 'freeze'));
+
+ALTER TABLE user_credits ADD COLUMN frozen_at TIMESTAMP;
+ALTER TABLE user_credits ADD COLUMN frozen_reason TEXT;
+
+ALTER TABLE credit_purchases ADD COLUMN refunded_at TIMESTAMP;
+ALTER TABLE credit_purchases ADD COLUMN refund_reason TEXT;
+CREATE INDEX IF NOT EXISTS idx_credit_purchases_payment ON credit_purchases(payment_provider, payment_id);
@@ -0,0 +1,31 @@
+-- 020: every verified Stripe, NOWPayments and RevenueCat webhook is stored with
+-- its raw payload before it is processed. (provider, event_id) is unique: a
+-- redelivery of an event that was already processed is acknowledged without
+-- running it again, while a redelivery of a failed event retries it. Admins can
+-- list failed events and replay them from the stored payload.
+--
+-- event_id is the provider's event id (Stripe evt_..., RevenueCat event id); for
+-- NOWPayments, which has none, it is "<payment_id>:<payment_status>". app_id is
+-- filled in when the event can be tied to a tenant on receipt and is NULL
+-- otherwise (e.g. a dispute on a charge we can't trace); only platform admins
+-- see those.
+--
+-- claimed_at is when the current attempt started. A row left in 'processing'
+-- past the timeout (the worker died mid-event) is claimed again like a failed
+-- one, so it can't wedge the event forever.
+CREATE TABLE IF NOT EXISTS webhook_events (
+    id           TEXT PRIMARY KEY,
+    provider     TEXT NOT NULL CHECK (provider IN ('stripe', 'nowpayments', 'revenuecat')),
+    event_id     TEXT NOT NULL,
+    event_type   TEXT NOT NULL,
+    app_id       TEXT,
+    payload      TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'processing' CHECK (status IN ('processing', 'processed', 'failed')),
+    error        TEXT,
+    attempts     INTEGER NOT NULL DEFAULT 1,
+    received_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    claimed_at   TIMESTAMP,
+    processed_at TIMESTAMP
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_webhook_events_provider_event ON webhook_events(provider, event_id);
+CREATE INDEX IF NOT EXISTS idx_webhook_events_app_status ON webhook_events(app_id, status, received_at);
@@ -0,0 +1,41 @@
+-- 021: daily ledger reconciliation (see reconciliation.rs). Every wallet across
+-- all tenants is checked against credit_transactions:
+--   balance            = SUM(amount)
+--   lifetime_purchased = SUM(amount) of 'purchase' + 'refund_clawback' (floored at 0)
+--   lifetime_spent     = -SUM(amount) of 'spend'
+-- and each entry's balance_after must equal the previous entry's balance_after
+-- plus its amount (in insertion order). Mismatches are recorded as open
+-- discrepancies; one that a later run no longer detects is resolved
+-- automatically. fingerprint identifies the same discrepancy across runs.
+CREATE TABLE IF NOT EXISTS ledger_reconciliation_runs (
+    id                   TEXT PRIMARY KEY,
+    started_at           TIMESTAMP NOT NULL,
+    finished_at          TIMESTAMP,
+    status               TEXT NOT NULL DEFAULT 'running' CHECK (status IN ('running', 'completed', 'failed')),
+    wallets_checked      INTEGER NOT NULL DEFAULT 0,
+    transactions_checked INTEGER NOT NULL DEFAULT 0,
+    discrepancies        INTEGER NOT NULL DEFAULT 0,
+    error                TEXT
+);
+
+CREATE TABLE IF NOT EXISTS ledger_discrepancies (
+    id               TEXT PRIMARY KEY,
+    fingerprint      TEXT NOT NULL,
+    app_id           TEXT NOT NULL,
+    user_id          TEXT NOT NULL,
+    kind             TEXT NOT NULL CHECK (kind IN ('balance', 'lifetime_purchased', 'lifetime_spent', 'balance_chain')),
+    -- What the ledger says vs. what is stored (wallet column, or balance_after for chain breaks).
+    expected         INTEGER NOT NULL,
+    actual           INTEGER NOT NULL,
+    -- The credit_transactions row where a balance_after chain breaks.
+    transaction_id   TEXT,
+    first_run_id     TEXT NOT NULL,
+    last_run_id      TEXT NOT NULL,
+    detected_at      TIMESTAMP NOT NULL,
+    last_seen_at     TIMESTAMP NOT NULL,
+    resolved_at      TIMESTAMP,
+    resolution_note  TEXT
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ledger_discrepancies_open
+    ON ledger_discrepancies(fingerprint) WHERE resolved_at IS NULL;
+CREATE INDEX IF NOT EXISTS idx_ledger_discrepancies_app ON ledger_discrepancies(app_id, resolved_at, detected_at);
@@ -0,0 +1,51 @@
+-- 022: user-to-user credit transfers within an app (POST /v1/credits/transfer).
+-- A transfer debits the sender with a 'transfer_out' entry and credits the
+-- recipient with a 'transfer_in' entry; both carry the credit_transfers id as
+-- reference_id. Only paid credits move: promotional buckets and escrowed holds
+-- stay with the sender, and the recipient receives paid credits. Tenants opt in
+-- with apps.transfers_enabled; transfer_daily_limit caps what one sender can
+-- send in a rolling 24 hours (NULL = no cap).
+--
+-- The ledger CHECK has to admit the two new types; rebuild as in 015.
+PRAGMA foreign_keys=OFF;
+
+CREATE TABLE credit_transactions_new (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    type TEXT NOT NULL CHECK (type IN ('purchase', 'spend', 'refund', 'bonus', 'admin_adjustment', 'expire', 'refund_clawback', 'transfer_out', 'transfer_in')),
+    amount INTEGER NOT NULL,
+    balance_after INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    reference_id TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    app_id TEXT NOT NULL DEFAULT 'pixie',
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+INSERT INTO credit_transactions_new (id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id)
+    SELECT id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id FROM credit_transactions;
+
+DROP TABLE credit_transactions;
+ALTER TABLE credit_transactions_new RENAME TO credit_transactions;
+
+CREATE INDEX idx_credit_transactions_user_id ON credit_transactions(user_id);
+CREATE INDEX idx_credit_transactions_created_at ON credit_transactions(created_at);
+CREATE INDEX idx_credit_transactions_app_user ON credit_transactions(app_id, user_id, created_at);
+
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+ALTER TABLE apps ADD COLUMN transfers_enabled INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE apps ADD COLUMN transfer_daily_limit INTEGER;
+
+CREATE TABLE IF NOT EXISTS credit_transfers (
+    id           TEXT PRIMARY KEY,
+    app_id       TEXT NOT NULL,
+    sender_id    TEXT NOT NULL,
+    recipient_id TEXT NOT NULL,
+    amount       INTEGER NOT NULL CHECK (amount > 0),
+    note         TEXT,
+    created_at   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_credit_transfers_sender ON credit_transfers(app_id, sender_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_credit_transfers_recipient ON credit_transfers(app_id, recipient_id, created_at);
@@ -0,0 +1,65 @@
+-- 023: organisation wallets shared by team members. An org's wallet is an
+-- ordinary user_credits row keyed by (app_id, org id), so holds, buckets,
+-- freezing and reconciliation all apply to it unchanged. Members charge it by
+-- sending X-Org-ID; the ledger row (and the hold) then carries the wallet in
+-- user_id and the spending member in member_id. Members fund the wallet by
+-- moving paid credits into it (a transfer_out/transfer_in pair).
+--
+-- Ledger rows are now keyed by wallet, which may be an org rather than a user,
+-- so the rebuild drops the users foreign key and adds member_id.
+PRAGMA foreign_keys=OFF;
+
+CREATE TABLE credit_transactions_new (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    type TEXT NOT NULL CHECK (type IN ('purchase', 'spend', 'refund', 'bonus', 'admin_adjustment', 'expire', 'refund_clawback', 'transfer_out', 'transfer_in')),
+    amount INTEGER NOT NULL,
+    balance_after INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    reference_id TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    app_id TEXT NOT NULL DEFAULT 'pixie',
+    -- The org member who spent, when user_id is an org wallet.
+    member_id TEXT
+);
+
+INSERT INTO credit_transactions_new (id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id)
+    SELECT id, user_id, type, amount, balance_after, description, reference_id, created_at, app_id FROM credit_transactions;
+
+DROP TABLE credit_transactions;
+ALTER TABLE credit_transactions_new RENAME TO credit_transactions;
+
+CREATE INDEX idx_credit_transactions_user_id ON credit_transactions(user_id);
+CREATE INDEX idx_credit_transactions_created_at ON credit_transactions(created_at);
+CREATE INDEX idx_credit_transactions_app_user ON credit_transactions(app_id, user_id, created_at);
+CREATE INDEX idx_credit_transactions_member ON credit_transactions(app_id, user_id, member_id, created_at)
+    WHERE member_id IS NOT NULL;
+
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
+
+ALTER TABLE credit_holds ADD COLUMN member_id TEXT;
+-- Realtime reservations are settled later; remember which wallet paid.
+ALTER TABLE realtime_sessions ADD COLUMN org_id TEXT;
+
+CREATE TABLE IF NOT EXISTS orgs (
+    id         TEXT PRIMARY KEY,
+    app_id     TEXT NOT NULL,
+    name       TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_orgs_app ON orgs(app_id);
+
+CREATE TABLE IF NOT EXISTS org_members (
+    org_id            TEXT NOT NULL,
+    app_id            TEXT NOT NULL,
+    user_id           TEXT NOT NULL,
+    role              TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('owner', 'admin', 'member')),
+    -- Max credits this member may spend from the org wallet per calendar month (UTC); NULL = no cap.
+    monthly_spend_cap INTEGER CHECK (monthly_spend_cap IS NULL OR monthly_spend_cap >= 0),
+    joined_at         TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (org_id, user_id),
+    FOREIGN KEY (org_id) REFERENCES orgs(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_org_members_user ON org_members(app_id, user_id);
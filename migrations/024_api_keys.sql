@@ -0,0 +1,20 @@
+-- 024: named, scoped API keys (GET/POST /v1/keys). A user can hold several,
+-- each limited to a set of scopes (see auth::SCOPES; '*' = everything), with
+-- an optional expiry. Revoking sets revoked_at; rotating swaps the secret in
+-- place, so the id, name and scopes survive. users.api_key stays the account
+-- key the login flows hand out, with full access.
+CREATE TABLE IF NOT EXISTS api_keys (
+    id           TEXT PRIMARY KEY,
+    app_id       TEXT NOT NULL,
+    user_id      TEXT NOT NULL,
+    name         TEXT NOT NULL,
+    key          TEXT NOT NULL UNIQUE,
+    -- Comma-separated scopes.
+    scopes       TEXT NOT NULL,
+    expires_at   TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at   TIMESTAMP,
+    created_at   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_api_keys_user ON api_keys(app_id, user_id, created_at);
@@ -0,0 +1,78 @@
+-- 025: stop storing API keys in plaintext. users and api_keys gain
+-- `*_prefix` (the first 12 characters, used for lookup and display) and
+-- `*_hash` (hex SHA-256 of the whole key, compared in constant time). D1 has
+-- no SHA-256, so existing rows keep their plaintext until their next
+-- successful authentication, which writes prefix + hash and NULLs the
+-- plaintext column. That needs the column nullable, hence the rebuilds (same
+-- foreign_keys=OFF procedure as 005).
+--
+-- A hashed account key can't be handed out again, so signing in as an
+-- existing user now issues a fresh full-access key into api_keys with
+-- source = 'login' instead of returning users.api_key. Those don't count
+-- towards the per-user cap on keys created through /v1/keys.
+PRAGMA foreign_keys=OFF;
+
+CREATE TABLE users_new (
+    id TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    provider_id TEXT NOT NULL,
+    email TEXT,
+    name TEXT,
+    -- Legacy plaintext key; NULL once hashed.
+    api_key TEXT UNIQUE,
+    api_key_prefix TEXT,
+    api_key_hash TEXT,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    preferred_model TEXT NOT NULL DEFAULT 'gemini-2.5-flash',
+    gemini_api_key TEXT,
+    openai_api_key TEXT,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    app_id TEXT NOT NULL DEFAULT 'pixie'
+);
+
+INSERT INTO users_new (id, provider, provider_id, email, name, api_key, is_admin, preferred_model, gemini_api_key, openai_api_key, created_at, updated_at, app_id)
+    SELECT id, provider, provider_id, email, name, api_key, is_admin, preferred_model, gemini_api_key, openai_api_key, created_at, updated_at, app_id FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE INDEX idx_users_api_key ON users(api_key);
+CREATE INDEX idx_users_provider ON users(provider, provider_id);
+CREATE UNIQUE INDEX idx_users_app_provider ON users(app_id, provider, provider_id);
+CREATE INDEX idx_users_app_api_key ON users(app_id, api_key);
+CREATE INDEX idx_users_app_api_key_prefix ON users(app_id, api_key_prefix);
+CREATE UNIQUE INDEX idx_users_api_key_hash ON users(api_key_hash) WHERE api_key_hash IS NOT NULL;
+
+CREATE TABLE api_keys_new (
+    id           TEXT PRIMARY KEY,
+    app_id       TEXT NOT NULL,
+    user_id      TEXT NOT NULL,
+    name         TEXT NOT NULL,
+    -- Legacy plaintext key; NULL once hashed.
+    key          TEXT UNIQUE,
+    key_prefix   TEXT,
+    key_hash     TEXT,
+    -- Comma-separated scopes.
+    scopes       TEXT NOT NULL,
+    -- 'user' (POST /v1/keys) or 'login' (issued by a sign-in flow).
+    source       TEXT NOT NULL DEFAULT 'user' CHECK (source IN ('user', 'login')),
+    expires_at   TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at   TIMESTAMP,
+    created_at   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO api_keys_new (id, app_id, user_id, name, key, scopes, expires_at, last_used_at, revoked_at, created_at)
+    SELECT id, app_id, user_id, name, key, scopes, expires_at, last_used_at, revoked_at, created_at FROM api_keys;
+
+DROP TABLE api_keys;
+ALTER TABLE api_keys_new RENAME TO api_keys;
+
+CREATE INDEX idx_api_keys_user ON api_keys(app_id, user_id, created_at);
+CREATE INDEX idx_api_keys_prefix ON api_keys(app_id, key_prefix);
+CREATE UNIQUE INDEX idx_api_keys_hash ON api_keys(key_hash) WHERE key_hash IS NOT NULL;
+
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
@@ -0,0 +1,22 @@
+-- 026: short-lived access tokens + rotating refresh tokens. Sign-in flows
+-- called with `X-Auth-Mode: tokens` return a signed access token (HS256 over
+-- models::Claims, JWT_SECRET) and a refresh token instead of an API
+-- key. POST /v1/auth/token/refresh trades a refresh token for a new pair and
+-- marks the old one used; every token from one sign-in shares a family_id.
+-- Presenting a used token again is treated as theft and revokes the whole
+-- family, which also stops its access tokens (authenticate checks the family).
+-- Only the SHA-256 of a refresh token is stored.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id          TEXT PRIMARY KEY,
+    app_id      TEXT NOT NULL,
+    user_id     TEXT NOT NULL,
+    family_id   TEXT NOT NULL,
+    token_hash  TEXT NOT NULL UNIQUE,
+    expires_at  TIMESTAMP NOT NULL,
+    used_at     TIMESTAMP,
+    revoked_at  TIMESTAMP,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family ON refresh_tokens(family_id);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_user ON refresh_tokens(app_id, user_id);
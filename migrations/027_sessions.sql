@@ -0,0 +1,38 @@
+-- 027: one session per sign-in (GET/DELETE /v1/sessions). A session is what
+-- a sign-in handed out: with X-Auth-Mode: tokens its refresh-token family
+-- (family_id = sessions.id), otherwise a login key in api_keys carrying
+-- session_id. Revoking a session revokes both, and authenticate rejects
+-- access tokens and login keys of a revoked session. Sign-in flows no longer
+-- hand out the account key itself (users.api_key_*); it only keeps working
+-- for users who already hold it, until they sign out everywhere.
+CREATE TABLE IF NOT EXISTS sessions (
+    id           TEXT PRIMARY KEY,
+    app_id       TEXT NOT NULL,
+    user_id      TEXT NOT NULL,
+    -- Sign-in method, e.g. 'GitHub', 'Apple', 'Device'.
+    provider     TEXT NOT NULL,
+    device_name  TEXT,
+    platform     TEXT,
+    -- Two-letter country from Cloudflare's CF-IPCountry at sign-in.
+    ip_country   TEXT,
+    created_at   TIMESTAMP NOT NULL,
+    last_seen_at TIMESTAMP NOT NULL,
+    revoked_at   TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(app_id, user_id, last_seen_at);
+
+ALTER TABLE api_keys ADD COLUMN session_id TEXT;
+CREATE INDEX IF NOT EXISTS idx_api_keys_session ON api_keys(session_id);
+
+-- Backfill: each existing login key and refresh-token family becomes a session.
+INSERT INTO sessions (id, app_id, user_id, provider, created_at, last_seen_at, revoked_at)
+    SELECT id, app_id, user_id, REPLACE(name, ' sign-in', ''), created_at,
+           COALESCE(last_used_at, created_at), revoked_at
+    FROM api_keys WHERE source = 'login';
+UPDATE api_keys SET session_id = id WHERE source = 'login';
+
+INSERT INTO sessions (id, app_id, user_id, provider, created_at, last_seen_at, revoked_at)
+    SELECT family_id, app_id, user_id, 'unknown', MIN(created_at), MAX(created_at),
+           CASE WHEN COUNT(revoked_at) = COUNT(*) THEN MAX(revoked_at) END
+    FROM refresh_tokens GROUP BY family_id, app_id, user_id;
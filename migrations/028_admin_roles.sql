@@ -0,0 +1,31 @@
+-- 028: admin roles. users.is_admin unlocked every admin endpoint for its app;
+-- access is now granted per app by role (see src/admin.rs for what each role
+-- may do):
+--   support    look up users, grant small credit adjustments
+--   finance    stats, exports, webhook events, ledger, promo codes, wallets
+--   moderator  image reports and visibility overrides
+--   owner      everything in the app, including granting roles
+-- Platform admins hold every permission in every app and may act on another
+-- tenant with X-Target-App-ID. They are added by hand:
+--   INSERT INTO platform_admins (user_id, created_at) VALUES ('<user id>', datetime('now'));
+--
+-- users.is_admin stays as "has any admin role" for clients that show admin
+-- screens; existing admins become owners of their app.
+CREATE TABLE IF NOT EXISTS admin_roles (
+    app_id     TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    role       TEXT NOT NULL CHECK (role IN ('support', 'finance', 'moderator', 'owner')),
+    granted_by TEXT,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (app_id, user_id, role),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS platform_admins (
+    user_id    TEXT PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT OR IGNORE INTO admin_roles (app_id, user_id, role, granted_by, created_at)
+    SELECT app_id, id, 'owner', NULL, datetime('now') FROM users WHERE is_admin = 1;
@@ -0,0 +1,24 @@
+-- 029: a structured trail of admin actions (GET /v1/admin/audit-log). One row
+-- per privileged request: who did it (and whether as a platform admin), what,
+-- to which target, the values before and after (JSON), the reason given and
+-- the request IP. Rows are only ever inserted.
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+    id             TEXT PRIMARY KEY,
+    -- The app that was administered.
+    app_id         TEXT NOT NULL,
+    actor_user_id  TEXT NOT NULL,
+    platform_admin INTEGER NOT NULL DEFAULT 0,
+    -- e.g. 'credits.adjust', 'roles.set', 'users.search'.
+    action         TEXT NOT NULL,
+    -- e.g. 'user', 'image', 'promo_code'; NULL when there is no single target.
+    target_type    TEXT,
+    target_id      TEXT,
+    before_value   TEXT,
+    after_value    TEXT,
+    reason         TEXT,
+    ip             TEXT,
+    created_at     TIMESTAMP NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_admin_audit_app_time ON admin_audit_log(app_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_admin_audit_actor ON admin_audit_log(app_id, actor_user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_admin_audit_target ON admin_audit_log(app_id, target_type, target_id);
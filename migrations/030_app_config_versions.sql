@@ -0,0 +1,18 @@
+-- 030: versioned tenant configuration. Platform admins now create and edit
+-- apps, credit_packs and capability_costs through /v1/admin/apps instead of
+-- seed migrations like 007/008 or ad-hoc UPDATEs against prod (see 013). Each
+-- change stores the full resulting config, the diff against what was live
+-- before it, who made it and why.
+--
+-- Apps seeded by migrations have no history until their first change through
+-- the API; that change's diff shows any drift from what the migrations set.
+CREATE TABLE IF NOT EXISTS app_config_versions (
+    app_id        TEXT NOT NULL,
+    version       INTEGER NOT NULL,
+    config        TEXT NOT NULL,
+    diff          TEXT NOT NULL,
+    actor_user_id TEXT NOT NULL,
+    reason        TEXT NOT NULL,
+    created_at    TIMESTAMP NOT NULL,
+    PRIMARY KEY (app_id, version)
+);
@@ -0,0 +1,34 @@
+-- 031: suspending and banning users. Until now the only way to stop an abusive
+-- user was deleting their row, after which the same device could sign up again
+-- and collect another welcome grant.
+--
+--   active     the default
+--   suspended  every request is refused until status_expires_at (or until
+--              lifted, when it has none); the user's sign-ins are kept
+--   banned     refused the same way, sign-ins revoked, and the devices the
+--              user registered from can't create a new anonymous account
+--
+-- A suspension past its expiry counts as active; the row is left as it was.
+ALTER TABLE users ADD COLUMN status TEXT NOT NULL DEFAULT 'active'
+    CHECK (status IN ('active', 'suspended', 'banned'));
+ALTER TABLE users ADD COLUMN status_reason TEXT;
+ALTER TABLE users ADD COLUMN status_expires_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN status_changed_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN status_changed_by TEXT;
+
+-- Linking an anonymous account to Apple replaces provider_id, so the device it
+-- signed up from is kept here for bans.
+ALTER TABLE users ADD COLUMN signup_device_id TEXT;
+UPDATE users SET signup_device_id = provider_id WHERE provider = 'anonymous';
+
+CREATE TABLE IF NOT EXISTS banned_devices (
+    app_id     TEXT NOT NULL,
+    device_id  TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    reason     TEXT,
+    banned_by  TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (app_id, device_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_banned_devices_user ON banned_devices(app_id, user_id);
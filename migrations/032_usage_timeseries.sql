@@ -0,0 +1,4 @@
+-- 032: usage analytics read usage_records by app and time range
+-- (/v1/usage/system, /v1/usage/timeseries); the existing (app_id, user_id,
+-- created_at) index only helps per-user lookups.
+CREATE INDEX IF NOT EXISTS idx_usage_records_app_created ON usage_records(app_id, created_at);
@@ -0,0 +1,45 @@
+-- 033: Prometheus metrics (GET /metrics). The endpoint only reads the rollup
+-- tables below; the scheduled handler (metrics::rollup, on METRICS_CRON)
+-- folds new rows from request_events, usage_records and credit_purchases into
+-- cumulative counters and snapshots the gauges.
+--
+-- request_events is a raw, append-only queue drained by every rollup, so it
+-- stays small. To keep a write off most requests it is sampled: server errors
+-- are always queued, other requests one in N with weight N, and scrapes of
+-- /metrics itself never. The rollup counts requests as SUM(weight).
+CREATE TABLE IF NOT EXISTS request_events (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    method      TEXT NOT NULL,
+    route       TEXT NOT NULL,
+    status      INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    weight      INTEGER NOT NULL DEFAULT 1,
+    created_at  TIMESTAMP NOT NULL
+);
+
+-- One row per series: `labels` is the rendered Prometheus label set, e.g.
+-- provider="openai",le="1000". Values only ever grow.
+CREATE TABLE IF NOT EXISTS metrics_counters (
+    name       TEXT NOT NULL,
+    labels     TEXT NOT NULL DEFAULT '',
+    value      REAL NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (name, labels)
+);
+
+-- Replaced wholesale by each rollup.
+CREATE TABLE IF NOT EXISTS metrics_gauges (
+    name       TEXT NOT NULL,
+    labels     TEXT NOT NULL DEFAULT '',
+    value      REAL NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (name, labels)
+);
+
+-- How far each time-ordered source has been folded in (its created_at or
+-- completed_at watermark).
+CREATE TABLE IF NOT EXISTS metrics_cursors (
+    source     TEXT PRIMARY KEY,
+    last_seen  TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,9 @@
+-- 034: request ids (X-Request-ID) on the rows a request writes, so one id a
+-- user quotes from an error leads to its usage row, its ledger entries and
+-- its log lines. Rows written outside a request (cron sweeps, older rows)
+-- have none.
+ALTER TABLE usage_records ADD COLUMN request_id TEXT;
+ALTER TABLE credit_transactions ADD COLUMN request_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_usage_records_request_id ON usage_records(request_id);
+CREATE INDEX IF NOT EXISTS idx_credit_transactions_request_id ON credit_transactions(request_id);
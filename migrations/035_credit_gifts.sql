@@ -0,0 +1,17 @@
+-- 035: bulk admin gifts (POST /v1/admin/credits/gift) are keyed by a
+-- caller-chosen idempotency key, so a retried request doesn't gift everyone a
+-- second time. The gift id is the reference_id of every ledger entry and
+-- bucket it creates; a retry of an interrupted gift only grants the recipients
+-- that have no bucket for it yet. `result` holds the response once the gift
+-- has run, and is returned as-is to later requests with the same key.
+CREATE TABLE IF NOT EXISTS credit_gifts (
+    id              TEXT PRIMARY KEY,
+    app_id          TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    actor_id        TEXT NOT NULL,
+    amount          INTEGER NOT NULL CHECK (amount > 0),
+    result          TEXT,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at    TIMESTAMP
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_credit_gifts_key ON credit_gifts(app_id, idempotency_key);
@@ -0,0 +1,402 @@
+use worker::{D1Database, Env, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::Utc;
+use crate::audit::{self, AuditEvent};
+use crate::auth;
+use crate::error::AppError;
+
+pub const ADMIN_ROLES: &[&str] = &["support", "finance", "moderator", "owner"];
+
+/// Largest credit grant a support agent may make in one request, counted
+/// across all recipients of a gift. Owners and platform admins have no limit.
+pub const SUPPORT_GRANT_LIMIT: u64 = 500;
+
+/// Most credits one support agent may grant in an app over a rolling 24
+/// hours, so the per-request limit can't be sidestepped with many requests.
+pub const SUPPORT_DAILY_GRANT_LIMIT: u64 = 2_000;
+
+/// Credits an actor granted, per audit log row: positive adjustments, and a
+/// gift's amount times the recipients it reached.
+const GRANTED_SQL: &str = "CASE g.action
+    WHEN 'credits.adjust' THEN MAX(COALESCE(json_extract(g.after_value, '$.adjustment'), 0), 0)
+    WHEN 'credits.gift' THEN COALESCE(json_extract(g.after_value, '$.amount'), 0)
+                             * COALESCE(json_array_length(g.after_value, '$.granted'), 0)
+    ELSE 0 END";
+
+/// What an admin endpoint needs. Every admin handler asks for exactly one;
+/// which roles hold it is decided here and nowhere else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Search and look up users.
+    ReadUsers,
+    /// Credit adjustments and gifts, capped for support.
+    GrantCredits,
+    /// Stats, exports, webhook events, ledger discrepancies, promo codes.
+    ReadFinance,
+    /// Promo codes, webhook replays, resolving discrepancies, unfreezing wallets.
+    ManageFinance,
+    /// Image reports, visibility overrides, suspensions and bans.
+    Moderate,
+    /// Granting and removing admin roles.
+    ManageRoles,
+    /// Reading the admin audit log.
+    ReadAudit,
+    /// Creating apps and changing their config, packs and pricing. No app
+    /// role holds this; it is for platform admins only.
+    ManageTenants,
+}
+
+impl Permission {
+    /// The app roles holding this permission. Platform admins hold them all.
+    pub fn roles(self) -> &'static [&'static str] {
+        match self {
+            Permission::ReadUsers => &["support", "owner"],
+            Permission::GrantCredits => &["support", "owner"],
+            Permission::ReadFinance => &["finance", "owner"],
+            Permission::ManageFinance => &["finance", "owner"],
+            Permission::Moderate => &["moderator", "owner"],
+            Permission::ManageRoles => &["owner"],
+            Permission::ReadAudit => &["owner"],
+            Permission::ManageTenants => &[],
+        }
+    }
+}
+
+/// An authorised admin request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminContext {
+    pub user_id: String,
+    /// The app being administered: the caller's own, or for a platform admin
+    /// the one named by `X-Target-App-ID`.
+    pub app_id: String,
+    /// The caller's roles in their own app.
+    pub roles: Vec<String>,
+    pub platform_admin: bool,
+    /// The request's client IP, for the audit log.
+    pub ip: Option<String>,
+}
+
+impl AdminContext {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.platform_admin || permission.roles().iter().any(|r| self.has_role(r))
+    }
+
+    /// Whether the caller's grants are capped (support without owner).
+    pub fn grant_limited(&self) -> bool {
+        !(self.platform_admin || self.has_role("owner"))
+    }
+
+    /// Rejects a grant of `credits` above the caller's per-request limit. The
+    /// daily limit is enforced as the grant is recorded (`grant_audit_statement`).
+    pub fn check_grant(&self, credits: u64) -> Result<(), AppError> {
+        if self.grant_limited() && credits > SUPPORT_GRANT_LIMIT {
+            return Err(AppError::Forbidden(format!(
+                "Support can grant at most {} credits per request; ask an app owner",
+                SUPPORT_GRANT_LIMIT
+            )));
+        }
+        Ok(())
+    }
+
+    /// The audit row `event` for a grant of `credits`, as an insert that only
+    /// goes through while the caller's grants in this app over the last 24
+    /// hours, this one included, stay within the daily limit (always, for
+    /// uncapped callers). The row is what counts the grant against the limit,
+    /// so the grant must be conditioned on it: run this first in the grant's
+    /// batch and condition the grant's writes on `audit::RECORDED_SQL`.
+    pub fn grant_audit_statement(
+        &self,
+        event: &AuditEvent,
+        id: &str,
+        credits: u64,
+        db: &D1Database,
+    ) -> worker::Result<worker::D1PreparedStatement> {
+        let since = (Utc::now() - chrono::Duration::hours(24)).to_rfc3339();
+        audit::conditional_insert(
+            self,
+            event,
+            id,
+            &within_daily_grant_limit_sql(),
+            vec![
+                (if self.grant_limited() { 1 } else { 0 }).into(),
+                since.into(),
+                (credits as f64).into(),
+                (SUPPORT_DAILY_GRANT_LIMIT as f64).into(),
+            ],
+            db,
+        )
+    }
+
+    /// The error for a grant whose audit row `grant_audit_statement` refused.
+    pub async fn daily_limit_error(&self, db: &D1Database) -> AppError {
+        let left = match self.granted_last_24h(db).await {
+            Ok(granted) => SUPPORT_DAILY_GRANT_LIMIT.saturating_sub(granted),
+            Err(e) => return e,
+        };
+        AppError::Forbidden(format!(
+            "Support can grant at most {} credits in 24 hours ({} left); ask an app owner",
+            SUPPORT_DAILY_GRANT_LIMIT, left
+        ))
+    }
+
+    /// Credits the caller granted in this app over the last 24 hours.
+    pub async fn granted_last_24h(&self, db: &D1Database) -> Result<u64, AppError> {
+        let since = (Utc::now() - chrono::Duration::hours(24)).to_rfc3339();
+        let row = db
+            .prepare(format!("SELECT {} AS granted", granted_since_sql("?1")))
+            .bind(&[since.into(), self.app_id.clone().into(), self.user_id.clone().into()])?
+            .first::<Value>(None)
+            .await?;
+        Ok(row.and_then(|r| r.get("granted").and_then(|v| v.as_i64())).unwrap_or(0).max(0) as u64)
+    }
+}
+
+/// Credits granted by actor ?3 in app ?2 since the bound parameter, from
+/// the audit log.
+fn granted_since_sql(since_param: &str) -> String {
+    format!(
+        "(SELECT COALESCE(SUM({}), 0) FROM admin_audit_log g
+          WHERE g.app_id = ?2 AND g.actor_user_id = ?3 AND g.created_at >= {}
+            AND g.action IN ('credits.adjust', 'credits.gift'))",
+        GRANTED_SQL, since_param
+    )
+}
+
+/// The condition `grant_audit_statement` writes its row under: ?13 is whether
+/// the caller is capped, ?14 the start of the window, ?15 the grant and ?16
+/// the daily limit. ?2 and ?3 are the row's app and actor.
+fn within_daily_grant_limit_sql() -> String {
+    format!("(?13 = 0 OR {} + ?15 <= ?16)", granted_since_sql("?14"))
+}
+
+/// Authenticates the request and resolves the caller's admin roles. Fails
+/// unless the caller holds at least one role or is a platform admin.
+pub async fn admin_context(req: &Request, env: &Env, db: &D1Database) -> Result<AdminContext, AppError> {
+    let auth = auth::authenticate(req, env, db).await?;
+    let platform_admin = db
+        .prepare("SELECT 1 FROM platform_admins WHERE user_id = ?1")
+        .bind(&[auth.user_id.clone().into()])?
+        .first::<Value>(None)
+        .await?
+        .is_some();
+    let roles = roles_of(&auth.app_id, &auth.user_id, db).await?;
+    if !platform_admin && roles.is_empty() {
+        return Err(AppError::Forbidden("Admin access required".to_string()));
+    }
+
+    let target = req
+        .headers()
+        .get("X-Target-App-ID")
+        .ok()
+        .flatten()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty() && *v != auth.app_id);
+    let app_id = match target {
+        Some(target) => {
+            if !platform_admin {
+                return Err(AppError::Forbidden("Only platform admins can administer other apps".to_string()));
+            }
+            db.prepare("SELECT 1 FROM apps WHERE app_id = ?1")
+                .bind(&[target.clone().into()])?
+                .first::<Value>(None)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("App {} not found", target)))?;
+            target
+        }
+        None => auth.app_id,
+    };
+
+    let ip = req.headers().get("CF-Connecting-IP").ok().flatten();
+    Ok(AdminContext { user_id: auth.user_id, app_id, roles, platform_admin, ip })
+}
+
+/// The central admin check: `admin_context` plus `permission`.
+pub async fn authorize(
+    req: &Request,
+    env: &Env,
+    db: &D1Database,
+    permission: Permission,
+) -> Result<AdminContext, AppError> {
+    let admin = admin_context(req, env, db).await?;
+    if !admin.can(permission) {
+        if permission.roles().is_empty() {
+            return Err(AppError::Forbidden("This action is for platform admins only".to_string()));
+        }
+        return Err(AppError::Forbidden(format!(
+            "This action needs one of the admin roles: {}",
+            permission.roles().join(", ")
+        )));
+    }
+    Ok(admin)
+}
+
+pub async fn roles_of(app_id: &str, user_id: &str, db: &D1Database) -> Result<Vec<String>, AppError> {
+    let rows = db
+        .prepare("SELECT role FROM admin_roles WHERE app_id = ?1 AND user_id = ?2 ORDER BY role")
+        .bind(&[app_id.into(), user_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .iter()
+        .filter_map(|r| r.get("role").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .collect())
+}
+
+/// A user holding admin roles in an app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleHolder {
+    pub user_id: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+}
+
+pub async fn list_role_holders(app_id: &str, db: &D1Database) -> Result<Vec<RoleHolder>, AppError> {
+    let rows = db
+        .prepare(
+            "SELECT r.user_id, u.email, r.role FROM admin_roles r JOIN users u ON u.id = r.user_id
+             WHERE r.app_id = ?1 ORDER BY r.user_id, r.role",
+        )
+        .bind(&[app_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+
+    let mut holders: Vec<RoleHolder> = Vec::new();
+    for row in rows {
+        let user_id = row.get("user_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let role = row.get("role").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        match holders.last_mut() {
+            Some(last) if last.user_id == user_id => last.roles.push(role),
+            _ => holders.push(RoleHolder {
+                user_id,
+                email: row.get("email").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                roles: vec![role],
+            }),
+        }
+    }
+    Ok(holders)
+}
+
+/// Replaces a user's roles in an app and keeps `users.is_admin` in step.
+/// Refuses to leave the app without an owner.
+pub async fn set_roles(
+    app_id: &str,
+    user_id: &str,
+    roles: &[String],
+    granted_by: &str,
+    db: &D1Database,
+) -> Result<Vec<String>, AppError> {
+    let mut roles: Vec<String> = roles.iter().map(|r| r.trim().to_lowercase()).collect();
+    roles.sort();
+    roles.dedup();
+    if let Some(bad) = roles.iter().find(|r| !ADMIN_ROLES.contains(&r.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown role '{}'. Valid roles: {}",
+            bad,
+            ADMIN_ROLES.join(", ")
+        )));
+    }
+
+    db.prepare("SELECT 1 FROM users WHERE app_id = ?1 AND id = ?2")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found in this app".to_string()))?;
+
+    if !roles.iter().any(|r| r == "owner") {
+        let other_owners = db
+            .prepare("SELECT COUNT(*) AS n FROM admin_roles WHERE app_id = ?1 AND role = 'owner' AND user_id != ?2")
+            .bind(&[app_id.into(), user_id.into()])?
+            .first::<Value>(None)
+            .await?
+            .and_then(|v| v.get("n").and_then(|n| n.as_i64()))
+            .unwrap_or(0);
+        let is_owner = roles_of(app_id, user_id, db).await?.iter().any(|r| r == "owner");
+        if is_owner && other_owners == 0 {
+            return Err(AppError::Conflict("An app needs at least one owner".to_string()));
+        }
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let mut statements = vec![db
+        .prepare("DELETE FROM admin_roles WHERE app_id = ?1 AND user_id = ?2")
+        .bind(&[app_id.into(), user_id.into()])?];
+    for role in &roles {
+        statements.push(
+            db.prepare(
+                "INSERT INTO admin_roles (app_id, user_id, role, granted_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(&[app_id.into(), user_id.into(), role.clone().into(), granted_by.into(), now.clone().into()])?,
+        );
+    }
+    statements.push(
+        db.prepare("UPDATE users SET is_admin = ?1, updated_at = ?2 WHERE app_id = ?3 AND id = ?4")
+            .bind(&[(if roles.is_empty() { 0 } else { 1 }).into(), now.clone().into(), app_id.into(), user_id.into()])?,
+    );
+    db.batch(statements).await?;
+    Ok(roles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(roles: &[&str], platform_admin: bool) -> AdminContext {
+        AdminContext {
+            user_id: "admin_1".to_string(),
+            app_id: "app_1".to_string(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            platform_admin,
+            ip: None,
+        }
+    }
+
+    #[test]
+    fn test_grant_limited() {
+        assert!(admin(&["support"], false).grant_limited());
+        assert!(!admin(&["support", "owner"], false).grant_limited());
+        assert!(!admin(&[], true).grant_limited());
+    }
+
+    #[test]
+    fn test_check_grant() {
+        let support = admin(&["support"], false);
+        assert!(support.check_grant(SUPPORT_GRANT_LIMIT).is_ok());
+        assert!(matches!(support.check_grant(SUPPORT_GRANT_LIMIT + 1), Err(AppError::Forbidden(_))));
+        assert!(admin(&["owner"], false).check_grant(SUPPORT_DAILY_GRANT_LIMIT * 10).is_ok());
+    }
+
+    #[test]
+    fn test_daily_grant_limit_sql() {
+        let sql = within_daily_grant_limit_sql();
+        // Capped callers only, counting this grant on top of the window's.
+        assert!(sql.starts_with("(?13 = 0 OR "));
+        assert!(sql.ends_with("+ ?15 <= ?16)"));
+        assert!(sql.contains("g.created_at >= ?14"));
+        // Scoped to the new row's app and actor.
+        assert!(sql.contains("g.app_id = ?2 AND g.actor_user_id = ?3"));
+        assert!(sql.contains(GRANTED_SQL));
+    }
+
+    #[test]
+    fn test_granted_sql_actions() {
+        // Adjustments count only when positive; gifts per recipient reached.
+        assert!(GRANTED_SQL.contains("WHEN 'credits.adjust' THEN MAX(COALESCE(json_extract(g.after_value, '$.adjustment'), 0), 0)"));
+        assert!(GRANTED_SQL.contains("json_array_length(g.after_value, '$.granted')"));
+        assert!(GRANTED_SQL.contains("ELSE 0 END"));
+    }
+
+    #[test]
+    fn test_permission_roles() {
+        assert!(admin(&["support"], false).can(Permission::GrantCredits));
+        assert!(!admin(&["support"], false).can(Permission::ReadFinance));
+        assert!(!admin(&["owner"], false).can(Permission::ManageTenants));
+        assert!(admin(&[], true).can(Permission::ManageTenants));
+    }
+}
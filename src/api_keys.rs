@@ -0,0 +1,272 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use sha2::{Digest, Sha256};
+use crate::error::AppError;
+
+/// Active (unrevoked) named keys one user may hold per app. Login keys,
+/// which belong to sessions, don't count.
+pub const MAX_ACTIVE_KEYS: i64 = 25;
+
+/// Leading characters of a key kept in the clear, for lookup and display.
+pub const KEY_PREFIX_LEN: usize = 12;
+
+/// A fresh secret for an account or named key.
+pub fn generate_api_key() -> String {
+    format!("pixie_{}", Uuid::new_v4().to_string().replace("-", ""))
+}
+
+pub fn key_prefix(key: &str) -> String {
+    key.chars().take(KEY_PREFIX_LEN).collect()
+}
+
+/// Hex SHA-256 of a key, the only form stored once it has been seen.
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Compares two secrets without leaking where they differ.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A named key as shown to its owner. The secret is only ever returned by
+/// create and rotate; listings show its first characters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+    pub created_at: String,
+}
+
+fn parse_key(row: &Value) -> Option<ApiKey> {
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    Some(ApiKey {
+        id: text("id")?,
+        name: text("name").unwrap_or_default(),
+        key_prefix: text("key_prefix")
+            .or_else(|| text("key").map(|k| key_prefix(&k)))
+            .unwrap_or_default(),
+        scopes: text("scopes")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        expires_at: text("expires_at"),
+        last_used_at: text("last_used_at"),
+        revoked_at: text("revoked_at"),
+        created_at: text("created_at").unwrap_or_default(),
+    })
+}
+
+/// Checks requested scopes against `auth::SCOPES` and returns them deduplicated.
+pub fn normalize_scopes(scopes: &[String]) -> Result<Vec<String>, AppError> {
+    let mut out: Vec<String> = Vec::new();
+    for scope in scopes.iter().map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        if scope != "*" && !crate::auth::SCOPES.contains(&scope) {
+            return Err(AppError::BadRequest(format!(
+                "Unknown scope '{}'. Expected '*' or one of: {}",
+                scope,
+                crate::auth::SCOPES.join(", ")
+            )));
+        }
+        if !out.iter().any(|s| s == scope) {
+            out.push(scope.to_string());
+        }
+    }
+    if out.is_empty() {
+        return Err(AppError::BadRequest("At least one scope is required".to_string()));
+    }
+    Ok(out)
+}
+
+pub async fn list_keys(app_id: &str, user_id: &str, include_revoked: bool, db: &D1Database) -> Result<Vec<ApiKey>, AppError> {
+    let rows = db
+        .prepare(format!(
+            "SELECT * FROM api_keys WHERE app_id = ?1 AND user_id = ?2 AND source = 'user' {} ORDER BY created_at DESC",
+            if include_revoked { "" } else { "AND revoked_at IS NULL" }
+        ))
+        .bind(&[app_id.into(), user_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows.iter().filter_map(parse_key).collect())
+}
+
+/// Creates a key and returns it with its secret.
+pub async fn create_key(
+    app_id: &str,
+    user_id: &str,
+    name: &str,
+    scopes: &[String],
+    expires_in_days: Option<u32>,
+    db: &D1Database,
+) -> Result<(ApiKey, String), AppError> {
+    let active = db
+        .prepare("SELECT COUNT(*) AS n FROM api_keys WHERE app_id = ?1 AND user_id = ?2 AND revoked_at IS NULL AND source = 'user'")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .and_then(|r| r.get("n").and_then(|v| v.as_i64()))
+        .unwrap_or(0);
+    if active >= MAX_ACTIVE_KEYS {
+        return Err(AppError::BadRequest(format!(
+            "You already have {} active keys; revoke one first",
+            MAX_ACTIVE_KEYS
+        )));
+    }
+
+    let expires_at = expires_in_days.map(|d| (Utc::now() + Duration::days(d as i64)).to_rfc3339());
+    let scopes = scopes.join(",");
+    let new_key = NewKey {
+        app_id,
+        user_id,
+        name,
+        scopes: &scopes,
+        source: "user",
+        expires_at: expires_at.as_deref(),
+        session_id: None,
+    };
+    let (id, secret) = insert_key(&new_key, db).await?;
+
+    let key = get_key(app_id, user_id, &id, db)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Created key not found".to_string()))?;
+    Ok((key, secret))
+}
+
+/// Issues the full-access key a sign-in hands out. It belongs to the session
+/// (see `sessions`) and is managed through /v1/sessions, not /v1/keys.
+pub async fn issue_login_key(
+    app_id: &str,
+    user_id: &str,
+    provider: &str,
+    session_id: &str,
+    db: &D1Database,
+) -> Result<String, AppError> {
+    let name = format!("{} sign-in", provider);
+    let new_key = NewKey {
+        app_id,
+        user_id,
+        name: &name,
+        scopes: "*",
+        source: "login",
+        expires_at: None,
+        session_id: Some(session_id),
+    };
+    let (_, secret) = insert_key(&new_key, db).await?;
+    Ok(secret)
+}
+
+/// A key row for `insert_key`; its secret is generated on insert.
+struct NewKey<'a> {
+    app_id: &'a str,
+    user_id: &'a str,
+    name: &'a str,
+    /// Comma-separated, as stored.
+    scopes: &'a str,
+    /// 'user' for keys managed through /v1/keys, 'login' for sign-in keys.
+    source: &'a str,
+    expires_at: Option<&'a str>,
+    /// The session a login key belongs to.
+    session_id: Option<&'a str>,
+}
+
+async fn insert_key(key: &NewKey<'_>, db: &D1Database) -> Result<(String, String), AppError> {
+    let id = Uuid::new_v4().to_string();
+    let secret = generate_api_key();
+    db.prepare(
+        "INSERT INTO api_keys (id, app_id, user_id, name, key_prefix, key_hash, scopes, source, expires_at, session_id, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )
+    .bind(&[
+        id.clone().into(),
+        key.app_id.into(),
+        key.user_id.into(),
+        key.name.into(),
+        key_prefix(&secret).into(),
+        hash_key(&secret).into(),
+        key.scopes.into(),
+        key.source.into(),
+        key.expires_at.map(|e| e.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        key.session_id.map(|s| s.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        Utc::now().to_rfc3339().into(),
+    ])?
+    .run()
+    .await?;
+    Ok((id, secret))
+}
+
+pub async fn get_key(app_id: &str, user_id: &str, key_id: &str, db: &D1Database) -> Result<Option<ApiKey>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM api_keys WHERE app_id = ?1 AND user_id = ?2 AND id = ?3 AND source = 'user'")
+        .bind(&[app_id.into(), user_id.into(), key_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.as_ref().and_then(parse_key))
+}
+
+/// Replaces an active key's secret. The old secret stops working at once;
+/// name, scopes and expiry are kept.
+pub async fn rotate_key(app_id: &str, user_id: &str, key_id: &str, db: &D1Database) -> Result<(ApiKey, String), AppError> {
+    let secret = generate_api_key();
+    let result = db
+        .prepare(
+            "UPDATE api_keys SET key = NULL, key_prefix = ?1, key_hash = ?2, last_used_at = NULL
+             WHERE app_id = ?3 AND user_id = ?4 AND id = ?5 AND revoked_at IS NULL AND source = 'user'",
+        )
+        .bind(&[
+            key_prefix(&secret).into(),
+            hash_key(&secret).into(),
+            app_id.into(),
+            user_id.into(),
+            key_id.into(),
+        ])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::NotFound("No active key with this id".to_string()));
+    }
+    let key = get_key(app_id, user_id, key_id, db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No active key with this id".to_string()))?;
+    Ok((key, secret))
+}
+
+pub async fn revoke_key(app_id: &str, user_id: &str, key_id: &str, db: &D1Database) -> Result<(), AppError> {
+    let result = db
+        .prepare(
+            "UPDATE api_keys SET revoked_at = ?1
+             WHERE app_id = ?2 AND user_id = ?3 AND id = ?4 AND revoked_at IS NULL AND source = 'user'",
+        )
+        .bind(&[Utc::now().to_rfc3339().into(), app_id.into(), user_id.into(), key_id.into()])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::NotFound("No active key with this id".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("pk_abc123", "pk_abc123"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("pk_abc123", "pk_abc124"));
+        assert!(!constant_time_eq("pk_abc123", "pk_abc12"));
+        assert!(!constant_time_eq("pk_abc12", "pk_abc123"));
+    }
+}
@@ -0,0 +1,91 @@
+use worker::{D1Database, Response};
+use serde::{Deserialize, Serialize};
+use crate::error::AppError;
+
+/// Request-time view of an `apps` row: whether the tenant is switched on and
+/// which capabilities it may call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppConfig {
+    pub app_id: String,
+    pub enabled: bool,
+    pub enabled_capabilities: Vec<String>,
+}
+
+impl AppConfig {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.enabled_capabilities.iter().any(|c| c == capability)
+    }
+}
+
+// Short TTL so a tenant flipping `enabled`/`enabled_capabilities` in D1 takes
+// effect within a minute, without a deploy and without a D1 read per request.
+const APP_CONFIG_TTL_SECS: u32 = 60;
+
+fn cache_key(app_id: &str) -> String {
+    format!("https://mako.midgarcorp.cc/__cache/apps/{}", app_id)
+}
+
+pub async fn get_app_config(app_id: &str, db: &D1Database) -> Result<Option<AppConfig>, AppError> {
+    let cache = worker::Cache::default();
+    let key = cache_key(app_id);
+    if let Ok(Some(mut cached)) = cache.get(&key, false).await {
+        if let Ok(config) = cached.json::<AppConfig>().await {
+            return Ok(Some(config));
+        }
+    }
+
+    let row = db
+        .prepare("SELECT enabled, enabled_capabilities FROM apps WHERE app_id = ?1")
+        .bind(&[app_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let config = AppConfig {
+        app_id: app_id.to_string(),
+        enabled: row.get("enabled").and_then(|v| v.as_i64()).map(|v| v != 0).unwrap_or(false),
+        enabled_capabilities: row
+            .get("enabled_capabilities")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect(),
+    };
+
+    if let Ok(mut resp) = Response::from_json(&config) {
+        let _ = resp
+            .headers_mut()
+            .set("Cache-Control", &format!("public, max-age={}", APP_CONFIG_TTL_SECS));
+        let _ = cache.put(&key, resp).await;
+    }
+    Ok(Some(config))
+}
+
+/// Drops the cached config so a change made through the admin API applies
+/// at once rather than after the TTL. Only this colo's cache is cleared;
+/// others catch up within `APP_CONFIG_TTL_SECS`.
+pub async fn invalidate_app_config(app_id: &str) {
+    let _ = worker::Cache::default().delete(cache_key(app_id), false).await;
+}
+
+/// Gate a metered call on the tenant being enabled and having `capability` in
+/// its allowlist. Unknown apps are refused rather than falling through.
+pub async fn require_capability(app_id: &str, capability: &str, db: &D1Database) -> Result<AppConfig, AppError> {
+    let config = get_app_config(app_id, db)
+        .await?
+        .ok_or_else(|| AppError::Forbidden(format!("App '{}' is not configured", app_id)))?;
+    if !config.enabled {
+        return Err(AppError::Forbidden(format!("App '{}' is disabled", app_id)));
+    }
+    if !config.has_capability(capability) {
+        return Err(AppError::Forbidden(format!(
+            "Capability '{}' is not enabled for app '{}'",
+            capability, app_id
+        )));
+    }
+    Ok(config)
+}
@@ -0,0 +1,221 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::Utc;
+use uuid::Uuid;
+use crate::admin::AdminContext;
+use crate::error::AppError;
+
+/// One admin action, as handed to `record`.
+#[derive(Debug, Clone, Default)]
+pub struct AuditEvent {
+    pub action: &'static str,
+    pub target_type: Option<&'static str>,
+    pub target_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub reason: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: &'static str) -> Self {
+        AuditEvent { action, ..Default::default() }
+    }
+
+    pub fn target(mut self, target_type: &'static str, target_id: &str) -> Self {
+        self.target_type = Some(target_type);
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    pub fn before(mut self, value: Value) -> Self {
+        self.before = Some(value);
+        self
+    }
+
+    pub fn after(mut self, value: Value) -> Self {
+        self.after = Some(value);
+        self
+    }
+
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string()).filter(|r| !r.is_empty());
+        self
+    }
+}
+
+/// High-value actions must say why. Returns the trimmed reason.
+pub fn require_reason(reason: Option<&str>) -> Result<&str, AppError> {
+    match reason.map(str::trim) {
+        Some(r) if !r.is_empty() => {
+            if r.chars().count() > 500 {
+                return Err(AppError::BadRequest("reason must be at most 500 characters".to_string()));
+            }
+            Ok(r)
+        }
+        _ => Err(AppError::BadRequest("A reason is required for this action".to_string())),
+    }
+}
+
+/// SQL condition that holds once the audit row with id ?1 exists, for the
+/// writes of an action whose row is inserted by `conditional_insert`.
+pub const RECORDED_SQL: &str = "EXISTS (SELECT 1 FROM admin_audit_log WHERE id = ?1)";
+
+/// The row's values, bound as ?1..?12 by `record` and `conditional_insert`.
+fn row_params(admin: &AdminContext, event: &AuditEvent, id: &str) -> Vec<worker::wasm_bindgen::JsValue> {
+    let json = |v: &Option<Value>| {
+        v.as_ref()
+            .map(|v| v.to_string().into())
+            .unwrap_or(worker::wasm_bindgen::JsValue::NULL)
+    };
+    let opt = |v: Option<String>| v.map(|s| s.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    vec![
+        id.into(),
+        admin.app_id.clone().into(),
+        admin.user_id.clone().into(),
+        (if admin.platform_admin { 1 } else { 0 }).into(),
+        event.action.into(),
+        opt(event.target_type.map(|t| t.to_string())),
+        opt(event.target_id.clone()),
+        json(&event.before),
+        json(&event.after),
+        opt(event.reason.clone()),
+        opt(admin.ip.clone()),
+        Utc::now().to_rfc3339().into(),
+    ]
+}
+
+const INSERT_SQL: &str =
+    "INSERT INTO admin_audit_log (id, app_id, actor_user_id, platform_admin, action, target_type, target_id,
+                                  before_value, after_value, reason, ip, created_at)";
+
+/// Writes an audit row. Called after the action has happened, so a failed
+/// write is logged rather than failing a request whose effect already stands.
+pub async fn record(admin: &AdminContext, event: AuditEvent, db: &D1Database) {
+    let stmt = db
+        .prepare(format!("{} VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)", INSERT_SQL))
+        .bind(&row_params(admin, &event, &Uuid::new_v4().to_string()));
+    let result = match stmt {
+        Ok(stmt) => stmt.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        crate::log_error!("Failed to write admin audit log", serde_json::json!({
+            "error": e.to_string(),
+            "action": event.action,
+            "actor": admin.user_id,
+            "target_id": event.target_id,
+        }));
+    }
+}
+
+/// An audit row with id `id` that is only written when `condition` holds, for
+/// the batch of the action it records: run it first and condition the action
+/// on `RECORDED_SQL`. `condition` sees the row's values as ?1..?12 and `extra`
+/// from ?13 on.
+pub fn conditional_insert(
+    admin: &AdminContext,
+    event: &AuditEvent,
+    id: &str,
+    condition: &str,
+    extra: Vec<worker::wasm_bindgen::JsValue>,
+    db: &D1Database,
+) -> worker::Result<worker::D1PreparedStatement> {
+    let mut params = row_params(admin, event, id);
+    params.extend(extra);
+    db.prepare(format!(
+        "{} SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12 WHERE {}",
+        INSERT_SQL, condition
+    ))
+    .bind(&params)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub app_id: String,
+    pub actor_user_id: String,
+    pub platform_admin: bool,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub reason: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: String,
+}
+
+fn parse_entry(row: &Value) -> Option<AuditEntry> {
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let json = |key: &str| text(key).and_then(|s| serde_json::from_str(&s).ok());
+    Some(AuditEntry {
+        id: text("id")?,
+        app_id: text("app_id").unwrap_or_default(),
+        actor_user_id: text("actor_user_id").unwrap_or_default(),
+        platform_admin: row.get("platform_admin").and_then(|v| v.as_i64()).unwrap_or(0) != 0,
+        action: text("action").unwrap_or_default(),
+        target_type: text("target_type"),
+        target_id: text("target_id"),
+        before: json("before_value"),
+        after: json("after_value"),
+        reason: text("reason"),
+        ip: text("ip"),
+        created_at: text("created_at").unwrap_or_default(),
+    })
+}
+
+/// Filters for `query`. Unset fields match everything; `action` matches a
+/// prefix, so "credits." finds every credit action.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor_user_id: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Newest first.
+pub async fn query(
+    app_id: &str,
+    filter: &AuditFilter,
+    limit: i32,
+    offset: i32,
+    db: &D1Database,
+) -> Result<Vec<AuditEntry>, AppError> {
+    let mut sql = "SELECT * FROM admin_audit_log WHERE app_id = ?1".to_string();
+    let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![app_id.into()];
+    // `clause` names the bound value `?`.
+    let mut push = |clause: &str, value: String| {
+        params.push(value.into());
+        sql.push_str(" AND ");
+        sql.push_str(&clause.replace('?', &format!("?{}", params.len())));
+    };
+    if let Some(actor) = &filter.actor_user_id {
+        push("actor_user_id = ?", actor.clone());
+    }
+    if let Some(action) = &filter.action {
+        let escaped = action.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        push("action LIKE ? ESCAPE '\\'", format!("{}%", escaped));
+    }
+    if let Some(target_type) = &filter.target_type {
+        push("target_type = ?", target_type.clone());
+    }
+    if let Some(target_id) = &filter.target_id {
+        push("target_id = ?", target_id.clone());
+    }
+    if let Some(from) = &filter.from {
+        push("created_at >= ?", from.clone());
+    }
+    if let Some(to) = &filter.to {
+        push("created_at < ?", to.clone());
+    }
+    params.push(limit.into());
+    params.push(offset.into());
+    sql.push_str(&format!(" ORDER BY created_at DESC LIMIT ?{} OFFSET ?{}", params.len() - 1, params.len()));
+
+    let rows = db.prepare(sql).bind(&params)?.all().await?.results::<Value>()?;
+    Ok(rows.iter().filter_map(parse_entry).collect())
+}
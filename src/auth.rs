@@ -1,5 +1,10 @@
-use worker::{Request, D1Database};
+use worker::{Request, D1Database, Env, Method};
+use chrono::{DateTime, Duration, Utc};
 use crate::error::AppError;
+use crate::api_keys;
+use crate::sessions;
+use crate::tokens;
+use crate::user_status::UserStatus;
 
 pub fn validate_api_key(req: &Request) -> Result<String, AppError> {
     let auth_header = req.headers()
@@ -29,38 +34,274 @@ pub fn resolve_app_id(req: &Request) -> String {
         .unwrap_or_else(|| "pixie".to_string())
 }
 
+/// Scopes a named API key can carry. A key with `*` can do anything the
+/// account key can.
+pub const SCOPES: &[&str] = &[
+    "images:write", "gallery:read", "gallery:write", "credits:read", "credits:write",
+    "chat:write", "realtime:write", "usage:read", "orgs:read", "orgs:write", "admin",
+];
+
 pub struct AuthedUser {
     pub user_id: String,
     pub app_id: String,
-    pub is_admin: bool,
     #[allow(dead_code)]
     pub preferred_model: Option<String>,
     #[allow(dead_code)]
     pub openai_api_key: Option<String>,
     #[allow(dead_code)]
     pub gemini_api_key: Option<String>,
+    /// The named key (api_keys.id) the request used; None for the account
+    /// key or an access token.
+    pub key_id: Option<String>,
+    /// The sign-in session behind a login key or access token.
+    pub session_id: Option<String>,
 }
 
-pub async fn authenticate(req: &Request, db: &D1Database) -> Result<AuthedUser, AppError> {
+fn authed_user(
+    value: &serde_json::Value,
+    app_id: String,
+    key_id: Option<String>,
+    session_id: Option<String>,
+) -> AuthedUser {
+    AuthedUser {
+        user_id: value.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        app_id,
+        preferred_model: value.get("preferred_model").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        openai_api_key: value.get("openai_api_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        gemini_api_key: value.get("gemini_api_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        key_id,
+        session_id,
+    }
+}
+
+/// The scope a named key needs for a route. None marks routes only the
+/// account key (or a `*` key) may use: key management, identity, sign-in.
+fn required_scope(method: &Method, path: &str) -> Option<&'static str> {
+    let read = matches!(method, Method::Get | Method::Head);
+    let pick = |r: &'static str, w: &'static str| Some(if read { r } else { w });
+    match path {
+        p if p.starts_with("/v1/admin/") => Some("admin"),
+        "/v1/images/generations" | "/v1/images/edits" => Some("images:write"),
+        p if p.starts_with("/v1/images") => pick("gallery:read", "gallery:write"),
+        "/v1/credits/estimate" => Some("credits:read"),
+        p if p.starts_with("/v1/credits/") || p.starts_with("/v1/subscriptions") => pick("credits:read", "credits:write"),
+        "/v1/run/chat.completion" => Some("chat:write"),
+        p if p.starts_with("/v1/run/realtime.translate/") => Some("realtime:write"),
+        "/v1/usage/system" | "/v1/usage/timeseries" => Some("admin"),
+        p if p.starts_with("/v1/usage/") => Some("usage:read"),
+        p if p.starts_with("/v1/orgs") => pick("orgs:read", "orgs:write"),
+        _ => None,
+    }
+}
+
+/// Resolves the bearer credential: an account key, a named key or an access
+/// token (see `tokens`). Access tokens carry the account key's full access.
+/// Login keys and access tokens stop working once their session is revoked.
+pub async fn authenticate(req: &Request, env: &Env, db: &D1Database) -> Result<AuthedUser, AppError> {
     let api_key = validate_api_key(req)?;
     let app_id = resolve_app_id(req);
 
-    let result = db
-        .prepare("SELECT id, is_admin, preferred_model, openai_api_key, gemini_api_key FROM users WHERE app_id = ? AND api_key = ?")
-        .bind(&[app_id.clone().into(), api_key.into()])
+    if tokens::is_access_token(&api_key) {
+        let claims = tokens::verify_access_token(env, &app_id, &api_key)?;
+        let value = db
+            .prepare(
+                "SELECT id, preferred_model, openai_api_key, gemini_api_key, status, status_reason, status_expires_at
+                 FROM users WHERE app_id = ?1 AND id = ?2
+                   AND EXISTS (SELECT 1 FROM sessions WHERE id = ?3 AND revoked_at IS NULL)",
+            )
+            .bind(&[app_id.clone().into(), claims.sub.into(), claims.sid.clone().into()])?
+            .first::<serde_json::Value>(None)
+            .await?
+            .ok_or_else(|| AppError::Unauthorized("This sign-in has been revoked".to_string()))?;
+        check_status(req, &value)?;
+        sessions::touch(&claims.sid, db).await;
+        return Ok(authed_user(&value, app_id, None, Some(claims.sid)));
+    }
+    let prefix = api_keys::key_prefix(&api_key);
+    let hash = api_keys::hash_key(&api_key);
+
+    let rows = db
+        .prepare(
+            "SELECT id, preferred_model, openai_api_key, gemini_api_key, api_key, api_key_hash,
+                    status, status_reason, status_expires_at
+             FROM users WHERE app_id = ?1 AND (api_key_prefix = ?2 OR api_key = ?3)",
+        )
+        .bind(&[app_id.clone().into(), prefix.clone().into(), api_key.clone().into()])
         .map_err(AppError::from)?
-        .first::<serde_json::Value>(None)
+        .all()
         .await
+        .map_err(AppError::from)?
+        .results::<serde_json::Value>()
         .map_err(AppError::from)?;
 
-    let value = result.ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))?;
+    let (value, key_id, session_id) = match find_key_match(rows, "api_key", "api_key_hash", &api_key, &hash) {
+        Some((value, legacy)) => {
+            if legacy {
+                let id = value.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+                hash_legacy_key(
+                    "UPDATE users SET api_key = NULL, api_key_prefix = ?1, api_key_hash = ?2 WHERE id = ?3 AND api_key = ?4",
+                    &id, &api_key, &prefix, &hash, db,
+                )
+                .await;
+            }
+            (value, None, None)
+        }
+        None => {
+            let (value, key_id, scopes) = authenticate_named_key(&app_id, &api_key, &prefix, &hash, db).await?;
+            if !scopes.iter().any(|s| s == "*") {
+                match required_scope(&req.method(), &req.path()) {
+                    Some(scope) if scopes.iter().any(|s| s == scope) => {}
+                    Some(scope) => {
+                        return Err(AppError::Forbidden(format!("This API key lacks the '{}' scope", scope)));
+                    }
+                    None => {
+                        return Err(AppError::Forbidden(
+                            "This endpoint needs your account key or a key with the '*' scope".to_string(),
+                        ));
+                    }
+                }
+            }
+            let session_id = value.get("session_id").and_then(|v| v.as_str()).map(|s| s.to_string());
+            if let Some(session_id) = &session_id {
+                sessions::touch(session_id, db).await;
+            }
+            (value, Some(key_id), session_id)
+        }
+    };
 
-    Ok(AuthedUser {
-        user_id: value.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-        app_id,
-        is_admin: value.get("is_admin").and_then(|v| v.as_i64()).map(|v| v != 0).unwrap_or(false),
-        preferred_model: value.get("preferred_model").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        openai_api_key: value.get("openai_api_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        gemini_api_key: value.get("gemini_api_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
+    check_status(req, &value)?;
+    Ok(authed_user(&value, app_id, key_id, session_id))
+}
+
+/// Refuses suspended and banned users everywhere except account deletion,
+/// which has to stay available to them.
+fn check_status(req: &Request, user: &serde_json::Value) -> Result<(), AppError> {
+    if matches!(req.method(), Method::Delete) && req.path() == "/v1/identity" {
+        return Ok(());
+    }
+    UserStatus::from_row(user).check()
+}
+
+/// Picks the row whose stored key matches. Hashed rows are compared by hash;
+/// rows still holding a plaintext key (from before keys were hashed) are
+/// compared directly and flagged so the caller can hash them.
+fn find_key_match(
+    rows: Vec<serde_json::Value>,
+    plain_column: &str,
+    hash_column: &str,
+    api_key: &str,
+    hash: &str,
+) -> Option<(serde_json::Value, bool)> {
+    rows.into_iter().find_map(|row| {
+        if let Some(stored) = row.get(hash_column).and_then(|v| v.as_str()) {
+            return api_keys::constant_time_eq(stored, hash).then_some((row, false));
+        }
+        let plain = row.get(plain_column).and_then(|v| v.as_str())?;
+        api_keys::constant_time_eq(plain, api_key).then_some((row, true))
     })
 }
+
+/// Replaces a legacy plaintext key with its prefix and hash. `sql` binds
+/// prefix, hash, row id and the plaintext. Best effort: a failure leaves the
+/// plaintext in place for the next request to retry.
+async fn hash_legacy_key(sql: &str, id: &str, api_key: &str, prefix: &str, hash: &str, db: &D1Database) {
+    if let Ok(stmt) = db.prepare(sql).bind(&[prefix.into(), hash.into(), id.into(), api_key.into()]) {
+        if let Err(e) = stmt.run().await {
+            worker::console_log!("Failed to hash legacy API key {}: {:?}", id, e);
+        }
+    }
+}
+
+/// Resolves a key from `api_keys`. Returns the owning user's row (with the
+/// key's `session_id`, if a sign-in issued it), the key id and its scopes.
+async fn authenticate_named_key(
+    app_id: &str,
+    api_key: &str,
+    prefix: &str,
+    hash: &str,
+    db: &D1Database,
+) -> Result<(serde_json::Value, String, Vec<String>), AppError> {
+    let rows = db
+        .prepare(
+            "SELECT u.id, u.preferred_model, u.openai_api_key, u.gemini_api_key,
+                    u.status, u.status_reason, u.status_expires_at,
+                    k.id AS key_id, k.key, k.key_hash, k.scopes, k.expires_at, k.revoked_at,
+                    k.session_id
+             FROM api_keys k JOIN users u ON u.id = k.user_id
+             WHERE k.app_id = ?1 AND (k.key_prefix = ?2 OR k.key = ?3)",
+        )
+        .bind(&[app_id.into(), prefix.into(), api_key.into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    let (value, legacy) = find_key_match(rows, "key", "key_hash", api_key, hash)
+        .ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))?;
+
+    let now = Utc::now();
+    if value.get("revoked_at").and_then(|v| v.as_str()).is_some() {
+        return Err(AppError::Unauthorized("API key has been revoked".to_string()));
+    }
+    let expired = value
+        .get("expires_at")
+        .and_then(|v| v.as_str())
+        .and_then(|e| DateTime::parse_from_rfc3339(e).ok())
+        .map(|e| e.with_timezone(&Utc) <= now)
+        .unwrap_or(false);
+    if expired {
+        return Err(AppError::Unauthorized("API key has expired".to_string()));
+    }
+
+    let key_id = value.get("key_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    if legacy {
+        hash_legacy_key(
+            "UPDATE api_keys SET key = NULL, key_prefix = ?1, key_hash = ?2 WHERE id = ?3 AND key = ?4",
+            &key_id, api_key, prefix, hash, db,
+        )
+        .await;
+    }
+    let scopes = value
+        .get("scopes")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    // At most one write a minute per key.
+    let stale = (now - Duration::minutes(1)).to_rfc3339();
+    if let Ok(stmt) = db
+        .prepare("UPDATE api_keys SET last_used_at = ?1 WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at < ?3)")
+        .bind(&[now.to_rfc3339().into(), key_id.clone().into(), stale.into()])
+    {
+        let _ = stmt.run().await;
+    }
+
+    Ok((value, key_id, scopes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(required_scope(&Method::Get, "/v1/admin/users"), Some("admin"));
+        assert_eq!(required_scope(&Method::Post, "/v1/images/generations"), Some("images:write"));
+        assert_eq!(required_scope(&Method::Get, "/v1/images/abc"), Some("gallery:read"));
+        assert_eq!(required_scope(&Method::Delete, "/v1/images/abc"), Some("gallery:write"));
+        assert_eq!(required_scope(&Method::Post, "/v1/credits/estimate"), Some("credits:read"));
+        assert_eq!(required_scope(&Method::Get, "/v1/credits/balance"), Some("credits:read"));
+        assert_eq!(required_scope(&Method::Post, "/v1/credits/transfer"), Some("credits:write"));
+        assert_eq!(required_scope(&Method::Post, "/v1/subscriptions/checkout"), Some("credits:write"));
+        assert_eq!(required_scope(&Method::Post, "/v1/run/chat.completion"), Some("chat:write"));
+        assert_eq!(required_scope(&Method::Post, "/v1/run/realtime.translate/start"), Some("realtime:write"));
+        assert_eq!(required_scope(&Method::Get, "/v1/usage/system"), Some("admin"));
+        assert_eq!(required_scope(&Method::Get, "/v1/usage/timeseries"), Some("admin"));
+        assert_eq!(required_scope(&Method::Get, "/v1/usage"), None);
+        assert_eq!(required_scope(&Method::Get, "/v1/usage/users/u1"), Some("usage:read"));
+        assert_eq!(required_scope(&Method::Head, "/v1/orgs"), Some("orgs:read"));
+        assert_eq!(required_scope(&Method::Patch, "/v1/orgs/o1/members/u1"), Some("orgs:write"));
+        assert_eq!(required_scope(&Method::Get, "/v1/user"), None);
+    }
+}
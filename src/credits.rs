@@ -1,12 +1,19 @@
-use worker::{D1Database, Result};
+use worker::{D1Database, D1PreparedStatement, Result};
 use crate::error::AppError;
-use crate::models::ImageUsage;
 use uuid::Uuid;
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 
-const CREDIT_MULTIPLIER: f64 = 3.0;
+/// Default margin over provider cost (1 credit = $0.01 of billed price). Built-in
+/// credit tables such as `estimate_image_cost` are already priced at this rate.
+pub const DEFAULT_CREDIT_MULTIPLIER: f64 = 3.0;
 const NEW_USER_FREE_CREDITS: i32 = 6;
+/// How long an unsettled hold may escrow credits before the cron settles it.
+/// Comfortably longer than any single metered request.
+const HOLD_TTL_MINUTES: i64 = 15;
+/// Subscription allowance outlives its billing period by this much, so a late
+/// renewal invoice can still roll it over before the cron lapses it.
+const ALLOWANCE_GRACE_DAYS: i64 = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserCredits {
@@ -29,6 +36,64 @@ pub struct CreditTransaction {
     pub description: String,
     pub reference_id: Option<String>,
     pub created_at: String,
+    /// The org member who spent, when `user_id` is an org wallet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member_id: Option<String>,
+    /// The request that wrote the entry, when it came from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The wallet a charge lands on: the caller's own, or an org wallet charged on
+/// behalf of a member (`X-Org-ID`, see orgs.rs). Org charges record the member
+/// on the ledger and count against the member's spending cap.
+#[derive(Debug, Clone)]
+pub struct Payer {
+    pub wallet_id: String,
+    pub member_id: Option<String>,
+}
+
+impl Payer {
+    pub fn user(user_id: &str) -> Self {
+        Self { wallet_id: user_id.to_string(), member_id: None }
+    }
+
+    pub fn org(org_id: &str, member_id: &str) -> Self {
+        Self { wallet_id: org_id.to_string(), member_id: Some(member_id.to_string()) }
+    }
+
+    /// The org id when this charges an org wallet.
+    pub fn org_id(&self) -> Option<&str> {
+        self.member_id.as_ref().map(|_| self.wallet_id.as_str())
+    }
+
+    fn member_value(&self) -> worker::wasm_bindgen::JsValue {
+        self.member_id
+            .clone()
+            .map(|m| m.into())
+            .unwrap_or(worker::wasm_bindgen::JsValue::NULL)
+    }
+}
+
+/// A non-purchased grant (welcome bonus, admin grant, promo) or a subscription
+/// allowance, tracked separately so it can be spent first and expire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditBucket {
+    pub id: String,
+    pub source: String,
+    pub original_amount: i32,
+    pub remaining: i32,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditBreakdown {
+    pub paid: i32,
+    pub promotional: i32,
+    /// Unspent subscription allowance (buckets with source `subscription`).
+    pub subscription: i32,
+    pub buckets: Vec<CreditBucket>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -125,21 +190,72 @@ pub async fn get_credit_packs_for_app(app_id: &str, db: &D1Database) -> Vec<Cred
     }
 }
 
+/// A tenant's pricing for one capability, from the `capability_costs` table.
+/// `flat_credits` wins outright (e.g. Dream Eater bills a flat 1 credit per
+/// "dream"); otherwise usage-based costs are marked up by `multiplier`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilityPricing {
+    pub flat_credits: Option<u32>,
+    pub multiplier: f64,
+}
+
+impl Default for CapabilityPricing {
+    fn default() -> Self {
+        CapabilityPricing { flat_credits: None, multiplier: DEFAULT_CREDIT_MULTIPLIER }
+    }
+}
+
+impl CapabilityPricing {
+    /// Credits for a raw provider cost in USD at this tenant's margin.
+    pub fn credits_from_cost(&self, cost_usd: f64) -> u32 {
+        self.flat_credits
+            .unwrap_or_else(|| credits_from_cost_with_multiplier(cost_usd, self.multiplier))
+    }
+
+    /// Re-prices a built-in credit estimate for `images` images (which assumes
+    /// the default margin) at this tenant's margin. A flat price is per image.
+    pub fn apply_to_builtin(&self, builtin_credits: u32, images: u32) -> u32 {
+        if let Some(flat) = self.flat_credits {
+            return flat * images.max(1);
+        }
+        if (self.multiplier - DEFAULT_CREDIT_MULTIPLIER).abs() < f64::EPSILON {
+            return builtin_credits;
+        }
+        ((builtin_credits as f64 * self.multiplier / DEFAULT_CREDIT_MULTIPLIER).ceil() as u32).max(1)
+    }
+}
+
+/// Resolves per-app, per-capability pricing. Missing rows, NULL columns and
+/// query failures all fall back to the built-in default so a tenant without
+/// overrides is billed exactly as before.
+pub async fn get_capability_pricing(app_id: &str, capability: &str, db: &D1Database) -> CapabilityPricing {
+    let row = match db
+        .prepare("SELECT flat_credits, credit_multiplier FROM capability_costs WHERE app_id = ?1 AND capability = ?2")
+        .bind(&[app_id.into(), capability.into()])
+    {
+        Ok(stmt) => stmt.first::<serde_json::Value>(None).await.ok().flatten(),
+        Err(_) => None,
+    };
+    let Some(row) = row else {
+        return CapabilityPricing::default();
+    };
+    CapabilityPricing {
+        flat_credits: row
+            .get("flat_credits")
+            .and_then(|v| v.as_i64())
+            .map(|n| n.max(0) as u32),
+        multiplier: row
+            .get("credit_multiplier")
+            .and_then(|v| v.as_f64())
+            .filter(|m| *m > 0.0)
+            .unwrap_or(DEFAULT_CREDIT_MULTIPLIER),
+    }
+}
+
 /// Per-app, per-capability flat credit cost override from the `capability_costs`
-/// table. When set, a capability charges this fixed amount instead of its
-/// token/usage-based cost (e.g. Dream Eater bills a flat 1 credit per "dream").
-/// Returns None when no override exists (use the built-in cost).
+/// table. Returns None when no override exists (use the built-in cost).
 pub async fn get_flat_capability_cost(app_id: &str, capability: &str, db: &D1Database) -> Option<u32> {
-    let row = db
-        .prepare("SELECT flat_credits FROM capability_costs WHERE app_id = ?1 AND capability = ?2")
-        .bind(&[app_id.into(), capability.into()])
-        .ok()?
-        .first::<serde_json::Value>(None)
-        .await
-        .ok()??;
-    row.get("flat_credits")
-        .and_then(|v| v.as_i64())
-        .map(|n| n.max(0) as u32)
+    get_capability_pricing(app_id, capability, db).await.flat_credits
 }
 
 fn parse_pack_row(v: &serde_json::Value) -> Option<CreditPack> {
@@ -153,16 +269,8 @@ fn parse_pack_row(v: &serde_json::Value) -> Option<CreditPack> {
     })
 }
 
-pub fn calculate_openai_cost_usd(usage: &ImageUsage) -> f64 {
-    let text_cost = (usage.input_tokens_details.text_tokens as f64 / 1_000_000.0) * 5.0;
-    let image_input_cost = (usage.input_tokens_details.image_tokens as f64 / 1_000_000.0) * 8.0;
-    let output_cost = (usage.output_tokens as f64 / 1_000_000.0) * 30.0;
-    
-    text_cost + image_input_cost + output_cost
-}
-
-pub fn calculate_credits_from_cost(cost_usd: f64) -> u32 {
-    ((cost_usd * CREDIT_MULTIPLIER * 100.0).ceil() as u32).max(1)
+pub fn credits_from_cost_with_multiplier(cost_usd: f64, multiplier: f64) -> u32 {
+    ((cost_usd * multiplier * 100.0).ceil() as u32).max(1)
 }
 
 pub async fn initialize_user_credits(app_id: &str, user_id: &str, db: &D1Database) -> Result<()> {
@@ -181,16 +289,27 @@ pub async fn initialize_user_credits(app_id: &str, user_id: &str, db: &D1Databas
     .run()
     .await?;
 
-    let free_credits = db
-        .prepare("SELECT new_user_free_credits FROM apps WHERE app_id = ?1")
+    let app = db
+        .prepare("SELECT new_user_free_credits, promo_credit_ttl_days FROM apps WHERE app_id = ?1")
         .bind(&[app_id.into()])?
         .first::<serde_json::Value>(None)
-        .await?
+        .await?;
+    let free_credits = app
+        .as_ref()
         .and_then(|v| v.get("new_user_free_credits").and_then(|n| n.as_i64()))
         .unwrap_or(NEW_USER_FREE_CREDITS as i64);
 
     if free_credits > 0 {
-        add_credits(app_id, user_id, free_credits as u32, "bonus", "Welcome bonus", None, db).await?;
+        let grant = PromoGrant {
+            app_id,
+            user_id,
+            amount: free_credits as u32,
+            source: "bonus",
+            description: "Welcome bonus",
+            reference_id: None,
+            expires_at: promo_expiry(app.as_ref()),
+        };
+        add_promo_credits(&grant, db).await?;
     }
 
     Ok(())
@@ -209,28 +328,29 @@ pub async fn get_user_balance(app_id: &str, user_id: &str, db: &D1Database) -> R
     }
 }
 
-pub async fn check_and_reserve_credits(
-    app_id: &str,
-    user_id: &str,
-    required_credits: u32,
-    db: &D1Database,
-) -> Result<()> {
-    // Use a transaction to ensure atomicity
-    let balance = get_user_balance(app_id, user_id, db).await?;
-    
-    if balance < required_credits as i32 {
-        return Err(AppError::PaymentRequired(format!(
-            "Insufficient credits. Need {} credits, have {}. Purchase more at /credits",
-            required_credits, balance
-        )).into());
+/// Balance minus credits escrowed by open holds — what can actually be spent.
+pub async fn get_available_balance(app_id: &str, user_id: &str, db: &D1Database) -> Result<i32> {
+    let result = db
+        .prepare("SELECT balance - held_credits AS available FROM user_credits WHERE app_id = ? AND user_id = ?")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+
+    match result {
+        Some(value) => Ok(value.get("available").and_then(|b| b.as_i64()).unwrap_or(0) as i32),
+        None => Ok(0),
     }
-    
-    Ok(())
 }
 
+/// Charges `amount` straight to a payer's wallet. The ledger entry and the
+/// debit are one D1 batch: the entry is only written if, at that instant, the
+/// wallet has `amount` available beyond its holds and the member is within
+/// their org spending cap, and the debit only applies if the entry was written.
+/// Concurrent charges to a shared wallet therefore can't overdraw it or lose
+/// each other's debits. Returns the new balance.
 pub async fn deduct_credits(
     app_id: &str,
-    user_id: &str,
+    payer: &Payer,
     amount: u32,
     description: &str,
     reference_id: &str,
@@ -238,54 +358,296 @@ pub async fn deduct_credits(
 ) -> Result<i32> {
     let transaction_id = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
+    let user_id = payer.wallet_id.as_str();
 
-    // Get current balance with lock
-    let current_balance = get_user_balance(app_id, user_id, db).await?;
+    ensure_wallet_active(app_id, user_id, db).await?;
+    crate::orgs::ensure_within_spend_cap(app_id, payer, amount, db).await?;
 
-    if current_balance < amount as i32 {
+    let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![
+        transaction_id.clone().into(),
+        app_id.into(),
+        user_id.into(),
+        amount.into(),
+        description.into(),
+        reference_id.into(),
+        now.clone().into(),
+        payer.member_value(),
+        crate::request_id::value(),
+    ];
+    params.extend(crate::orgs::spend_cap_params(app_id, payer, amount));
+    let results = db
+        .batch(vec![
+            db.prepare(format!(
+                "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, member_id, request_id)
+                 SELECT ?1, ?2, ?3, 'spend', -?4, balance - ?4, ?5, ?6, ?7, ?8, ?9
+                 FROM user_credits WHERE app_id = ?2 AND user_id = ?3 AND balance - held_credits >= ?4 AND {}",
+                crate::orgs::within_spend_cap_sql(10)
+            ))
+            .bind(&params)?,
+            db.prepare(
+                "UPDATE user_credits SET balance = balance - ?1, lifetime_spent = lifetime_spent + ?1, updated_at = ?2
+                 WHERE app_id = ?3 AND user_id = ?4 AND EXISTS (SELECT 1 FROM credit_transactions WHERE id = ?5)",
+            )
+            .bind(&[amount.into(), now.into(), app_id.into(), user_id.into(), transaction_id.into()])?,
+        ])
+        .await?;
+    let charged = results
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|m| m.changes)
+        .unwrap_or(0)
+        > 0;
+    if !charged {
+        // Lost a race with another charge; report whichever limit it hit.
+        crate::orgs::ensure_within_spend_cap(app_id, payer, amount, db).await?;
+        let available = get_available_balance(app_id, user_id, db).await?;
         return Err(AppError::PaymentRequired(format!(
             "Insufficient credits. Need {} credits, have {}",
-            amount, current_balance
+            amount, available
         )).into());
     }
 
-    let new_balance = current_balance - amount as i32;
+    consume_promo_credits(app_id, user_id, amount, db).await?;
+    get_user_balance(app_id, user_id, db).await
+}
 
-    // Update balance
-    db.prepare(
-        "UPDATE user_credits
-         SET balance = ?, lifetime_spent = lifetime_spent + ?, updated_at = ?
-         WHERE app_id = ? AND user_id = ?"
-    )
-    .bind(&[
-        new_balance.into(),
-        amount.into(),
-        now.clone().into(),
+/// Escrows `amount` credits for a metered call before any provider work. The
+/// hold row and the escrow are one D1 batch: the row is only written if the
+/// wallet has `amount` available beyond its other holds and the member is
+/// within their org spending cap, and the escrow only applies if the row was
+/// written, so two concurrent calls can never both pass on the same credits.
+/// Returns the hold id to `capture_hold` or `release_hold` once the call
+/// finishes.
+pub async fn place_hold(
+    app_id: &str,
+    payer: &Payer,
+    capability: &str,
+    amount: u32,
+    db: &D1Database,
+) -> Result<String> {
+    let user_id = payer.wallet_id.as_str();
+    ensure_wallet_active(app_id, user_id, db).await?;
+    crate::orgs::ensure_within_spend_cap(app_id, payer, amount, db).await?;
+    let hold_id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let expires_at = now + Duration::minutes(HOLD_TTL_MINUTES);
+
+    let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![
+        hold_id.clone().into(),
         app_id.into(),
         user_id.into(),
-    ])?
+        capability.into(),
+        amount.into(),
+        now.to_rfc3339().into(),
+        expires_at.to_rfc3339().into(),
+        payer.member_value(),
+    ];
+    params.extend(crate::orgs::spend_cap_params(app_id, payer, amount));
+    let results = db
+        .batch(vec![
+            db.prepare(format!(
+                "INSERT INTO credit_holds (id, app_id, user_id, capability, amount, status, created_at, expires_at, member_id)
+                 SELECT ?1, ?2, ?3, ?4, ?5, 'held', ?6, ?7, ?8
+                 FROM user_credits WHERE app_id = ?2 AND user_id = ?3 AND balance - held_credits >= ?5 AND {}",
+                crate::orgs::within_spend_cap_sql(9)
+            ))
+            .bind(&params)?,
+            db.prepare(
+                "UPDATE user_credits SET held_credits = held_credits + ?1, updated_at = ?2
+                 WHERE app_id = ?3 AND user_id = ?4 AND EXISTS (SELECT 1 FROM credit_holds WHERE id = ?5)",
+            )
+            .bind(&[amount.into(), now.to_rfc3339().into(), app_id.into(), user_id.into(), hold_id.clone().into()])?,
+        ])
+        .await?;
+    let placed = results
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|m| m.changes)
+        .unwrap_or(0)
+        > 0;
+    if !placed {
+        // Lost a race with another charge; report whichever limit it hit.
+        crate::orgs::ensure_within_spend_cap(app_id, payer, amount, db).await?;
+        let available = get_available_balance(app_id, user_id, db).await?;
+        return Err(AppError::PaymentRequired(format!(
+            "Insufficient credits. Need {} credits, have {}. Purchase more at /credits",
+            amount, available
+        )).into());
+    }
+
+    Ok(hold_id)
+}
+
+/// Charges the actual cost of a held call as a normal 'spend' and releases the
+/// escrow. The cost may exceed the hold (a chat reply longer than estimated);
+/// the work is done, so it is charged in full even if that takes the wallet
+/// negative. The charge is recorded on the hold first, so if settling fails the
+/// cron finishes the capture instead of releasing the escrow. Returns the new
+/// balance.
+pub async fn capture_hold(
+    hold_id: &str,
+    actual: u32,
+    description: &str,
+    reference_id: &str,
+    db: &D1Database,
+) -> Result<i32> {
+    db.prepare(
+        "UPDATE credit_holds SET captured_amount = ?1, capture_description = ?2, capture_reference_id = ?3
+         WHERE id = ?4 AND status = 'held'",
+    )
+    .bind(&[actual.into(), description.into(), reference_id.into(), hold_id.into()])?
     .run()
     .await?;
+    settle_capture(hold_id, db).await
+}
+
+/// Charges the amount recorded on a hold by `capture_hold`. Balance, escrow and
+/// ledger move in one D1 batch so a capture can't land half-applied.
+async fn settle_capture(hold_id: &str, db: &D1Database) -> Result<i32> {
+    let (app_id, user_id, amount) = claim_hold(hold_id, "captured", db).await?;
+    let capture = db
+        .prepare("SELECT member_id, captured_amount, capture_description, capture_reference_id FROM credit_holds WHERE id = ?1")
+        .bind(&[hold_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .unwrap_or_default();
+    let text = |key: &str| capture.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let charge = capture
+        .get("captured_amount")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0)
+        .max(0) as u32;
+    let now = Utc::now().to_rfc3339();
 
-    // Record transaction
+    let mut statements = vec![db
+        .prepare(
+            "UPDATE user_credits
+             SET held_credits = MAX(held_credits - ?1, 0), balance = balance - ?2,
+                 lifetime_spent = lifetime_spent + ?2, updated_at = ?3
+             WHERE app_id = ?4 AND user_id = ?5",
+        )
+        .bind(&[amount.into(), charge.into(), now.clone().into(), app_id.clone().into(), user_id.clone().into()])?];
+    if charge > 0 {
+        statements.push(
+            db.prepare(
+                "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, member_id, request_id)
+                 VALUES (?1, ?2, ?3, 'spend', ?4, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?5, ?6, ?7, ?8, ?9)",
+            )
+            .bind(&[
+                Uuid::new_v4().to_string().into(),
+                app_id.clone().into(),
+                user_id.clone().into(),
+                (-(charge as i32)).into(),
+                text("capture_description").unwrap_or_default().into(),
+                text("capture_reference_id").unwrap_or_default().into(),
+                now.clone().into(),
+                text("member_id").map(|m| m.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+                crate::request_id::value(),
+            ])?,
+        );
+    }
+
+    if let Err(e) = db.batch(statements).await {
+        unclaim_hold(hold_id, db).await;
+        return Err(e);
+    }
+    consume_promo_credits(&app_id, &user_id, charge, db).await?;
+
+    get_user_balance(&app_id, &user_id, db).await
+}
+
+/// Returns a hold's escrow untouched — the call failed before producing anything.
+pub async fn release_hold(hold_id: &str, db: &D1Database) -> Result<()> {
+    let (app_id, user_id, amount) = claim_hold(hold_id, "released", db).await?;
+    if let Err(e) = unescrow(&app_id, &user_id, amount, db).await {
+        unclaim_hold(hold_id, db).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Settles holds that were never captured or released. One whose capture was
+/// recorded but failed to settle is charged what it recorded; the rest (the
+/// worker was killed or errored out before capturing) are released. Same
+/// claim-then-act shape as the realtime reservation sweep, so it can never race
+/// a late capture. Returns the number of holds settled.
+pub async fn expire_stale_holds(db: &D1Database) -> Result<u32> {
+    let now = Utc::now().to_rfc3339();
+    let rows = db
+        .prepare("SELECT id, captured_amount FROM credit_holds WHERE status = 'held' AND expires_at < ?1 LIMIT 200")
+        .bind(&[now.into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+
+    let mut settled = 0u32;
+    for row in rows {
+        let Some(id) = row.get("id").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        if row.get("captured_amount").is_some_and(|v| !v.is_null()) {
+            if settle_capture(id, db).await.is_ok() {
+                settled += 1;
+            }
+            continue;
+        }
+        let Ok((app_id, user_id, amount)) = claim_hold(id, "expired", db).await else {
+            continue;
+        };
+        if unescrow(&app_id, &user_id, amount, db).await.is_err() {
+            unclaim_hold(id, db).await;
+            continue;
+        }
+        settled += 1;
+    }
+    Ok(settled)
+}
+
+/// Moves a hold out of 'held' exactly once. Returns (app_id, user_id, amount)
+/// for the caller that won; everyone else gets a Conflict.
+async fn claim_hold(hold_id: &str, status: &str, db: &D1Database) -> Result<(String, String, u32)> {
+    let now = Utc::now().to_rfc3339();
+    let claim = db
+        .prepare("UPDATE credit_holds SET status = ?1, settled_at = ?2 WHERE id = ?3 AND status = 'held'")
+        .bind(&[status.into(), now.into(), hold_id.into()])?
+        .run()
+        .await?;
+    let won = claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0;
+    if !won {
+        return Err(AppError::Conflict(format!("Hold {} is already settled", hold_id)).into());
+    }
+
+    let row = db
+        .prepare("SELECT app_id, user_id, amount FROM credit_holds WHERE id = ?1")
+        .bind(&[hold_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Hold not found".to_string()))?;
+    Ok((
+        row.get("app_id").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        row.get("user_id").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        row.get("amount").and_then(|v| v.as_i64()).unwrap_or(0).max(0) as u32,
+    ))
+}
+
+async fn unclaim_hold(hold_id: &str, db: &D1Database) {
+    if let Ok(stmt) = db
+        .prepare("UPDATE credit_holds SET status = 'held', settled_at = NULL WHERE id = ?1")
+        .bind(&[hold_id.into()])
+    {
+        let _ = stmt.run().await;
+    }
+}
+
+async fn unescrow(app_id: &str, user_id: &str, amount: u32, db: &D1Database) -> Result<()> {
     db.prepare(
-        "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at)
-         VALUES (?, ?, ?, 'spend', ?, ?, ?, ?, ?)"
+        "UPDATE user_credits SET held_credits = MAX(held_credits - ?1, 0), updated_at = ?2
+         WHERE app_id = ?3 AND user_id = ?4",
     )
-    .bind(&[
-        transaction_id.into(),
-        app_id.into(),
-        user_id.into(),
-        (-(amount as i32)).into(),
-        new_balance.into(),
-        description.into(),
-        reference_id.into(),
-        now.into(),
-    ])?
+    .bind(&[amount.into(), Utc::now().to_rfc3339().into(), app_id.into(), user_id.into()])?
     .run()
     .await?;
-
-    Ok(new_balance)
+    Ok(())
 }
 
 pub async fn add_credits(
@@ -297,61 +659,708 @@ pub async fn add_credits(
     reference_id: Option<&str>,
     db: &D1Database,
 ) -> Result<i32> {
-    let transaction_id = Uuid::new_v4().to_string();
+    credit_wallet(app_id, &Payer::user(user_id), amount, transaction_type, description, reference_id, db).await
+}
+
+/// Gives back credits a payer was charged for work that didn't happen. On an
+/// org wallet the refund is recorded against the member, so it also frees up
+/// their spending cap.
+pub async fn refund_credits(
+    app_id: &str,
+    payer: &Payer,
+    amount: u32,
+    description: &str,
+    reference_id: &str,
+    db: &D1Database,
+) -> Result<i32> {
+    credit_wallet(app_id, payer, amount, "refund", description, Some(reference_id), db).await
+}
+
+async fn credit_wallet(
+    app_id: &str,
+    payer: &Payer,
+    amount: u32,
+    transaction_type: &str,
+    description: &str,
+    reference_id: Option<&str>,
+    db: &D1Database,
+) -> Result<i32> {
+    let user_id = payer.wallet_id.as_str();
     let now = Utc::now().to_rfc3339();
 
-    // Get current balance
-    let current_balance = get_user_balance(app_id, user_id, db).await?;
-    let new_balance = current_balance + amount as i32;
+    // A relative update and the ledger entry in one batch, so a concurrent
+    // charge to the same wallet can't be overwritten.
+    let lifetime_purchased = if transaction_type == "purchase" { amount } else { 0 };
+    db.batch(vec![
+        db.prepare(
+            "UPDATE user_credits SET balance = balance + ?1, lifetime_purchased = lifetime_purchased + ?2, updated_at = ?3
+             WHERE app_id = ?4 AND user_id = ?5",
+        )
+        .bind(&[amount.into(), lifetime_purchased.into(), now.clone().into(), app_id.into(), user_id.into()])?,
+        db.prepare(
+            "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, member_id, request_id)
+             SELECT ?1, ?2, ?3, ?4, ?5, balance, ?6, ?7, ?8, ?9, ?10 FROM user_credits WHERE app_id = ?2 AND user_id = ?3",
+        )
+        .bind(&[
+            Uuid::new_v4().to_string().into(),
+            app_id.into(),
+            user_id.into(),
+            transaction_type.into(),
+            amount.into(),
+            description.into(),
+            reference_id.map(|r| r.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            now.into(),
+            payer.member_value(),
+            crate::request_id::value(),
+        ])?,
+    ])
+    .await?;
 
-    // Update balance and lifetime_purchased if it's a purchase
-    let update_query = if transaction_type == "purchase" {
-        "UPDATE user_credits
-         SET balance = ?, lifetime_purchased = lifetime_purchased + ?, updated_at = ?
-         WHERE app_id = ? AND user_id = ?"
-    } else {
-        "UPDATE user_credits
-         SET balance = ?, updated_at = ?
-         WHERE app_id = ? AND user_id = ?"
-    };
+    get_user_balance(app_id, user_id, db).await
+}
 
-    let mut params = vec![
-        new_balance.into(),
-    ];
+/// Expiry for a promo grant from the tenant's `promo_credit_ttl_days`, given
+/// an `apps` row. None (column NULL or row missing) means it never expires.
+pub fn promo_expiry(app_row: Option<&serde_json::Value>) -> Option<DateTime<Utc>> {
+    app_row
+        .and_then(|v| v.get("promo_credit_ttl_days"))
+        .and_then(|v| v.as_i64())
+        .filter(|days| *days > 0)
+        .map(|days| Utc::now() + Duration::days(days))
+}
 
-    if transaction_type == "purchase" {
-        params.push(amount.into());
-    }
+/// A grant of non-purchased credits, for `add_promo_credits`.
+#[derive(Debug, Clone, Copy)]
+pub struct PromoGrant<'a> {
+    pub app_id: &'a str,
+    pub user_id: &'a str,
+    pub amount: u32,
+    /// Ledger transaction type, also recorded as the bucket's source.
+    pub source: &'a str,
+    pub description: &'a str,
+    pub reference_id: Option<&'a str>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
 
-    params.push(now.clone().into());
-    params.push(app_id.into());
-    params.push(user_id.into());
+/// Grants non-purchased credits: a normal ledger entry via `add_credits`, plus
+/// a bucket recording the source and expiry so they are spent first and can
+/// lapse.
+pub async fn add_promo_credits(grant: &PromoGrant<'_>, db: &D1Database) -> Result<i32> {
+    let new_balance = add_credits(
+        grant.app_id,
+        grant.user_id,
+        grant.amount,
+        grant.source,
+        grant.description,
+        grant.reference_id,
+        db,
+    )
+    .await?;
+    open_bucket(grant, db).await?;
+    Ok(new_balance)
+}
 
-    db.prepare(update_query)
-        .bind(&params)?
-        .run()
+/// `add_promo_credits` in one D1 batch behind `guard`, a conditional insert
+/// run first. Every write of the grant is conditioned on `recorded`, SQL that
+/// binds `guard_id` as ?1 and holds once the guard's row exists, so the grant
+/// applies only if the guard went in. Returns the new balance, or None when
+/// the guard inserted nothing.
+pub async fn add_promo_credits_guarded(
+    grant: &PromoGrant<'_>,
+    guard: worker::D1PreparedStatement,
+    recorded: &str,
+    guard_id: &str,
+    db: &D1Database,
+) -> Result<Option<i32>> {
+    let now = Utc::now().to_rfc3339();
+    let reference_id = grant
+        .reference_id
+        .map(|r| r.into())
+        .unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    let results = db
+        .batch(vec![
+            guard,
+            db.prepare(format!(
+                "UPDATE user_credits SET balance = balance + ?2, updated_at = ?3 WHERE app_id = ?4 AND user_id = ?5 AND {}",
+                recorded
+            ))
+            .bind(&[guard_id.into(), grant.amount.into(), now.clone().into(), grant.app_id.into(), grant.user_id.into()])?,
+            db.prepare(format!(
+                "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+                 SELECT ?2, ?3, ?4, ?5, ?6, balance, ?7, ?8, ?9, ?10 FROM user_credits WHERE app_id = ?3 AND user_id = ?4 AND {}",
+                recorded
+            ))
+            .bind(&[
+                guard_id.into(),
+                Uuid::new_v4().to_string().into(),
+                grant.app_id.into(),
+                grant.user_id.into(),
+                grant.source.into(),
+                grant.amount.into(),
+                grant.description.into(),
+                reference_id.clone(),
+                now.clone().into(),
+                crate::request_id::value(),
+            ])?,
+            db.prepare(format!(
+                "INSERT INTO credit_buckets (id, app_id, user_id, source, original_amount, remaining, reference_id, expires_at, created_at)
+                 SELECT ?2, ?3, ?4, ?5, ?6, ?6, ?7, ?8, ?9 WHERE {}",
+                recorded
+            ))
+            .bind(&[
+                guard_id.into(),
+                Uuid::new_v4().to_string().into(),
+                grant.app_id.into(),
+                grant.user_id.into(),
+                grant.source.into(),
+                grant.amount.into(),
+                reference_id,
+                grant.expires_at.map(|t| t.to_rfc3339().into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+                now.into(),
+            ])?,
+        ])
         .await?;
+    let guarded = results
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+    if guarded == 0 {
+        return Ok(None);
+    }
+    get_user_balance(grant.app_id, grant.user_id, db).await.map(Some)
+}
+
+/// Balances of several wallets of an app, keyed by wallet id; wallets that
+/// don't exist are left out.
+pub async fn get_balances(
+    app_id: &str,
+    user_ids: &[String],
+    db: &D1Database,
+) -> Result<std::collections::HashMap<String, i32>> {
+    let mut balances = std::collections::HashMap::new();
+    // D1 binds at most 100 values per statement.
+    for chunk in user_ids.chunks(90) {
+        let sql = format!(
+            "SELECT user_id, balance FROM user_credits WHERE app_id = ? AND user_id IN ({})",
+            vec!["?"; chunk.len()].join(", ")
+        );
+        let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![app_id.into()];
+        params.extend(chunk.iter().map(|id| id.clone().into()));
+        let rows = db.prepare(&sql).bind(&params)?.all().await?.results::<serde_json::Value>()?;
+        for row in rows {
+            if let (Some(id), Some(balance)) = (
+                row.get("user_id").and_then(|v| v.as_str()),
+                row.get("balance").and_then(|v| v.as_i64()),
+            ) {
+                balances.insert(id.to_string(), balance as i32);
+            }
+        }
+    }
+    Ok(balances)
+}
+
+/// Recipients granted per D1 batch by `gift_promo_credits`; each takes three
+/// statements.
+const GIFT_BATCH_RECIPIENTS: usize = 50;
 
-    // Record transaction
+/// Grants `amount` promotional credits (source 'admin_adjustment') to each of
+/// `user_ids` under one gift id, a batch of recipients at a time rather than a
+/// round of queries per user. Every write for a recipient is conditioned on
+/// that recipient having no bucket for `gift_id` yet, so running the same gift
+/// again (a retried request) only grants whoever it missed.
+pub async fn gift_promo_credits(
+    app_id: &str,
+    gift_id: &str,
+    user_ids: &[String],
+    amount: u32,
+    description: &str,
+    expires_at: Option<DateTime<Utc>>,
+    db: &D1Database,
+) -> Result<()> {
+    let not_granted = "NOT EXISTS (SELECT 1 FROM credit_buckets WHERE app_id = ?2 AND user_id = ?3 AND reference_id = ?4)";
+    let expires_at = expires_at
+        .map(|t| t.to_rfc3339().into())
+        .unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    for chunk in user_ids.chunks(GIFT_BATCH_RECIPIENTS) {
+        let now = Utc::now().to_rfc3339();
+        let mut statements = Vec::with_capacity(chunk.len() * 3);
+        for user_id in chunk {
+            statements.push(
+                db.prepare(format!(
+                    "UPDATE user_credits SET balance = balance + ?1, updated_at = ?5 WHERE app_id = ?2 AND user_id = ?3 AND {}",
+                    not_granted
+                ))
+                .bind(&[amount.into(), app_id.into(), user_id.into(), gift_id.into(), now.clone().into()])?,
+            );
+            statements.push(
+                db.prepare(format!(
+                    "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+                     SELECT ?1, ?2, ?3, 'admin_adjustment', ?5, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?6, ?4, ?7, ?8
+                     WHERE {}",
+                    not_granted
+                ))
+                .bind(&[
+                    Uuid::new_v4().to_string().into(),
+                    app_id.into(),
+                    user_id.into(),
+                    gift_id.into(),
+                    amount.into(),
+                    description.into(),
+                    now.clone().into(),
+                    crate::request_id::value(),
+                ])?,
+            );
+            statements.push(
+                db.prepare(format!(
+                    "INSERT INTO credit_buckets (id, app_id, user_id, source, original_amount, remaining, reference_id, expires_at, created_at)
+                     SELECT ?1, ?2, ?3, 'admin_adjustment', ?5, ?5, ?4, ?6, ?7
+                     WHERE {}",
+                    not_granted
+                ))
+                .bind(&[
+                    Uuid::new_v4().to_string().into(),
+                    app_id.into(),
+                    user_id.into(),
+                    gift_id.into(),
+                    amount.into(),
+                    expires_at.clone(),
+                    now.clone().into(),
+                ])?,
+            );
+        }
+        db.batch(statements).await?;
+    }
+    Ok(())
+}
+
+/// A paid subscription invoice, for `grant_subscription_allowance`.
+#[derive(Debug, Clone, Copy)]
+pub struct AllowanceGrant<'a> {
+    pub app_id: &'a str,
+    pub user_id: &'a str,
+    pub plan_id: &'a str,
+    pub amount: u32,
+    pub amount_paid_cents: u32,
+    pub description: &'a str,
+    /// The purchase row's payment_id.
+    pub invoice_id: &'a str,
+    /// The Stripe subscription, which the allowance buckets reference.
+    pub subscription_id: &'a str,
+    pub period_end: DateTime<Utc>,
+    /// Most unused allowance carried into the new period.
+    pub rollover_cap: i32,
+}
+
+/// Grants a paid subscription invoice's allowance. Up to `rollover_cap` of the
+/// subscription's unused allowance (newest buckets first) is carried into the
+/// period ending `period_end` and the rest lapses with an 'expire' entry; then
+/// the invoice gets a completed `credit_purchases` row (payment_id = invoice
+/// id), a 'purchase' ledger entry tied to it, and a 'subscription' bucket
+/// sharing the purchase's id (so a refund can empty it) and referencing the
+/// Stripe subscription, which lapses `ALLOWANCE_GRACE_DAYS` after `period_end`
+/// unless rolled over. It is all one D1 batch that only lands if
+/// the invoice has no purchase row yet, so a redelivered (or older) invoice
+/// neither grants nor lapses anything. Returns the purchase id and the credits
+/// carried over when this call made the grant.
+pub async fn grant_subscription_allowance(
+    grant: &AllowanceGrant<'_>,
+    db: &D1Database,
+) -> Result<Option<(String, i32)>> {
+    let purchase_id = Uuid::new_v4().to_string();
+    let now = Utc::now().to_rfc3339();
+    let expires_at = (grant.period_end + Duration::days(ALLOWANCE_GRACE_DAYS)).to_rfc3339();
+
+    let mut statements = vec![db
+        .prepare(
+            "INSERT INTO credit_purchases (id, app_id, user_id, pack_id, credits, amount_usd_cents, payment_provider, payment_id, status, created_at, completed_at)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6, 'stripe', ?7, 'completed', ?8, ?8
+             WHERE NOT EXISTS (SELECT 1 FROM credit_purchases WHERE payment_provider = 'stripe' AND payment_id = ?7)"
+        )
+        .bind(&[
+            purchase_id.clone().into(),
+            grant.app_id.into(),
+            grant.user_id.into(),
+            grant.plan_id.into(),
+            grant.amount.into(),
+            grant.amount_paid_cents.into(),
+            grant.invoice_id.into(),
+            now.clone().into(),
+        ])?];
+    let (rollover, carried) = rollover_statements(grant, &purchase_id, &expires_at, &now, db).await?;
+    statements.extend(rollover);
+    statements.extend([
+        db.prepare(
+            "UPDATE user_credits SET balance = balance + ?1, lifetime_purchased = lifetime_purchased + ?1, updated_at = ?2
+             WHERE app_id = ?3 AND user_id = ?4 AND EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?5)"
+        )
+        .bind(&[
+            grant.amount.into(),
+            now.clone().into(),
+            grant.app_id.into(),
+            grant.user_id.into(),
+            purchase_id.clone().into(),
+        ])?,
+        db.prepare(
+            "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+             SELECT ?1, ?2, ?3, 'purchase', ?4, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?5, ?6, ?7, ?8
+             WHERE EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?6)"
+        )
+        .bind(&[
+            Uuid::new_v4().to_string().into(),
+            grant.app_id.into(),
+            grant.user_id.into(),
+            grant.amount.into(),
+            grant.description.into(),
+            purchase_id.clone().into(),
+            now.clone().into(),
+            crate::request_id::value(),
+        ])?,
+        db.prepare(
+            "INSERT INTO credit_buckets (id, app_id, user_id, source, original_amount, remaining, reference_id, expires_at, created_at)
+             SELECT ?1, ?2, ?3, 'subscription', ?4, ?4, ?5, ?6, ?7
+             WHERE EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?1)"
+        )
+        .bind(&[
+            purchase_id.clone().into(),
+            grant.app_id.into(),
+            grant.user_id.into(),
+            grant.amount.into(),
+            grant.subscription_id.into(),
+            expires_at.into(),
+            now.into(),
+        ])?,
+    ]);
+
+    let results = db.batch(statements).await?;
+    let granted = results
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|m| m.changes)
+        .unwrap_or(0)
+        > 0;
+    Ok(granted.then_some((purchase_id, carried)))
+}
+
+/// How much of each unused allowance bucket (newest first) carries over, up to
+/// `cap` in total; the rest of each bucket lapses.
+fn plan_rollover(remaining: &[i32], cap: i32) -> Vec<i32> {
+    let mut carry_left = cap.max(0);
+    remaining
+        .iter()
+        .map(|&remaining| {
+            let keep = remaining.max(0).min(carry_left);
+            carry_left -= keep;
+            keep
+        })
+        .collect()
+}
+
+/// The rollover half of `grant_subscription_allowance`, as statements for its
+/// batch, each conditioned on the invoice's purchase row `purchase_id` having
+/// landed. A lapse is debited (capped at the available balance) with an
+/// 'expire' entry like `lapse_bucket`, and only if the bucket still holds what
+/// was read; if a spend moved it first, it is neither lapsed nor extended and
+/// lapses with its old expiry. Returns the statements and the credits carried.
+async fn rollover_statements(
+    grant: &AllowanceGrant<'_>,
+    purchase_id: &str,
+    expires_at: &str,
+    now: &str,
+    db: &D1Database,
+) -> Result<(Vec<D1PreparedStatement>, i32)> {
+    let rows = db
+        .prepare(
+            "SELECT id, remaining FROM credit_buckets
+             WHERE app_id = ?1 AND user_id = ?2 AND source = 'subscription' AND reference_id = ?3 AND remaining > 0
+             ORDER BY created_at DESC"
+        )
+        .bind(&[grant.app_id.into(), grant.user_id.into(), grant.subscription_id.into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    let buckets: Vec<(String, i32)> = rows
+        .iter()
+        .filter_map(|row| {
+            let id = row.get("id")?.as_str()?.to_string();
+            let remaining = row.get("remaining")?.as_i64()? as i32;
+            Some((id, remaining))
+        })
+        .collect();
+    let remaining: Vec<i32> = buckets.iter().map(|(_, r)| *r).collect();
+
+    let mut statements = Vec::new();
+    let mut carried = 0;
+    for ((id, remaining), keep) in buckets.iter().zip(plan_rollover(&remaining, grant.rollover_cap)) {
+        let lapse = remaining - keep;
+        if lapse > 0 {
+            let entry_id = Uuid::new_v4().to_string();
+            statements.push(
+                db.prepare(
+                    "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+                     SELECT ?1, ?2, ?3, 'expire', -MIN(?4, balance - held_credits), balance - MIN(?4, balance - held_credits),
+                            'Expired subscription credits', ?5, ?6, ?7
+                     FROM user_credits
+                     WHERE app_id = ?2 AND user_id = ?3 AND MIN(?4, balance - held_credits) > 0
+                       AND EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?8)
+                       AND EXISTS (SELECT 1 FROM credit_buckets WHERE id = ?5 AND remaining = ?9)"
+                )
+                .bind(&[
+                    entry_id.clone().into(),
+                    grant.app_id.into(),
+                    grant.user_id.into(),
+                    lapse.into(),
+                    id.clone().into(),
+                    now.into(),
+                    crate::request_id::value(),
+                    purchase_id.into(),
+                    (*remaining).into(),
+                ])?,
+            );
+            statements.push(
+                db.prepare(
+                    "UPDATE credit_buckets
+                     SET remaining = remaining - ?1,
+                         expired_amount = expired_amount + COALESCE((SELECT -amount FROM credit_transactions WHERE id = ?2), 0)
+                     WHERE id = ?3 AND remaining = ?4 AND EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?5)"
+                )
+                .bind(&[lapse.into(), entry_id.clone().into(), id.clone().into(), (*remaining).into(), purchase_id.into()])?,
+            );
+            statements.push(
+                db.prepare(
+                    "UPDATE user_credits
+                     SET balance = balance + (SELECT amount FROM credit_transactions WHERE id = ?1), updated_at = ?2
+                     WHERE app_id = ?3 AND user_id = ?4 AND EXISTS (SELECT 1 FROM credit_transactions WHERE id = ?1)"
+                )
+                .bind(&[entry_id.into(), now.into(), grant.app_id.into(), grant.user_id.into()])?,
+            );
+        }
+        if keep > 0 {
+            statements.push(
+                db.prepare(
+                    "UPDATE credit_buckets SET expires_at = ?1
+                     WHERE id = ?2 AND remaining <= ?3 AND EXISTS (SELECT 1 FROM credit_purchases WHERE id = ?4)"
+                )
+                .bind(&[expires_at.into(), id.clone().into(), keep.into(), purchase_id.into()])?,
+            );
+            carried += keep;
+        }
+    }
+    Ok((statements, carried))
+}
+
+async fn open_bucket(grant: &PromoGrant<'_>, db: &D1Database) -> Result<()> {
     db.prepare(
-        "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at)
+        "INSERT INTO credit_buckets (id, app_id, user_id, source, original_amount, remaining, reference_id, expires_at, created_at)
          VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&[
-        transaction_id.into(),
-        app_id.into(),
-        user_id.into(),
-        transaction_type.into(),
-        amount.into(),
-        new_balance.into(),
-        description.into(),
-        reference_id.map(|r| r.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
-        now.into(),
+        Uuid::new_v4().to_string().into(),
+        grant.app_id.into(),
+        grant.user_id.into(),
+        grant.source.into(),
+        grant.amount.into(),
+        grant.amount.into(),
+        grant.reference_id.map(|r| r.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        grant.expires_at.map(|t| t.to_rfc3339().into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        Utc::now().to_rfc3339().into(),
     ])?
     .run()
     .await?;
+    Ok(())
+}
 
-    Ok(new_balance)
+/// Live (unexpired, non-empty) buckets, in spending order.
+async fn active_buckets(app_id: &str, user_id: &str, db: &D1Database) -> Result<Vec<CreditBucket>> {
+    let now = Utc::now().to_rfc3339();
+    let rows = db
+        .prepare(
+            "SELECT id, source, original_amount, remaining, expires_at, created_at FROM credit_buckets
+             WHERE app_id = ?1 AND user_id = ?2 AND remaining > 0 AND (expires_at IS NULL OR expires_at > ?3)"
+        )
+        .bind(&[app_id.into(), user_id.into(), now.into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    let mut buckets: Vec<CreditBucket> = rows
+        .into_iter()
+        .filter_map(|row| serde_json::from_value::<CreditBucket>(row).ok())
+        .collect();
+    sort_for_spending(&mut buckets);
+    Ok(buckets)
+}
+
+/// Spending order: soonest-expiring first, oldest first among equals, and
+/// buckets that never expire last.
+fn sort_for_spending(buckets: &mut [CreditBucket]) {
+    buckets.sort_by(|a, b| {
+        (a.expires_at.is_none(), &a.expires_at, &a.created_at)
+            .cmp(&(b.expires_at.is_none(), &b.expires_at, &b.created_at))
+    });
+}
+
+/// How much of `amount` each bucket gives up, taking them in order until the
+/// amount is covered. Whatever the buckets don't cover is paid credit.
+fn draw_from_buckets(buckets: &[CreditBucket], amount: i32) -> Vec<(&str, i32)> {
+    let mut left = amount.max(0);
+    let mut draws = Vec::new();
+    for bucket in buckets {
+        if left <= 0 {
+            break;
+        }
+        let take = bucket.remaining.min(left);
+        if take > 0 {
+            draws.push((bucket.id.as_str(), take));
+            left -= take;
+        }
+    }
+    draws
+}
+
+/// Draws `amount` of a spend from promo buckets, soonest-expiring first. The
+/// balance itself has already moved; this only keeps the buckets in step so
+/// promotional credits are never outlived by paid ones.
+pub async fn consume_promo_credits(app_id: &str, user_id: &str, amount: u32, db: &D1Database) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let buckets = active_buckets(app_id, user_id, db).await?;
+    let mut statements = Vec::new();
+    for (id, take) in draw_from_buckets(&buckets, amount as i32) {
+        statements.push(
+            db.prepare("UPDATE credit_buckets SET remaining = MAX(remaining - ?1, 0) WHERE id = ?2")
+                .bind(&[take.into(), id.into()])?,
+        );
+    }
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+    Ok(())
+}
+
+/// Paid, promotional and subscription-allowance split of the current balance,
+/// with the live buckets.
+pub async fn get_credit_breakdown(app_id: &str, user_id: &str, db: &D1Database) -> Result<CreditBreakdown> {
+    let balance = get_user_balance(app_id, user_id, db).await?;
+    Ok(split_balance(balance, active_buckets(app_id, user_id, db).await?))
+}
+
+/// Splits a balance over its live buckets: subscription allowance first, then
+/// promotional credits, and whatever they don't cover is paid.
+fn split_balance(balance: i32, buckets: Vec<CreditBucket>) -> CreditBreakdown {
+    let balance = balance.max(0);
+    let remaining = |subscription: bool| {
+        buckets
+            .iter()
+            .filter(|b| (b.source == "subscription") == subscription)
+            .map(|b| b.remaining)
+            .sum::<i32>()
+    };
+    let subscription = remaining(true).min(balance);
+    let promotional = remaining(false).min(balance - subscription);
+    CreditBreakdown {
+        paid: balance - subscription - promotional,
+        promotional,
+        subscription,
+        buckets,
+    }
+}
+
+/// Lapses promo buckets past their expiry, debiting whatever is left with an
+/// 'expire' ledger entry. Each bucket is claimed by zeroing `remaining` against
+/// the value just read, so a concurrent spend simply defers it to the next run.
+/// Never takes escrowed (held) credits: an in-flight call keeps what it reserved.
+pub async fn expire_promo_credits(db: &D1Database) -> Result<u32> {
+    let now = Utc::now().to_rfc3339();
+    let rows = db
+        .prepare(
+            "SELECT id, app_id, user_id, source, remaining FROM credit_buckets
+             WHERE remaining > 0 AND expires_at IS NOT NULL AND expires_at <= ?1 LIMIT 200"
+        )
+        .bind(&[now.into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+
+    let mut expired = 0u32;
+    for row in rows {
+        let id = row.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let app_id = row.get("app_id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let user_id = row.get("user_id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let source = row.get("source").and_then(|v| v.as_str()).unwrap_or("promo").to_string();
+        let remaining = row.get("remaining").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+        if id.is_empty() || remaining <= 0 {
+            continue;
+        }
+        if lapse_bucket(&id, &app_id, &user_id, &source, remaining, remaining, db).await? {
+            expired += 1;
+        }
+    }
+    Ok(expired)
+}
+
+/// Lapses `lapse` of a bucket last seen holding `remaining`, debiting it (capped
+/// at the available balance) with an 'expire' ledger entry. Returns false if a
+/// concurrent spend moved the bucket first or the debit failed; the bucket is
+/// then left as it was for the next attempt.
+async fn lapse_bucket(
+    id: &str,
+    app_id: &str,
+    user_id: &str,
+    source: &str,
+    remaining: i32,
+    lapse: i32,
+    db: &D1Database,
+) -> Result<bool> {
+    let now = Utc::now().to_rfc3339();
+    let amount = lapse_debit(lapse, get_available_balance(app_id, user_id, db).await?);
+    let claim = db
+        .prepare("UPDATE credit_buckets SET remaining = ?1, expired_amount = expired_amount + ?2 WHERE id = ?3 AND remaining = ?4")
+        .bind(&[(remaining - lapse).into(), amount.into(), id.into(), remaining.into()])?
+        .run()
+        .await?;
+    let won = claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0;
+    if !won {
+        return Ok(false);
+    }
+    if amount > 0 {
+        let debit = vec![
+            db.prepare(
+                "UPDATE user_credits SET balance = balance - ?1, updated_at = ?2
+                 WHERE app_id = ?3 AND user_id = ?4"
+            )
+            .bind(&[amount.into(), now.clone().into(), app_id.into(), user_id.into()])?,
+            db.prepare(
+                "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+                 VALUES (?1, ?2, ?3, 'expire', ?4, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?5, ?6, ?7, ?8)"
+            )
+            .bind(&[
+                Uuid::new_v4().to_string().into(),
+                app_id.into(),
+                user_id.into(),
+                (-amount).into(),
+                format!("Expired {} credits", source).into(),
+                id.into(),
+                now.into(),
+                crate::request_id::value(),
+            ])?,
+        ];
+        if db.batch(debit).await.is_err() {
+            let _ = db
+                .prepare("UPDATE credit_buckets SET remaining = ?1, expired_amount = expired_amount - ?2 WHERE id = ?3")
+                .bind(&[remaining.into(), amount.into(), id.into()])?
+                .run()
+                .await;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// What lapsing `lapse` bucket credits debits: never more than is available,
+/// so a lapse can't eat into escrow or take the wallet negative.
+fn lapse_debit(lapse: i32, available: i32) -> i32 {
+    lapse.min(available).max(0)
 }
 
 pub async fn record_purchase(
@@ -402,8 +1411,8 @@ pub async fn complete_purchase(
         .await?;
     
     if let Some(purchase) = existing {
-        if purchase.get("status").and_then(|s| s.as_str()) == Some("completed") {
-            // Already completed, return success (idempotent)
+        if matches!(purchase.get("status").and_then(|s| s.as_str()), Some("completed") | Some("refunded")) {
+            // Already completed (or since refunded), return success (idempotent)
             worker::console_log!("Purchase {} already completed, skipping", purchase_id);
             return Ok(());
         }
@@ -411,7 +1420,7 @@ pub async fn complete_purchase(
     
     // Get purchase details
     let purchase = db
-        .prepare("SELECT app_id, user_id, pack_id, credits, payment_provider FROM credit_purchases WHERE id = ? AND status = 'pending'")
+        .prepare("SELECT app_id, user_id, pack_id, credits, payment_provider, promo_code FROM credit_purchases WHERE id = ? AND status = 'pending'")
         .bind(&[purchase_id.into()])?
         .first::<serde_json::Value>(None)
         .await?
@@ -440,6 +1449,192 @@ pub async fn complete_purchase(
     };
     add_credits(app_id, user_id, credits, "purchase", &description, Some(purchase_id), db).await?;
 
+    // A discount code is only used up once the discounted purchase is paid for.
+    if let Some(code) = purchase.get("promo_code").and_then(|v| v.as_str()) {
+        if let Err(e) = crate::promo_codes::claim_for_purchase(app_id, code, user_id, purchase_id, db).await {
+            worker::console_error!("Promo code {} claim for purchase {} failed: {:?}", code, purchase_id, e);
+        }
+    }
+
+    // The purchase itself has settled; a referral payout failure must not undo it.
+    if let Err(e) = crate::referrals::reward_on_purchase(app_id, user_id, purchase_id, db).await {
+        worker::console_error!("Referral reward for purchase {} failed: {:?}", purchase_id, e);
+    }
+
+    Ok(())
+}
+
+/// Finds our purchase row for a provider's payment reference (checkout session,
+/// invoice, store transaction or payment id).
+pub async fn find_purchase_by_payment(
+    payment_provider: &str,
+    payment_id: &str,
+    db: &D1Database,
+) -> Result<Option<String>> {
+    Ok(db
+        .prepare("SELECT id FROM credit_purchases WHERE payment_provider = ?1 AND payment_id = ?2 ORDER BY created_at DESC LIMIT 1")
+        .bind(&[payment_provider.into(), payment_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("id").and_then(|i| i.as_str()).map(|s| s.to_string())))
+}
+
+/// Takes back the credits of a refunded or disputed purchase: the purchase moves
+/// to 'refunded' and a negative 'refund_clawback' entry (reference_id = purchase
+/// id) debits the full amount, even below zero. What is left of a subscription
+/// invoice's allowance bucket is emptied in the same batch, so it no longer
+/// counts as allowance or lapses later. Under the tenant's 'freeze' policy the
+/// wallet is frozen as well. Returns false if the purchase was not
+/// completed (never credited, or already clawed back), so redelivered events are
+/// no-ops.
+pub async fn claw_back_purchase(purchase_id: &str, reason: &str, db: &D1Database) -> Result<bool> {
+    let now = Utc::now().to_rfc3339();
+    let claim = db
+        .prepare(
+            "UPDATE credit_purchases SET status = 'refunded', refunded_at = ?1, refund_reason = ?2
+             WHERE id = ?3 AND status = 'completed'"
+        )
+        .bind(&[now.clone().into(), reason.into(), purchase_id.into()])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Ok(false);
+    }
+
+    let purchase = db
+        .prepare("SELECT app_id, user_id, pack_id, credits FROM credit_purchases WHERE id = ?1")
+        .bind(&[purchase_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Purchase not found".to_string()))?;
+    let app_id = purchase.get("app_id").and_then(|v| v.as_str()).unwrap_or("pixie").to_string();
+    let user_id = purchase.get("user_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let pack_id = purchase.get("pack_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let credits = purchase.get("credits").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+
+    let freeze = db
+        .prepare("SELECT refund_policy FROM apps WHERE app_id = ?1")
+        .bind(&[app_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("refund_policy").and_then(|p| p.as_str()).map(|p| p == "freeze"))
+        .unwrap_or(false);
+
+    let mut statements = vec![
+        db.prepare(
+            "UPDATE user_credits SET balance = balance - ?1, lifetime_purchased = MAX(lifetime_purchased - ?1, 0), updated_at = ?2
+             WHERE app_id = ?3 AND user_id = ?4"
+        )
+        .bind(&[credits.into(), now.clone().into(), app_id.clone().into(), user_id.clone().into()])?,
+        db.prepare(
+            "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+             VALUES (?1, ?2, ?3, 'refund_clawback', ?4, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?5, ?6, ?7, ?8)"
+        )
+        .bind(&[
+            Uuid::new_v4().to_string().into(),
+            app_id.clone().into(),
+            user_id.clone().into(),
+            (-credits).into(),
+            format!("Clawback ({}): {} pack", reason, pack_id).into(),
+            purchase_id.into(),
+            now.clone().into(),
+            crate::request_id::value(),
+        ])?,
+        db.prepare("UPDATE credit_buckets SET remaining = 0 WHERE id = ?1 AND source = 'subscription' AND remaining > 0")
+            .bind(&[purchase_id.into()])?,
+    ];
+    if freeze {
+        statements.push(
+            db.prepare(
+                "UPDATE user_credits SET frozen_at = COALESCE(frozen_at, ?1), frozen_reason = ?2
+                 WHERE app_id = ?3 AND user_id = ?4"
+            )
+            .bind(&[
+                now.into(),
+                format!("{} on purchase {}", reason, purchase_id).into(),
+                app_id.clone().into(),
+                user_id.clone().into(),
+            ])?,
+        );
+    }
+    if let Err(e) = db.batch(statements).await {
+        let _ = db
+            .prepare("UPDATE credit_purchases SET status = 'completed', refunded_at = NULL, refund_reason = NULL WHERE id = ?1")
+            .bind(&[purchase_id.into()])?
+            .run()
+            .await;
+        return Err(e);
+    }
+
+    worker::console_log!("Clawed back {} credits from {} for purchase {} ({})", credits, user_id, purchase_id, reason);
+
+    // Referral rewards paid out on this purchase go too, so buy-refund can't farm them.
+    if let Err(e) = crate::referrals::claw_back_on_refund(purchase_id, reason, db).await {
+        worker::console_error!("Referral clawback for purchase {} failed: {:?}", purchase_id, e);
+    }
+    Ok(true)
+}
+
+/// Reverses a clawback when the merchant wins a dispute: the credits come back
+/// as a 'purchase' entry and the purchase is completed again. A freeze is left
+/// for an admin to lift.
+pub async fn restore_purchase(purchase_id: &str, reason: &str, db: &D1Database) -> Result<bool> {
+    let claim = db
+        .prepare(
+            "UPDATE credit_purchases SET status = 'completed', refunded_at = NULL, refund_reason = NULL
+             WHERE id = ?1 AND status = 'refunded'"
+        )
+        .bind(&[purchase_id.into()])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Ok(false);
+    }
+
+    let purchase = db
+        .prepare("SELECT app_id, user_id, pack_id, credits FROM credit_purchases WHERE id = ?1")
+        .bind(&[purchase_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Purchase not found".to_string()))?;
+    let app_id = purchase.get("app_id").and_then(|v| v.as_str()).unwrap_or("pixie");
+    let user_id = purchase.get("user_id").and_then(|v| v.as_str()).unwrap_or("");
+    let pack_id = purchase.get("pack_id").and_then(|v| v.as_str()).unwrap_or("");
+    let credits = purchase.get("credits").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+
+    add_credits(
+        app_id,
+        user_id,
+        credits,
+        "purchase",
+        &format!("Restored ({}): {} pack", reason, pack_id),
+        Some(purchase_id),
+        db,
+    )
+    .await?;
+    if let Err(e) = crate::referrals::restore_on_purchase(purchase_id, db).await {
+        worker::console_error!("Referral restore for purchase {} failed: {:?}", purchase_id, e);
+    }
+    Ok(true)
+}
+
+/// When the wallet was frozen after a refund or chargeback, if it is.
+pub async fn get_frozen_at(app_id: &str, user_id: &str, db: &D1Database) -> Result<Option<String>> {
+    Ok(db
+        .prepare("SELECT frozen_at FROM user_credits WHERE app_id = ?1 AND user_id = ?2")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("frozen_at").and_then(|f| f.as_str()).map(|s| s.to_string())))
+}
+
+/// Rejects spending from a frozen wallet.
+pub async fn ensure_wallet_active(app_id: &str, user_id: &str, db: &D1Database) -> Result<()> {
+    if get_frozen_at(app_id, user_id, db).await?.is_some() {
+        return Err(AppError::Forbidden(
+            "Your wallet is frozen pending review of a refunded payment. Please contact support.".to_string(),
+        ).into());
+    }
     Ok(())
 }
 
@@ -473,6 +1668,153 @@ pub async fn get_user_transactions(
     Ok(transactions)
 }
 
+/// Every ledger entry type, as allowed by the credit_transactions CHECK.
+pub const TRANSACTION_TYPES: &[&str] = &[
+    "purchase", "spend", "refund", "bonus", "admin_adjustment", "expire", "refund_clawback",
+    "transfer_out", "transfer_in",
+];
+
+/// Which slice of the ledger an export covers. `user_id` None means every user
+/// of the app (the admin export); `member_id` narrows an org wallet's ledger to
+/// one member's spending. `from` is inclusive, `to` exclusive.
+#[derive(Debug, Clone)]
+pub struct TransactionExportFilter {
+    pub app_id: String,
+    pub user_id: Option<String>,
+    pub member_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(Self::Csv),
+            "jsonl" | "ndjson" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Self::Csv => Some("id,created_at,user_id,type,amount,balance_after,description,reference_id,member_id,request_id\n"),
+            Self::Jsonl => None,
+        }
+    }
+
+    pub fn row(self, tx: &CreditTransaction) -> Result<String> {
+        Ok(match self {
+            Self::Csv => format!(
+                "{},{},{},{},{},{},{},{},{},{}\n",
+                csv_text(&tx.id),
+                csv_text(&tx.created_at),
+                csv_text(&tx.user_id),
+                csv_text(&tx.transaction_type),
+                tx.amount,
+                tx.balance_after,
+                csv_text(&tx.description),
+                csv_text(tx.reference_id.as_deref().unwrap_or("")),
+                csv_text(tx.member_id.as_deref().unwrap_or("")),
+                csv_text(tx.request_id.as_deref().unwrap_or("")),
+            ),
+            Self::Jsonl => {
+                let line = serde_json::to_string(tx).map_err(|e| {
+                    worker::Error::RustError(format!("Failed to serialize transaction {}: {}", tx.id, e))
+                })?;
+                format!("{}\n", line)
+            }
+        })
+    }
+}
+
+/// Quotes a CSV text field when needed. Text that a spreadsheet would run as a
+/// formula gets a leading apostrophe; descriptions can carry user input.
+fn csv_text(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// One page of an export, oldest first, strictly after `after` (created_at,
+/// id). Keyset paging keeps every page an index range scan however deep the
+/// export goes. A row that doesn't deserialize fails the page rather than
+/// being dropped, so a short page always means the end of the ledger.
+pub async fn get_transactions_page(
+    filter: &TransactionExportFilter,
+    after: Option<&(String, String)>,
+    limit: i32,
+    db: &D1Database,
+) -> Result<Vec<CreditTransaction>> {
+    let mut sql = String::from("SELECT * FROM credit_transactions WHERE app_id = ?");
+    let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![filter.app_id.clone().into()];
+    if let Some(user_id) = &filter.user_id {
+        sql.push_str(" AND user_id = ?");
+        params.push(user_id.clone().into());
+    }
+    if let Some(member_id) = &filter.member_id {
+        sql.push_str(" AND member_id = ?");
+        params.push(member_id.clone().into());
+    }
+    if let Some(from) = &filter.from {
+        sql.push_str(" AND created_at >= ?");
+        params.push(from.clone().into());
+    }
+    if let Some(to) = &filter.to {
+        sql.push_str(" AND created_at < ?");
+        params.push(to.clone().into());
+    }
+    if !filter.types.is_empty() {
+        sql.push_str(&format!(" AND type IN ({})", vec!["?"; filter.types.len()].join(", ")));
+        params.extend(filter.types.iter().map(|t| t.clone().into()));
+    }
+    if let Some((created_at, id)) = after {
+        sql.push_str(" AND (created_at > ? OR (created_at = ? AND id > ?))");
+        params.push(created_at.clone().into());
+        params.push(created_at.clone().into());
+        params.push(id.clone().into());
+    }
+    sql.push_str(" ORDER BY created_at, id LIMIT ?");
+    params.push(limit.into());
+
+    let rows = db.prepare(&sql).bind(&params)?.all().await?.results::<serde_json::Value>()?;
+    rows.into_iter()
+        .map(|r| {
+            let id = r.get("id").and_then(|v| v.as_str()).unwrap_or("?").to_string();
+            serde_json::from_value::<CreditTransaction>(r).map_err(|e| {
+                worker::Error::RustError(format!("Unreadable ledger row {}: {}", id, e))
+            })
+        })
+        .collect()
+}
+
 pub fn estimate_image_cost(
     model: &str,
     quality: &str,
@@ -526,28 +1868,18 @@ mod tests {
     use super::*;
     
     #[test]
-    fn test_calculate_openai_cost() {
-        let usage = ImageUsage {
-            total_tokens: 1000,
-            input_tokens: 200,
-            output_tokens: 800,
-            input_tokens_details: crate::models::InputTokenDetails {
-                text_tokens: 100,
-                image_tokens: 100,
-            },
-        };
-        
-        let cost = calculate_openai_cost_usd(&usage);
-        // 100 text tokens: 100/1M * $5 = $0.0005
-        // 100 image tokens: 100/1M * $10 = $0.001
-        // 800 output tokens: 800/1M * $40 = $0.032
-        // Total: $0.0335
-        assert!((cost - 0.0335).abs() < 0.0001);
+    fn test_csv_text_escaping() {
+        assert_eq!(csv_text("Generated 2 images"), "Generated 2 images");
+        assert_eq!(csv_text("a, b"), "\"a, b\"");
+        assert_eq!(csv_text("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_text("=SUM(A1)"), "'=SUM(A1)");
+        assert_eq!(csv_text("-1,2"), "\"'-1,2\"");
     }
-    
+
     #[test]
     fn test_calculate_credits_from_cost() {
         // Test various costs - using ceil() ensures we never lose money
+        let calculate_credits_from_cost = |cost| CapabilityPricing::default().credits_from_cost(cost);
         assert_eq!(calculate_credits_from_cost(0.01), 3);   // 0.01 * 3 * 100 = 3.0 -> ceil = 3
         assert_eq!(calculate_credits_from_cost(0.10), 31);  // 0.10 * 3 * 100 = 30.0 -> ceil = 31 (due to floating point)
         assert_eq!(calculate_credits_from_cost(0.0033), 1); // 0.0033 * 3 * 100 = 0.99 -> ceil = 1
@@ -555,6 +1887,107 @@ mod tests {
         assert_eq!(calculate_credits_from_cost(0.0001), 1); // Very small cost -> min 1 credit
     }
     
+    fn bucket(id: &str, remaining: i32, expires_at: Option<&str>, created_at: &str) -> CreditBucket {
+        CreditBucket {
+            id: id.to_string(),
+            source: "bonus".to_string(),
+            original_amount: remaining,
+            remaining,
+            expires_at: expires_at.map(|t| t.to_string()),
+            created_at: created_at.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_bucket_spending_order() {
+        let mut buckets = vec![
+            bucket("forever", 5, None, "2026-01-01T00:00:00+00:00"),
+            bucket("later", 5, Some("2026-03-01T00:00:00+00:00"), "2026-01-01T00:00:00+00:00"),
+            bucket("sooner-newer", 5, Some("2026-02-01T00:00:00+00:00"), "2026-01-02T00:00:00+00:00"),
+            bucket("sooner-older", 5, Some("2026-02-01T00:00:00+00:00"), "2026-01-01T00:00:00+00:00"),
+        ];
+        sort_for_spending(&mut buckets);
+        let order: Vec<&str> = buckets.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(order, ["sooner-older", "sooner-newer", "later", "forever"]);
+    }
+
+    #[test]
+    fn test_draw_from_buckets() {
+        let buckets = vec![
+            bucket("a", 3, Some("2026-02-01T00:00:00+00:00"), "2026-01-01T00:00:00+00:00"),
+            bucket("b", 10, None, "2026-01-01T00:00:00+00:00"),
+        ];
+        assert_eq!(draw_from_buckets(&buckets, 2), vec![("a", 2)]);
+        assert_eq!(draw_from_buckets(&buckets, 8), vec![("a", 3), ("b", 5)]);
+        // Beyond the buckets the spend is paid credit.
+        assert_eq!(draw_from_buckets(&buckets, 20), vec![("a", 3), ("b", 10)]);
+        assert!(draw_from_buckets(&buckets, 0).is_empty());
+    }
+
+    #[test]
+    fn test_refund_then_lapse() {
+        // A 100-credit allowance with 70 left. Until a refund it counts as
+        // allowance, and at expiry all 70 lapse.
+        let mut allowance = bucket("invoice", 70, Some("2026-02-04T00:00:00+00:00"), "2026-01-01T00:00:00+00:00");
+        allowance.source = "subscription".to_string();
+        assert_eq!(split_balance(70, vec![allowance.clone()]).subscription, 70);
+        assert_eq!(lapse_debit(allowance.remaining, 70), 70);
+
+        // The invoice is refunded: the clawback debits 100 (balance -30) and
+        // empties the bucket, which drops out of the live buckets. The user then
+        // buys 200 credits; none count as allowance, and nothing lapses.
+        allowance.remaining = 0;
+        let breakdown = split_balance(170, Vec::new());
+        assert_eq!((breakdown.paid, breakdown.subscription), (170, 0));
+        assert_eq!(lapse_debit(allowance.remaining, 170), 0);
+    }
+
+    #[test]
+    fn test_lapse_debit() {
+        assert_eq!(lapse_debit(50, 80), 50);
+        assert_eq!(lapse_debit(50, 20), 20);
+        assert_eq!(lapse_debit(50, -10), 0);
+    }
+
+    #[test]
+    fn test_split_balance() {
+        let mut allowance = bucket("sub", 40, None, "2026-01-01T00:00:00+00:00");
+        allowance.source = "subscription".to_string();
+        let promo = bucket("promo", 30, None, "2026-01-01T00:00:00+00:00");
+        let breakdown = split_balance(100, vec![allowance.clone(), promo.clone()]);
+        assert_eq!((breakdown.paid, breakdown.promotional, breakdown.subscription), (30, 30, 40));
+        // Buckets can't claim more than the balance holds.
+        let breakdown = split_balance(50, vec![allowance, promo]);
+        assert_eq!((breakdown.paid, breakdown.promotional, breakdown.subscription), (0, 10, 40));
+    }
+
+    #[test]
+    fn test_plan_rollover() {
+        // Newest bucket first: it carries in full, the older one up to the cap.
+        assert_eq!(plan_rollover(&[20, 30], 25), vec![20, 5]);
+        assert_eq!(plan_rollover(&[20, 30], 0), vec![0, 0]);
+        assert_eq!(plan_rollover(&[20, 30], 100), vec![20, 30]);
+        assert_eq!(plan_rollover(&[20, 30], -1), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_capability_pricing() {
+        let default = CapabilityPricing::default();
+        assert_eq!(default.apply_to_builtin(16, 1), 16);
+        assert_eq!(default.credits_from_cost(0.01), 3);
+
+        let flat = CapabilityPricing { flat_credits: Some(1), multiplier: 5.0 };
+        assert_eq!(flat.apply_to_builtin(62, 1), 1);
+        assert_eq!(flat.apply_to_builtin(186, 3), 3); // flat price is per image
+        assert_eq!(flat.credits_from_cost(0.50), 1);
+
+        let half_margin = CapabilityPricing { flat_credits: None, multiplier: 1.5 };
+        assert_eq!(half_margin.apply_to_builtin(62, 1), 31);
+        assert_eq!(half_margin.apply_to_builtin(186, 3), 93);
+        assert_eq!(half_margin.credits_from_cost(0.50), 75);
+        assert_eq!(half_margin.apply_to_builtin(1, 1), 1); // never rounds down to free
+    }
+
     #[test]
     fn test_estimate_image_cost() {
         // Test Gemini costs (always 15)
@@ -14,6 +14,21 @@ pub enum AppError {
 }
 
 impl AppError {
+    /// The error's own message, for logs and stored records. Unlike the
+    /// response, an internal error keeps its detail.
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::PaymentRequired(msg)
+            | AppError::InternalError(msg) => msg,
+            AppError::RateLimitExceeded => "Rate limit exceeded",
+        }
+    }
+
     pub fn to_response(&self) -> Result<Response> {
         let (status, error_type, message, code) = match self {
             AppError::BadRequest(msg) => (400, "invalid_request_error", msg.clone(), "bad_request"),
@@ -26,12 +41,19 @@ impl AppError {
             AppError::RateLimitExceeded => (429, "rate_limit_exceeded", "Rate limit exceeded. Please try again later.".to_string(), "rate_limit_exceeded"),
         };
 
+        // The real cause of an internal error is only logged; the request id
+        // in the response is what ties the two together.
+        if let AppError::InternalError(detail) = self {
+            crate::log_error!("Internal error", serde_json::json!({ "error": detail }));
+        }
+
         let error_response = ErrorResponse {
             error: ErrorDetail {
                 message,
                 error_type: error_type.to_string(),
                 param: None,
                 code: Some(code.to_string()),
+                request_id: crate::request_id::current(),
             },
         };
 
@@ -0,0 +1,359 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::admin::{self, Permission};
+use crate::audit::{self, AuditEvent, AuditFilter};
+use crate::user_status;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The caller's admin roles and what they allow.
+pub async fn admin_me(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_me_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_me_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::admin_context(&req, &ctx.env, &db).await?;
+    let permissions: Vec<&str> = [
+        (Permission::ReadUsers, "read_users"),
+        (Permission::GrantCredits, "grant_credits"),
+        (Permission::ReadFinance, "read_finance"),
+        (Permission::ManageFinance, "manage_finance"),
+        (Permission::Moderate, "moderate"),
+        (Permission::ManageRoles, "manage_roles"),
+        (Permission::ReadAudit, "read_audit"),
+        (Permission::ManageTenants, "manage_tenants"),
+    ]
+    .into_iter()
+    .filter(|(p, _)| admin.can(*p))
+    .map(|(_, name)| name)
+    .collect();
+    let (grant_limit, daily_grant_limit) = if admin.grant_limited() {
+        (Some(admin::SUPPORT_GRANT_LIMIT), Some(admin::SUPPORT_DAILY_GRANT_LIMIT))
+    } else {
+        (None, None)
+    };
+    Response::from_json(&json!({
+        "user_id": admin.user_id,
+        "app_id": admin.app_id,
+        "roles": admin.roles,
+        "platform_admin": admin.platform_admin,
+        "permissions": permissions,
+        "grant_limit": grant_limit,
+        "daily_grant_limit": daily_grant_limit,
+    }))
+    .map_err(AppError::from)
+}
+
+pub async fn admin_list_roles(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_roles_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_roles_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ManageRoles).await?;
+    let holders = admin::list_role_holders(&admin.app_id, &db).await?;
+    Response::from_json(&json!({ "admins": holders })).map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRolesRequest {
+    pub roles: Vec<String>,
+    pub reason: Option<String>,
+}
+
+/// Replaces a user's admin roles; an empty list removes their admin access.
+pub async fn admin_set_roles(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_set_roles_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_set_roles_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ManageRoles).await?;
+    let user_id = ctx
+        .param("user_id")
+        .ok_or_else(|| AppError::BadRequest("Missing user_id".to_string()))?
+        .to_string();
+    let body: SetRolesRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let reason = audit::require_reason(body.reason.as_deref())?;
+
+    let before = admin::roles_of(&admin.app_id, &user_id, &db).await?;
+    let roles = admin::set_roles(&admin.app_id, &user_id, &body.roles, &admin.user_id, &db).await?;
+    worker::console_log!("Admin {} set roles of {} in {} to {:?}", admin.user_id, user_id, admin.app_id, roles);
+    audit::record(
+        &admin,
+        AuditEvent::new("roles.set")
+            .target("user", &user_id)
+            .before(json!({ "roles": before }))
+            .after(json!({ "roles": roles }))
+            .reason(reason),
+        &db,
+    )
+    .await;
+    Response::from_json(&json!({ "user_id": user_id, "roles": roles })).map_err(AppError::from)
+}
+
+/// Images with open reports, most reported first.
+pub async fn admin_list_reports(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_reports_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_reports_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::Moderate).await?;
+
+    let rows = db
+        .prepare(
+            "SELECT r.image_id, COUNT(*) AS report_count, MAX(r.created_at) AS last_reported_at,
+                    GROUP_CONCAT(NULLIF(r.reason, ''), ' | ') AS reasons,
+                    i.user_id, i.prompt, i.is_public, i.created_at
+             FROM image_reports r JOIN stored_images i ON i.app_id = r.app_id AND i.id = r.image_id
+             WHERE r.app_id = ?1
+             GROUP BY r.image_id
+             ORDER BY report_count DESC, last_reported_at DESC
+             LIMIT 200",
+        )
+        .bind(&[admin.app_id.clone().into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    Response::from_json(&json!({ "reports": rows })).map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolveReportRequest {
+    /// "hide" takes the image out of the public gallery; "dismiss" keeps it.
+    pub action: String,
+    pub reason: Option<String>,
+}
+
+/// Closes the reports on an image, hiding it or leaving it as it is.
+pub async fn admin_resolve_report(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_resolve_report_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_resolve_report_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::Moderate).await?;
+    let image_id = ctx
+        .param("image_id")
+        .ok_or_else(|| AppError::BadRequest("Missing image_id".to_string()))?
+        .to_string();
+    let body: ResolveReportRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let hide = match body.action.as_str() {
+        "hide" => true,
+        "dismiss" => false,
+        _ => return Err(AppError::BadRequest("action must be 'hide' or 'dismiss'".to_string())),
+    };
+
+    let image = db
+        .prepare("SELECT is_public FROM stored_images WHERE app_id = ?1 AND id = ?2")
+        .bind(&[admin.app_id.clone().into(), image_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Image {} not found", image_id)))?;
+    let was_public = image.get("is_public").and_then(|v| v.as_i64()).unwrap_or(0) != 0;
+    let report_count = db
+        .prepare("SELECT COUNT(*) AS n FROM image_reports WHERE app_id = ?1 AND image_id = ?2")
+        .bind(&[admin.app_id.clone().into(), image_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("n").and_then(|n| n.as_i64()))
+        .unwrap_or(0);
+
+    let mut statements = vec![db
+        .prepare("DELETE FROM image_reports WHERE app_id = ?1 AND image_id = ?2")
+        .bind(&[admin.app_id.clone().into(), image_id.clone().into()])?];
+    if hide {
+        statements.push(
+            db.prepare("UPDATE stored_images SET is_public = 0 WHERE app_id = ?1 AND id = ?2")
+                .bind(&[admin.app_id.clone().into(), image_id.clone().into()])?,
+        );
+    }
+    db.batch(statements).await?;
+
+    worker::console_log!("Admin {} resolved reports on {} ({})", admin.user_id, image_id, body.action);
+    audit::record(
+        &admin,
+        AuditEvent::new("report.resolve")
+            .target("image", &image_id)
+            .before(json!({ "is_public": was_public, "reports": report_count }))
+            .after(json!({ "is_public": was_public && !hide, "action": body.action }))
+            .reason(body.reason.as_deref().unwrap_or("").trim()),
+        &db,
+    )
+    .await;
+    Response::from_json(&json!({ "image_id": image_id, "action": body.action })).map_err(AppError::from)
+}
+
+/// The app's admin audit log, newest first. Query parameters: `actor`,
+/// `action` (a prefix such as `credits.`), `target_type`, `target_id`,
+/// `from`/`to` (RFC 3339), `page` and `per_page`.
+pub async fn admin_list_audit_log(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_audit_log_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_audit_log_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ReadAudit).await?;
+
+    let url = req.url()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let timestamp = |name: &str| -> std::result::Result<Option<String>, AppError> {
+        match param(name) {
+            Some(v) => chrono::DateTime::parse_from_rfc3339(&v)
+                .map(|t| Some(t.with_timezone(&chrono::Utc).to_rfc3339()))
+                .map_err(|_| AppError::BadRequest(format!("{} must be an RFC 3339 timestamp", name))),
+            None => Ok(None),
+        }
+    };
+    let filter = AuditFilter {
+        actor_user_id: param("actor"),
+        action: param("action"),
+        target_type: param("target_type"),
+        target_id: param("target_id"),
+        from: timestamp("from")?,
+        to: timestamp("to")?,
+    };
+    let page = param("page").and_then(|p| p.parse::<i32>().ok()).unwrap_or(1).max(1);
+    let per_page = param("per_page").and_then(|p| p.parse::<i32>().ok()).unwrap_or(50).clamp(1, 200);
+
+    let entries = audit::query(&admin.app_id, &filter, per_page, (page - 1) * per_page, &db).await?;
+    Response::from_json(&json!({ "entries": entries, "page": page, "per_page": per_page })).map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetUserStatusRequest {
+    /// "active", "suspended" or "banned".
+    pub status: String,
+    pub reason: Option<String>,
+    /// Suspensions only; without it a suspension lasts until lifted.
+    pub expires_in_days: Option<u32>,
+}
+
+/// A user's status, and the devices blocked because of them.
+pub async fn admin_get_user_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_get_user_status_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_get_user_status_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::Moderate).await?;
+    let user_id = ctx
+        .param("user_id")
+        .ok_or_else(|| AppError::BadRequest("Missing user_id".to_string()))?
+        .to_string();
+    let status = user_status::get_status(&admin.app_id, &user_id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found in this app".to_string()))?;
+    let banned_devices = db
+        .prepare("SELECT device_id, reason, banned_by, created_at FROM banned_devices WHERE app_id = ?1 AND user_id = ?2")
+        .bind(&[admin.app_id.clone().into(), user_id.clone().into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    Response::from_json(&json!({
+        "user_id": user_id,
+        "status": status.status,
+        "effective_status": status.effective(),
+        "reason": status.reason,
+        "expires_at": status.expires_at,
+        "changed_at": status.changed_at,
+        "changed_by": status.changed_by,
+        "banned_devices": banned_devices,
+    }))
+    .map_err(AppError::from)
+}
+
+/// Suspends, bans or reinstates a user.
+pub async fn admin_set_user_status(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_set_user_status_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_set_user_status_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::Moderate).await?;
+    let user_id = ctx
+        .param("user_id")
+        .ok_or_else(|| AppError::BadRequest("Missing user_id".to_string()))?
+        .to_string();
+    let body: SetUserStatusRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let reason = audit::require_reason(body.reason.as_deref())?;
+    if user_id == admin.user_id {
+        return Err(AppError::BadRequest("You can't change your own status".to_string()));
+    }
+    let expires_at = body
+        .expires_in_days
+        .filter(|d| *d > 0)
+        .map(|d| (chrono::Utc::now() + chrono::Duration::days(d as i64)).to_rfc3339());
+
+    let before = user_status::get_status(&admin.app_id, &user_id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found in this app".to_string()))?;
+    let after = user_status::set_status(
+        &admin.app_id,
+        &user_id,
+        body.status.trim(),
+        reason,
+        expires_at.as_deref(),
+        &admin.user_id,
+        &db,
+    )
+    .await?;
+    worker::console_log!("Admin {} set status of {} in {} to {}", admin.user_id, user_id, admin.app_id, after.status);
+    audit::record(
+        &admin,
+        AuditEvent::new("user.set_status")
+            .target("user", &user_id)
+            .before(json!({ "status": before.effective(), "expires_at": before.expires_at }))
+            .after(json!({ "status": after.status, "expires_at": after.expires_at }))
+            .reason(reason),
+        &db,
+    )
+    .await;
+    Response::from_json(&json!({
+        "user_id": user_id,
+        "status": after.status,
+        "reason": after.reason,
+        "expires_at": after.expires_at,
+    }))
+    .map_err(AppError::from)
+}
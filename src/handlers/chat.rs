@@ -1,19 +1,26 @@
-use worker::{Request, Response, RouteContext, Result, console_log, Fetch, Method, Headers, RequestInit};
+use worker::{Request, Response, RouteContext, Result, Fetch, Method, Headers, RequestInit};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use uuid::Uuid;
+use chrono::Utc;
 use crate::error::AppError;
+use crate::models::UsageRecord;
+use crate::usage_records;
 use crate::auth::authenticate;
-use crate::credits::{check_and_reserve_credits, deduct_credits, get_flat_capability_cost};
+use crate::credits::{capture_hold, get_capability_pricing, place_hold, release_hold, CapabilityPricing};
 use crate::rate_limit::{check_and_acquire_lock, release_lock};
 
 const GEMINI_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
 const DEFAULT_CHAT_MODEL: &str = "gemini-3-flash-preview";
-const CREDIT_MULTIPLIER: f64 = 3.0;
 const MIN_BALANCE_GUARD: u32 = 2;
+/// Reply tokens escrowed on top of the prompt when a chat call is held. A
+/// longer reply is billed for the excess once its size is known.
+const HOLD_OUTPUT_TOKENS: u64 = 4_096;
+/// Rough token weight of one input image, for sizing the hold.
+const HOLD_IMAGE_TOKENS: u64 = 1_300;
 
 /// Per-1M-token USD prices (input, output) across providers, used to convert
-/// real token usage into credits. Defaults to a mid-tier estimate for unknown
+/// real token usage into provider cost (then credits at the tenant's margin). Defaults to a mid-tier estimate for unknown
 /// models. (Apps with a flat capability cost ignore this entirely.)
 fn model_prices(model: &str) -> (f64, f64) {
     match model {
@@ -54,6 +61,13 @@ impl Provider {
             Provider::OpenAI => "OPENAI_API_KEY",
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Gemini => "gemini",
+            Provider::OpenAI => "openai",
+        }
+    }
 }
 
 /// The tenant's configured default chat model (apps.default_chat_model), used
@@ -73,11 +87,22 @@ async fn app_default_chat_model(app_id: &str, db: &worker::D1Database) -> Option
         .map(|s| s.to_string())
 }
 
-fn credits_from_tokens(model: &str, prompt_tokens: u64, output_tokens: u64) -> u32 {
+fn cost_usd_from_tokens(model: &str, prompt_tokens: u64, output_tokens: u64) -> f64 {
     let (price_in, price_out) = model_prices(model);
-    let cost_usd =
-        (prompt_tokens as f64 / 1_000_000.0) * price_in + (output_tokens as f64 / 1_000_000.0) * price_out;
-    ((cost_usd * CREDIT_MULTIPLIER * 100.0).ceil() as u32).max(1)
+    (prompt_tokens as f64 / 1_000_000.0) * price_in + (output_tokens as f64 / 1_000_000.0) * price_out
+}
+
+/// Credits to escrow before calling the provider: a flat price as is, otherwise
+/// the prompt (~4 characters per token) plus a reply budget at the tenant's rate.
+fn hold_estimate(model: &str, body: &ChatRequest, pricing: &CapabilityPricing) -> u32 {
+    if let Some(flat) = pricing.flat_credits {
+        return flat;
+    }
+    let chars: usize = body.messages.iter().map(|m| m.role.len() + m.content.len() + 4).sum();
+    let prompt_tokens = chars as u64 / 4 + body.images.len() as u64 * HOLD_IMAGE_TOKENS;
+    pricing
+        .credits_from_cost(cost_usd_from_tokens(model, prompt_tokens, HOLD_OUTPUT_TOKENS))
+        .max(MIN_BALANCE_GUARD)
 }
 
 #[derive(Deserialize)]
@@ -122,9 +147,12 @@ async fn chat_completion_inner(
     mut req: Request,
     ctx: RouteContext<()>,
 ) -> std::result::Result<Response, AppError> {
+    let start_time = worker::Date::now().as_millis();
     let db = ctx.env.d1("DB")?;
-    let auth = authenticate(&req, &db).await?;
+    let auth = authenticate(&req, &ctx.env, &db).await?;
+    crate::apps::require_capability(&auth.app_id, "chat.completion", &db).await?;
     crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "chat.completion").await?;
+    let payer = crate::orgs::resolve_payer(&req, &auth.app_id, &auth.user_id, &db).await?;
 
     let body: ChatRequest = req
         .json()
@@ -140,21 +168,13 @@ async fn chat_completion_inner(
             .unwrap_or_else(|| DEFAULT_CHAT_MODEL.to_string()),
     };
 
-    let flat_cost = get_flat_capability_cost(&auth.app_id, "chat.completion", &db).await;
+    let pricing = get_capability_pricing(&auth.app_id, "chat.completion", &db).await;
     let premium = crate::handlers::credits::is_premium_user(&ctx.env, &db, &auth.app_id, &auth.user_id).await;
 
     check_and_acquire_lock(&auth.app_id, &auth.user_id, &db)
         .await
         .map_err(|_| AppError::RateLimitExceeded)?;
 
-    if !premium {
-        let guard = flat_cost.unwrap_or(MIN_BALANCE_GUARD);
-        if let Err(e) = check_and_reserve_credits(&auth.app_id, &auth.user_id, guard, &db).await {
-            let _ = release_lock(&auth.app_id, &auth.user_id, &db).await;
-            return Err(AppError::from(e));
-        }
-    }
-
     let provider = Provider::for_model(&model);
     let api_key = match ctx.env.secret(provider.secret_name()) {
         Ok(k) => k.to_string(),
@@ -164,6 +184,19 @@ async fn chat_completion_inner(
         }
     };
 
+    let hold = if premium {
+        None
+    } else {
+        let estimate = hold_estimate(&model, &body, &pricing);
+        match place_hold(&auth.app_id, &payer, "chat.completion", estimate, &db).await {
+            Ok(hold_id) => Some(hold_id),
+            Err(e) => {
+                let _ = release_lock(&auth.app_id, &auth.user_id, &db).await;
+                return Err(AppError::from(e));
+            }
+        }
+    };
+
     let result = match provider {
         Provider::Gemini => gemini_generate(&api_key, &model, &body).await,
         Provider::OpenAI => openai_generate(&api_key, &model, &body).await,
@@ -171,7 +204,23 @@ async fn chat_completion_inner(
     let (content, prompt_tokens, output_tokens) = match result {
         Ok(v) => v,
         Err(e) => {
+            if let Some(hold_id) = &hold {
+                let _ = release_hold(hold_id, &db).await;
+            }
             let _ = release_lock(&auth.app_id, &auth.user_id, &db).await;
+            let response_time_ms = (worker::Date::now().as_millis() - start_time) as u32;
+            usage_records::record_failure(
+                &auth.app_id,
+                &auth.user_id,
+                "chat.completion",
+                "chat",
+                provider.name(),
+                &model,
+                e.message(),
+                response_time_ms,
+                &db,
+            )
+            .await;
             return Err(e);
         }
     };
@@ -179,18 +228,46 @@ async fn chat_completion_inner(
     let credits = if premium {
         0
     } else {
-        flat_cost.unwrap_or_else(|| credits_from_tokens(&model, prompt_tokens, output_tokens))
+        pricing.credits_from_cost(cost_usd_from_tokens(&model, prompt_tokens, output_tokens))
     };
-    if credits > 0 {
+    if let Some(hold_id) = &hold {
+        // The reply may have cost more than the hold; capture charges it all,
+        // and if settling fails the cron charges what was recorded.
         let reference = format!("chat:{}", Uuid::new_v4());
-        if let Err(e) = deduct_credits(&auth.app_id, &auth.user_id, credits, "chat.completion", &reference, &db).await {
-            let _ = release_lock(&auth.app_id, &auth.user_id, &db).await;
-            return Err(AppError::from(e));
+        if let Err(e) = capture_hold(hold_id, credits, "chat.completion", &reference, &db).await {
+            crate::log_error!("Failed to capture credit hold", json!({
+                "error": e.to_string(),
+                "user_id": &auth.user_id,
+                "hold_id": hold_id,
+                "credits": credits,
+            }));
         }
     }
 
     let _ = release_lock(&auth.app_id, &auth.user_id, &db).await;
 
+    let usage_record = UsageRecord {
+        id: Uuid::new_v4().to_string(),
+        user_id: auth.user_id.clone(),
+        request_type: "chat".to_string(),
+        model: model.clone(),
+        prompt: String::new(),
+        image_size: String::new(),
+        image_quality: String::new(),
+        image_count: 0,
+        input_images_count: Some(body.images.len().min(u8::MAX as usize) as u8),
+        total_tokens: (prompt_tokens + output_tokens) as u32,
+        input_tokens: prompt_tokens as u32,
+        output_tokens: output_tokens as u32,
+        text_tokens: 0,
+        image_tokens: 0,
+        r2_keys: Vec::new(),
+        response_time_ms: (worker::Date::now().as_millis() - start_time) as u32,
+        error: None,
+        created_at: Utc::now(),
+    };
+    usage_records::record(&auth.app_id, "chat.completion", provider.name(), &usage_record, credits, &db).await;
+
     Response::from_json(&ChatResponse {
         content,
         model,
@@ -252,7 +329,7 @@ async fn gemini_generate(
 
     if resp.status_code() >= 400 {
         let detail = resp.text().await.unwrap_or_default();
-        console_log!("Gemini chat error {}: {}", resp.status_code(), detail);
+        crate::log_error!("Gemini chat error", json!({ "status": resp.status_code(), "detail": detail }));
         return Err(AppError::InternalError("AI provider error".to_string()));
     }
 
@@ -349,7 +426,7 @@ async fn openai_generate(
 
     if resp.status_code() >= 400 {
         let detail = resp.text().await.unwrap_or_default();
-        console_log!("OpenAI chat error {}: {}", resp.status_code(), detail);
+        crate::log_error!("OpenAI chat error", json!({ "status": resp.status_code(), "detail": detail }));
         return Err(AppError::InternalError("AI provider error".to_string()));
     }
 
@@ -383,3 +460,30 @@ async fn openai_generate(
 
     Ok((text, prompt_tokens, output_tokens))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content: &str, images: usize) -> ChatRequest {
+        ChatRequest {
+            messages: vec![ChatMessage { role: "user".to_string(), content: content.to_string() }],
+            images: vec![String::new(); images],
+            response_json: false,
+            model: None,
+        }
+    }
+
+    #[test]
+    fn test_hold_estimate() {
+        let default = CapabilityPricing::default();
+        // The reply budget dominates a short prompt: 4,096 tokens at $10/1M, x3.
+        assert_eq!(hold_estimate("gpt-5", &request("hi", 0), &default), 13);
+        assert_eq!(hold_estimate("gpt-5", &request("hi", 8), &default), 17);
+        // Never below the balance guard.
+        assert_eq!(hold_estimate("gpt-5-nano", &request("hi", 0), &default), MIN_BALANCE_GUARD);
+
+        let flat = CapabilityPricing { flat_credits: Some(5), multiplier: 3.0 };
+        assert_eq!(hold_estimate("gpt-5", &request("hi", 8), &flat), 5);
+    }
+}
@@ -1,10 +1,18 @@
 use worker::{Request, Response, RouteContext, Result, Env, Fetch, Method, Headers};
 use crate::error::AppError;
 use crate::auth;
+use crate::admin::{self, Permission};
+use crate::audit::{self, AuditEvent};
 use crate::credits::{
     get_user_balance, get_user_transactions, get_credit_packs, get_credit_packs_for_app,
-    record_purchase, complete_purchase, add_credits, estimate_image_cost
+    record_purchase, complete_purchase, estimate_image_cost, get_capability_pricing,
+    add_promo_credits, add_promo_credits_guarded, consume_promo_credits, get_credit_breakdown,
+    CreditBreakdown, PromoGrant,
+    find_purchase_by_payment, claw_back_purchase, restore_purchase, get_frozen_at,
+    get_transactions_page, ExportFormat, TransactionExportFilter, TRANSACTION_TYPES
 };
+use crate::promo_codes::{find_redeemable, claim_redemption, ensure_redeemable_by, release_redemption, normalize_code, PromoCode};
+use crate::stripe_payments::{LineItem, LineItemPriceData};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
@@ -14,6 +22,9 @@ use chrono::Utc;
 pub struct CreditBalanceResponse {
     pub balance: i32,
     pub currency: String,
+    pub breakdown: CreditBreakdown,
+    /// Set while the wallet is frozen after a refund or chargeback.
+    pub frozen_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +62,7 @@ pub struct CreateStripeCheckoutRequest {
     pub pack_id: String,
     pub success_url: String,
     pub cancel_url: String,
+    pub promo_code: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,13 +83,15 @@ pub struct AdminAdjustCreditsRequest {
     pub user_id: String,
     pub amount: i32,
     pub reason: String,
+    /// Grants only: lapse the granted credits after this many days.
+    pub expires_in_days: Option<u32>,
 }
 
 pub async fn get_balance(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let env = ctx.env;
 
     let db = env.d1("DB")?;
-    let auth = match auth::authenticate(&req, &db).await {
+    let auth = match auth::authenticate(&req, &env, &db).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
@@ -86,10 +100,14 @@ pub async fn get_balance(req: Request, ctx: RouteContext<()>) -> Result<Response
     }
 
     let balance = get_user_balance(&auth.app_id, &auth.user_id, &db).await?;
+    let breakdown = get_credit_breakdown(&auth.app_id, &auth.user_id, &db).await?;
+    let frozen_at = get_frozen_at(&auth.app_id, &auth.user_id, &db).await?;
 
     Response::from_json(&CreditBalanceResponse {
         balance,
         currency: "credits".to_string(),
+        breakdown,
+        frozen_at,
     })
 }
 
@@ -116,7 +134,7 @@ pub async fn list_transactions(req: Request, ctx: RouteContext<()>) -> Result<Re
     let offset = (page - 1) * per_page;
 
     let db = env.d1("DB")?;
-    let auth = match auth::authenticate(&req, &db).await {
+    let auth = match auth::authenticate(&req, &env, &db).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
@@ -146,6 +164,162 @@ pub async fn list_transactions(req: Request, ctx: RouteContext<()>) -> Result<Re
     }))
 }
 
+/// Ledger rows fetched per streamed chunk of an export.
+const EXPORT_CHUNK_ROWS: i32 = 500;
+
+pub async fn export_transactions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match export_transactions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn export_transactions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_read_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "transactions.export").await?;
+
+    let (format, filter) = parse_export_query(&req, &auth.app_id, Some(auth.user_id.clone()))?;
+    stream_export(format, filter, db)
+}
+
+/// Per-app export across all users; `user_id` narrows it to one.
+pub async fn admin_export_transactions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_export_transactions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_export_transactions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
+    let user_id = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "user_id")
+        .map(|(_, v)| v.into_owned())
+        .filter(|v| !v.is_empty());
+    let (format, filter) = parse_export_query(&req, &auth.app_id, user_id)?;
+    let query = req.url()?.query().unwrap_or("").to_string();
+    audit::record(&auth, AuditEvent::new("transactions.export").after(json!({ "query": query })), &db).await;
+    stream_export(format, filter, db)
+}
+
+/// Reads `format`, `from`, `to` and `type` (comma-separated). A date-only `to`
+/// includes that whole day.
+pub fn parse_export_query(
+    req: &Request,
+    app_id: &str,
+    user_id: Option<String>,
+) -> std::result::Result<(ExportFormat, TransactionExportFilter), AppError> {
+    let url = req.url()?;
+    let query_params: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    let format = match query_params.get("format") {
+        Some(f) => ExportFormat::parse(f)
+            .ok_or_else(|| AppError::BadRequest("format must be 'csv' or 'jsonl'".to_string()))?,
+        None => ExportFormat::Csv,
+    };
+
+    let bound = |key: &str, end_of_day: bool| -> std::result::Result<Option<String>, AppError> {
+        let Some(raw) = query_params.get(key).filter(|v| !v.is_empty()) else {
+            return Ok(None);
+        };
+        if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(raw) {
+            return Ok(Some(ts.with_timezone(&Utc).to_rfc3339()));
+        }
+        let date = chrono::NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+            .map_err(|_| AppError::BadRequest(format!("{} must be YYYY-MM-DD or an RFC 3339 timestamp", key)))?;
+        let date = if end_of_day { date.succ_opt().unwrap_or(date) } else { date };
+        Ok(Some(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().to_rfc3339()))
+    };
+    let from = bound("from", false)?;
+    let to = bound("to", true)?;
+
+    let types: Vec<String> = query_params
+        .get("type")
+        .map(|t| t.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    if let Some(bad) = types.iter().find(|t| !TRANSACTION_TYPES.contains(&t.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown transaction type '{}'. Expected one of: {}",
+            bad,
+            TRANSACTION_TYPES.join(", ")
+        )));
+    }
+
+    Ok((format, TransactionExportFilter { app_id: app_id.to_string(), user_id, member_id: None, from, to, types }))
+}
+
+#[derive(Default)]
+struct ExportCursor {
+    after: Option<(String, String)>,
+    header_sent: bool,
+    done: bool,
+}
+
+/// Streams the export a page at a time so a long history never has to fit in
+/// one response body or one query.
+pub fn stream_export(
+    format: ExportFormat,
+    filter: TransactionExportFilter,
+    db: worker::D1Database,
+) -> std::result::Result<Response, AppError> {
+    let filename = format!(
+        "transactions-{}-{}.{}",
+        filter.app_id,
+        Utc::now().format("%Y%m%d"),
+        format.extension()
+    );
+    let db = std::rc::Rc::new(db);
+    let filter = std::rc::Rc::new(filter);
+    let stream = futures::stream::try_unfold(ExportCursor::default(), move |cursor| {
+        let db = db.clone();
+        let filter = filter.clone();
+        async move { next_export_chunk(format, &filter, cursor, &db).await }
+    });
+
+    let mut response = Response::from_stream(stream)?;
+    response.headers_mut().set("Content-Type", format.content_type())?;
+    response
+        .headers_mut()
+        .set("Content-Disposition", &format!("attachment; filename=\"{}\"", filename))?;
+    Ok(response)
+}
+
+async fn next_export_chunk(
+    format: ExportFormat,
+    filter: &TransactionExportFilter,
+    mut cursor: ExportCursor,
+    db: &worker::D1Database,
+) -> Result<Option<(Vec<u8>, ExportCursor)>> {
+    if cursor.done {
+        return Ok(None);
+    }
+
+    let mut chunk = String::new();
+    if !cursor.header_sent {
+        chunk.push_str(format.header().unwrap_or(""));
+        cursor.header_sent = true;
+    }
+
+    let page = get_transactions_page(filter, cursor.after.as_ref(), EXPORT_CHUNK_ROWS, db).await?;
+    cursor.done = (page.len() as i32) < EXPORT_CHUNK_ROWS;
+    if let Some(last) = page.last() {
+        cursor.after = Some((last.created_at.clone(), last.id.clone()));
+    }
+    for tx in &page {
+        chunk.push_str(&format.row(tx)?);
+    }
+
+    if chunk.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((chunk.into_bytes(), cursor)))
+}
+
 pub async fn list_packs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let app_id = auth::resolve_app_id(&req);
     let cache_key = format!("https://mako.midgarcorp.cc/__cache/credits/packs/{}", app_id);
@@ -163,7 +337,8 @@ pub async fn list_packs(req: Request, ctx: RouteContext<()>) -> Result<Response>
     Ok(resp)
 }
 
-pub async fn estimate_cost(mut req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+pub async fn estimate_cost(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let app_id = auth::resolve_app_id(&req);
     let estimate_req: EstimateCostRequest = match req.json().await {
         Ok(req) => req,
         Err(e) => return AppError::BadRequest(format!("Invalid request body: {}", e)).to_response(),
@@ -173,14 +348,21 @@ pub async fn estimate_cost(mut req: Request, _ctx: RouteContext<()>) -> Result<R
     let is_edit = estimate_req.is_edit.unwrap_or(false);
     let model = estimate_req.model.as_deref().unwrap_or("gemini-2.5-flash");
 
-    let credits_per_image = estimate_image_cost(model, &estimate_req.quality, &estimate_req.size, is_edit);
-    
-    let total_credits = credits_per_image * n as u32;
+    let capability = if is_edit { "image.edit" } else { "image.generate" };
+    let db = ctx.env.d1("DB")?;
+    let pricing = get_capability_pricing(&app_id, capability, &db).await;
+    let total_credits = pricing.apply_to_builtin(
+        estimate_image_cost(model, &estimate_req.quality, &estimate_req.size, is_edit) * n as u32,
+        n as u32,
+    );
+    let credits_per_image = total_credits / (n as u32).max(1);
     
     Response::from_json(&EstimateCostResponse {
         estimated_credits: total_credits,
         estimated_usd: format!("${:.2}", total_credits as f64 / 100.0),
-        note: if model.starts_with("gemini") {
+        note: if pricing.flat_credits.is_some() {
+            format!("Flat rate: {} credits per image", credits_per_image)
+        } else if model.starts_with("gemini") {
             format!("Gemini flat rate: {} credits per image", credits_per_image)
         } else {
             format!(
@@ -196,7 +378,7 @@ pub async fn purchase_credits(mut req: Request, ctx: RouteContext<()>) -> Result
 
     let auth = {
         let db = env.d1("DB")?;
-        match auth::authenticate(&req, &db).await {
+        match auth::authenticate(&req, &env, &db).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         }
@@ -302,20 +484,603 @@ pub async fn complete_purchase_webhook(mut req: Request, ctx: RouteContext<()>)
     Response::ok("Purchase completed")
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedeemPromoCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedeemPromoCodeResponse {
+    pub code: String,
+    pub credits_granted: i32,
+    pub balance: i32,
+    pub expires_at: Option<String>,
+    pub pack_discount_percent: Option<i32>,
+    pub discount_pack_id: Option<String>,
+}
+
+pub async fn redeem_promo_code(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match redeem_promo_code_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn redeem_promo_code_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "credits.redeem").await?;
+
+    let body: RedeemPromoCodeRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let promo = find_redeemable(&auth.app_id, &body.code, &db).await?;
+
+    // Discount-only codes grant nothing here; they're claimed when a discounted checkout is paid for.
+    if promo.credits <= 0 {
+        let balance = get_user_balance(&auth.app_id, &auth.user_id, &db).await?;
+        return Response::from_json(&RedeemPromoCodeResponse {
+            code: promo.code,
+            credits_granted: 0,
+            balance,
+            expires_at: None,
+            pack_discount_percent: promo.pack_discount_percent,
+            discount_pack_id: promo.discount_pack_id,
+        })
+        .map_err(AppError::from);
+    }
+
+    let redemption_id = claim_redemption(&promo, &auth.user_id, None, &db).await?;
+
+    let app = db
+        .prepare("SELECT promo_credit_ttl_days FROM apps WHERE app_id = ?1")
+        .bind(&[auth.app_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    let expires_at = promo.credit_expiry(app.as_ref());
+    let granted = add_promo_credits(
+        &PromoGrant {
+            app_id: &auth.app_id,
+            user_id: &auth.user_id,
+            amount: promo.credits as u32,
+            source: "bonus",
+            description: &format!("Promo code {}", promo.code),
+            reference_id: Some(&promo.code),
+            expires_at,
+        },
+        &db,
+    )
+    .await;
+    let balance = match granted {
+        Ok(balance) => balance,
+        Err(e) => {
+            // Nothing was granted, so the code isn't used up either.
+            let _ = release_redemption(&promo, &redemption_id, &db).await;
+            return Err(AppError::from(e));
+        }
+    };
+
+    Response::from_json(&RedeemPromoCodeResponse {
+        code: promo.code,
+        credits_granted: promo.credits,
+        balance,
+        expires_at: expires_at.map(|t| t.to_rfc3339()),
+        pack_discount_percent: promo.pack_discount_percent,
+        discount_pack_id: promo.discount_pack_id,
+    })
+    .map_err(AppError::from)
+}
+
+pub async fn get_referral_stats(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let db = ctx.env.d1("DB")?;
+    let auth = match auth::authenticate(&req, &ctx.env, &db).await {
+        Ok(a) => a,
+        Err(e) => return e.to_response(),
+    };
+
+    match crate::referrals::get_referral_stats(&auth.app_id, &auth.user_id, &db).await {
+        Ok(stats) => Response::from_json(&stats),
+        Err(e) => e.to_response(),
+    }
+}
+
 // Admin endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminCreatePromoCodeRequest {
+    pub code: String,
+    #[serde(default)]
+    pub credits: i32,
+    pub max_redemptions: Option<i32>,
+    pub per_user_limit: Option<i32>,
+    pub valid_from: Option<String>,
+    pub valid_until: Option<String>,
+    pub pack_discount_percent: Option<i32>,
+    pub discount_pack_id: Option<String>,
+    pub credit_ttl_days: Option<i32>,
+    /// Required for codes that grant credits.
+    pub reason: Option<String>,
+}
+
+pub async fn admin_create_promo_code(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_create_promo_code_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_create_promo_code_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ManageFinance).await?;
+
+    let body: AdminCreatePromoCodeRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+
+    let code = normalize_code(&body.code);
+    if code.is_empty() || code.len() > 32 || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(AppError::BadRequest("code must be 1-32 characters of A-Z, 0-9, '-' or '_'".to_string()));
+    }
+    if body.credits < 0 {
+        return Err(AppError::BadRequest("credits must not be negative".to_string()));
+    }
+    if let Some(percent) = body.pack_discount_percent {
+        if !(1..=99).contains(&percent) {
+            return Err(AppError::BadRequest("pack_discount_percent must be between 1 and 99".to_string()));
+        }
+    }
+    if body.credits == 0 && body.pack_discount_percent.is_none() {
+        return Err(AppError::BadRequest("A promo code must grant credits or discount a pack".to_string()));
+    }
+    let reason = if body.credits > 0 {
+        audit::require_reason(body.reason.as_deref())?
+    } else {
+        body.reason.as_deref().unwrap_or("").trim()
+    };
+    for ts in [&body.valid_from, &body.valid_until].into_iter().flatten() {
+        if chrono::DateTime::parse_from_rfc3339(ts).is_err() {
+            return Err(AppError::BadRequest(format!("Invalid RFC 3339 timestamp: {}", ts)));
+        }
+    }
+    let normalize_ts = |ts: &Option<String>| {
+        ts.as_deref()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc).to_rfc3339())
+    };
+    let null = || worker::wasm_bindgen::JsValue::NULL;
+
+    let inserted = db
+        .prepare(
+            "INSERT INTO promo_codes (app_id, code, credits, max_redemptions, per_user_limit, valid_from, valid_until,
+                                      pack_discount_percent, discount_pack_id, credit_ttl_days, enabled, created_by, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1, ?, ?)
+             ON CONFLICT(app_id, code) DO NOTHING",
+        )
+        .bind(&[
+            auth.app_id.clone().into(),
+            code.clone().into(),
+            body.credits.into(),
+            body.max_redemptions.map(|n| n.into()).unwrap_or_else(null),
+            body.per_user_limit.unwrap_or(1).max(1).into(),
+            normalize_ts(&body.valid_from).map(|t| t.into()).unwrap_or_else(null),
+            normalize_ts(&body.valid_until).map(|t| t.into()).unwrap_or_else(null),
+            body.pack_discount_percent.map(|n| n.into()).unwrap_or_else(null),
+            body.discount_pack_id.clone().map(|p| p.into()).unwrap_or_else(null),
+            body.credit_ttl_days.map(|n| n.into()).unwrap_or_else(null),
+            auth.user_id.clone().into(),
+            Utc::now().to_rfc3339().into(),
+        ])?
+        .run()
+        .await?;
+    let created = inserted.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0;
+    if !created {
+        return Err(AppError::Conflict(format!("Promo code {} already exists", code)));
+    }
+
+    let promo = crate::promo_codes::get_promo_code(&auth.app_id, &code, &db)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Promo code vanished after insert".to_string()))?;
+    audit::record(
+        &auth,
+        AuditEvent::new("promo_code.create")
+            .target("promo_code", &code)
+            .after(serde_json::to_value(&promo).unwrap_or_default())
+            .reason(reason),
+        &db,
+    )
+    .await;
+    Response::from_json(&promo).map(|r| r.with_status(201)).map_err(AppError::from)
+}
+
+pub async fn admin_list_promo_codes(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let db = ctx.env.d1("DB")?;
+    let auth = match admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await {
+        Ok(a) => a,
+        Err(e) => return e.to_response(),
+    };
+
+    let rows = db
+        .prepare("SELECT * FROM promo_codes WHERE app_id = ?1 ORDER BY created_at DESC LIMIT 500")
+        .bind(&[auth.app_id.clone().into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    let codes: Vec<PromoCode> = rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<PromoCode>(r).ok())
+        .collect();
+
+    Response::from_json(&json!({ "promo_codes": codes }))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferCreditsRequest {
+    pub recipient_user_id: Option<String>,
+    pub recipient_email: Option<String>,
+    pub amount: u32,
+    pub note: Option<String>,
+}
+
+pub async fn transfer_credits(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match transfer_credits_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn transfer_credits_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "credits.transfer").await?;
+
+    let body: TransferCreditsRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    if body.note.as_deref().map(|n| n.chars().count() > 200).unwrap_or(false) {
+        return Err(AppError::BadRequest("note must be at most 200 characters".to_string()));
+    }
+
+    let transfer = crate::transfers::transfer_credits(
+        &auth.app_id,
+        &auth.user_id,
+        body.recipient_user_id.as_deref(),
+        body.recipient_email.as_deref(),
+        body.amount,
+        body.note.as_deref(),
+        &db,
+    ).await?;
+    let balance = get_user_balance(&auth.app_id, &auth.user_id, &db).await?;
+
+    // The recipient's internal id stays out of the response.
+    Response::from_json(&json!({
+        "transfer": {
+            "id": transfer.id,
+            "sender_id": transfer.sender_id,
+            "amount": transfer.amount,
+            "note": transfer.note,
+            "created_at": transfer.created_at,
+        },
+        "balance": balance,
+    }))
+    .map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminGiftCreditsRequest {
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    pub amount: u32,
+    pub reason: String,
+    /// Lapse the gifted credits after this many days.
+    pub expires_in_days: Option<u32>,
+    /// Caller-chosen key; repeating a gift with the same key returns the first
+    /// result instead of gifting again.
+    pub idempotency_key: String,
+}
+
+/// Most recipients one bulk gift may name.
+const MAX_GIFT_RECIPIENTS: usize = 500;
+
+/// Grants the same amount to a list of users, e.g. everyone hit by an outage.
+/// Gifts are promotional like any admin grant. Recipients that can't be
+/// resolved are reported back instead of failing the whole batch. Lookups and
+/// grants are batched (see `resolve_users`, `gift_promo_credits`), so the
+/// number of queries grows with batches, not with recipients.
+pub async fn admin_gift_credits(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_gift_credits_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_gift_credits_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::GrantCredits).await?;
+
+    let body: AdminGiftCreditsRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    if body.amount == 0 {
+        return Err(AppError::BadRequest("amount must be positive".to_string()));
+    }
+    let idempotency_key = body.idempotency_key.trim();
+    if idempotency_key.is_empty() || idempotency_key.len() > 200 {
+        return Err(AppError::BadRequest("idempotency_key must be 1 to 200 characters".to_string()));
+    }
+    let reason = audit::require_reason(Some(&body.reason))?;
+    let recipient_count = body.user_ids.len() + body.emails.len();
+    if recipient_count == 0 || recipient_count > MAX_GIFT_RECIPIENTS {
+        return Err(AppError::BadRequest(format!(
+            "Name between 1 and {} recipients in user_ids and emails",
+            MAX_GIFT_RECIPIENTS
+        )));
+    }
+    // Claim the key, or pick up the gift an earlier request made with it.
+    db.prepare(
+        "INSERT INTO credit_gifts (id, app_id, idempotency_key, actor_id, amount, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(app_id, idempotency_key) DO NOTHING",
+    )
+    .bind(&[
+        Uuid::new_v4().to_string().into(),
+        auth.app_id.clone().into(),
+        idempotency_key.into(),
+        auth.user_id.clone().into(),
+        body.amount.into(),
+        Utc::now().to_rfc3339().into(),
+    ])?
+    .run()
+    .await?;
+    let gift = db
+        .prepare("SELECT id, amount, result FROM credit_gifts WHERE app_id = ?1 AND idempotency_key = ?2")
+        .bind(&[auth.app_id.clone().into(), idempotency_key.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Gift vanished after claim".to_string()))?;
+    if let Some(result) = gift.get("result").and_then(|r| r.as_str()) {
+        let result: serde_json::Value = serde_json::from_str(result)
+            .map_err(|e| AppError::InternalError(format!("Stored gift result doesn't parse: {}", e)))?;
+        return Response::from_json(&result).map_err(AppError::from);
+    }
+    if gift.get("amount").and_then(|a| a.as_i64()) != Some(body.amount as i64) {
+        return Err(AppError::Conflict("idempotency_key was already used for a different gift".to_string()));
+    }
+    auth.check_grant(body.amount as u64 * recipient_count as u64)?;
+    let gift_id = gift.get("id").and_then(|i| i.as_str()).unwrap_or_default().to_string();
+
+    let user_ids: Vec<&str> = body.user_ids.iter().map(String::as_str).collect();
+    let emails: Vec<&str> = body.emails.iter().map(String::as_str).collect();
+    let resolved = crate::transfers::resolve_users(&auth.app_id, &user_ids, &emails, &db).await?;
+
+    let mut targets: Vec<(String, String)> = Vec::new();
+    let mut failed = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let recipients = user_ids
+        .iter()
+        .map(|id| (id.to_string(), resolved.ids.get(id.trim()).cloned().into_iter().collect::<Vec<_>>()))
+        .chain(emails.iter().map(|email| {
+            (email.to_string(), resolved.emails.get(&email.trim().to_lowercase()).cloned().unwrap_or_default())
+        }));
+    for (recipient, matches) in recipients {
+        let error = match matches.as_slice() {
+            [id] if seen.insert(id.clone()) => {
+                targets.push((recipient, id.clone()));
+                continue;
+            }
+            [_] => "Duplicate recipient",
+            [] => "No user with that id or email in this app",
+            _ => "More than one account uses that email; send to the user id instead",
+        };
+        failed.push(json!({ "recipient": recipient, "error": error }));
+    }
+
+    let description = format!("Gift from admin {}: {}", auth.user_id, reason);
+    let expires_at = body
+        .expires_in_days
+        .filter(|d| *d > 0)
+        .map(|d| Utc::now() + chrono::Duration::days(d as i64));
+    let target_ids: Vec<String> = targets.iter().map(|(_, id)| id.clone()).collect();
+
+    // The audit row goes in before any credits do, and only within the daily
+    // grant limit; it's what counts the gift against the limit. A retry of a
+    // gift that got this far reuses the row instead of being counted again.
+    let recorded = db
+        .prepare("SELECT 1 FROM admin_audit_log WHERE app_id = ?1 AND action = 'credits.gift' AND target_id = ?2")
+        .bind(&[auth.app_id.clone().into(), gift_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some();
+    if !recorded {
+        let event = AuditEvent::new("credits.gift")
+            .target("gift", &gift_id)
+            .after(json!({ "amount": body.amount, "granted": target_ids, "failed": failed.len() }))
+            .reason(reason);
+        let credits = body.amount as u64 * target_ids.len() as u64;
+        let inserted = auth
+            .grant_audit_statement(&event, &Uuid::new_v4().to_string(), credits, &db)?
+            .run()
+            .await?;
+        if inserted.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+            return Err(auth.daily_limit_error(&db).await);
+        }
+    }
+    crate::credits::gift_promo_credits(&auth.app_id, &gift_id, &target_ids, body.amount, &description, expires_at, &db)
+        .await?;
+
+    let balances = crate::credits::get_balances(&auth.app_id, &target_ids, &db).await?;
+    let granted: Vec<serde_json::Value> = targets
+        .iter()
+        .map(|(recipient, id)| json!({ "recipient": recipient, "user_id": id, "new_balance": balances.get(id) }))
+        .collect();
+    let result = json!({
+        "gift_id": gift_id,
+        "amount": body.amount,
+        "granted": granted,
+        "failed": failed,
+    });
+    db.prepare("UPDATE credit_gifts SET result = ?1, completed_at = ?2 WHERE id = ?3")
+        .bind(&[result.to_string().into(), Utc::now().to_rfc3339().into(), gift_id.clone().into()])?
+        .run()
+        .await?;
+
+    worker::console_log!(
+        "Admin {} gifted {} credits to {} users in {} ({} failed)",
+        auth.user_id, body.amount, granted.len(), auth.app_id, failed.len()
+    );
+    Response::from_json(&result).map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminUnfreezeWalletRequest {
+    pub user_id: String,
+    pub reason: String,
+}
+
+/// Lifts a refund/chargeback freeze. Any negative balance stays; the user still
+/// has to top up before spending.
+pub async fn admin_unfreeze_wallet(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_unfreeze_wallet_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_unfreeze_wallet_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ManageFinance).await?;
+
+    let body: AdminUnfreezeWalletRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let reason = audit::require_reason(Some(&body.reason))?;
+    let frozen_at = get_frozen_at(&auth.app_id, &body.user_id, &db).await?;
+
+    let result = db
+        .prepare(
+            "UPDATE user_credits SET frozen_at = NULL, frozen_reason = NULL, updated_at = ?1
+             WHERE app_id = ?2 AND user_id = ?3 AND frozen_at IS NOT NULL",
+        )
+        .bind(&[Utc::now().to_rfc3339().into(), auth.app_id.clone().into(), body.user_id.clone().into()])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::NotFound("No frozen wallet for this user".to_string()));
+    }
+
+    worker::console_log!("Admin {} unfroze wallet of {}: {}", auth.user_id, body.user_id, reason);
+    audit::record(
+        &auth,
+        AuditEvent::new("wallet.unfreeze")
+            .target("user", &body.user_id)
+            .before(json!({ "frozen_at": frozen_at }))
+            .after(json!({ "frozen_at": null }))
+            .reason(reason),
+        &db,
+    )
+    .await;
+    let balance = get_user_balance(&auth.app_id, &body.user_id, &db).await?;
+    Response::from_json(&json!({
+        "user_id": body.user_id,
+        "balance": balance,
+        "frozen_at": null,
+    }))
+    .map_err(AppError::from)
+}
+
+/// Ledger discrepancies found by the daily reconciliation for this app, plus
+/// the latest run so finance can see the check is actually running.
+pub async fn admin_list_ledger_discrepancies(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_ledger_discrepancies_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_ledger_discrepancies_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
+    let url = req.url()?;
+    let query_params: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let status = query_params.get("status").map(|s| s.as_str()).unwrap_or("open");
+    let page = query_params.get("page").and_then(|p| p.parse::<i32>().ok()).unwrap_or(1).max(1);
+    let per_page = query_params
+        .get("per_page")
+        .and_then(|p| p.parse::<i32>().ok())
+        .unwrap_or(50)
+        .clamp(1, 100);
+
+    let discrepancies = crate::reconciliation::list_discrepancies(
+        &auth.app_id, status, per_page, (page - 1) * per_page, &db,
+    ).await?;
+    let last_run = crate::reconciliation::latest_run(&db).await?;
+    Response::from_json(&json!({
+        "discrepancies": discrepancies,
+        "last_run": last_run,
+        "page": page,
+        "per_page": per_page,
+    }))
+    .map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminResolveDiscrepancyRequest {
+    pub note: String,
+}
+
+pub async fn admin_resolve_ledger_discrepancy(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_resolve_ledger_discrepancy_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_resolve_ledger_discrepancy_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ManageFinance).await?;
+
+    let id = ctx
+        .param("discrepancy_id")
+        .ok_or_else(|| AppError::BadRequest("Missing discrepancy_id".to_string()))?
+        .to_string();
+    let body: AdminResolveDiscrepancyRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    if body.note.trim().is_empty() {
+        return Err(AppError::BadRequest("A resolution note is required".to_string()));
+    }
+
+    crate::reconciliation::resolve_discrepancy(&auth.app_id, &id, body.note.trim(), &db).await?;
+    worker::console_log!("Admin {} resolved ledger discrepancy {}", auth.user_id, id);
+    audit::record(
+        &auth,
+        AuditEvent::new("ledger.resolve_discrepancy")
+            .target("discrepancy", &id)
+            .after(json!({ "status": "resolved" }))
+            .reason(body.note.trim()),
+        &db,
+    )
+    .await;
+    Response::from_json(&json!({ "id": id, "resolved": true })).map_err(AppError::from)
+}
+
 pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let env = ctx.env;
 
     let auth = {
         let db = env.d1("DB")?;
-        match auth::authenticate(&req, &db).await {
+        match admin::authorize(&req, &env, &db, Permission::GrantCredits).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         }
     };
-    if !auth.is_admin {
-        return AppError::Forbidden("Admin access required".to_string()).to_response();
-    }
     let admin_user_id = auth.user_id.clone();
     let app_id = auth.app_id.clone();
 
@@ -323,8 +1088,14 @@ pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Re
         Ok(req) => req,
         Err(e) => return AppError::BadRequest(format!("Invalid request body: {}", e)).to_response(),
     };
+    if let Err(e) = audit::require_reason(Some(&adjust_req.reason)) {
+        return e.to_response();
+    }
     
     let db = env.d1("DB")?;
+    if let Err(e) = auth.check_grant(adjust_req.amount.unsigned_abs() as u64) {
+        return e.to_response();
+    }
 
     let exists = db
         .prepare("SELECT 1 FROM users WHERE app_id = ? AND id = ?")
@@ -335,19 +1106,37 @@ pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Re
         return AppError::NotFound("User not found in this app".to_string()).to_response();
     }
 
-    let description = format!("Admin adjustment by {}: {}", admin_user_id, adjust_req.reason);
+    let description = format!("Admin adjustment by {}: {}", admin_user_id, adjust_req.reason.trim());
+    let balance_before = get_user_balance(&app_id, &adjust_req.user_id, &db).await?;
     
     let new_balance = if adjust_req.amount > 0 {
-        // Positive adjustment - add credits
-        add_credits(
-            &app_id,
-            &adjust_req.user_id,
-            adjust_req.amount as u32,
-            "admin_adjustment",
-            &description,
-            None,
-            &db,
-        ).await?
+        // Positive adjustment - a grant, tracked as a promo bucket so it's
+        // spent before paid credits and can be given an expiry. Its audit row
+        // is written in the same batch, only within the daily grant limit.
+        let expires_at = adjust_req
+            .expires_in_days
+            .filter(|d| *d > 0)
+            .map(|d| Utc::now() + chrono::Duration::days(d as i64));
+        let audit_id = Uuid::new_v4().to_string();
+        let event = AuditEvent::new("credits.adjust")
+            .target("user", &adjust_req.user_id)
+            .before(json!({ "balance": balance_before }))
+            .after(json!({ "balance": balance_before + adjust_req.amount, "adjustment": adjust_req.amount }))
+            .reason(adjust_req.reason.trim());
+        let guard = auth.grant_audit_statement(&event, &audit_id, adjust_req.amount as u64, &db)?;
+        let grant = PromoGrant {
+            app_id: &app_id,
+            user_id: &adjust_req.user_id,
+            amount: adjust_req.amount as u32,
+            source: "admin_adjustment",
+            description: &description,
+            reference_id: None,
+            expires_at,
+        };
+        match add_promo_credits_guarded(&grant, guard, audit::RECORDED_SQL, &audit_id, &db).await? {
+            Some(balance) => balance,
+            None => return auth.daily_limit_error(&db).await.to_response(),
+        }
     } else {
         // Negative adjustment - deduct credits but ensure balance doesn't go below 0
         let current_balance = get_user_balance(&app_id, &adjust_req.user_id, &db).await?;
@@ -381,8 +1170,8 @@ pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Re
         // Record transaction with actual amount deducted
         let transaction_id = Uuid::new_v4().to_string();
         db.prepare(
-            "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at)
-             VALUES (?, ?, ?, ?, ?, ?, ?, NULL, ?)"
+            "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+             VALUES (?, ?, ?, ?, ?, ?, ?, NULL, ?, ?)"
         )
         .bind(&[
             transaction_id.into(),
@@ -393,9 +1182,21 @@ pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Re
             new_balance.into(),
             description.into(),
             Utc::now().to_rfc3339().into(),
+            crate::request_id::value(),
         ])?
         .run()
         .await?;
+        consume_promo_credits(&app_id, &adjust_req.user_id, actual_deduction as u32, &db).await?;
+        audit::record(
+            &auth,
+            AuditEvent::new("credits.adjust")
+                .target("user", &adjust_req.user_id)
+                .before(json!({ "balance": balance_before }))
+                .after(json!({ "balance": new_balance, "adjustment": adjust_req.amount }))
+                .reason(adjust_req.reason.trim()),
+            &db,
+        )
+        .await;
         
         new_balance
     };
@@ -411,17 +1212,14 @@ pub async fn admin_adjust_credits(mut req: Request, ctx: RouteContext<()>) -> Re
 pub async fn admin_search_users(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let env = ctx.env;
 
-    let app_id = {
+    let auth = {
         let db = env.d1("DB")?;
-        let auth = match auth::authenticate(&req, &db).await {
+        match admin::authorize(&req, &env, &db, Permission::ReadUsers).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
-        };
-        if !auth.is_admin {
-            return AppError::Forbidden("Admin access required".to_string()).to_response();
         }
-        auth.app_id
     };
+    let app_id = auth.app_id.clone();
 
     let url = req.url()?;
     let search = url.query_pairs()
@@ -430,7 +1228,7 @@ pub async fn admin_search_users(req: Request, ctx: RouteContext<()>) -> Result<R
     
     let db = env.d1("DB")?;
     
-    let results = if let Some(search_term) = search {
+    let results = if let Some(search_term) = &search {
         // Clean up the search term - trim whitespace and normalize
         let cleaned_search = search_term.trim();
         if cleaned_search.is_empty() {
@@ -439,7 +1237,7 @@ pub async fn admin_search_users(req: Request, ctx: RouteContext<()>) -> Result<R
         
         // Search by ID (exact match with trimmed spaces) or email (flexible matching)
         let query = "
-            SELECT u.id, u.email, u.is_admin, u.created_at,
+            SELECT u.id, u.email, u.is_admin, u.status, u.status_expires_at, u.created_at,
                    COALESCE(uc.balance, 0) as credits
             FROM users u
             LEFT JOIN user_credits uc ON uc.app_id = u.app_id AND uc.user_id = u.id
@@ -473,7 +1271,7 @@ pub async fn admin_search_users(req: Request, ctx: RouteContext<()>) -> Result<R
     } else {
         // Return recent users if no search term
         let query = "
-            SELECT u.id, u.email, u.is_admin, u.created_at,
+            SELECT u.id, u.email, u.is_admin, u.status, u.status_expires_at, u.created_at,
                    COALESCE(uc.balance, 0) as credits
             FROM users u
             LEFT JOIN user_credits uc ON uc.app_id = u.app_id AND uc.user_id = u.id
@@ -496,11 +1294,18 @@ pub async fn admin_search_users(req: Request, ctx: RouteContext<()>) -> Result<R
                 "id": user.get("id").and_then(|v| v.as_str()).unwrap_or(""),
                 "email": user.get("email").and_then(|v| v.as_str()),
                 "is_admin": user.get("is_admin").and_then(|v| v.as_i64()).map(|v| v != 0).unwrap_or(false),
+                "status": crate::user_status::UserStatus::from_row(&user).effective(),
                 "credits": user.get("credits").and_then(|v| v.as_i64()).unwrap_or(0),
                 "created_at": user.get("created_at").and_then(|v| v.as_str()).unwrap_or("")
             })
         })
         .collect();
+    audit::record(
+        &auth,
+        AuditEvent::new("users.search").after(json!({ "search": search, "results": users.len() })),
+        &db,
+    )
+    .await;
     
     Response::from_json(&users)
 }
@@ -509,13 +1314,10 @@ pub async fn admin_system_stats(req: Request, ctx: RouteContext<()>) -> Result<R
     let env = ctx.env;
 
     let db = env.d1("DB")?;
-    let auth = match auth::authenticate(&req, &db).await {
+    let auth = match admin::authorize(&req, &env, &db, Permission::ReadFinance).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
-    if !auth.is_admin {
-        return AppError::Forbidden("Admin access required".to_string()).to_response();
-    }
     let app_id = auth.app_id.clone();
 
     // Tenant-scoped statistics (per app_id)
@@ -614,16 +1416,38 @@ pub async fn crypto_payment_webhook(mut req: Request, ctx: RouteContext<()>) ->
     // Parse the webhook data
     let webhook: crate::crypto_payments::NOWPaymentsWebhook = serde_json::from_str(&body)
         .map_err(|e| AppError::BadRequest(format!("Invalid webhook body: {}", e)))?;
-    
+
+    let db = env.d1("DB")?;
+    // NOWPayments has no event id; each status change of a payment is one event.
+    let event_id = format!("{}:{}", webhook.payment_id, webhook.payment_status);
+    let app_id = db
+        .prepare("SELECT app_id FROM credit_purchases WHERE id = ?1")
+        .bind(&[webhook.order_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("app_id").and_then(|a| a.as_str()).map(|s| s.to_string()));
+    let Some(record_id) = crate::webhook_events::begin(
+        "nowpayments", &event_id, &webhook.payment_status, app_id.as_deref(), &body, &db,
+    ).await? else {
+        return Response::ok("OK");
+    };
+
+    let outcome = process_nowpayments_event(&webhook, &db).await;
+    crate::webhook_events::complete(&record_id, outcome, &db).await
+}
+
+pub async fn process_nowpayments_event(
+    webhook: &crate::crypto_payments::NOWPaymentsWebhook,
+    db: &worker::D1Database,
+) -> std::result::Result<(), AppError> {
     // Only process confirmed payments
     if webhook.payment_status == "finished" || webhook.payment_status == "confirmed" {
-        let db = env.d1("DB")?;
-        
         // Complete the purchase using the order_id (which is our purchase_id)
-        complete_purchase(&webhook.order_id, &db).await?;
+        complete_purchase(&webhook.order_id, db).await?;
+    } else if webhook.payment_status == "refunded" {
+        claw_back_purchase(&webhook.order_id, "refund", db).await?;
     }
-    
-    Response::ok("OK")
+    Ok(())
 }
 
 pub async fn get_purchase_status(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
@@ -746,7 +1570,7 @@ pub async fn create_stripe_checkout(mut req: Request, ctx: RouteContext<()>) ->
 
     let user_id = {
         let db = env.d1("DB")?;
-        let auth = match auth::authenticate(&req, &db).await {
+        let auth = match auth::authenticate(&req, &env, &db).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         };
@@ -782,6 +1606,27 @@ pub async fn create_stripe_checkout(mut req: Request, ctx: RouteContext<()>) ->
     let total_credits = pack.credits + pack.bonus_credits;
     
     let db = env.d1("DB")?;
+
+    // A discount code swaps the fixed Stripe price for an inline discounted one.
+    let promo = match checkout_req.promo_code.as_deref().filter(|c| !c.trim().is_empty()) {
+        Some(code) => {
+            let promo = match find_redeemable(&user_id.1, code, &db).await {
+                Ok(p) => p,
+                Err(e) => return e.to_response(),
+            };
+            let Some(price) = promo.discounted_price_cents(&pack.id, pack.price_usd_cents) else {
+                return AppError::BadRequest(format!("Promo code does not apply to the '{}' pack", pack.id)).to_response();
+            };
+            // Only checked here; the code is claimed when the purchase completes,
+            // so an abandoned checkout doesn't use it up.
+            if let Err(e) = ensure_redeemable_by(&promo, &user_id.0, &db).await {
+                return e.to_response();
+            }
+            Some((promo, price))
+        }
+        None => None,
+    };
+    let price_usd_cents = promo.as_ref().map(|(_, price)| *price).unwrap_or(pack.price_usd_cents);
     
     // Record the purchase in pending state
     let purchase_id = record_purchase(
@@ -789,11 +1634,28 @@ pub async fn create_stripe_checkout(mut req: Request, ctx: RouteContext<()>) ->
         &user_id.0,
         &checkout_req.pack_id,
         total_credits as u32,
-        pack.price_usd_cents as u32,
+        price_usd_cents as u32,
         "stripe",
         "", // We'll update with session_id after creating
         &db,
     ).await?;
+
+    let line_item = match &promo {
+        Some((_, price)) => LineItem {
+            price: None,
+            price_data: Some(LineItemPriceData {
+                currency: "usd".to_string(),
+                unit_amount: *price as i64,
+                product_name: format!("{} ({} credits)", pack.name, total_credits),
+            }),
+            quantity: 1,
+        },
+        None => LineItem {
+            price: Some(stripe_price_id),
+            price_data: None,
+            quantity: 1,
+        },
+    };
     
     // Create Stripe checkout session
     let session = crate::stripe_payments::create_checkout_session(
@@ -802,18 +1664,19 @@ pub async fn create_stripe_checkout(mut req: Request, ctx: RouteContext<()>) ->
         &checkout_req.pack_id,
         &pack.name,
         total_credits as u32,
-        &stripe_price_id,
+        line_item,
         &checkout_req.success_url,
         &checkout_req.cancel_url,
         user_id.2.as_deref(),
     ).await?;
     
-    // Update purchase with Stripe session ID
+    // Update purchase with Stripe session ID (and the discount code to claim on completion)
     db.prepare(
-        "UPDATE credit_purchases SET payment_id = ? WHERE id = ?"
+        "UPDATE credit_purchases SET payment_id = ?, promo_code = ? WHERE id = ?"
     )
     .bind(&[
         session.id.clone().into(),
+        promo.as_ref().map(|(p, _)| p.code.clone().into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
         purchase_id.clone().into(),
     ])?
     .run()
@@ -857,7 +1720,49 @@ pub async fn stripe_webhook(mut req: Request, ctx: RouteContext<()>) -> Result<R
         .map_err(|e| AppError::BadRequest(format!("Invalid webhook body: {}", e)))?;
     
     worker::console_log!("Received Stripe webhook event: {}", event.event_type);
-    
+
+    let db = env.d1("DB")?;
+    let app_id = stripe_event_app_id(&event.data.object, &db).await?;
+    let Some(record_id) = crate::webhook_events::begin(
+        "stripe", &event.id, &event.event_type, app_id.as_deref(), &body, &db,
+    ).await? else {
+        return Response::ok("OK");
+    };
+
+    let outcome = process_stripe_event(&env, event).await;
+    crate::webhook_events::complete(&record_id, outcome, &db).await
+}
+
+/// The tenant a Stripe event belongs to, when the object says so: pack checkouts
+/// carry our purchase_id, subscription objects and invoices carry app_id.
+async fn stripe_event_app_id(
+    object: &serde_json::Value,
+    db: &worker::D1Database,
+) -> Result<Option<String>> {
+    let metadata_app = object
+        .pointer("/metadata/app_id")
+        .or_else(|| object.pointer("/subscription_details/metadata/app_id"))
+        .and_then(|v| v.as_str());
+    if let Some(app_id) = metadata_app {
+        return Ok(Some(app_id.to_string()));
+    }
+    match object.pointer("/metadata/purchase_id").and_then(|v| v.as_str()) {
+        Some(purchase_id) => Ok(db
+            .prepare("SELECT app_id FROM credit_purchases WHERE id = ?1")
+            .bind(&[purchase_id.into()])?
+            .first::<serde_json::Value>(None)
+            .await?
+            .and_then(|v| v.get("app_id").and_then(|a| a.as_str()).map(|s| s.to_string()))),
+        None => Ok(None),
+    }
+}
+
+pub async fn process_stripe_event(
+    env: &Env,
+    event: crate::stripe_payments::StripeWebhookEvent,
+) -> std::result::Result<(), AppError> {
+    let db = env.d1("DB")?;
+
     // Handle different event types
     match event.event_type.as_str() {
         "checkout.session.completed" => {
@@ -869,12 +1774,40 @@ pub async fn stripe_webhook(mut req: Request, ctx: RouteContext<()>) -> Result<R
             if session.payment_status == "paid" {
                 // Extract purchase_id from metadata
                 if let Some(purchase_id) = session.metadata.get("purchase_id") {
-                    let db = env.d1("DB")?;
                     complete_purchase(purchase_id, &db).await?;
                     worker::console_log!("Completed purchase: {}", purchase_id);
                 }
             }
         },
+        "invoice.paid" => {
+            crate::subscriptions::handle_invoice_paid(&event.data.object, &db).await?;
+        },
+        "customer.subscription.updated" | "customer.subscription.deleted" => {
+            crate::subscriptions::sync_subscription(&event.data.object, &db).await?;
+        },
+        "charge.refunded" => {
+            let charge = &event.data.object;
+            if !crate::stripe_payments::is_full_refund(charge) {
+                // Partial refunds keep the credits; they're left for manual review.
+                worker::console_log!("Partial refund on charge {:?}, not clawing back", charge.get("id"));
+            } else {
+                claw_back_stripe_payment(env, charge, "refund").await?;
+            }
+        },
+        "charge.dispute.created" => {
+            claw_back_stripe_payment(env, &event.data.object, "dispute").await?;
+        },
+        "charge.dispute.closed" => {
+            let dispute = &event.data.object;
+            if dispute.get("status").and_then(|s| s.as_str()) == Some("won") {
+                match stripe_purchase_for(env, dispute, &db).await? {
+                    Some(purchase_id) => {
+                        restore_purchase(&purchase_id, "dispute won", &db).await?;
+                    }
+                    None => worker::console_log!("Won dispute matches no purchase"),
+                }
+            }
+        },
         "payment_intent.succeeded" => {
             worker::console_log!("Payment intent succeeded");
         },
@@ -885,8 +1818,39 @@ pub async fn stripe_webhook(mut req: Request, ctx: RouteContext<()>) -> Result<R
             worker::console_log!("Unhandled event type: {}", event.event_type);
         }
     }
-    
-    Response::ok("OK")
+
+    Ok(())
+}
+
+/// Our purchase for a Stripe charge or dispute: subscription charges carry the
+/// invoice id directly, pack charges are traced back through the payment intent.
+async fn stripe_purchase_for(
+    env: &Env,
+    object: &serde_json::Value,
+    db: &worker::D1Database,
+) -> std::result::Result<Option<String>, AppError> {
+    let payment_id = match object.get("invoice").and_then(|v| v.as_str()) {
+        Some(invoice) => Some(invoice.to_string()),
+        None => match object.get("payment_intent").and_then(|v| v.as_str()) {
+            Some(intent) => crate::stripe_payments::resolve_payment_reference(env, intent).await?,
+            None => None,
+        },
+    };
+    match payment_id {
+        Some(payment_id) => Ok(find_purchase_by_payment("stripe", &payment_id, db).await?),
+        None => Ok(None),
+    }
+}
+
+async fn claw_back_stripe_payment(env: &Env, object: &serde_json::Value, reason: &str) -> std::result::Result<(), AppError> {
+    let db = env.d1("DB")?;
+    match stripe_purchase_for(env, object, &db).await? {
+        Some(purchase_id) => {
+            claw_back_purchase(&purchase_id, reason, &db).await?;
+        }
+        None => worker::console_log!("Stripe {} matches no purchase: {:?}", reason, object.get("id")),
+    }
+    Ok(())
 }
 
 pub async fn get_stripe_config(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
@@ -928,7 +1892,7 @@ pub async fn validate_revenuecat_purchase(mut req: Request, ctx: RouteContext<()
 
     let auth = {
         let db = env.d1("DB")?;
-        match auth::authenticate(&req, &db).await {
+        match auth::authenticate(&req, &env, &db).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         }
@@ -986,12 +1950,18 @@ pub async fn validate_revenuecat_purchase(mut req: Request, ctx: RouteContext<()
     .first::<serde_json::Value>(None)
     .await?;
     
-    if let Some(existing) = existing_purchase {
-        let status = existing.get("status").and_then(|v| v.as_str()).unwrap_or("");
-        if status == "completed" {
+    // A token is only ever credited once: completed purchases, and refunded ones
+    // whose credits were clawed back, are both final. A pending row left by an
+    // interrupted grant is completed below instead of being recorded again.
+    let pending_purchase_id = match existing_purchase {
+        Some(existing) if existing.get("status").and_then(|v| v.as_str()) == Some("pending") => {
+            existing.get("id").and_then(|v| v.as_str()).map(|s| s.to_string())
+        }
+        Some(_) => {
             return AppError::BadRequest("Purchase has already been processed".to_string()).to_response();
         }
-    }
+        None => None,
+    };
     
     // Verify with RevenueCat BEFORE granting anything. The authenticated webhook
     // (revenuecat_webhook) is the authoritative, idempotent source of truth; this
@@ -1025,16 +1995,19 @@ pub async fn validate_revenuecat_purchase(mut req: Request, ctx: RouteContext<()
     }
 
     // Verified. Record + complete (idempotent; the dedup check above prevents double-grant).
-    let purchase_id = record_purchase(
-        &app_id,
-        &user_id,
-        &validate_req.pack_id,
-        total_credits as u32,
-        pack.price_usd_cents as u32,
-        "revenuecat",
-        &validate_req.purchase_token,
-        &db,
-    ).await?;
+    let purchase_id = match pending_purchase_id {
+        Some(id) => id,
+        None => record_purchase(
+            &app_id,
+            &user_id,
+            &validate_req.pack_id,
+            total_credits as u32,
+            pack.price_usd_cents as u32,
+            "revenuecat",
+            &validate_req.purchase_token,
+            &db,
+        ).await?,
+    };
 
     complete_purchase(&purchase_id, &db).await?;
 
@@ -1317,6 +2290,8 @@ pub struct RevenueCatEvent {
     pub transaction_id: String,
     #[serde(rename = "type")]
     pub event_type: String,
+    /// Set on CANCELLATION; CUSTOMER_SUPPORT means the store refunded it.
+    pub cancel_reason: Option<String>,
 }
 
 /// Resolve which tenant a RevenueCat product belongs to by matching the product
@@ -1356,25 +2331,40 @@ pub async fn revenuecat_webhook(mut req: Request, ctx: RouteContext<()>) -> Resu
     }
     
     // Parse the webhook event
-    let webhook_event: RevenueCatWebhookEvent = match req.json().await {
+    let body = req.text().await?;
+    let webhook_event: RevenueCatWebhookEvent = match serde_json::from_str(&body) {
         Ok(event) => event,
         Err(e) => return AppError::BadRequest(format!("Invalid webhook body: {}", e)).to_response(),
     };
     
     let event = &webhook_event.event;
     worker::console_log!("RevenueCat webhook: {} for user {}", event.event_type, event.app_user_id);
-    
+
+    let db = env.d1("DB")?;
+    let app_id = resolve_tenant_by_product(&db, &event.product_id).await.map(|(app_id, _)| app_id);
+    let Some(record_id) = crate::webhook_events::begin(
+        "revenuecat", &event.id, &event.event_type, app_id.as_deref(), &body, &db,
+    ).await? else {
+        return Response::ok("OK");
+    };
+
+    let outcome = process_revenuecat_event(event, &db).await;
+    crate::webhook_events::complete(&record_id, outcome, &db).await
+}
+
+pub async fn process_revenuecat_event(
+    event: &RevenueCatEvent,
+    db: &worker::D1Database,
+) -> std::result::Result<(), AppError> {
     // Handle different event types
     match event.event_type.as_str() {
         "INITIAL_PURCHASE" | "RENEWAL" => {
-            let db = env.d1("DB")?;
-
             // Resolve the tenant from the product id via apps.rc_product_prefix.
-            let (app_id, prefix) = match resolve_tenant_by_product(&db, &event.product_id).await {
+            let (app_id, prefix) = match resolve_tenant_by_product(db, &event.product_id).await {
                 Some(t) => t,
                 None => {
                     worker::console_log!("No tenant matched product {}", event.product_id);
-                    return Response::ok("OK");
+                    return Ok(());
                 }
             };
 
@@ -1384,12 +2374,12 @@ pub async fn revenuecat_webhook(mut req: Request, ctx: RouteContext<()>) -> Resu
                 .unwrap_or(&event.product_id)
                 .to_string();
 
-            let packs = get_credit_packs_for_app(&app_id, &db).await;
+            let packs = get_credit_packs_for_app(&app_id, db).await;
             let pack = match packs.iter().find(|p| p.id == pack_id) {
                 Some(p) => p,
                 None => {
                     worker::console_log!("Unknown pack {} for app {} (product {})", pack_id, app_id, event.product_id);
-                    return Response::ok("OK");
+                    return Ok(());
                 }
             };
 
@@ -1404,7 +2394,7 @@ pub async fn revenuecat_webhook(mut req: Request, ctx: RouteContext<()>) -> Resu
 
             if existing.is_some() {
                 worker::console_log!("Transaction {} already processed", event.transaction_id);
-                return Response::ok("OK");
+                return Ok(());
             }
 
             let purchase_id = record_purchase(
@@ -1415,13 +2405,21 @@ pub async fn revenuecat_webhook(mut req: Request, ctx: RouteContext<()>) -> Resu
                 pack.price_usd_cents as u32,
                 "revenuecat",
                 &event.transaction_id,
-                &db,
+                db,
             ).await?;
 
-            complete_purchase(&purchase_id, &db).await?;
+            complete_purchase(&purchase_id, db).await?;
 
             worker::console_log!("Processed RevenueCat purchase {} for app {} user {}", purchase_id, app_id, event.app_user_id);
         },
+        "CANCELLATION" if event.cancel_reason.as_deref() == Some("CUSTOMER_SUPPORT") => {
+            match find_purchase_by_payment("revenuecat", &event.transaction_id, db).await? {
+                Some(purchase_id) => {
+                    claw_back_purchase(&purchase_id, "refund", db).await?;
+                }
+                None => worker::console_log!("Refunded transaction {} matches no purchase", event.transaction_id),
+            }
+        },
         "CANCELLATION" | "UNCANCELLATION" | "EXPIRATION" => {
             // Handle subscription events (we don't have subscriptions yet)
             worker::console_log!("Subscription event {} for user {}", event.event_type, event.app_user_id);
@@ -1433,6 +2431,6 @@ pub async fn revenuecat_webhook(mut req: Request, ctx: RouteContext<()>) -> Resu
             worker::console_log!("Unhandled RevenueCat event type: {}", event.event_type);
         }
     }
-    
-    Response::ok("OK")
+
+    Ok(())
 }
\ No newline at end of file
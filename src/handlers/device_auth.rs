@@ -2,6 +2,8 @@ use worker::{Request, Response, RouteContext, Result, console_log};
 use crate::error::AppError;
 use crate::auth::resolve_app_id;
 use crate::credits::initialize_user_credits;
+use crate::sessions::DeviceInfo;
+use crate::tokens;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
@@ -27,11 +29,13 @@ pub struct DeviceCodeResponse {
 pub struct DeviceTokenRequest {
     pub device_code: String,
     pub client_type: String,
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceTokenResponse {
-    pub api_key: String,
+    #[serde(flatten)]
+    pub credentials: tokens::SignInCredentials,
     pub user_id: String,
 }
 
@@ -254,6 +258,8 @@ pub async fn start_device_flow(mut req: Request, ctx: RouteContext<()>) -> Resul
 pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let env = ctx.env;
     
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
     let device_token_req: DeviceTokenRequest = match req.json().await {
         Ok(req) => req,
         Err(e) => return AppError::BadRequest(format!("Invalid request body: {}", e)).to_response(),
@@ -287,25 +293,10 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
         }
     }
     
-    if let Some(user_id) = device_data.get("user_id").and_then(|v| v.as_str()) {
-        let user_stmt = db.prepare("SELECT api_key FROM users WHERE app_id = ? AND id = ?");
-        let user_result = user_stmt
-            .bind(&[app_id.clone().into(), user_id.into()])?
-            .first::<serde_json::Value>(None)
-            .await?;
-        
-        if let Some(user_data) = user_result {
-            let api_key = user_data.get("api_key")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| AppError::InternalError("User has no API key".to_string()))?;
-            
-            let response = DeviceTokenResponse {
-                api_key: api_key.to_string(),
-                user_id: user_id.to_string(),
-            };
-            
-            return Response::from_json(&response);
-        }
+    // The key was handed out on the poll that completed the flow and is only
+    // stored hashed, so a completed flow can't be redeemed again.
+    if device_data.get("user_id").and_then(|v| v.as_str()).is_some() {
+        return AppError::BadRequest("Device code already used".to_string()).to_response();
     }
     
     let device_code = device_data.get("device_code")
@@ -316,7 +307,7 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
         .and_then(|v| v.as_str())
         .unwrap_or("github"); // Default to github for backward compatibility
     
-    let (user_id, api_key) = match provider {
+    let (user_id, provider_label) = match provider {
         "github" => {
             let client_id = env.var("GITHUB_CLIENT_ID")
                 .map_err(|_| AppError::InternalError("GitHub client ID not configured".to_string()))?
@@ -391,7 +382,7 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
             
             let provider_id = github_user.id.to_string();
             let existing_user_stmt = db.prepare(
-                "SELECT id, api_key FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
+                "SELECT id FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
             );
 
             let existing_user = existing_user_stmt
@@ -402,16 +393,15 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
             if let Some(user_data) = existing_user {
                 (
                     user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                    user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+                    "GitHub",
                 )
             } else {
                 let new_user_id = Uuid::new_v4().to_string();
-                let new_api_key = generate_api_key();
                 let now = Utc::now().to_rfc3339();
 
                 let insert_stmt = db.prepare(
-                    "INSERT INTO users (id, app_id, provider, provider_id, email, name, api_key, created_at, updated_at)
-                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                    "INSERT INTO users (id, app_id, provider, provider_id, email, name, created_at, updated_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
                 );
 
                 insert_stmt
@@ -422,7 +412,6 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
                         provider_id.into(),
                         github_user.email.clone().unwrap_or_default().into(),
                         github_user.name.clone().unwrap_or(github_user.login.clone()).into(),
-                        new_api_key.clone().into(),
                         now.clone().into(),
                         now.into(),
                     ])?
@@ -431,8 +420,15 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
 
                 // Initialize credits for new user
                 initialize_user_credits(&app_id, &new_user_id, &db).await?;
+                crate::referrals::attach_on_signup(
+                    &app_id,
+                    &new_user_id,
+                    device_token_req.referral_code.as_deref(),
+                    &db,
+                )
+                .await;
 
-                (new_user_id, new_api_key)
+                (new_user_id, "GitHub")
             }
         },
         "google" => {
@@ -503,7 +499,7 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
             
             let provider_id = google_user.id;
             let existing_user_stmt = db.prepare(
-                "SELECT id, api_key FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
+                "SELECT id FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
             );
 
             let existing_user = existing_user_stmt
@@ -514,16 +510,15 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
             if let Some(user_data) = existing_user {
                 (
                     user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                    user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+                    "Google",
                 )
             } else {
                 let new_user_id = Uuid::new_v4().to_string();
-                let new_api_key = generate_api_key();
                 let now = Utc::now().to_rfc3339();
 
                 let insert_stmt = db.prepare(
-                    "INSERT INTO users (id, app_id, provider, provider_id, email, name, api_key, created_at, updated_at)
-                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                    "INSERT INTO users (id, app_id, provider, provider_id, email, name, created_at, updated_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
                 );
 
                 insert_stmt
@@ -534,7 +529,6 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
                         provider_id.into(),
                         google_user.email.into(),
                         google_user.name.unwrap_or_else(|| "Google User".to_string()).into(),
-                        new_api_key.clone().into(),
                         now.clone().into(),
                         now.into(),
                     ])?
@@ -543,8 +537,15 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
 
                 // Initialize credits for new user
                 initialize_user_credits(&app_id, &new_user_id, &db).await?;
+                crate::referrals::attach_on_signup(
+                    &app_id,
+                    &new_user_id,
+                    device_token_req.referral_code.as_deref(),
+                    &db,
+                )
+                .await;
 
-                (new_user_id, new_api_key)
+                (new_user_id, "Google")
             }
         },
         _ => {
@@ -552,6 +553,8 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
         }
     };
     
+    let credentials = tokens::sign_in(token_mode, &env, &app_id, &user_id, provider_label, &device, &db).await?;
+    
     let update_stmt = db.prepare(
         "UPDATE device_auth_flows SET user_id = ? WHERE id = ?"
     );
@@ -562,17 +565,13 @@ pub async fn poll_device_token(mut req: Request, ctx: RouteContext<()>) -> Resul
         .await?;
     
     let response = DeviceTokenResponse {
-        api_key,
+        credentials,
         user_id,
     };
     
     Response::from_json(&response)
 }
 
-fn generate_api_key() -> String {
-    format!("oip_{}", Uuid::new_v4().to_string().replace("-", ""))
-}
-
 pub async fn device_auth_status(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let device_code = ctx.param("device_code")
         .ok_or_else(|| AppError::BadRequest("Missing device_code parameter".to_string()))?
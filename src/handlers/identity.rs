@@ -1,6 +1,8 @@
 use worker::{Request, Response, RouteContext, Result, console_log, D1Database, Fetch, Method, Headers, RequestInit, RateLimiter};
 use crate::error::AppError;
-use crate::auth::{resolve_app_id, authenticate};
+use crate::auth::{resolve_app_id, authenticate, validate_api_key};
+use crate::api_keys;
+use crate::sessions::{self, DeviceInfo};
 use crate::credits::{initialize_user_credits, add_credits, get_user_balance};
 use crate::handlers::oauth_native::validate_apple_identity_token;
 use serde::{Deserialize, Serialize};
@@ -30,6 +32,8 @@ pub struct AnonymousRegisterRequest {
     /// Non-production simulator/dev fallback only.
     pub device_id: Option<String>,
     pub unverified: Option<bool>,
+    /// Another user's referral code; only honoured when this call creates the account.
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -83,6 +87,7 @@ async fn anonymous_register_inner(
     enforce_anon_register_rate_limit(&req, &ctx).await?;
 
     let app_id = resolve_app_id(&req);
+    let device = DeviceInfo::from_request(&req);
     let body: AnonymousRegisterRequest = req
         .json()
         .await
@@ -96,12 +101,22 @@ async fn anonymous_register_inner(
             .as_deref()
             .filter(|s| !s.is_empty())
             .ok_or_else(|| AppError::BadRequest("Missing client_device_id".to_string()))?;
-        return register_with_devicecheck(&ctx, &db, &app_id, device_token, client_device_id).await;
+        return register_with_devicecheck(
+            &ctx,
+            &db,
+            &app_id,
+            device_token,
+            client_device_id,
+            body.referral_code.as_deref(),
+            &device,
+        )
+        .await;
     }
 
     if body.unverified == Some(true) {
         let device_id = verify_unverified(&ctx, body.device_id.as_deref())?;
-        let (user_id, api_key) = create_or_reuse_anonymous(&db, &app_id, &device_id).await?;
+        let (user_id, api_key) =
+            create_or_reuse_anonymous(&db, &app_id, &device_id, body.referral_code.as_deref(), &device).await?;
         return Response::from_json(&IdentityResponse { api_key, user_id }).map_err(AppError::from);
     }
 
@@ -115,6 +130,8 @@ async fn register_with_devicecheck(
     _app_id: &str,
     _device_token: &str,
     _client_device_id: &str,
+    _referral_code: Option<&str>,
+    _device: &DeviceInfo,
 ) -> std::result::Result<Response, AppError> {
     Err(AppError::InternalError("DeviceCheck is not supported on Windows servers".to_string()))
 }
@@ -126,6 +143,8 @@ async fn register_with_devicecheck(
     app_id: &str,
     device_token: &str,
     client_device_id: &str,
+    referral_code: Option<&str>,
+    device: &DeviceInfo,
 ) -> std::result::Result<Response, AppError> {
     let cfg = DeviceCheckConfig::load(ctx)?;
     let jwt = cfg.sign_jwt()?;
@@ -137,7 +156,7 @@ async fn register_with_devicecheck(
     // Per-app, per-device idempotent grant. The unique index
     // (app_id, provider, provider_id) makes first-insert-wins atomic, so a real
     // device gets the free trial exactly once per app regardless of concurrency.
-    let (user_id, api_key) = create_or_reuse_anonymous(db, app_id, client_device_id).await?;
+    let (user_id, api_key) = create_or_reuse_anonymous(db, app_id, client_device_id, referral_code, device).await?;
     Response::from_json(&IdentityResponse { api_key, user_id }).map_err(AppError::from)
 }
 
@@ -320,23 +339,28 @@ async fn fetch_apple_app_identity(db: &D1Database, app_id: &str) -> std::result:
     Ok((team_id, bundle_id))
 }
 
-/// Create the anonymous user for `device_key`, or return the existing one.
+/// Create the anonymous user for `device_key`, or return the existing one
+/// with a freshly issued key (the one it signed up with is only stored hashed).
+/// Devices a banned user registered from are refused.
 /// Idempotency is enforced atomically by the unique index
 /// (app_id, provider, provider_id) via ON CONFLICT DO NOTHING: the free trial is
 /// granted only on the row that is actually inserted, so concurrent or repeated
-/// calls for the same device never double-grant.
+/// calls for the same device never double-grant. A referral code is likewise
+/// attached only to the inserted row.
 async fn create_or_reuse_anonymous(
     db: &D1Database,
     app_id: &str,
     device_key: &str,
+    referral_code: Option<&str>,
+    device: &DeviceInfo,
 ) -> std::result::Result<(String, String), AppError> {
+    crate::user_status::ensure_device_allowed(app_id, device_key, db).await?;
     let new_user_id = Uuid::new_v4().to_string();
-    let new_api_key = format!("pixie_{}", Uuid::new_v4().to_string().replace("-", ""));
     let now = Utc::now().to_rfc3339();
 
     let result = db
         .prepare(
-            "INSERT INTO users (id, app_id, email, provider, provider_id, name, api_key, created_at, updated_at)
+            "INSERT INTO users (id, app_id, email, provider, provider_id, name, signup_device_id, created_at, updated_at)
              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
              ON CONFLICT(app_id, provider, provider_id) DO NOTHING",
         )
@@ -347,7 +371,7 @@ async fn create_or_reuse_anonymous(
             ANON_PROVIDER.into(),
             device_key.into(),
             "Anonymous".into(),
-            new_api_key.clone().into(),
+            device_key.into(),
             now.clone().into(),
             now.into(),
         ])?
@@ -358,26 +382,40 @@ async fn create_or_reuse_anonymous(
 
     if inserted {
         initialize_user_credits(app_id, &new_user_id, db).await?;
+        crate::referrals::attach_on_signup(app_id, &new_user_id, referral_code, db).await;
         console_log!("Created anonymous user: {}", new_user_id);
-        return Ok((new_user_id, new_api_key));
+        let api_key = start_device_session(app_id, &new_user_id, "Device", device, db).await?;
+        return Ok((new_user_id, api_key));
     }
 
     let existing = db
-        .prepare("SELECT id, api_key FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?")
+        .prepare("SELECT id FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?")
         .bind(&[app_id.into(), ANON_PROVIDER.into(), device_key.into()])?
         .first::<Value>(None)
         .await?
         .ok_or_else(|| AppError::InternalError("Anonymous user lookup failed".to_string()))?;
 
     let user_id = existing.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-    let api_key = existing.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string();
-    if user_id.is_empty() || api_key.is_empty() {
+    if user_id.is_empty() {
         return Err(AppError::InternalError("Anonymous user lookup failed".to_string()));
     }
+    let api_key = start_device_session(app_id, &user_id, "Device", device, db).await?;
     console_log!("Reusing anonymous user: {}", user_id);
     Ok((user_id, api_key))
 }
 
+/// Starts a session for an identity sign-in and returns its login key.
+async fn start_device_session(
+    app_id: &str,
+    user_id: &str,
+    provider: &str,
+    device: &DeviceInfo,
+    db: &D1Database,
+) -> std::result::Result<String, AppError> {
+    let session_id = sessions::start_session(app_id, user_id, provider, device, db).await?;
+    api_keys::issue_login_key(app_id, user_id, provider, &session_id, db).await
+}
+
 pub async fn link(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     match link_inner(req, ctx).await {
         Ok(response) => Ok(response),
@@ -392,7 +430,9 @@ async fn link_inner(
     let app_id = resolve_app_id(&req);
     let db = ctx.env.d1("DB")?;
 
-    let anon = authenticate(&req, &db).await?;
+    let anon = authenticate(&req, &ctx.env, &db).await?;
+    let presented_key = validate_api_key(&req)?;
+    let device = DeviceInfo::from_request(&req);
 
     let body: LinkRequest = req
         .json()
@@ -403,18 +443,17 @@ async fn link_inner(
     let claims = validate_apple_identity_token(&body.identity_token, &bundle_id).await?;
 
     let existing_apple = db
-        .prepare("SELECT id, api_key FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?")
+        .prepare("SELECT id FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?")
         .bind(&[app_id.clone().into(), "apple".into(), claims.sub.clone().into()])?
         .first::<Value>(None)
         .await?;
 
     if let Some(apple_user) = existing_apple {
         let existing_id = apple_user.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let existing_api_key = apple_user.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string();
 
         if existing_id == anon.user_id {
             return Response::from_json(&IdentityResponse {
-                api_key: existing_api_key,
+                api_key: presented_key,
                 user_id: existing_id,
             })
             .map_err(AppError::from);
@@ -437,8 +476,9 @@ async fn link_inner(
         }
 
         console_log!("Merged anonymous {} into apple user {}", anon.user_id, existing_id);
+        let api_key = start_device_session(&app_id, &existing_id, "Apple", &device, &db).await?;
         return Response::from_json(&IdentityResponse {
-            api_key: existing_api_key,
+            api_key,
             user_id: existing_id,
         })
         .map_err(AppError::from);
@@ -446,17 +486,9 @@ async fn link_inner(
 
     relink_anonymous_to_apple(&db, &app_id, &anon.user_id, &claims).await?;
 
-    let api_key = db
-        .prepare("SELECT api_key FROM users WHERE app_id = ? AND id = ?")
-        .bind(&[app_id.clone().into(), anon.user_id.clone().into()])?
-        .first::<Value>(None)
-        .await?
-        .and_then(|v| v.get("api_key").and_then(|k| k.as_str()).map(|s| s.to_string()))
-        .ok_or_else(|| AppError::InternalError("User vanished during link".to_string()))?;
-
     console_log!("Relinked anonymous {} to apple", anon.user_id);
     Response::from_json(&IdentityResponse {
-        api_key,
+        api_key: presented_key,
         user_id: anon.user_id,
     })
     .map_err(AppError::from)
@@ -506,7 +538,7 @@ async fn relink_anonymous_to_apple(
 /// only ever delete their own account.
 pub async fn delete_identity(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let db = ctx.env.d1("DB")?;
-    let auth = match authenticate(&req, &db).await {
+    let auth = match authenticate(&req, &ctx.env, &db).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
@@ -514,7 +546,7 @@ pub async fn delete_identity(req: Request, ctx: RouteContext<()>) -> Result<Resp
 
     purge_user_images(&ctx.env, &db, &uid).await?;
 
-    for table in ["credit_transactions", "credit_purchases", "user_credits", "user_locks"] {
+    for table in ["credit_transactions", "credit_purchases", "user_credits", "user_locks", "org_members", "api_keys", "refresh_tokens", "sessions"] {
         db.prepare(&format!("DELETE FROM {} WHERE user_id = ?", table))
             .bind(&[uid.clone().into()])?
             .run()
@@ -568,7 +600,7 @@ async fn purge_user_images(env: &worker::Env, db: &D1Database, uid: &str) -> std
 /// without sharing mako's database.
 pub async fn identity_me(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let db = ctx.env.d1("DB")?;
-    let auth = match authenticate(&req, &db).await {
+    let auth = match authenticate(&req, &ctx.env, &db).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
@@ -580,7 +612,7 @@ pub async fn identity_me(req: Request, ctx: RouteContext<()>) -> Result<Response
 /// Returns 402 if the balance is insufficient.
 pub async fn charge_capability(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let db = ctx.env.d1("DB")?;
-    let auth = match authenticate(&req, &db).await {
+    let auth = match authenticate(&req, &ctx.env, &db).await {
         Ok(a) => a,
         Err(e) => return e.to_response(),
     };
@@ -592,16 +624,25 @@ pub async fn charge_capability(mut req: Request, ctx: RouteContext<()>) -> Resul
     if capability.is_empty() {
         return AppError::BadRequest("capability is required".to_string()).to_response();
     }
+    if let Err(e) = crate::apps::require_capability(&auth.app_id, capability, &db).await {
+        return e.to_response();
+    }
     let cost = crate::credits::get_flat_capability_cost(&auth.app_id, capability, &db)
         .await
         .unwrap_or(0);
     if cost == 0 {
         return AppError::BadRequest(format!("Unknown or free capability: {}", capability)).to_response();
     }
+    let payer = match crate::orgs::resolve_payer(&req, &auth.app_id, &auth.user_id, &db).await {
+        Ok(p) => p,
+        Err(e) => return e.to_response(),
+    };
     let reference = body.get("reference").and_then(|v| v.as_str()).unwrap_or(capability);
-    match crate::credits::deduct_credits(&auth.app_id, &auth.user_id, cost, capability, reference, &db).await {
+    match crate::credits::deduct_credits(&auth.app_id, &payer, cost, capability, reference, &db).await {
         Ok(balance) => Response::from_json(&json!({ "charged": cost, "balance": balance })),
-        Err(_) => AppError::PaymentRequired(format!("Insufficient credits for {}", capability)).to_response(),
+        // Insufficient credits and a reached org cap are both 402s already; a
+        // frozen wallet stays a 403 and a database failure a 500.
+        Err(e) => AppError::from(e).to_response(),
     }
 }
 
@@ -611,5 +652,9 @@ async fn zero_wallet(db: &D1Database, app_id: &str, user_id: &str) -> std::resul
         .bind(&[now.into(), app_id.into(), user_id.into()])?
         .run()
         .await?;
+    db.prepare("UPDATE credit_buckets SET remaining = 0 WHERE app_id = ? AND user_id = ?")
+        .bind(&[app_id.into(), user_id.into()])?
+        .run()
+        .await?;
     Ok(())
 }
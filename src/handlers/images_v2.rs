@@ -3,9 +3,10 @@ use crate::models::{ImageGenerationRequest, ImageEditRequest, ImageResponse, Ima
 use crate::error::AppError;
 use crate::auth;
 use crate::storage::store_image_from_bytes;
-use crate::credits::{check_and_reserve_credits, deduct_credits, get_flat_capability_cost};
+use crate::credits::{place_hold, capture_hold, release_hold, get_capability_pricing};
 use crate::rate_limit::{check_and_acquire_lock, release_lock};
 use crate::providers::{self, UnifiedImageRequest, UnifiedEditRequest};
+use crate::usage_records;
 use crate::{log_debug, log_error};
 use serde_json::json;
 use uuid::Uuid;
@@ -18,7 +19,7 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
 
     let auth = {
         let db = env.d1("DB")?;
-        match auth::authenticate(&req, &db).await {
+        match auth::authenticate(&req, &env, &db).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         }
@@ -26,6 +27,13 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
     let user_id = auth.user_id.clone();
     let app_id = auth.app_id.clone();
 
+    {
+        let db = env.d1("DB")?;
+        if let Err(e) = crate::apps::require_capability(&app_id, "image.generate", &db).await {
+            return e.to_response();
+        }
+    }
+
     if let Err(e) = crate::rate_limit::enforce_write_rate_limit(&env, &app_id, &user_id, "image.generate").await {
         return e.to_response();
     }
@@ -68,15 +76,24 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
         api_key: generation_req.openai_api_key.clone(),
     };
 
+    let pricing = get_capability_pricing(&app_id, "image.generate", &db).await;
     let mut cost_estimate = provider.estimate_cost(&unified_request);
-    if let Some(flat) = get_flat_capability_cost(&app_id, "image.generate", &db).await {
-        cost_estimate.credits = flat;
-    }
+    cost_estimate.credits = pricing.apply_to_builtin(cost_estimate.credits, generation_req.n as u32);
 
-    if let Err(e) = check_and_reserve_credits(&app_id, &user_id, cost_estimate.credits, &db).await {
-        let _ = release_lock(&app_id, &user_id, &db).await;
-        return AppError::from(e).to_response();
-    }
+    let payer = match crate::orgs::resolve_payer(&req, &app_id, &user_id, &db).await {
+        Ok(p) => p,
+        Err(e) => {
+            let _ = release_lock(&app_id, &user_id, &db).await;
+            return e.to_response();
+        }
+    };
+    let hold_id = match place_hold(&app_id, &payer, "image.generate", cost_estimate.credits, &db).await {
+        Ok(id) => id,
+        Err(e) => {
+            let _ = release_lock(&app_id, &user_id, &db).await;
+            return AppError::from(e).to_response();
+        }
+    };
 
     log_debug!("Sending request to provider", json!({
         "provider": provider.get_name(),
@@ -88,9 +105,23 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
     let provider_response = match provider.generate_image(&unified_request).await {
         Ok(resp) => resp,
         Err(e) => {
+            let _ = release_hold(&hold_id, &db).await;
             let _ = release_lock(&app_id, &user_id, &db).await;
 
             let error_msg = e.to_string();
+            let response_time_ms = (worker::Date::now().as_millis() - start_time) as u32;
+            usage_records::record_failure(
+                &app_id,
+                &user_id,
+                "image.generate",
+                "generation",
+                provider.get_name(),
+                &generation_req.model,
+                &error_msg,
+                response_time_ms,
+                &db,
+            )
+            .await;
             if error_msg.contains("content_policy_violation") || error_msg.contains("moderation") {
                 let custom_error = ErrorResponse {
                     error: ErrorDetail {
@@ -98,6 +129,7 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
                         error_type: "moderation_error".to_string(),
                         param: None,
                         code: Some("moderation_blocked".to_string()),
+                        request_id: crate::request_id::current(),
                     }
                 };
                 return Response::from_json(&custom_error)
@@ -129,7 +161,7 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
                 let db = env.d1("DB")?;
 
                 let per_image_credits = cost_estimate.credits / generation_req.n as u32;
-                let cost_cents = (cost_estimate.credits as f32 / 3.0) as i32;
+                let cost_cents = (cost_estimate.credits as f64 / pricing.multiplier) as i32;
                 let is_public: i32 = if generation_req.is_public.unwrap_or(true) { 1 } else { 0 };
 
                 let stmt = db.prepare(
@@ -181,31 +213,35 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
         }
     }
     
-    if images_stored > 0 {
+    let credits_charged = if images_stored > 0 {
         let actual_credits_to_charge = (cost_estimate.credits * images_stored) / generation_req.n as u32;
         let description = format!("Generated {} image(s) using {}", images_stored, generation_req.model);
         
-        if let Err(e) = deduct_credits(
-            &app_id,
-            &user_id,
+        if let Err(e) = capture_hold(
+            &hold_id,
             actual_credits_to_charge,
             &description,
             &r2_keys.join(","),
             &db
         ).await {
-            log_error!("Failed to deduct credits", json!({
+            log_error!("Failed to capture credit hold", json!({
                 "error": e.to_string(),
                 "user_id": &user_id,
+                "hold_id": &hold_id,
                 "credits": actual_credits_to_charge,
                 "images_stored": images_stored
             }));
         }
-    }
+        actual_credits_to_charge
+    } else {
+        let _ = release_hold(&hold_id, &db).await;
+        0
+    };
 
     let response_time_ms = (worker::Date::now().as_millis() - start_time) as u32;
     let usage = provider_response.usage.as_ref();
 
-    let _usage_record = UsageRecord {
+    let usage_record = UsageRecord {
         id: Uuid::new_v4().to_string(),
         user_id: user_id.clone(),
         request_type: "generation".to_string(),
@@ -227,41 +263,7 @@ pub async fn handle_generation(mut req: Request, ctx: RouteContext<()>) -> Resul
     };
 
     let db = env.d1("DB")?;
-    let stmt = db.prepare(
-        "INSERT INTO usage_records (id, app_id, user_id, request_type, provider, model, prompt, image_size, image_quality,
-         image_count, input_images_count, total_tokens, input_tokens, output_tokens, text_tokens,
-         image_tokens, r2_keys, response_time_ms, simplified_cost, error, created_at)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
-    );
-
-    let simplified_cost = provider.get_name() == "gemini";
-
-    let _ = stmt
-        .bind(&[
-            _usage_record.id.into(),
-            app_id.clone().into(),
-            _usage_record.user_id.into(),
-            _usage_record.request_type.into(),
-            provider.get_name().into(),
-            _usage_record.model.into(),
-            _usage_record.prompt.into(),
-            _usage_record.image_size.into(),
-            _usage_record.image_quality.into(),
-            _usage_record.image_count.into(),
-            _usage_record.input_images_count.map(|n| n.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
-            _usage_record.total_tokens.into(),
-            _usage_record.input_tokens.into(),
-            _usage_record.output_tokens.into(),
-            _usage_record.text_tokens.into(),
-            _usage_record.image_tokens.into(),
-            serde_json::to_string(&_usage_record.r2_keys).unwrap().into(),
-            _usage_record.response_time_ms.into(),
-            simplified_cost.into(),
-            worker::wasm_bindgen::JsValue::NULL,
-            _usage_record.created_at.to_rfc3339().into(),
-        ])?
-        .run()
-        .await?;
+    usage_records::record(&app_id, "image.generate", provider.get_name(), &usage_record, credits_charged, &db).await;
 
     let _ = release_lock(&app_id, &user_id, &db).await;
 
@@ -284,7 +286,7 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
 
     let auth = {
         let db = env.d1("DB")?;
-        match auth::authenticate(&req, &db).await {
+        match auth::authenticate(&req, &env, &db).await {
             Ok(a) => a,
             Err(e) => return e.to_response(),
         }
@@ -292,6 +294,13 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
     let user_id = auth.user_id.clone();
     let app_id = auth.app_id.clone();
 
+    {
+        let db = env.d1("DB")?;
+        if let Err(e) = crate::apps::require_capability(&app_id, "image.edit", &db).await {
+            return e.to_response();
+        }
+    }
+
     if let Err(e) = crate::rate_limit::enforce_write_rate_limit(&env, &app_id, &user_id, "image.edit").await {
         return e.to_response();
     }
@@ -341,12 +350,24 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
         api_key: edit_req.openai_api_key.clone(),
     };
 
-    let cost_estimate = provider.estimate_edit_cost(&unified_request);
+    let pricing = get_capability_pricing(&app_id, "image.edit", &db).await;
+    let mut cost_estimate = provider.estimate_edit_cost(&unified_request);
+    cost_estimate.credits = pricing.apply_to_builtin(cost_estimate.credits, edit_req.n as u32);
 
-    if let Err(e) = check_and_reserve_credits(&app_id, &user_id, cost_estimate.credits, &db).await {
-        let _ = release_lock(&app_id, &user_id, &db).await;
-        return AppError::from(e).to_response();
-    }
+    let payer = match crate::orgs::resolve_payer(&req, &app_id, &user_id, &db).await {
+        Ok(p) => p,
+        Err(e) => {
+            let _ = release_lock(&app_id, &user_id, &db).await;
+            return e.to_response();
+        }
+    };
+    let hold_id = match place_hold(&app_id, &payer, "image.edit", cost_estimate.credits, &db).await {
+        Ok(id) => id,
+        Err(e) => {
+            let _ = release_lock(&app_id, &user_id, &db).await;
+            return AppError::from(e).to_response();
+        }
+    };
 
     log_debug!("Sending edit request to provider", json!({
         "provider": provider.get_name(),
@@ -358,9 +379,23 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
     let provider_response = match provider.edit_image(&unified_request).await {
         Ok(resp) => resp,
         Err(e) => {
+            let _ = release_hold(&hold_id, &db).await;
             let _ = release_lock(&app_id, &user_id, &db).await;
 
             let error_msg = e.to_string();
+            let response_time_ms = (worker::Date::now().as_millis() - start_time) as u32;
+            usage_records::record_failure(
+                &app_id,
+                &user_id,
+                "image.edit",
+                "edit",
+                provider.get_name(),
+                &edit_req.model,
+                &error_msg,
+                response_time_ms,
+                &db,
+            )
+            .await;
             if error_msg.contains("content_policy_violation") || error_msg.contains("moderation") {
                 let custom_error = ErrorResponse {
                     error: ErrorDetail {
@@ -368,6 +403,7 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
                         error_type: "moderation_error".to_string(),
                         param: None,
                         code: Some("moderation_blocked".to_string()),
+                        request_id: crate::request_id::current(),
                     }
                 };
                 return Response::from_json(&custom_error)
@@ -397,7 +433,7 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
                 let db = env.d1("DB")?;
 
                 let per_image_credits = cost_estimate.credits / edit_req.n as u32;
-                let cost_cents = (cost_estimate.credits as f32 / 3.0) as i32;
+                let cost_cents = (cost_estimate.credits as f64 / pricing.multiplier) as i32;
                 let is_public: i32 = if edit_req.is_public.unwrap_or(true) { 1 } else { 0 };
 
                 let stmt = db.prepare(
@@ -449,31 +485,35 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
         }
     }
     
-    if images_stored > 0 {
+    let credits_charged = if images_stored > 0 {
         let actual_credits_to_charge = (cost_estimate.credits * images_stored) / edit_req.n as u32;
         let description = format!("Edited {} image(s) using {}", images_stored, edit_req.model);
         
-        if let Err(e) = deduct_credits(
-            &app_id,
-            &user_id,
+        if let Err(e) = capture_hold(
+            &hold_id,
             actual_credits_to_charge,
             &description,
             &r2_keys.join(","),
             &db
         ).await {
-            log_error!("Failed to deduct credits", json!({
+            log_error!("Failed to capture credit hold", json!({
                 "error": e.to_string(),
                 "user_id": &user_id,
+                "hold_id": &hold_id,
                 "credits": actual_credits_to_charge,
                 "images_stored": images_stored
             }));
         }
-    }
+        actual_credits_to_charge
+    } else {
+        let _ = release_hold(&hold_id, &db).await;
+        0
+    };
 
     let response_time_ms = (worker::Date::now().as_millis() - start_time) as u32;
     let usage = provider_response.usage.as_ref();
 
-    let _usage_record = UsageRecord {
+    let usage_record = UsageRecord {
         id: Uuid::new_v4().to_string(),
         user_id: user_id.clone(),
         request_type: "edit".to_string(),
@@ -495,41 +535,7 @@ pub async fn handle_edit(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
     };
 
     let db = env.d1("DB")?;
-    let stmt = db.prepare(
-        "INSERT INTO usage_records (id, app_id, user_id, request_type, provider, model, prompt, image_size, image_quality,
-         image_count, input_images_count, total_tokens, input_tokens, output_tokens, text_tokens,
-         image_tokens, r2_keys, response_time_ms, simplified_cost, error, created_at)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
-    );
-
-    let simplified_cost = provider.get_name() == "gemini";
-
-    let _ = stmt
-        .bind(&[
-            _usage_record.id.into(),
-            app_id.clone().into(),
-            _usage_record.user_id.into(),
-            _usage_record.request_type.into(),
-            provider.get_name().into(),
-            _usage_record.model.into(),
-            _usage_record.prompt.into(),
-            _usage_record.image_size.into(),
-            _usage_record.image_quality.into(),
-            _usage_record.image_count.into(),
-            _usage_record.input_images_count.map(|n| n.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
-            _usage_record.total_tokens.into(),
-            _usage_record.input_tokens.into(),
-            _usage_record.output_tokens.into(),
-            _usage_record.text_tokens.into(),
-            _usage_record.image_tokens.into(),
-            serde_json::to_string(&_usage_record.r2_keys).unwrap().into(),
-            _usage_record.response_time_ms.into(),
-            simplified_cost.into(),
-            worker::wasm_bindgen::JsValue::NULL,
-            _usage_record.created_at.to_rfc3339().into(),
-        ])?
-        .run()
-        .await?;
+    usage_records::record(&app_id, "image.edit", provider.get_name(), &usage_record, credits_charged, &db).await;
 
     let _ = release_lock(&app_id, &user_id, &db).await;
 
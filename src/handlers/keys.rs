@@ -0,0 +1,112 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::auth;
+use crate::api_keys;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u32>,
+}
+
+fn key_id_param(ctx: &RouteContext<()>) -> std::result::Result<String, AppError> {
+    ctx.param("key_id")
+        .map(|s| s.to_string())
+        .ok_or_else(|| AppError::BadRequest("Missing key_id".to_string()))
+}
+
+pub async fn list_keys(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match list_keys_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn list_keys_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let include_revoked = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "include_revoked" && v == "true");
+
+    let keys = api_keys::list_keys(&auth.app_id, &auth.user_id, include_revoked, &db).await?;
+    let keys: Vec<_> = keys
+        .into_iter()
+        .map(|k| {
+            let current = auth.key_id.as_deref() == Some(k.id.as_str());
+            let mut value = serde_json::to_value(&k).unwrap_or_default();
+            value["current"] = json!(current);
+            value
+        })
+        .collect();
+    Response::from_json(&json!({ "keys": keys })).map_err(AppError::from)
+}
+
+/// Creates a named key. The secret is in this response only.
+pub async fn create_key(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match create_key_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn create_key_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "keys.create").await?;
+
+    let body: CreateKeyRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::BadRequest("name must be 1-100 characters".to_string()));
+    }
+    if body.expires_in_days == Some(0) {
+        return Err(AppError::BadRequest("expires_in_days must be positive".to_string()));
+    }
+    let scopes = api_keys::normalize_scopes(&body.scopes)?;
+
+    let (key, secret) = api_keys::create_key(&auth.app_id, &auth.user_id, name, &scopes, body.expires_in_days, &db).await?;
+    Response::from_json(&json!({ "key": key, "secret": secret }))
+        .map(|r| r.with_status(201))
+        .map_err(AppError::from)
+}
+
+pub async fn rotate_key(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match rotate_key_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn rotate_key_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "keys.rotate").await?;
+    let key_id = key_id_param(&ctx)?;
+
+    let (key, secret) = api_keys::rotate_key(&auth.app_id, &auth.user_id, &key_id, &db).await?;
+    Response::from_json(&json!({ "key": key, "secret": secret })).map_err(AppError::from)
+}
+
+pub async fn revoke_key(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match revoke_key_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn revoke_key_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let key_id = key_id_param(&ctx)?;
+
+    api_keys::revoke_key(&auth.app_id, &auth.user_id, &key_id, &db).await?;
+    Response::from_json(&json!({ "revoked": true })).map_err(AppError::from)
+}
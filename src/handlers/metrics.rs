@@ -0,0 +1,36 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::{api_keys, metrics};
+
+/// Prometheus scrape target. Serves the last rollup (see `metrics::rollup`)
+/// to callers presenting `METRICS_TOKEN` as a bearer token.
+pub async fn metrics(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match metrics_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn metrics_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let expected = ctx
+        .env
+        .secret("METRICS_TOKEN")
+        .map_err(|_| AppError::InternalError("METRICS_TOKEN not configured".to_string()))?
+        .to_string();
+    let presented = req
+        .headers()
+        .get("Authorization")?
+        .and_then(|h| h.strip_prefix("Bearer ").map(|t| t.trim().to_string()));
+    match presented {
+        Some(token) if api_keys::constant_time_eq(&token, &expected) => {}
+        _ => return Err(AppError::Unauthorized("Invalid metrics token".to_string())),
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let body = metrics::render(&db).await?;
+    let mut response = Response::ok(body)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "text/plain; version=0.0.4; charset=utf-8")?;
+    Ok(response)
+}
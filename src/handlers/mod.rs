@@ -10,5 +10,14 @@ pub mod oauth_native;
 pub mod device_auth;
 pub mod identity;
 pub mod credits;
+pub mod subscriptions;
+pub mod webhooks;
+pub mod orgs;
+pub mod keys;
+pub mod tokens;
+pub mod sessions;
 pub mod chat;
-pub mod realtime;
\ No newline at end of file
+pub mod realtime;
+pub mod admin;
+pub mod tenants;
+pub mod metrics;
\ No newline at end of file
@@ -2,6 +2,8 @@ use worker::{Request, Response, RouteContext, Result, console_log};
 use crate::error::AppError;
 use crate::auth::resolve_app_id;
 use crate::credits::initialize_user_credits;
+use crate::sessions::DeviceInfo;
+use crate::tokens;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
@@ -20,11 +22,13 @@ pub struct OAuthCallbackRequest {
     #[allow(dead_code)]
     pub state: String,
     pub redirect_uri: String,
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct OAuthTokenResponse {
-    pub api_key: String,
+    #[serde(flatten)]
+    pub credentials: tokens::SignInCredentials,
     pub user_id: String,
     pub is_admin: bool,
 }
@@ -114,6 +118,8 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
     let env = ctx.env;
 
     let app_id = resolve_app_id(&req);
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
 
     let callback_req: OAuthCallbackRequest = match req.json().await {
         Ok(req) => req,
@@ -212,7 +218,7 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
     
     let provider_id = github_user.id.to_string();
     let existing_user_stmt = db.prepare(
-        "SELECT id, api_key, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
+        "SELECT id, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
     );
 
     let existing_user = existing_user_stmt
@@ -220,20 +226,18 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
         .first::<serde_json::Value>(None)
         .await?;
 
-    let (user_id, api_key, is_admin) = if let Some(user_data) = existing_user {
+    let (user_id, is_admin) = if let Some(user_data) = existing_user {
         (
             user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string(),
             user_data.get("is_admin").and_then(|v| v.as_i64()).unwrap_or(0) == 1,
         )
     } else {
         let new_user_id = Uuid::new_v4().to_string();
-        let new_api_key = generate_api_key();
         let now = Utc::now().to_rfc3339();
 
         let insert_stmt = db.prepare(
-            "INSERT INTO users (id, app_id, provider, provider_id, email, name, api_key, created_at, updated_at)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO users (id, app_id, provider, provider_id, email, name, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         );
 
         insert_stmt
@@ -244,7 +248,6 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
                 provider_id.into(),
                 github_user.email.clone().unwrap_or_default().into(),
                 github_user.name.clone().unwrap_or(github_user.login.clone()).into(),
-                new_api_key.clone().into(),
                 now.clone().into(),
                 now.into(),
             ])?
@@ -253,12 +256,14 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
 
         // Initialize credits for new user
         initialize_user_credits(&app_id, &new_user_id, &db).await?;
+        crate::referrals::attach_on_signup(&app_id, &new_user_id, callback_req.referral_code.as_deref(), &db).await;
 
-        (new_user_id, new_api_key, false)
+        (new_user_id, false)
     };
+    let credentials = tokens::sign_in(token_mode, &env, &app_id, &user_id, "GitHub", &device, &db).await?;
     
     let response = OAuthTokenResponse {
-        api_key,
+        credentials,
         user_id,
         is_admin,
     };
@@ -266,10 +271,6 @@ pub async fn github_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
     Response::from_json(&response)
 }
 
-pub fn generate_api_key() -> String {
-    format!("pixie_{}", Uuid::new_v4().to_string().replace("-", ""))
-}
-
 pub async fn google_auth_start(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let env = ctx.env;
     let url = req.url()?;
@@ -301,6 +302,8 @@ pub async fn google_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
     let env = ctx.env;
 
     let app_id = resolve_app_id(&req);
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
 
     let callback_req: OAuthCallbackRequest = match req.json().await {
         Ok(req) => req,
@@ -368,7 +371,7 @@ pub async fn google_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
     
     let provider_id = google_user.id;
     let existing_user_stmt = db.prepare(
-        "SELECT id, api_key, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
+        "SELECT id, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
     );
 
     let existing_user = existing_user_stmt
@@ -376,20 +379,18 @@ pub async fn google_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
         .first::<serde_json::Value>(None)
         .await?;
 
-    let (user_id, api_key, is_admin) = if let Some(user_data) = existing_user {
+    let (user_id, is_admin) = if let Some(user_data) = existing_user {
         (
             user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string(),
             user_data.get("is_admin").and_then(|v| v.as_i64()).unwrap_or(0) == 1,
         )
     } else {
         let new_user_id = Uuid::new_v4().to_string();
-        let new_api_key = generate_api_key();
         let now = Utc::now().to_rfc3339();
 
         let insert_stmt = db.prepare(
-            "INSERT INTO users (id, app_id, provider, provider_id, email, name, api_key, created_at, updated_at)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO users (id, app_id, provider, provider_id, email, name, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         );
 
         insert_stmt
@@ -400,7 +401,6 @@ pub async fn google_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
                 provider_id.into(),
                 google_user.email.into(),
                 google_user.name.unwrap_or_else(|| "Google User".to_string()).into(),
-                new_api_key.clone().into(),
                 now.clone().into(),
                 now.into(),
             ])?
@@ -409,12 +409,14 @@ pub async fn google_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Re
 
         // Initialize credits for new user
         initialize_user_credits(&app_id, &new_user_id, &db).await?;
+        crate::referrals::attach_on_signup(&app_id, &new_user_id, callback_req.referral_code.as_deref(), &db).await;
 
-        (new_user_id, new_api_key, false)
+        (new_user_id, false)
     };
+    let credentials = tokens::sign_in(token_mode, &env, &app_id, &user_id, "Google", &device, &db).await?;
     
     let response = OAuthTokenResponse {
-        api_key,
+        credentials,
         user_id,
         is_admin,
     };
@@ -1,9 +1,11 @@
 use worker::{Request, Response, RouteContext, Result, console_log};
 use crate::error::AppError;
 use crate::credits::initialize_user_credits;
+use crate::sessions::DeviceInfo;
+use crate::tokens;
 #[cfg(not(target_os = "windows"))]
 use crate::auth::resolve_app_id;
-use crate::handlers::oauth::{OAuthCallbackRequest, OAuthTokenResponse, generate_api_key};
+use crate::handlers::oauth::{OAuthCallbackRequest, OAuthTokenResponse};
 use serde::Deserialize;
 use uuid::Uuid;
 use chrono::Utc;
@@ -85,6 +87,8 @@ pub async fn apple_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Res
     let env = ctx.env;
 
     let app_id = resolve_app_id(&req);
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
 
     let callback_req: OAuthCallbackRequest = match req.json().await {
         Ok(req) => req,
@@ -156,7 +160,7 @@ pub async fn apple_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Res
     
     let provider_id = claims.sub;
     let existing_user_stmt = db.prepare(
-        "SELECT id, api_key, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
+        "SELECT id, is_admin FROM users WHERE app_id = ? AND provider = ? AND provider_id = ?"
     );
 
     let existing_user = existing_user_stmt
@@ -164,20 +168,18 @@ pub async fn apple_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Res
         .first::<serde_json::Value>(None)
         .await?;
 
-    let (user_id, api_key, is_admin) = if let Some(user_data) = existing_user {
+    let (user_id, is_admin) = if let Some(user_data) = existing_user {
         (
             user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-            user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string(),
             user_data.get("is_admin").and_then(|v| v.as_i64()).unwrap_or(0) == 1,
         )
     } else {
         let new_user_id = Uuid::new_v4().to_string();
-        let new_api_key = generate_api_key();
         let now = Utc::now().to_rfc3339();
 
         let insert_stmt = db.prepare(
-            "INSERT INTO users (id, app_id, provider, provider_id, email, name, api_key, created_at, updated_at)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO users (id, app_id, provider, provider_id, email, name, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         );
 
         insert_stmt
@@ -188,7 +190,6 @@ pub async fn apple_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Res
                 provider_id.into(),
                 claims.email.clone().unwrap_or_default().into(),
                 "Apple User".into(), // Apple doesn't provide name in ID token
-                new_api_key.clone().into(),
                 now.clone().into(),
                 now.into(),
             ])?
@@ -197,12 +198,14 @@ pub async fn apple_auth_callback(mut req: Request, ctx: RouteContext<()>) -> Res
 
         // Initialize credits for new user
         initialize_user_credits(&app_id, &new_user_id, &db).await?;
+        crate::referrals::attach_on_signup(&app_id, &new_user_id, callback_req.referral_code.as_deref(), &db).await;
 
-        (new_user_id, new_api_key, false)
+        (new_user_id, false)
     };
+    let credentials = tokens::sign_in(token_mode, &env, &app_id, &user_id, "Apple", &device, &db).await?;
     
     let response = OAuthTokenResponse {
-        api_key,
+        credentials,
         user_id,
         is_admin,
     };
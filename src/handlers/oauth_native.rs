@@ -2,6 +2,8 @@ use worker::{Request, Response, RouteContext, Result, console_log, Fetch, Method
 use crate::error::AppError;
 use crate::auth::resolve_app_id;
 use crate::credits::initialize_user_credits;
+use crate::sessions::DeviceInfo;
+use crate::tokens;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::Utc;
@@ -17,11 +19,13 @@ const APPLE_JWKS_URL: &str = "https://appleid.apple.com/auth/keys";
 #[derive(Debug, Deserialize)]
 pub struct GoogleTokenRequest {
     pub id_token: String,
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AuthTokenResponse {
-    pub api_key: String,
+    #[serde(flatten)]
+    pub credentials: tokens::SignInCredentials,
     pub user_id: String,
     pub is_admin: bool,
 }
@@ -41,6 +45,8 @@ struct GoogleIdTokenClaims {
 /// Handle native Google Sign-In tokens from mobile/desktop apps
 pub async fn google_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let app_id = resolve_app_id(&req);
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
     let token_req: GoogleTokenRequest = req.json().await?;
     
     // Get all valid client IDs from environment
@@ -99,27 +105,25 @@ pub async fn google_token_auth(mut req: Request, ctx: RouteContext<()>) -> Resul
     
     // Check if user exists with this Google ID
     let existing_user = db
-        .prepare("SELECT id, api_key, is_admin FROM users WHERE app_id = ?1 AND provider = ?2 AND provider_id = ?3")
+        .prepare("SELECT id, is_admin FROM users WHERE app_id = ?1 AND provider = ?2 AND provider_id = ?3")
         .bind(&[app_id.clone().into(), "google".into(), token_info.sub.clone().into()])?
         .first::<serde_json::Value>(None)
         .await?;
 
-    let (user_id, api_key, is_admin) = if let Some(user_data) = existing_user {
+    let (user_id, is_admin) = if let Some(user_data) = existing_user {
         let id = user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let key = user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string();
         let admin = user_data.get("is_admin").and_then(|v| v.as_i64()).unwrap_or(0) == 1;
         console_log!("Found existing user: {}", id);
-        (id, key, admin)
+        (id, admin)
     } else {
         // Create new user
         let new_user_id = Uuid::new_v4().to_string();
-        let new_api_key = format!("pixie_{}", Uuid::new_v4().to_string().replace("-", ""));
         let now = Utc::now().to_rfc3339();
 
         console_log!("Creating new user: {}", new_user_id);
 
         db
-            .prepare("INSERT INTO users (id, app_id, email, provider, provider_id, name, api_key, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")
+            .prepare("INSERT INTO users (id, app_id, email, provider, provider_id, name, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")
             .bind(&[
                 new_user_id.clone().into(),
                 app_id.clone().into(),
@@ -127,7 +131,6 @@ pub async fn google_token_auth(mut req: Request, ctx: RouteContext<()>) -> Resul
                 "google".into(),
                 token_info.sub.into(),
                 token_info.name.unwrap_or_else(|| "Google User".to_string()).into(),
-                new_api_key.clone().into(),
                 now.clone().into(),
                 now.into(),
             ])?
@@ -136,12 +139,14 @@ pub async fn google_token_auth(mut req: Request, ctx: RouteContext<()>) -> Resul
 
         // Initialize credits for new user
         initialize_user_credits(&app_id, &new_user_id, &db).await?;
+        crate::referrals::attach_on_signup(&app_id, &new_user_id, token_req.referral_code.as_deref(), &db).await;
 
-        (new_user_id, new_api_key, false)
+        (new_user_id, false)
     };
+    let credentials = tokens::sign_in(token_mode, &ctx.env, &app_id, &user_id, "Google", &device, &db).await?;
     
     let response = AuthTokenResponse {
-        api_key,
+        credentials,
         user_id,
         is_admin,
     };
@@ -152,6 +157,7 @@ pub async fn google_token_auth(mut req: Request, ctx: RouteContext<()>) -> Resul
 #[derive(Debug, Deserialize)]
 pub struct AppleTokenRequest {
     pub identity_token: String,
+    pub referral_code: Option<String>,
 }
 
 #[derive(Debug)]
@@ -306,6 +312,8 @@ fn apple_jwk_to_public_key(jwk: &AppleJwk) -> std::result::Result<RS256PublicKey
 
 pub async fn apple_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let app_id = resolve_app_id(&req);
+    let token_mode = tokens::wants_tokens(&req);
+    let device = DeviceInfo::from_request(&req);
     let token_req: AppleTokenRequest = req.json().await?;
 
     let ios_bundle_id = match ctx.env.var("APPLE_IOS_BUNDLE_ID") {
@@ -321,20 +329,18 @@ pub async fn apple_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result
     let db = ctx.env.d1("DB")?;
     
     let existing_user = db
-        .prepare("SELECT id, api_key, is_admin FROM users WHERE app_id = ?1 AND provider = ?2 AND provider_id = ?3")
+        .prepare("SELECT id, is_admin FROM users WHERE app_id = ?1 AND provider = ?2 AND provider_id = ?3")
         .bind(&[app_id.clone().into(), "apple".into(), claims.sub.clone().into()])?
         .first::<serde_json::Value>(None)
         .await?;
 
-    let (user_id, api_key, is_admin) = if let Some(user_data) = existing_user {
+    let (user_id, is_admin) = if let Some(user_data) = existing_user {
         let id = user_data.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-        let key = user_data.get("api_key").and_then(|v| v.as_str()).unwrap_or("").to_string();
         let admin = user_data.get("is_admin").and_then(|v| v.as_i64()).unwrap_or(0) == 1;
         console_log!("Found existing Apple user: {}", id);
-        (id, key, admin)
+        (id, admin)
     } else {
         let new_user_id = Uuid::new_v4().to_string();
-        let new_api_key = format!("pixie_{}", Uuid::new_v4().to_string().replace("-", ""));
         let now = Utc::now().to_rfc3339();
 
         console_log!("Creating new Apple user: {}", new_user_id);
@@ -343,7 +349,7 @@ pub async fn apple_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result
             .unwrap_or_else(|| format!("{}@privaterelay.appleid.com", claims.sub.chars().take(8).collect::<String>()));
 
         db
-            .prepare("INSERT INTO users (id, app_id, email, provider, provider_id, name, api_key, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")
+            .prepare("INSERT INTO users (id, app_id, email, provider, provider_id, name, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")
             .bind(&[
                 new_user_id.clone().into(),
                 app_id.clone().into(),
@@ -351,7 +357,6 @@ pub async fn apple_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result
                 "apple".into(),
                 claims.sub.into(),
                 "Apple User".into(),
-                new_api_key.clone().into(),
                 now.clone().into(),
                 now.into(),
             ])?
@@ -359,12 +364,14 @@ pub async fn apple_token_auth(mut req: Request, ctx: RouteContext<()>) -> Result
             .await?;
 
         initialize_user_credits(&app_id, &new_user_id, &db).await?;
+        crate::referrals::attach_on_signup(&app_id, &new_user_id, token_req.referral_code.as_deref(), &db).await;
 
-        (new_user_id, new_api_key, false)
+        (new_user_id, false)
     };
+    let credentials = tokens::sign_in(token_mode, &ctx.env, &app_id, &user_id, "Apple", &device, &db).await?;
     
     let response = AuthTokenResponse {
-        api_key,
+        credentials,
         user_id,
         is_admin,
     };
@@ -0,0 +1,392 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::auth;
+use crate::orgs::{self, OrgMember, ORG_ROLES};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateOrgRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddOrgMemberRequest {
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<String>,
+    pub monthly_spend_cap: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FundOrgRequest {
+    pub amount: u32,
+    pub note: Option<String>,
+}
+
+fn org_id_param(ctx: &RouteContext<()>) -> std::result::Result<String, AppError> {
+    ctx.param("org_id")
+        .map(|s| s.to_string())
+        .ok_or_else(|| AppError::BadRequest("Missing org_id".to_string()))
+}
+
+fn validate_role(role: &str) -> std::result::Result<(), AppError> {
+    if !ORG_ROLES.contains(&role) {
+        return Err(AppError::BadRequest(format!(
+            "role must be one of: {}",
+            ORG_ROLES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+fn validate_cap(cap: Option<i32>) -> std::result::Result<(), AppError> {
+    if cap.map(|c| c < 0).unwrap_or(false) {
+        return Err(AppError::BadRequest("monthly_spend_cap must not be negative".to_string()));
+    }
+    Ok(())
+}
+
+/// Admins manage plain members; only owners may touch admins and owners or
+/// hand out those roles.
+fn ensure_can_assign(caller: &OrgMember, current_role: &str, new_role: &str) -> std::result::Result<(), AppError> {
+    if caller.role != "owner" && (current_role != "member" || new_role != "member") {
+        return Err(AppError::Forbidden("Only organisation owners can manage admins and owners".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn create_org(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match create_org_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn create_org_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "orgs.create").await?;
+
+    let body: CreateOrgRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::BadRequest("name must be 1-100 characters".to_string()));
+    }
+
+    let org = orgs::create_org(&auth.app_id, &auth.user_id, name, &db).await?;
+    Ok(Response::from_json(&org)?.with_status(201))
+}
+
+pub async fn list_orgs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match list_orgs_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn list_orgs_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let orgs = orgs::list_user_orgs(&auth.app_id, &auth.user_id, &db).await?;
+    Response::from_json(&json!({ "orgs": orgs })).map_err(AppError::from)
+}
+
+/// The org, its wallet balance and members. Owners and admins see everyone's
+/// caps and spending; members see only their own.
+pub async fn get_org(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match get_org_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_org_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let org_id = org_id_param(&ctx)?;
+    let caller = orgs::require_member(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+
+    let org = orgs::get_org(&auth.app_id, &org_id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Organisation not found".to_string()))?;
+    let balance = crate::credits::get_user_balance(&auth.app_id, &org_id, &db).await?;
+    let members = if caller.can_manage() {
+        orgs::list_members(&auth.app_id, &org_id, &db).await?
+    } else {
+        vec![caller.clone()]
+    };
+
+    Response::from_json(&json!({
+        "org": org,
+        "role": caller.role,
+        "balance": balance,
+        "members": members,
+    }))
+    .map_err(AppError::from)
+}
+
+pub async fn add_org_member(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match add_org_member_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn add_org_member_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let org_id = org_id_param(&ctx)?;
+    let caller = orgs::require_manager(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+
+    let body: AddOrgMemberRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let role = body.role.as_deref().unwrap_or("member");
+    validate_role(role)?;
+    validate_cap(body.monthly_spend_cap)?;
+    ensure_can_assign(&caller, "member", role)?;
+
+    let user_id = crate::transfers::resolve_user(
+        &auth.app_id,
+        body.user_id.as_deref(),
+        body.email.as_deref(),
+        &db,
+    ).await?;
+    orgs::add_member(&auth.app_id, &org_id, &user_id, role, body.monthly_spend_cap, &db).await?;
+
+    let member = orgs::require_member(&auth.app_id, &org_id, &user_id, &db).await?;
+    Ok(Response::from_json(&member)?.with_status(201))
+}
+
+/// Changes a member's role and/or cap. An explicit `"monthly_spend_cap": null`
+/// removes the cap; leaving the field out keeps it.
+pub async fn update_org_member(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match update_org_member_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn update_org_member_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let org_id = org_id_param(&ctx)?;
+    let member_id = ctx
+        .param("user_id")
+        .map(|s| s.to_string())
+        .ok_or_else(|| AppError::BadRequest("Missing user_id".to_string()))?;
+    let caller = orgs::require_manager(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+    let target = orgs::get_member(&auth.app_id, &org_id, &member_id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Member not found".to_string()))?;
+
+    let body: Value = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    let role = match body.get("role") {
+        Some(Value::String(r)) => r.clone(),
+        Some(_) => return Err(AppError::BadRequest("role must be a string".to_string())),
+        None => target.role.clone(),
+    };
+    let cap = match body.get("monthly_spend_cap") {
+        Some(Value::Null) => None,
+        Some(v) => Some(
+            v.as_i64()
+                .map(|c| c as i32)
+                .ok_or_else(|| AppError::BadRequest("monthly_spend_cap must be an integer or null".to_string()))?,
+        ),
+        None => target.monthly_spend_cap,
+    };
+    validate_role(&role)?;
+    validate_cap(cap)?;
+    ensure_can_assign(&caller, &target.role, &role)?;
+    if target.role == "owner" && role != "owner" && orgs::count_owners(&auth.app_id, &org_id, &db).await? <= 1 {
+        return Err(AppError::BadRequest("An organisation needs at least one owner".to_string()));
+    }
+
+    orgs::update_member(&auth.app_id, &org_id, &member_id, &role, cap, &db).await?;
+    let member = orgs::require_member(&auth.app_id, &org_id, &member_id, &db).await?;
+    Response::from_json(&member).map_err(AppError::from)
+}
+
+/// Removes a member. Anyone may leave; removing someone else needs the same
+/// rights as changing their role.
+pub async fn remove_org_member(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match remove_org_member_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn remove_org_member_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let org_id = org_id_param(&ctx)?;
+    let member_id = ctx
+        .param("user_id")
+        .map(|s| s.to_string())
+        .ok_or_else(|| AppError::BadRequest("Missing user_id".to_string()))?;
+
+    let caller = orgs::require_member(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+    let target = if member_id == auth.user_id {
+        caller.clone()
+    } else {
+        if !caller.can_manage() {
+            return Err(AppError::Forbidden("Organisation owner or admin access required".to_string()));
+        }
+        let target = orgs::get_member(&auth.app_id, &org_id, &member_id, &db)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Member not found".to_string()))?;
+        ensure_can_assign(&caller, &target.role, "member")?;
+        target
+    };
+    if target.role == "owner" && orgs::count_owners(&auth.app_id, &org_id, &db).await? <= 1 {
+        return Err(AppError::BadRequest("An organisation needs at least one owner".to_string()));
+    }
+
+    orgs::remove_member(&auth.app_id, &org_id, &member_id, &db).await?;
+    Response::from_json(&json!({ "removed": true })).map_err(AppError::from)
+}
+
+/// Moves paid credits from the caller's wallet into the org wallet. Any member
+/// may contribute.
+pub async fn fund_org(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match fund_org_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn fund_org_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "orgs.fund").await?;
+    let org_id = org_id_param(&ctx)?;
+    orgs::require_member(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+
+    let body: FundOrgRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    if body.note.as_deref().map(|n| n.chars().count() > 200).unwrap_or(false) {
+        return Err(AppError::BadRequest("note must be at most 200 characters".to_string()));
+    }
+
+    let transfer = crate::transfers::move_credits(
+        &crate::transfers::Transfer {
+            app_id: &auth.app_id,
+            sender_id: &auth.user_id,
+            recipient_id: &org_id,
+            recipient_label: &org_id,
+            amount: body.amount,
+            note: body.note.as_deref(),
+            daily_limit: None,
+        },
+        &db,
+    ).await?;
+    let balance = crate::credits::get_user_balance(&auth.app_id, &auth.user_id, &db).await?;
+    let org_balance = crate::credits::get_user_balance(&auth.app_id, &org_id, &db).await?;
+
+    Response::from_json(&json!({
+        "transfer": transfer,
+        "balance": balance,
+        "org_balance": org_balance,
+    }))
+    .map_err(AppError::from)
+}
+
+/// Whose org spending the caller may see: everyone's (optionally one member's)
+/// for owners and admins, only their own for members.
+fn ledger_member_scope(
+    caller: &OrgMember,
+    requested: Option<String>,
+) -> std::result::Result<Option<String>, AppError> {
+    if caller.can_manage() {
+        return Ok(requested);
+    }
+    match requested {
+        Some(m) if m != caller.user_id => Err(AppError::Forbidden(
+            "Members can only see their own organisation spending".to_string(),
+        )),
+        _ => Ok(Some(caller.user_id.clone())),
+    }
+}
+
+pub async fn list_org_transactions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match list_org_transactions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn list_org_transactions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_read_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "orgs.transactions").await?;
+    let org_id = org_id_param(&ctx)?;
+    let caller = orgs::require_member(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+
+    let url = req.url()?;
+    let query_params: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let member_id = ledger_member_scope(
+        &caller,
+        query_params.get("member_id").filter(|m| !m.is_empty()).cloned(),
+    )?;
+    let page = query_params.get("page").and_then(|p| p.parse::<i32>().ok()).unwrap_or(1).max(1);
+    let per_page = query_params
+        .get("per_page")
+        .and_then(|p| p.parse::<i32>().ok())
+        .unwrap_or(50)
+        .clamp(1, 100);
+
+    let (transactions, total) = orgs::list_org_transactions(
+        &auth.app_id,
+        &org_id,
+        member_id.as_deref(),
+        per_page,
+        (page - 1) * per_page,
+        &db,
+    ).await?;
+    Response::from_json(&json!({
+        "transactions": transactions,
+        "total": total,
+        "page": page,
+        "per_page": per_page,
+    }))
+    .map_err(AppError::from)
+}
+
+/// Streams the org ledger like `/v1/credits/transactions/export`, with a
+/// member_id column saying who spent.
+pub async fn export_org_transactions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match export_org_transactions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn export_org_transactions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_read_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "orgs.transactions.export").await?;
+    let org_id = org_id_param(&ctx)?;
+    let caller = orgs::require_member(&auth.app_id, &org_id, &auth.user_id, &db).await?;
+
+    let requested = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "member_id")
+        .map(|(_, v)| v.into_owned())
+        .filter(|v| !v.is_empty());
+    let member_id = ledger_member_scope(&caller, requested)?;
+    let (format, mut filter) =
+        crate::handlers::credits::parse_export_query(&req, &auth.app_id, Some(org_id))?;
+    filter.member_id = member_id;
+    crate::handlers::credits::stream_export(format, filter, db)
+}
@@ -5,7 +5,9 @@ use uuid::Uuid;
 use chrono::{Utc, Duration};
 use crate::error::AppError;
 use crate::auth::authenticate;
-use crate::credits::{get_user_balance, get_flat_capability_cost, deduct_credits, add_credits};
+use crate::credits::{get_available_balance, get_user_balance, get_flat_capability_cost, deduct_credits, refund_credits, Payer};
+use crate::models::UsageRecord;
+use crate::usage_records;
 
 const CAPABILITY: &str = "realtime.translate";
 const DEFAULT_RATE_CREDITS: u32 = 1; // 1 credit per minute
@@ -68,8 +70,10 @@ pub async fn settle(req: Request, ctx: RouteContext<()>) -> Result<Response> {
 
 async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
     let db = ctx.env.d1("DB")?;
-    let auth = authenticate(&req, &db).await?;
+    let auth = authenticate(&req, &ctx.env, &db).await?;
+    crate::apps::require_capability(&auth.app_id, CAPABILITY, &db).await?;
     crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "realtime.start").await?;
+    let payer = crate::orgs::resolve_payer(&req, &auth.app_id, &auth.user_id, &db).await?;
 
     let body: StartRequest = req
         .json()
@@ -84,7 +88,11 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
         .await
         .unwrap_or(DEFAULT_RATE_CREDITS)
         .max(1);
-    let balance = get_user_balance(&auth.app_id, &auth.user_id, &db).await?;
+    // Credits escrowed by in-flight calls on the same wallet can't pay for this.
+    let mut balance = get_available_balance(&auth.app_id, &payer.wallet_id, &db).await?;
+    if let Some(remaining) = crate::orgs::remaining_spend_cap(&auth.app_id, &payer, &db).await? {
+        balance = balance.min(remaining);
+    }
     let affordable_minutes = balance / rate as i32;
     if affordable_minutes < 1 {
         return Err(AppError::PaymentRequired(format!(
@@ -100,7 +108,7 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
     // mint so a session can never be opened without being paid for.
     let new_balance = deduct_credits(
         &auth.app_id,
-        &auth.user_id,
+        &payer,
         reserved_credits,
         "realtime.translate reservation",
         &session_id,
@@ -109,17 +117,31 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
     .await
     .map_err(AppError::from)?;
 
-    let (client_secret, expires_at) = match mint_ephemeral(&ctx.env, &model, &body.language).await {
+    let mint_started = worker::Date::now().as_millis();
+    let minted = mint_ephemeral(&ctx.env, &model, &body.language).await;
+    let mint_ms = (worker::Date::now().as_millis() - mint_started) as u32;
+    let (client_secret, expires_at) = match minted {
         Ok(m) => m,
         Err(e) => {
-            // No session was opened — give the reservation back.
-            let _ = add_credits(
+            usage_records::record_failure(
                 &auth.app_id,
                 &auth.user_id,
+                CAPABILITY,
+                "realtime",
+                "openai",
+                &model,
+                e.message(),
+                mint_ms,
+                &db,
+            )
+            .await;
+            // No session was opened — give the reservation back.
+            let _ = refund_credits(
+                &auth.app_id,
+                &payer,
                 reserved_credits,
-                "refund",
                 "realtime.translate mint failed",
-                Some(&session_id),
+                &session_id,
                 &db,
             )
             .await;
@@ -129,8 +151,8 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
 
     let now = Utc::now().to_rfc3339();
     db.prepare(
-        "INSERT INTO realtime_sessions (id, app_id, user_id, capability, rate_credits, reserved_minutes, reserved_credits, settled, created_at)
-         VALUES (?, ?, ?, ?, ?, ?, ?, 0, ?)",
+        "INSERT INTO realtime_sessions (id, app_id, user_id, capability, rate_credits, reserved_minutes, reserved_credits, settled, created_at, org_id)
+         VALUES (?, ?, ?, ?, ?, ?, ?, 0, ?, ?)",
     )
     .bind(&[
         session_id.clone().into(),
@@ -141,10 +163,35 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
         reserved_minutes.into(),
         reserved_credits.into(),
         now.into(),
+        payer.org_id().map(|o| o.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
     ])?
     .run()
     .await?;
 
+    // Shares the session's id so settle and the orphan sweep can correct the
+    // credits once the real minutes are known.
+    let usage_record = UsageRecord {
+        id: session_id.clone(),
+        user_id: auth.user_id.clone(),
+        request_type: "realtime".to_string(),
+        model: model.clone(),
+        prompt: String::new(),
+        image_size: String::new(),
+        image_quality: String::new(),
+        image_count: 0,
+        input_images_count: None,
+        total_tokens: 0,
+        input_tokens: 0,
+        output_tokens: 0,
+        text_tokens: 0,
+        image_tokens: 0,
+        r2_keys: Vec::new(),
+        response_time_ms: mint_ms,
+        error: None,
+        created_at: Utc::now(),
+    };
+    usage_records::record(&auth.app_id, CAPABILITY, "openai", &usage_record, reserved_credits, &db).await;
+
     Response::from_json(&StartResponse {
         session_id,
         client_secret,
@@ -160,7 +207,7 @@ async fn start_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Re
 
 async fn settle_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
     let db = ctx.env.d1("DB")?;
-    let auth = authenticate(&req, &db).await?;
+    let auth = authenticate(&req, &ctx.env, &db).await?;
     let body: SettleRequest = req
         .json()
         .await
@@ -168,7 +215,7 @@ async fn settle_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::R
 
     let row = db
         .prepare(
-            "SELECT rate_credits, reserved_credits, settled
+            "SELECT rate_credits, reserved_credits, settled, org_id
              FROM realtime_sessions WHERE id = ?1 AND app_id = ?2 AND user_id = ?3",
         )
         .bind(&[
@@ -183,9 +230,10 @@ async fn settle_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::R
     let already_settled = row.get("settled").and_then(|v| v.as_i64()).unwrap_or(0) != 0;
     let reserved_credits = row.get("reserved_credits").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
     let rate = row.get("rate_credits").and_then(|v| v.as_i64()).unwrap_or(1).max(1) as i32;
+    let payer = session_payer(&row, &auth.user_id);
 
     if already_settled {
-        return settled_noop(&auth.app_id, &auth.user_id, &db).await;
+        return settled_noop(&auth.app_id, &payer, &db).await;
     }
 
     let actual = body.minutes_used.max(0);
@@ -207,11 +255,12 @@ async fn settle_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::R
         .await?;
     let won = claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0;
     if !won {
-        return settled_noop(&auth.app_id, &auth.user_id, &db).await;
+        return settled_noop(&auth.app_id, &payer, &db).await;
     }
 
-    match bill_settlement(&auth.app_id, &auth.user_id, actual, rate, reserved_credits, &body.session_id, &db).await {
-        Ok((balance, refund)) => {
+    match bill_settlement(&auth.app_id, &payer, actual, rate, reserved_credits, &body.session_id, &db).await {
+        Ok((balance, refund, billed)) => {
+            usage_records::set_credits_charged(&auth.app_id, &body.session_id, billed, &db).await;
             console_log!(
                 "realtime settle app={} minutes={} refund={} balance={}",
                 auth.app_id, actual, refund, balance
@@ -241,12 +290,21 @@ async fn settle_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::R
     }
 }
 
+/// The wallet a session was reserved against: the org recorded at start, else
+/// the user's own.
+fn session_payer(row: &Value, user_id: &str) -> Payer {
+    match row.get("org_id").and_then(|v| v.as_str()) {
+        Some(org_id) => Payer::org(org_id, user_id),
+        None => Payer::user(user_id),
+    }
+}
+
 async fn settled_noop(
     app_id: &str,
-    user_id: &str,
+    payer: &Payer,
     db: &worker::D1Database,
 ) -> std::result::Result<Response, AppError> {
-    let balance = get_user_balance(app_id, user_id, db).await?;
+    let balance = get_user_balance(app_id, &payer.wallet_id, db).await?;
     Response::from_json(&SettleResponse {
         settled: true,
         minutes_charged: 0,
@@ -257,37 +315,43 @@ async fn settled_noop(
 }
 
 /// Bills the reported minutes against the up-front reservation: deducts the
-/// remainder beyond the guard (floored at the available balance) when the
-/// session ran over, or refunds the unused guard when it ran under. Returns the
-/// resulting balance and the refunded amount.
+/// remainder beyond the guard (floored at the available balance, and at the
+/// member's remaining cap on an org wallet) when the session ran over, or
+/// refunds the unused guard when it ran under. Returns the resulting balance,
+/// the refunded amount and the session's total charge.
 async fn bill_settlement(
     app_id: &str,
-    user_id: &str,
+    payer: &Payer,
     actual: i32,
     rate: i32,
     reserved_credits: i32,
     session_id: &str,
     db: &worker::D1Database,
-) -> std::result::Result<(i32, i32), AppError> {
+) -> std::result::Result<(i32, i32, i32), AppError> {
     let charged = actual * rate;
-    let mut balance = get_user_balance(app_id, user_id, db).await?;
+    let mut extra: u32 = 0;
+    let mut balance = get_user_balance(app_id, &payer.wallet_id, db).await?;
     let mut refund: i32 = 0;
     if charged > reserved_credits {
-        let extra = (charged - reserved_credits).min(balance.max(0)) as u32;
+        let mut payable = get_available_balance(app_id, &payer.wallet_id, db).await?.max(0);
+        if let Some(remaining) = crate::orgs::remaining_spend_cap(app_id, payer, db).await? {
+            payable = payable.min(remaining.max(0));
+        }
+        extra = (charged - reserved_credits).min(payable) as u32;
         if extra > 0 {
-            balance = deduct_credits(app_id, user_id, extra, "realtime.translate", session_id, db)
+            balance = deduct_credits(app_id, payer, extra, "realtime.translate", session_id, db)
                 .await
                 .map_err(AppError::from)?;
         }
     } else {
         refund = (reserved_credits - charged).max(0);
         if refund > 0 {
-            balance = add_credits(app_id, user_id, refund as u32, "refund", "realtime.translate unused", Some(session_id), db)
+            balance = refund_credits(app_id, payer, refund as u32, "realtime.translate unused", session_id, db)
                 .await
                 .map_err(AppError::from)?;
         }
     }
-    Ok((balance, refund))
+    Ok((balance, refund, reserved_credits + extra as i32 - refund))
 }
 
 /// Refunds reservations that were deducted up-front in `/start` but can never
@@ -305,7 +369,7 @@ pub async fn sweep_orphaned_reservations(env: &Env) -> std::result::Result<u32,
     let cutoff = (Utc::now() - Duration::hours(2)).to_rfc3339();
     let rows = db
         .prepare(
-            "SELECT id, app_id, user_id, reserved_credits FROM realtime_sessions
+            "SELECT id, app_id, user_id, reserved_credits, org_id FROM realtime_sessions
              WHERE settled = 0 AND created_at < ?1 LIMIT 200",
         )
         .bind(&[cutoff.into()])?
@@ -338,13 +402,15 @@ pub async fn sweep_orphaned_reservations(env: &Env) -> std::result::Result<u32,
         }
 
         if reserved > 0 {
-            match add_credits(&app_id, &user_id, reserved as u32, "refund", "realtime.translate orphaned reservation", Some(&id), &db).await {
+            let payer = session_payer(&row, &user_id);
+            match refund_credits(&app_id, &payer, reserved as u32, "realtime.translate orphaned reservation", &id, &db).await {
                 Ok(_) => {
                     let _ = db
                         .prepare("UPDATE realtime_sessions SET refunded_credits = ?1 WHERE id = ?2")
                         .bind(&[reserved.into(), id.clone().into()])?
                         .run()
                         .await;
+                    usage_records::set_credits_charged(&app_id, &id, 0, &db).await;
                 }
                 Err(_) => {
                     let _ = db
@@ -388,7 +454,7 @@ async fn mint_ephemeral(
 
     if resp.status_code() >= 400 {
         let detail = resp.text().await.unwrap_or_default();
-        console_log!("realtime mint error {}: {}", resp.status_code(), detail);
+        crate::log_error!("Realtime mint error", json!({ "status": resp.status_code(), "detail": detail }));
         return Err(AppError::InternalError("Realtime mint failed".to_string()));
     }
 
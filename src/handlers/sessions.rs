@@ -0,0 +1,75 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::auth;
+use crate::sessions;
+use serde_json::json;
+
+pub async fn list_sessions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match list_sessions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn list_sessions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+
+    let list = sessions::list_sessions(&auth.app_id, &auth.user_id, &db).await?;
+    let list: Vec<_> = list
+        .into_iter()
+        .map(|s| {
+            let current = auth.session_id.as_deref() == Some(s.id.as_str());
+            let mut value = serde_json::to_value(&s).unwrap_or_default();
+            value["current"] = json!(current);
+            value
+        })
+        .collect();
+    Response::from_json(&json!({ "sessions": list })).map_err(AppError::from)
+}
+
+/// Signs one device out.
+pub async fn revoke_session(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match revoke_session_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn revoke_session_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let session_id = ctx
+        .param("session_id")
+        .map(|s| s.to_string())
+        .ok_or_else(|| AppError::BadRequest("Missing session_id".to_string()))?;
+
+    sessions::revoke_session(&auth.app_id, &auth.user_id, &session_id, &db).await?;
+    Response::from_json(&json!({ "revoked": true })).map_err(AppError::from)
+}
+
+/// Signs out everywhere. With `keep_current=true` the calling session (or
+/// account key) keeps working; otherwise the caller is signed out too.
+pub async fn revoke_all_sessions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match revoke_all_sessions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn revoke_all_sessions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    let keep_current = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "keep_current" && v == "true");
+
+    let keep = if keep_current { auth.session_id.as_deref() } else { None };
+    let using_account_key = auth.session_id.is_none() && auth.key_id.is_none();
+    let retire_account_key = !(keep_current && using_account_key);
+
+    let revoked = sessions::revoke_all_sessions(&auth.app_id, &auth.user_id, keep, retire_account_key, &db).await?;
+    Response::from_json(&json!({ "revoked": revoked, "account_key_revoked": retire_account_key }))
+        .map_err(AppError::from)
+}
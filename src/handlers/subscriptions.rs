@@ -0,0 +1,142 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::auth;
+use crate::subscriptions::{get_current_subscription, get_plan, get_plans, Subscription, SubscriptionPlan};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionStatusResponse {
+    pub subscription: Option<Subscription>,
+    pub plan: Option<SubscriptionPlan>,
+    pub plans: Vec<SubscriptionPlan>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSubscriptionCheckoutRequest {
+    pub plan_id: String,
+    pub success_url: String,
+    pub cancel_url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSubscriptionCheckoutResponse {
+    pub checkout_url: String,
+    pub session_id: String,
+}
+
+pub async fn get_subscription(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match get_subscription_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_subscription_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    status_response(&auth.app_id, &auth.user_id, &db).await
+}
+
+async fn status_response(
+    app_id: &str,
+    user_id: &str,
+    db: &worker::D1Database,
+) -> std::result::Result<Response, AppError> {
+    let subscription = get_current_subscription(app_id, user_id, db).await?;
+    let plan = match &subscription {
+        Some(sub) => get_plan(app_id, &sub.plan_id, db).await?,
+        None => None,
+    };
+    let plans = get_plans(app_id, db).await?;
+
+    Response::from_json(&SubscriptionStatusResponse { subscription, plan, plans }).map_err(AppError::from)
+}
+
+pub async fn create_subscription_checkout(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match create_subscription_checkout_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn create_subscription_checkout_inner(
+    mut req: Request,
+    ctx: RouteContext<()>,
+) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+    crate::rate_limit::enforce_write_rate_limit(&ctx.env, &auth.app_id, &auth.user_id, "subscriptions.checkout").await?;
+
+    let body: CreateSubscriptionCheckoutRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+
+    let plan = get_plans(&auth.app_id, &db)
+        .await?
+        .into_iter()
+        .find(|p| p.plan_id == body.plan_id)
+        .ok_or_else(|| AppError::BadRequest("Invalid plan_id".to_string()))?;
+    if let Some(current) = get_current_subscription(&auth.app_id, &auth.user_id, &db).await? {
+        if current.is_live() {
+            return Err(AppError::Conflict("You already have an active subscription".to_string()));
+        }
+    }
+
+    let email = db
+        .prepare("SELECT email FROM users WHERE app_id = ? AND id = ?")
+        .bind(&[auth.app_id.clone().into(), auth.user_id.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("email").and_then(|e| e.as_str()).map(|s| s.to_string()))
+        .filter(|e| !e.is_empty());
+
+    let mut metadata = HashMap::new();
+    metadata.insert("app_id".to_string(), auth.app_id.clone());
+    metadata.insert("user_id".to_string(), auth.user_id.clone());
+    metadata.insert("plan_id".to_string(), plan.plan_id.clone());
+
+    let session = crate::stripe_payments::create_subscription_checkout_session(
+        &ctx.env,
+        &plan.stripe_price_id,
+        metadata,
+        &body.success_url,
+        &body.cancel_url,
+        email.as_deref(),
+    )
+    .await?;
+
+    Response::from_json(&CreateSubscriptionCheckoutResponse {
+        checkout_url: session.url.unwrap_or_default(),
+        session_id: session.id,
+    })
+    .map_err(AppError::from)
+}
+
+pub async fn cancel_subscription(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match cancel_subscription_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Cancels at the end of the paid period; the allowance already granted stays
+/// usable until it lapses.
+async fn cancel_subscription_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = auth::authenticate(&req, &ctx.env, &db).await?;
+
+    let subscription = get_current_subscription(&auth.app_id, &auth.user_id, &db)
+        .await?
+        .filter(|s| s.is_live())
+        .ok_or_else(|| AppError::NotFound("No active subscription".to_string()))?;
+
+    crate::stripe_payments::cancel_subscription_at_period_end(&ctx.env, &subscription.stripe_subscription_id).await?;
+    db.prepare("UPDATE subscriptions SET cancel_at_period_end = 1, updated_at = ?1 WHERE id = ?2")
+        .bind(&[chrono::Utc::now().to_rfc3339().into(), subscription.id.clone().into()])?
+        .run()
+        .await?;
+
+    status_response(&auth.app_id, &auth.user_id, &db).await
+}
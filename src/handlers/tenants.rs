@@ -0,0 +1,189 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::admin::{self, AdminContext, Permission};
+use crate::audit::{self, AuditEvent};
+use crate::tenants::{self, TenantConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+fn app_id_param(ctx: &RouteContext<()>) -> std::result::Result<String, AppError> {
+    let app_id = ctx
+        .param("app_id")
+        .ok_or_else(|| AppError::BadRequest("Missing app_id".to_string()))?
+        .to_string();
+    tenants::validate_app_id(&app_id)?;
+    Ok(app_id)
+}
+
+/// Every app with its current config version.
+pub async fn admin_list_apps(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_apps_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_apps_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    admin::authorize(&req, &ctx.env, &db, Permission::ManageTenants).await?;
+    let apps = db
+        .prepare(
+            "SELECT a.app_id, a.name, a.enabled, a.created_at,
+                    COALESCE((SELECT MAX(version) FROM app_config_versions v WHERE v.app_id = a.app_id), 0) AS config_version
+             FROM apps a ORDER BY a.app_id",
+        )
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+    Response::from_json(&json!({ "apps": apps })).map_err(AppError::from)
+}
+
+pub async fn admin_get_app_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_get_app_config_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_get_app_config_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    admin::authorize(&req, &ctx.env, &db, Permission::ManageTenants).await?;
+    let app_id = app_id_param(&ctx)?;
+    let config = tenants::load_config(&app_id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("App {} not found", app_id)))?;
+    let version = tenants::current_version(&app_id, &db).await?;
+    Response::from_json(&json!({ "app_id": app_id, "version": version, "config": config })).map_err(AppError::from)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PutAppConfigRequest {
+    pub config: TenantConfig,
+    /// Required unless `dry_run`.
+    pub reason: Option<String>,
+    /// The version the change was made against; a mismatch means someone
+    /// else changed the app first.
+    pub expected_version: Option<i64>,
+    /// Validate and return the diff without applying it.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Creates an app or replaces its config, packs and pricing in one go.
+pub async fn admin_put_app_config(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_put_app_config_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_put_app_config_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ManageTenants).await?;
+    let app_id = app_id_param(&ctx)?;
+    let body: PutAppConfigRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+    body.config.validate()?;
+
+    let before = tenants::load_config(&app_id, &db).await?;
+    let version = tenants::current_version(&app_id, &db).await?;
+    if let Some(expected) = body.expected_version {
+        if expected != version {
+            return Err(AppError::Conflict(format!(
+                "The app is at config version {}, not {}; reload and retry",
+                version, expected
+            )));
+        }
+    }
+    let create = before.is_none();
+    let changes = tenants::diff(before.as_ref(), &body.config);
+
+    if body.dry_run {
+        return Response::from_json(&json!({
+            "app_id": app_id,
+            "dry_run": true,
+            "create": create,
+            "version": version,
+            "changes": changes,
+        }))
+        .map_err(AppError::from);
+    }
+    let reason = audit::require_reason(body.reason.as_deref())?;
+    if changes.is_empty() {
+        return Response::from_json(&json!({ "app_id": app_id, "version": version, "changes": changes }))
+            .map_err(AppError::from);
+    }
+
+    let new_version = version + 1;
+    let update = tenants::ConfigUpdate {
+        app_id: &app_id,
+        config: &body.config,
+        changes: &changes,
+        version: new_version,
+        create,
+        actor_user_id: &admin.user_id,
+        reason,
+    };
+    tenants::apply(&update, &db).await?;
+    worker::console_log!(
+        "Platform admin {} {} app {} (config version {})",
+        admin.user_id,
+        if create { "created" } else { "updated" },
+        app_id,
+        new_version
+    );
+    // The log belongs to the app being changed, not the caller's own.
+    let target = AdminContext { app_id: app_id.clone(), ..admin };
+    audit::record(
+        &target,
+        AuditEvent::new(if create { "app.create" } else { "app.update_config" })
+            .target("app", &app_id)
+            .after(json!({ "version": new_version, "changes": changes }))
+            .reason(reason),
+        &db,
+    )
+    .await;
+
+    let response = Response::from_json(&json!({ "app_id": app_id, "version": new_version, "changes": changes }))?;
+    Ok(if create { response.with_status(201) } else { response })
+}
+
+/// Config history, newest first, with each version's diff.
+pub async fn admin_list_app_config_versions(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_app_config_versions_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_app_config_versions_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    admin::authorize(&req, &ctx.env, &db, Permission::ManageTenants).await?;
+    let app_id = app_id_param(&ctx)?;
+    let versions = tenants::list_versions(&app_id, &db).await?;
+    Response::from_json(&json!({ "app_id": app_id, "versions": versions })).map_err(AppError::from)
+}
+
+/// One stored version, including the full config it set.
+pub async fn admin_get_app_config_version(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_get_app_config_version_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_get_app_config_version_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    admin::authorize(&req, &ctx.env, &db, Permission::ManageTenants).await?;
+    let app_id = app_id_param(&ctx)?;
+    let version: i64 = ctx
+        .param("version")
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| AppError::BadRequest("version must be a number".to_string()))?;
+    let stored = tenants::get_version(&app_id, version, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("App {} has no config version {}", app_id, version)))?;
+    Response::from_json(&stored).map_err(AppError::from)
+}
@@ -0,0 +1,30 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::auth::resolve_app_id;
+use crate::tokens;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Trades a refresh token for a new access/refresh token pair.
+pub async fn refresh_token(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match refresh_token_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn refresh_token_inner(mut req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let app_id = resolve_app_id(&req);
+    let db = ctx.env.d1("DB")?;
+    let body: RefreshRequest = req
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Invalid request body: {}", e)))?;
+
+    let pair = tokens::refresh(&ctx.env, &app_id, body.refresh_token.trim(), &db).await?;
+    Response::from_json(&pair).map_err(AppError::from)
+}
@@ -1,6 +1,7 @@
 use worker::{Request, Response, RouteContext, Result};
 use crate::error::AppError;
 use crate::auth::resolve_app_id;
+use crate::admin::{self, Permission};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
@@ -159,17 +160,96 @@ pub async fn get_user_usage_details(req: Request, ctx: RouteContext<()>) -> Resu
     }))
 }
 
+/// Aggregates shared by every section of the system report. Failed calls
+/// count towards requests and errors but never carry images or credits.
+const USAGE_METRICS: &str = "COUNT(*) AS requests,
+    COALESCE(SUM(CASE WHEN error IS NULL THEN 0 ELSE 1 END), 0) AS errors,
+    COALESCE(SUM(credits_charged), 0) AS credits_charged,
+    COALESCE(SUM(total_tokens), 0) AS tokens,
+    COALESCE(SUM(CASE WHEN error IS NULL THEN image_count ELSE 0 END), 0) AS images,
+    COALESCE(AVG(response_time_ms), 0) AS avg_response_time_ms";
+
+/// Adds `error_rate` (percent of requests that failed) to a metrics row.
+fn with_error_rate(mut row: serde_json::Value) -> serde_json::Value {
+    let requests = row.get("requests").and_then(|v| v.as_i64()).unwrap_or(0);
+    let errors = row.get("errors").and_then(|v| v.as_i64()).unwrap_or(0);
+    let error_rate = if requests > 0 { errors as f64 / requests as f64 * 100.0 } else { 0.0 };
+    if let Some(obj) = row.as_object_mut() {
+        obj.insert("error_rate".to_string(), json!(error_rate));
+    }
+    row
+}
+
+/// Which rows a system report covers: one app, or every app.
+struct UsageScope {
+    app_id: Option<String>,
+    period_start: String,
+    period_end: String,
+}
+
+impl UsageScope {
+    fn where_clause(&self) -> &'static str {
+        if self.app_id.is_some() {
+            "created_at >= ?1 AND created_at <= ?2 AND app_id = ?3"
+        } else {
+            "created_at >= ?1 AND created_at <= ?2"
+        }
+    }
+
+    fn params(&self) -> Vec<worker::wasm_bindgen::JsValue> {
+        let mut params = vec![self.period_start.clone().into(), self.period_end.clone().into()];
+        if let Some(app_id) = &self.app_id {
+            params.push(app_id.clone().into());
+        }
+        params
+    }
+
+    /// Metrics grouped by `expr`, reported under `name` and sorted by `order`.
+    async fn breakdown(
+        &self,
+        expr: &str,
+        name: &str,
+        order: &str,
+        db: &worker::D1Database,
+    ) -> std::result::Result<Vec<serde_json::Value>, AppError> {
+        let rows = db
+            .prepare(format!(
+                "SELECT {expr} AS {name}, {USAGE_METRICS} FROM usage_records WHERE {} GROUP BY {expr} ORDER BY {order}",
+                self.where_clause()
+            ))
+            .bind(&self.params())?
+            .all()
+            .await?
+            .results::<serde_json::Value>()?;
+        Ok(rows.into_iter().map(with_error_rate).collect())
+    }
+}
+
+/// Usage across an app for admins with finance access: totals plus
+/// breakdowns by capability, provider, model and day. Platform admins can
+/// pass `all_apps=true` to see every app, which adds a per-app breakdown.
 pub async fn get_system_usage(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let env = ctx.env;
-    let db = env.d1("DB")?;
-    
-    // TODO: Add admin authentication check
+    match get_system_usage_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_system_usage_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
     let url = req.url()?;
     let query_params: std::collections::HashMap<String, String> = url
         .query_pairs()
         .into_owned()
         .collect();
-    
+
+    let all_apps = query_params.get("all_apps").map(|v| v == "true").unwrap_or(false);
+    if all_apps && !admin.platform_admin {
+        return Err(AppError::Forbidden("Only platform admins can view usage across apps".to_string()));
+    }
+
     let period_start = query_params
         .get("start")
         .cloned()
@@ -177,99 +257,287 @@ pub async fn get_system_usage(req: Request, ctx: RouteContext<()>) -> Result<Res
             let thirty_days_ago = chrono::Utc::now() - chrono::Duration::days(30);
             thirty_days_ago.to_rfc3339()
         });
-    
+
     let period_end = query_params
         .get("end")
         .cloned()
         .unwrap_or_else(|| chrono::Utc::now().to_rfc3339());
-    
-    let overall_stmt = db.prepare(
-        "SELECT 
-            COUNT(DISTINCT user_id) as total_users,
-            COUNT(*) as total_requests,
-            COALESCE(SUM(total_tokens), 0) as total_tokens,
-            COALESCE(SUM(image_count), 0) as total_images,
-            COALESCE(AVG(response_time_ms), 0) as avg_response_time
-         FROM usage_records 
-         WHERE created_at >= ? 
-           AND created_at <= ?
-           AND error IS NULL"
-    );
-    
-    let overall_result = overall_stmt
-        .bind(&[period_start.clone().into(), period_end.clone().into()])?
+
+    let scope = UsageScope {
+        app_id: if all_apps { None } else { Some(admin.app_id.clone()) },
+        period_start,
+        period_end,
+    };
+
+    let overall = db
+        .prepare(format!(
+            "SELECT COUNT(DISTINCT user_id) AS total_users, {USAGE_METRICS} FROM usage_records WHERE {}",
+            scope.where_clause()
+        ))
+        .bind(&scope.params())?
         .first::<serde_json::Value>(None)
-        .await?;
-    
-    let top_users_stmt = db.prepare(
-        "SELECT 
-            user_id,
-            COUNT(*) as requests,
-            COALESCE(SUM(total_tokens), 0) as tokens
-         FROM usage_records 
-         WHERE created_at >= ? 
-           AND created_at <= ?
-           AND error IS NULL
-         GROUP BY user_id
-         ORDER BY tokens DESC
-         LIMIT 10"
-    );
-    
-    let top_users_results = top_users_stmt
-        .bind(&[period_start.clone().into(), period_end.clone().into()])?
+        .await?
+        .map(with_error_rate)
+        .unwrap_or_else(|| json!({}));
+    let int = |key: &str| overall.get(key).and_then(|v| v.as_i64()).unwrap_or(0);
+
+    let top_users = db
+        .prepare(format!(
+            "SELECT app_id, user_id, {USAGE_METRICS} FROM usage_records WHERE {}
+             GROUP BY app_id, user_id ORDER BY credits_charged DESC, tokens DESC LIMIT 10",
+            scope.where_clause()
+        ))
+        .bind(&scope.params())?
         .all()
-        .await?;
-    
-    let mut top_users = Vec::new();
-    if let Ok(rows) = top_users_results.results() {
-        for row in rows {
-            if let Ok(value) = serde_json::from_value::<serde_json::Value>(row) {
-                top_users.push(json!({
-                    "user_id": value.get("user_id").and_then(|v| v.as_str()).unwrap_or(""),
-                    "requests": value.get("requests").and_then(|v| v.as_i64()).unwrap_or(0),
-                    "tokens": value.get("tokens").and_then(|v| v.as_i64()).unwrap_or(0),
-                }));
-            }
-        }
-    }
-    
-    let error_stmt = db.prepare(
-        "SELECT 
-            COUNT(*) as error_count
-         FROM usage_records 
-         WHERE created_at >= ? 
-           AND created_at <= ?
-           AND error IS NOT NULL"
-    );
-    
-    let error_result = error_stmt
-        .bind(&[period_start.clone().into(), period_end.clone().into()])?
-        .first::<serde_json::Value>(None)
-        .await?;
-    
-    let error_count = error_result
-        .and_then(|v| v.get("error_count").and_then(|count| count.as_i64()))
-        .unwrap_or(0);
-    
-    let overall_stats = overall_result.unwrap_or_else(|| json!({}));
-    let total_requests = overall_stats.get("total_requests").and_then(|v| v.as_i64()).unwrap_or(0);
-    let error_rate = if total_requests > 0 {
-        (error_count as f64 / (total_requests + error_count) as f64) * 100.0
+        .await?
+        .results::<serde_json::Value>()?
+        .into_iter()
+        .map(with_error_rate)
+        .collect::<Vec<_>>();
+
+    let busiest = "requests DESC";
+    let by_capability = scope.breakdown("capability", "capability", busiest, &db).await?;
+    let by_provider = scope.breakdown("provider", "provider", busiest, &db).await?;
+    let by_model = scope.breakdown("model", "model", busiest, &db).await?;
+    let by_day = scope.breakdown("DATE(created_at)", "date", "date DESC", &db).await?;
+    let by_app = if all_apps {
+        Some(scope.breakdown("app_id", "app_id", busiest, &db).await?)
     } else {
-        0.0
+        None
     };
-    
+
     Response::from_json(&json!({
-        "period_start": period_start,
-        "period_end": period_end,
+        "app_id": scope.app_id,
+        "period_start": scope.period_start,
+        "period_end": scope.period_end,
         "overall_stats": {
-            "total_users": overall_stats.get("total_users").and_then(|v| v.as_i64()).unwrap_or(0),
-            "total_requests": total_requests,
-            "total_tokens": overall_stats.get("total_tokens").and_then(|v| v.as_i64()).unwrap_or(0),
-            "total_images": overall_stats.get("total_images").and_then(|v| v.as_i64()).unwrap_or(0),
-            "avg_response_time": overall_stats.get("avg_response_time").and_then(|v| v.as_f64()).unwrap_or(0.0),
-            "error_rate": error_rate,
+            "total_users": int("total_users"),
+            "total_requests": int("requests"),
+            "total_errors": int("errors"),
+            "total_credits_charged": int("credits_charged"),
+            "total_tokens": int("tokens"),
+            "total_images": int("images"),
+            "avg_response_time": overall.get("avg_response_time_ms").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            "error_rate": overall.get("error_rate").and_then(|v| v.as_f64()).unwrap_or(0.0),
         },
+        "by_capability": by_capability,
+        "by_provider": by_provider,
+        "by_model": by_model,
+        "by_day": by_day,
+        "by_app": by_app,
         "top_users": top_users
     }))
-}
\ No newline at end of file
+    .map_err(AppError::from)
+}
+
+/// How `/v1/usage/timeseries` buckets `created_at`. Buckets are labelled by
+/// their UTC start; weeks start on Monday.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Bucket {
+    pub fn parse(value: &str) -> std::result::Result<Self, AppError> {
+        match value {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            _ => Err(AppError::BadRequest("bucket must be one of: hour, day, week".to_string())),
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Bucket::Hour => "strftime('%Y-%m-%dT%H:00:00Z', created_at)",
+            Bucket::Day => "strftime('%Y-%m-%dT00:00:00Z', created_at)",
+            Bucket::Week => "strftime('%Y-%m-%dT00:00:00Z', created_at, 'weekday 0', '-6 days')",
+        }
+    }
+
+    fn duration(&self) -> chrono::Duration {
+        match self {
+            Bucket::Hour => chrono::Duration::hours(1),
+            Bucket::Day => chrono::Duration::days(1),
+            Bucket::Week => chrono::Duration::weeks(1),
+        }
+    }
+
+    /// The window used when the caller doesn't give `start`.
+    fn default_span(&self) -> chrono::Duration {
+        match self {
+            Bucket::Hour => chrono::Duration::hours(48),
+            Bucket::Day => chrono::Duration::days(30),
+            Bucket::Week => chrono::Duration::weeks(26),
+        }
+    }
+}
+
+/// The most buckets one timeseries request may span.
+const MAX_BUCKETS: i64 = 1000;
+
+const GROUP_BY_COLUMNS: &[(&str, &str)] = &[
+    ("capability", "capability"),
+    ("model", "model"),
+    ("provider", "provider"),
+    ("app", "app_id"),
+];
+
+const TIMESERIES_METRICS: &[&str] = &[
+    "requests",
+    "errors",
+    "images",
+    "tokens",
+    "credits",
+    "p50_response_time_ms",
+    "p95_response_time_ms",
+];
+
+fn parse_time(name: &str, value: &str) -> std::result::Result<chrono::DateTime<chrono::Utc>, AppError> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&chrono::Utc))
+        .map_err(|_| AppError::BadRequest(format!("{} must be an RFC 3339 timestamp", name)))
+}
+
+/// Bucketed usage for dashboards: one series per group (or a single series),
+/// each point carrying request, error, image, token and credit totals plus
+/// p50/p95 latency of the successful calls. Same access rules as
+/// `/v1/usage/system`.
+pub async fn get_usage_timeseries(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match get_usage_timeseries_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_usage_timeseries_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let admin = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
+    let url = req.url()?;
+    let query_params: std::collections::HashMap<String, String> = url
+        .query_pairs()
+        .into_owned()
+        .collect();
+
+    let all_apps = query_params.get("all_apps").map(|v| v == "true").unwrap_or(false);
+    if all_apps && !admin.platform_admin {
+        return Err(AppError::Forbidden("Only platform admins can view usage across apps".to_string()));
+    }
+
+    let bucket = Bucket::parse(query_params.get("bucket").map(|s| s.as_str()).unwrap_or("day"))?;
+    let group_by = match query_params.get("group_by").filter(|g| !g.is_empty()) {
+        Some(g) => Some(
+            GROUP_BY_COLUMNS
+                .iter()
+                .find(|(name, _)| *name == g.as_str())
+                .copied()
+                .ok_or_else(|| AppError::BadRequest("group_by must be one of: capability, model, provider, app".to_string()))?,
+        ),
+        None => None,
+    };
+    let metrics: Vec<&str> = match query_params.get("metrics").filter(|m| !m.is_empty()) {
+        Some(list) => {
+            let mut metrics = Vec::new();
+            for name in list.split(',').map(|m| m.trim()) {
+                let metric = TIMESERIES_METRICS
+                    .iter()
+                    .find(|m| **m == name)
+                    .ok_or_else(|| AppError::BadRequest(format!(
+                        "Unknown metric {}; expected some of: {}",
+                        name,
+                        TIMESERIES_METRICS.join(", ")
+                    )))?;
+                metrics.push(*metric);
+            }
+            metrics
+        }
+        None => TIMESERIES_METRICS.to_vec(),
+    };
+
+    let end = match query_params.get("end") {
+        Some(end) => parse_time("end", end)?,
+        None => chrono::Utc::now(),
+    };
+    let start = match query_params.get("start") {
+        Some(start) => parse_time("start", start)?,
+        None => end - bucket.default_span(),
+    };
+    if start >= end {
+        return Err(AppError::BadRequest("start must be before end".to_string()));
+    }
+    if (end - start).num_seconds() / bucket.duration().num_seconds() > MAX_BUCKETS {
+        return Err(AppError::BadRequest(format!(
+            "That range spans more than {} buckets; use a larger bucket or a shorter range",
+            MAX_BUCKETS
+        )));
+    }
+
+    let scope = UsageScope {
+        app_id: if all_apps { None } else { Some(admin.app_id.clone()) },
+        period_start: start.to_rfc3339(),
+        period_end: end.to_rfc3339(),
+    };
+    let group_sql = group_by.map(|(_, column)| column).unwrap_or("NULL");
+
+    // Percentiles are nearest-rank over the successful calls in each bucket:
+    // failures are ranked in their own partition and left out.
+    let rows = db
+        .prepare(format!(
+            "SELECT bucket, grp,
+                    COUNT(*) AS requests,
+                    COALESCE(SUM(CASE WHEN error IS NULL THEN 0 ELSE 1 END), 0) AS errors,
+                    COALESCE(SUM(CASE WHEN error IS NULL THEN image_count ELSE 0 END), 0) AS images,
+                    COALESCE(SUM(total_tokens), 0) AS tokens,
+                    COALESCE(SUM(credits_charged), 0) AS credits,
+                    MIN(CASE WHEN error IS NULL AND rn >= 0.5 * n THEN response_time_ms END) AS p50_response_time_ms,
+                    MIN(CASE WHEN error IS NULL AND rn >= 0.95 * n THEN response_time_ms END) AS p95_response_time_ms
+             FROM (
+                 SELECT {bucket} AS bucket, {group} AS grp, error, image_count, total_tokens, credits_charged, response_time_ms,
+                        ROW_NUMBER() OVER (PARTITION BY {bucket}, {group}, error IS NULL ORDER BY response_time_ms) AS rn,
+                        COUNT(*) OVER (PARTITION BY {bucket}, {group}, error IS NULL) AS n
+                 FROM usage_records
+                 WHERE {filter}
+             )
+             GROUP BY bucket, grp
+             ORDER BY grp, bucket",
+            bucket = bucket.sql(),
+            group = group_sql,
+            filter = scope.where_clause(),
+        ))
+        .bind(&scope.params())?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?;
+
+    let mut series: Vec<serde_json::Value> = Vec::new();
+    for row in rows {
+        let key = row.get("grp").cloned().unwrap_or(serde_json::Value::Null);
+        let mut point = serde_json::Map::new();
+        point.insert("bucket".to_string(), row.get("bucket").cloned().unwrap_or(serde_json::Value::Null));
+        for metric in &metrics {
+            point.insert(metric.to_string(), row.get(*metric).cloned().unwrap_or(serde_json::Value::Null));
+        }
+        match series.last_mut() {
+            Some(last) if last.get("key") == Some(&key) => {
+                if let Some(points) = last.get_mut("points").and_then(|p| p.as_array_mut()) {
+                    points.push(serde_json::Value::Object(point));
+                }
+            }
+            _ => series.push(json!({ "key": key, "points": [point] })),
+        }
+    }
+
+    Response::from_json(&json!({
+        "app_id": scope.app_id,
+        "bucket": query_params.get("bucket").map(|s| s.as_str()).unwrap_or("day"),
+        "group_by": group_by.map(|(name, _)| name),
+        "start": scope.period_start,
+        "end": scope.period_end,
+        "metrics": metrics,
+        "series": series
+    }))
+    .map_err(AppError::from)
+}
@@ -0,0 +1,118 @@
+use worker::{Request, Response, RouteContext, Result};
+use crate::error::AppError;
+use crate::admin::{self, Permission};
+use crate::audit::{self, AuditEvent};
+use crate::webhook_events::{claim_replay, get_event, list_events, WebhookEvent};
+use serde_json::json;
+
+pub async fn admin_list_webhook_events(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_list_webhook_events_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_list_webhook_events_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
+    let url = req.url()?;
+    let query_params: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let status = query_params.get("status").map(|s| s.as_str());
+    let provider = query_params.get("provider").map(|s| s.as_str());
+    let page = query_params.get("page").and_then(|p| p.parse::<i32>().ok()).unwrap_or(1).max(1);
+    let per_page = query_params
+        .get("per_page")
+        .and_then(|p| p.parse::<i32>().ok())
+        .unwrap_or(50)
+        .clamp(1, 100);
+
+    let offset = (page - 1) * per_page;
+    let events = list_events(&auth.app_id, auth.platform_admin, status, provider, per_page, offset, &db).await?;
+    Response::from_json(&json!({
+        "events": events,
+        "page": page,
+        "per_page": per_page,
+    }))
+    .map_err(AppError::from)
+}
+
+pub async fn admin_get_webhook_event(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_get_webhook_event_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn admin_get_webhook_event_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ReadFinance).await?;
+
+    let id = ctx.param("event_id").ok_or_else(|| AppError::BadRequest("Missing event_id".to_string()))?;
+    let event = get_event(&auth.app_id, auth.platform_admin, id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Webhook event not found".to_string()))?;
+    Response::from_json(&event).map_err(AppError::from)
+}
+
+pub async fn admin_replay_webhook_event(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match admin_replay_webhook_event_inner(req, ctx).await {
+        Ok(r) => Ok(r),
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Re-runs a failed (or stuck) event from its stored payload. The signature was checked
+/// when the event was first received, so it isn't checked again.
+async fn admin_replay_webhook_event_inner(req: Request, ctx: RouteContext<()>) -> std::result::Result<Response, AppError> {
+    let db = ctx.env.d1("DB")?;
+    let auth = admin::authorize(&req, &ctx.env, &db, Permission::ManageFinance).await?;
+
+    let id = ctx
+        .param("event_id")
+        .ok_or_else(|| AppError::BadRequest("Missing event_id".to_string()))?
+        .to_string();
+    let event = claim_replay(&auth.app_id, auth.platform_admin, &id, &db).await?;
+    worker::console_log!("Admin {} replaying {} webhook {}", auth.user_id, event.provider, event.event_id);
+
+    let outcome = process(&ctx.env, &event, &db).await;
+    // The response of `complete` is meant for the provider; the admin gets the
+    // updated event instead.
+    crate::webhook_events::complete(&id, outcome, &db).await?;
+
+    let before_status = event.status.clone();
+    let event: WebhookEvent = get_event(&auth.app_id, auth.platform_admin, &id, &db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Webhook event not found".to_string()))?;
+    audit::record(
+        &auth,
+        AuditEvent::new("webhook.replay")
+            .target("webhook_event", &id)
+            .before(json!({ "status": before_status }))
+            .after(json!({ "status": event.status, "error": event.error })),
+        &db,
+    )
+    .await;
+    Response::from_json(&event).map_err(AppError::from)
+}
+
+async fn process(env: &worker::Env, event: &WebhookEvent, db: &worker::D1Database) -> std::result::Result<(), AppError> {
+    let payload = event.payload.as_deref().unwrap_or_default();
+    let invalid = |e: serde_json::Error| AppError::InternalError(format!("Stored payload no longer parses: {}", e));
+    match event.provider.as_str() {
+        "stripe" => {
+            let parsed = serde_json::from_str(payload).map_err(invalid)?;
+            crate::handlers::credits::process_stripe_event(env, parsed).await
+        }
+        "nowpayments" => {
+            let parsed = serde_json::from_str(payload).map_err(invalid)?;
+            crate::handlers::credits::process_nowpayments_event(&parsed, db).await
+        }
+        "revenuecat" => {
+            let parsed: crate::handlers::credits::RevenueCatWebhookEvent =
+                serde_json::from_str(payload).map_err(invalid)?;
+            crate::handlers::credits::process_revenuecat_event(&parsed.event, db).await
+        }
+        other => Err(AppError::InternalError(format!("Unknown webhook provider {}", other))),
+    }
+}
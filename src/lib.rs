@@ -3,6 +3,7 @@ use worker::*;
 mod models;
 mod error;
 mod auth;
+mod apps;
 mod handlers;
 mod storage;
 mod deployment;
@@ -13,23 +14,108 @@ mod rate_limit;
 mod logger;
 mod providers;
 mod privacy;
+mod promo_codes;
+mod referrals;
+mod subscriptions;
+mod webhook_events;
+mod reconciliation;
+mod transfers;
+mod orgs;
+mod api_keys;
+mod tokens;
+mod sessions;
+mod admin;
+mod audit;
+mod tenants;
+mod user_status;
+mod usage_records;
+mod metrics;
+mod request_id;
 
 use handlers::{images, gallery, r2, usage, oauth, oauth_apple, oauth_apple_callback, oauth_native, device_auth, identity};
 
 #[event(scheduled)]
-async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
     console_error_panic_hook::set_once();
+    if event.cron() == reconciliation::RECONCILE_CRON {
+        match env.d1("DB") {
+            Ok(db) => match reconciliation::reconcile_ledger(&db).await {
+                Ok(run) => console_log!(
+                    "ledger reconciliation checked {} wallets / {} entries, {} discrepancies",
+                    run.wallets_checked, run.transactions_checked, run.discrepancies
+                ),
+                Err(e) => console_error!("ledger reconciliation failed: {:?}", e),
+            },
+            Err(e) => console_error!("scheduled: no DB binding: {:?}", e),
+        }
+        return;
+    }
+    if event.cron() == metrics::METRICS_CRON {
+        match env.d1("DB") {
+            Ok(db) => match metrics::rollup(&db).await {
+                Ok(run) => console_log!(
+                    "metrics rollup folded {} requests, {} usage records, {} purchases",
+                    run.request_events, run.usage_records, run.purchases
+                ),
+                Err(e) => console_error!("metrics rollup failed: {:?}", e),
+            },
+            Err(e) => console_error!("scheduled: no DB binding: {:?}", e),
+        }
+        return;
+    }
     match handlers::realtime::sweep_orphaned_reservations(&env).await {
         Ok(n) if n > 0 => console_log!("realtime sweep refunded {} orphaned reservations", n),
         Ok(_) => {}
         Err(e) => console_error!("realtime sweep failed: {:?}", e),
     }
+    let db = match env.d1("DB") {
+        Ok(db) => db,
+        Err(e) => {
+            console_error!("scheduled: no DB binding: {:?}", e);
+            return;
+        }
+    };
+    match credits::expire_stale_holds(&db).await {
+        Ok(n) if n > 0 => console_log!("credit hold sweep settled {} stale holds", n),
+        Ok(_) => {}
+        Err(e) => console_error!("credit hold sweep failed: {:?}", e),
+    }
+    match credits::expire_promo_credits(&db).await {
+        Ok(n) if n > 0 => console_log!("promo credit sweep expired {} buckets", n),
+        Ok(_) => {}
+        Err(e) => console_error!("promo credit sweep failed: {:?}", e),
+    }
 }
 
 #[event(fetch)]
-async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
-    
+    let started = Date::now().as_millis();
+    let method = req.method().to_string();
+    let path = req.path();
+    let id = request_id::from_request(&req);
+    let metrics_env = env.clone();
+
+    // A worker::Error that escapes a handler still becomes an ErrorResponse
+    // carrying the request id, rather than a bare 500.
+    let mut response = request_id::scope(id.clone(), async move {
+        match route(req, env).await {
+            Ok(response) => Ok(response),
+            Err(e) => error::AppError::from(e).to_response(),
+        }
+    })
+    .await?;
+    let _ = response.headers_mut().set(request_id::HEADER, &id);
+
+    let status = response.status_code();
+    let duration_ms = (Date::now().as_millis() - started) as u32;
+    ctx.wait_until(async move {
+        metrics::record_request(&metrics_env, &method, &path, status, duration_ms).await;
+    });
+    Ok(response)
+}
+
+async fn route(req: Request, env: Env) -> Result<Response> {
     let router = Router::new();
     
     router
@@ -63,6 +149,7 @@ Privacy Policy: /privacy-policy"#)
             let base = format!("{}://{}", url.scheme(), url.host().unwrap());
             Response::redirect(format!("{}/docs", base).parse().unwrap())
         })
+        .get_async("/metrics", handlers::metrics::metrics)
         .get("/openapi.yaml", |_, _| {
             Response::ok(include_str!("../openapi.yaml"))
                 .map(|mut r| {
@@ -83,6 +170,7 @@ Privacy Policy: /privacy-policy"#)
         .get_async("/v1/usage/users/:user_id", usage::get_user_usage)
         .get_async("/v1/usage/users/:user_id/details", usage::get_user_usage_details)
         .get_async("/v1/usage/system", usage::get_system_usage)
+        .get_async("/v1/usage/timeseries", usage::get_usage_timeseries)
         .get_async("/v1/auth/github", oauth::github_auth_start)
         .post_async("/v1/auth/github/callback", oauth::github_auth_callback)
         .get_async("/v1/auth/google", oauth::google_auth_start)
@@ -95,19 +183,31 @@ Privacy Policy: /privacy-policy"#)
         .post_async("/v1/auth/device/code", device_auth::start_device_flow)
         .post_async("/v1/auth/device/token", device_auth::poll_device_token)
         .get_async("/v1/auth/device/:device_code/status", device_auth::device_auth_status)
+        .post_async("/v1/auth/token/refresh", handlers::tokens::refresh_token)
         .post_async("/v1/identity/anonymous", identity::anonymous_register)
         .post_async("/v1/identity/link", identity::link)
         .delete_async("/v1/identity", identity::delete_identity)
         .get_async("/v1/identity/me", identity::identity_me)
+        .get_async("/v1/keys", handlers::keys::list_keys)
+        .post_async("/v1/keys", handlers::keys::create_key)
+        .post_async("/v1/keys/:key_id/rotate", handlers::keys::rotate_key)
+        .delete_async("/v1/keys/:key_id", handlers::keys::revoke_key)
+        .get_async("/v1/sessions", handlers::sessions::list_sessions)
+        .delete_async("/v1/sessions", handlers::sessions::revoke_all_sessions)
+        .delete_async("/v1/sessions/:session_id", handlers::sessions::revoke_session)
         .post_async("/v1/credits/charge", identity::charge_capability)
         .post_async("/v1/run/chat.completion", handlers::chat::chat_completion)
         .post_async("/v1/run/realtime.translate/start", handlers::realtime::start)
         .post_async("/v1/run/realtime.translate/settle", handlers::realtime::settle)
         .get_async("/v1/credits/balance", handlers::credits::get_balance)
         .get_async("/v1/credits/transactions", handlers::credits::list_transactions)
+        .get_async("/v1/credits/transactions/export", handlers::credits::export_transactions)
         .get_async("/v1/credits/packs", handlers::credits::list_packs)
         .post_async("/v1/credits/estimate", handlers::credits::estimate_cost)
         .post_async("/v1/credits/purchase", handlers::credits::purchase_credits)
+        .post_async("/v1/credits/redeem", handlers::credits::redeem_promo_code)
+        .post_async("/v1/credits/transfer", handlers::credits::transfer_credits)
+        .get_async("/v1/credits/referral", handlers::credits::get_referral_stats)
         .get_async("/v1/credits/purchase/:purchase_id/status", handlers::credits::get_purchase_status)
         .post_async("/v1/credits/webhook", handlers::credits::complete_purchase_webhook)
         .post_async("/v1/credits/webhook/crypto", handlers::credits::crypto_payment_webhook)
@@ -115,11 +215,46 @@ Privacy Policy: /privacy-policy"#)
         .post_async("/v1/credits/purchase/stripe", handlers::credits::create_stripe_checkout)
         .post_async("/v1/stripe/webhook", handlers::credits::stripe_webhook)
         .get_async("/v1/stripe/config", handlers::credits::get_stripe_config)
+        .post_async("/v1/orgs", handlers::orgs::create_org)
+        .get_async("/v1/orgs", handlers::orgs::list_orgs)
+        .get_async("/v1/orgs/:org_id", handlers::orgs::get_org)
+        .post_async("/v1/orgs/:org_id/members", handlers::orgs::add_org_member)
+        .patch_async("/v1/orgs/:org_id/members/:user_id", handlers::orgs::update_org_member)
+        .delete_async("/v1/orgs/:org_id/members/:user_id", handlers::orgs::remove_org_member)
+        .post_async("/v1/orgs/:org_id/fund", handlers::orgs::fund_org)
+        .get_async("/v1/orgs/:org_id/transactions", handlers::orgs::list_org_transactions)
+        .get_async("/v1/orgs/:org_id/transactions/export", handlers::orgs::export_org_transactions)
+        .get_async("/v1/subscriptions", handlers::subscriptions::get_subscription)
+        .post_async("/v1/subscriptions/checkout", handlers::subscriptions::create_subscription_checkout)
+        .post_async("/v1/subscriptions/cancel", handlers::subscriptions::cancel_subscription)
         .post_async("/v1/credits/purchase/revenuecat/validate", handlers::credits::validate_revenuecat_purchase)
         .post_async("/v1/revenuecat/webhook", handlers::credits::revenuecat_webhook)
         .post_async("/v1/admin/credits/adjust", handlers::credits::admin_adjust_credits)
+        .post_async("/v1/admin/credits/unfreeze", handlers::credits::admin_unfreeze_wallet)
+        .post_async("/v1/admin/credits/gift", handlers::credits::admin_gift_credits)
         .get_async("/v1/admin/credits/stats", handlers::credits::admin_system_stats)
+        .get_async("/v1/admin/credits/transactions/export", handlers::credits::admin_export_transactions)
         .get_async("/v1/admin/users", handlers::credits::admin_search_users)
+        .post_async("/v1/admin/promo-codes", handlers::credits::admin_create_promo_code)
+        .get_async("/v1/admin/promo-codes", handlers::credits::admin_list_promo_codes)
+        .get_async("/v1/admin/webhooks", handlers::webhooks::admin_list_webhook_events)
+        .get_async("/v1/admin/webhooks/:event_id", handlers::webhooks::admin_get_webhook_event)
+        .post_async("/v1/admin/webhooks/:event_id/replay", handlers::webhooks::admin_replay_webhook_event)
+        .get_async("/v1/admin/ledger/discrepancies", handlers::credits::admin_list_ledger_discrepancies)
+        .post_async("/v1/admin/ledger/discrepancies/:discrepancy_id/resolve", handlers::credits::admin_resolve_ledger_discrepancy)
+        .get_async("/v1/admin/me", handlers::admin::admin_me)
+        .get_async("/v1/admin/roles", handlers::admin::admin_list_roles)
+        .put_async("/v1/admin/roles/:user_id", handlers::admin::admin_set_roles)
+        .get_async("/v1/admin/reports", handlers::admin::admin_list_reports)
+        .post_async("/v1/admin/reports/:image_id/resolve", handlers::admin::admin_resolve_report)
+        .get_async("/v1/admin/audit-log", handlers::admin::admin_list_audit_log)
+        .get_async("/v1/admin/users/:user_id/status", handlers::admin::admin_get_user_status)
+        .put_async("/v1/admin/users/:user_id/status", handlers::admin::admin_set_user_status)
+        .get_async("/v1/admin/apps", handlers::tenants::admin_list_apps)
+        .get_async("/v1/admin/apps/:app_id/config", handlers::tenants::admin_get_app_config)
+        .put_async("/v1/admin/apps/:app_id/config", handlers::tenants::admin_put_app_config)
+        .get_async("/v1/admin/apps/:app_id/config/versions", handlers::tenants::admin_list_app_config_versions)
+        .get_async("/v1/admin/apps/:app_id/config/versions/:version", handlers::tenants::admin_get_app_config_version)
         .run(req, env)
         .await
 }
\ No newline at end of file
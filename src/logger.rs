@@ -26,7 +26,7 @@ pub struct Logger;
 #[allow(dead_code)]
 impl Logger {
     fn log(level: LogLevel, message: &str, fields: Option<serde_json::Value>) {
-        let log_entry = if let Some(fields) = fields {
+        let mut log_entry = if let Some(fields) = fields {
             json!({
                 "level": level.as_str(),
                 "message": message,
@@ -40,6 +40,9 @@ impl Logger {
                 "timestamp": chrono::Utc::now().to_rfc3339(),
             })
         };
+        if let Some(request_id) = crate::request_id::current() {
+            log_entry["request_id"] = json!(request_id);
+        }
         
         console_log!("{}", log_entry.to_string());
     }
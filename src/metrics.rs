@@ -0,0 +1,397 @@
+use worker::{D1Database, D1PreparedStatement, Env};
+use serde_json::Value;
+use chrono::{Duration, Utc};
+use crate::error::AppError;
+
+/// The cron trigger (wrangler.toml) that runs the rollup; `/metrics` is at
+/// most this stale.
+pub const METRICS_CRON: &str = "*/5 * * * *";
+
+/// Upper bounds (ms) of the provider latency histogram buckets, below `+Inf`.
+const LATENCY_BUCKETS_MS: &[u32] = &[250, 500, 1000, 2500, 5000, 10000, 20000, 30000, 60000, 120000];
+
+/// One in this many ordinary requests is queued in `request_events`, standing
+/// for all of them; server errors are always queued.
+const REQUEST_SAMPLE_RATE: u32 = 10;
+
+/// Rows stamped within this many seconds of a rollup are left for the next
+/// one: a call stamps `created_at` before its insert lands.
+const SETTLE_DELAY_SECONDS: i64 = 60;
+
+/// Sorts `usage_records.error` into a fixed set of types, keeping the errors
+/// counter's label cardinality bounded.
+const ERROR_TYPE_SQL: &str = "CASE
+    WHEN error LIKE '%moderation%' OR error LIKE '%content_policy%' THEN 'moderation'
+    WHEN error LIKE '%timeout%' OR error LIKE '%timed out%' THEN 'timeout'
+    WHEN error LIKE '%429%' OR error LIKE '%rate limit%' THEN 'rate_limited'
+    WHEN error LIKE '%401%' OR error LIKE '%403%' OR error LIKE '%api key%' OR error LIKE '%not configured%' THEN 'auth'
+    WHEN error LIKE '%500%' OR error LIKE '%502%' OR error LIKE '%503%' OR error LIKE '%provider error%' THEN 'upstream'
+    ELSE 'other'
+END";
+
+/// Every metric `/metrics` exposes: name, type and help text.
+const FAMILIES: &[(&str, &str, &str)] = &[
+    ("pixie_http_requests_total", "counter", "HTTP requests served, by method, route and status (sampled; 5xx counted exactly)."),
+    ("pixie_provider_latency_ms", "histogram", "Latency of successful provider calls in milliseconds."),
+    ("pixie_provider_errors_total", "counter", "Failed provider calls, by provider and error type."),
+    ("pixie_credits_deducted_total", "counter", "Credits charged for metered calls, by app and capability."),
+    ("pixie_purchases_completed_total", "counter", "Completed credit purchases, by app and payment provider."),
+    ("pixie_purchase_revenue_usd_cents_total", "counter", "Revenue from completed purchases in US cents, by app and payment provider."),
+    ("pixie_user_locks_open", "gauge", "Per-user request locks currently held, by app."),
+    ("pixie_realtime_sessions_unsettled", "gauge", "Realtime sessions started but not yet settled, by app."),
+    ("pixie_realtime_reserved_credits_unsettled", "gauge", "Credits reserved by unsettled realtime sessions, by app."),
+    ("pixie_metrics_rollup_timestamp_seconds", "gauge", "When the metrics rollup last ran, as a Unix timestamp."),
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct RollupRun {
+    pub request_events: i64,
+    pub usage_records: i64,
+    pub purchases: i64,
+}
+
+/// Renders a Prometheus label set, escaping the values.
+pub fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The route label for a request path: ids and other free-form segments
+/// collapse to `:id`. Every 404 is counted as `unmatched` so scanners can't
+/// mint new series.
+pub fn route_label(path: &str, status: u16) -> String {
+    if status == 404 {
+        return "unmatched".to_string();
+    }
+    path.split('/')
+        .map(|segment| {
+            // Words like `images` or `chat.completion`, and short prefixes
+            // like `v1` and `r2`; anything else is an id.
+            let charset = segment
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'));
+            let has_digit = segment.chars().any(|c| c.is_ascii_digit());
+            if charset && segment.len() <= 32 && (!has_digit || segment.len() <= 2) {
+                segment
+            } else {
+                ":id"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// How many requests queueing this one would stand for, or None to skip it.
+/// Scrapes of `/metrics` aren't counted at all.
+fn sample_weight(path: &str, status: u16) -> Option<u32> {
+    if path == "/metrics" {
+        return None;
+    }
+    if status >= 500 {
+        return Some(1);
+    }
+    (js_sys::Math::random() < 1.0 / REQUEST_SAMPLE_RATE as f64).then_some(REQUEST_SAMPLE_RATE)
+}
+
+/// Queues a sample of served requests for the next rollup (see
+/// `sample_weight`). Best effort: metrics must never fail a request.
+pub async fn record_request(env: &Env, method: &str, path: &str, status: u16, duration_ms: u32) {
+    let Some(weight) = sample_weight(path, status) else { return };
+    let Ok(db) = env.d1("DB") else { return };
+    let stmt = db
+        .prepare(
+            "INSERT INTO request_events (method, route, status, duration_ms, weight, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .bind(&[
+            method.into(),
+            route_label(path, status).into(),
+            (status as u32).into(),
+            duration_ms.into(),
+            weight.into(),
+            Utc::now().to_rfc3339().into(),
+        ]);
+    if let Ok(stmt) = stmt {
+        let _ = stmt.run().await;
+    }
+}
+
+fn text(row: &Value, key: &str) -> String {
+    match row.get(key) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn number(row: &Value, key: &str) -> f64 {
+    row.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0)
+}
+
+async fn cursor(source: &str, db: &D1Database) -> Result<String, AppError> {
+    Ok(db
+        .prepare("SELECT last_seen FROM metrics_cursors WHERE source = ?1")
+        .bind(&[source.into()])?
+        .first::<Value>(None)
+        .await?
+        .map(|row| text(&row, "last_seen"))
+        .unwrap_or_default())
+}
+
+fn set_cursor(source: &str, last_seen: &str, now: &str, db: &D1Database) -> Result<D1PreparedStatement, AppError> {
+    Ok(db
+        .prepare(
+            "INSERT INTO metrics_cursors (source, last_seen, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(source) DO UPDATE SET last_seen = excluded.last_seen, updated_at = excluded.updated_at",
+        )
+        .bind(&[source.into(), last_seen.into(), now.into()])?)
+}
+
+/// Folds everything new since the last run into the counters and replaces
+/// the gauges, all in one batch so a failed run changes nothing.
+pub async fn rollup(db: &D1Database) -> Result<RollupRun, AppError> {
+    let now = Utc::now();
+    let updated_at = now.to_rfc3339();
+    let cutoff = (now - Duration::seconds(SETTLE_DELAY_SECONDS)).to_rfc3339();
+    let mut run = RollupRun::default();
+    let mut counters: Vec<(&str, String, f64)> = Vec::new();
+    let mut gauges: Vec<(&str, String, f64)> = Vec::new();
+    let mut statements = Vec::new();
+
+    // HTTP requests: drain the queue up to its current head.
+    let head = db
+        .prepare("SELECT COALESCE(MAX(id), 0) AS head FROM request_events")
+        .first::<Value>(None)
+        .await?
+        .map(|row| number(&row, "head"))
+        .unwrap_or(0.0);
+    let requests = db
+        .prepare(
+            "SELECT method, route, status, COUNT(*) AS queued, SUM(weight) AS n
+             FROM request_events WHERE id <= ?1 GROUP BY method, route, status",
+        )
+        .bind(&[head.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &requests {
+        run.request_events += number(row, "queued") as i64;
+        let status = text(row, "status");
+        counters.push((
+            "pixie_http_requests_total",
+            labels(&[("method", &text(row, "method")), ("route", &text(row, "route")), ("status", &status)]),
+            number(row, "n"),
+        ));
+    }
+    statements.push(db.prepare("DELETE FROM request_events WHERE id <= ?1").bind(&[head.into()])?);
+
+    // Provider calls, from usage_records.
+    let since = cursor("usage_records", db).await?;
+    let bucket_columns = LATENCY_BUCKETS_MS
+        .iter()
+        .map(|b| format!("SUM(CASE WHEN response_time_ms <= {b} THEN 1 ELSE 0 END) AS le_{b}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let latency = db
+        .prepare(format!(
+            "SELECT provider, COUNT(*) AS n, COALESCE(SUM(response_time_ms), 0) AS total_ms, {}
+             FROM usage_records
+             WHERE created_at > ?1 AND created_at <= ?2 AND error IS NULL
+             GROUP BY provider",
+            bucket_columns
+        ))
+        .bind(&[since.clone().into(), cutoff.clone().into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &latency {
+        let provider = text(row, "provider");
+        run.usage_records += number(row, "n") as i64;
+        for bound in LATENCY_BUCKETS_MS {
+            counters.push((
+                "pixie_provider_latency_ms_bucket",
+                labels(&[("provider", &provider), ("le", &bound.to_string())]),
+                number(row, &format!("le_{}", bound)),
+            ));
+        }
+        counters.push((
+            "pixie_provider_latency_ms_bucket",
+            labels(&[("provider", &provider), ("le", "+Inf")]),
+            number(row, "n"),
+        ));
+        counters.push(("pixie_provider_latency_ms_sum", labels(&[("provider", &provider)]), number(row, "total_ms")));
+        counters.push(("pixie_provider_latency_ms_count", labels(&[("provider", &provider)]), number(row, "n")));
+    }
+
+    let errors = db
+        .prepare(format!(
+            "SELECT provider, {} AS error_type, COUNT(*) AS n
+             FROM usage_records
+             WHERE created_at > ?1 AND created_at <= ?2 AND error IS NOT NULL
+             GROUP BY provider, error_type",
+            ERROR_TYPE_SQL
+        ))
+        .bind(&[since.clone().into(), cutoff.clone().into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &errors {
+        run.usage_records += number(row, "n") as i64;
+        counters.push((
+            "pixie_provider_errors_total",
+            labels(&[("provider", &text(row, "provider")), ("error_type", &text(row, "error_type"))]),
+            number(row, "n"),
+        ));
+    }
+
+    // Realtime rows carry the up-front reservation; a settle that bills a
+    // different amount after the row was rolled up isn't reflected here.
+    let credits = db
+        .prepare(
+            "SELECT app_id, capability, COALESCE(SUM(credits_charged), 0) AS credits
+             FROM usage_records
+             WHERE created_at > ?1 AND created_at <= ?2
+             GROUP BY app_id, capability",
+        )
+        .bind(&[since.into(), cutoff.clone().into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &credits {
+        counters.push((
+            "pixie_credits_deducted_total",
+            labels(&[("app_id", &text(row, "app_id")), ("capability", &text(row, "capability"))]),
+            number(row, "credits"),
+        ));
+    }
+    statements.push(set_cursor("usage_records", &cutoff, &updated_at, db)?);
+
+    // Purchases, by when they completed. Ones refunded since still count.
+    let since = cursor("credit_purchases", db).await?;
+    let purchases = db
+        .prepare(
+            "SELECT app_id, payment_provider, COUNT(*) AS n, COALESCE(SUM(amount_usd_cents), 0) AS cents
+             FROM credit_purchases
+             WHERE completed_at > ?1 AND completed_at <= ?2 AND status IN ('completed', 'refunded')
+             GROUP BY app_id, payment_provider",
+        )
+        .bind(&[since.into(), cutoff.clone().into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &purchases {
+        run.purchases += number(row, "n") as i64;
+        let set = labels(&[("app_id", &text(row, "app_id")), ("payment_provider", &text(row, "payment_provider"))]);
+        counters.push(("pixie_purchases_completed_total", set.clone(), number(row, "n")));
+        counters.push(("pixie_purchase_revenue_usd_cents_total", set, number(row, "cents")));
+    }
+    statements.push(set_cursor("credit_purchases", &cutoff, &updated_at, db)?);
+
+    // Gauges are a snapshot of now.
+    let locks = db
+        .prepare("SELECT app_id, COUNT(*) AS n FROM user_locks GROUP BY app_id")
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &locks {
+        gauges.push(("pixie_user_locks_open", labels(&[("app_id", &text(row, "app_id"))]), number(row, "n")));
+    }
+    let sessions = db
+        .prepare(
+            "SELECT app_id, COUNT(*) AS n, COALESCE(SUM(reserved_credits), 0) AS credits
+             FROM realtime_sessions WHERE settled = 0 GROUP BY app_id",
+        )
+        .all()
+        .await?
+        .results::<Value>()?;
+    for row in &sessions {
+        let set = labels(&[("app_id", &text(row, "app_id"))]);
+        gauges.push(("pixie_realtime_sessions_unsettled", set.clone(), number(row, "n")));
+        gauges.push(("pixie_realtime_reserved_credits_unsettled", set, number(row, "credits")));
+    }
+    gauges.push(("pixie_metrics_rollup_timestamp_seconds", String::new(), now.timestamp() as f64));
+
+    for (name, set, value) in counters {
+        statements.push(
+            db.prepare(
+                "INSERT INTO metrics_counters (name, labels, value, updated_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(name, labels) DO UPDATE SET value = metrics_counters.value + excluded.value,
+                                                        updated_at = excluded.updated_at",
+            )
+            .bind(&[name.into(), set.into(), value.into(), updated_at.clone().into()])?,
+        );
+    }
+    statements.push(db.prepare("DELETE FROM metrics_gauges"));
+    for (name, set, value) in gauges {
+        statements.push(
+            db.prepare("INSERT INTO metrics_gauges (name, labels, value, updated_at) VALUES (?1, ?2, ?3, ?4)")
+                .bind(&[name.into(), set.into(), value.into(), updated_at.clone().into()])?,
+        );
+    }
+    db.batch(statements).await?;
+    Ok(run)
+}
+
+/// The `le` bound of a histogram bucket's label set, for ordering.
+fn le_bound(set: &str) -> f64 {
+    set.split(',')
+        .find_map(|pair| pair.strip_prefix("le=\""))
+        .map(|v| v.trim_end_matches('"'))
+        .map(|v| if v == "+Inf" { f64::INFINITY } else { v.parse().unwrap_or(0.0) })
+        .unwrap_or(0.0)
+}
+
+/// Renders series in the Prometheus text exposition format.
+pub fn render_samples(samples: &[(String, String, f64)]) -> String {
+    let mut out = String::new();
+    for (family, kind, help) in FAMILIES {
+        let names: Vec<String> = if *kind == "histogram" {
+            vec![format!("{}_bucket", family), format!("{}_sum", family), format!("{}_count", family)]
+        } else {
+            vec![family.to_string()]
+        };
+        let mut series: Vec<&(String, String, f64)> = samples.iter().filter(|(name, _, _)| names.contains(name)).collect();
+        // Buckets of one label set together and in `le` order, then its sum and count.
+        series.sort_by(|a, b| {
+            let base = |s: &str| s.split(',').filter(|p| !p.starts_with("le=")).collect::<Vec<_>>().join(",");
+            let rank = |n: &str| names.iter().position(|x| x == n).unwrap_or(0);
+            base(&a.1)
+                .cmp(&base(&b.1))
+                .then(rank(&a.0).cmp(&rank(&b.0)))
+                .then(le_bound(&a.1).partial_cmp(&le_bound(&b.1)).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", family, help, family, kind));
+        for (name, set, value) in series {
+            if set.is_empty() {
+                out.push_str(&format!("{} {}\n", name, value));
+            } else {
+                out.push_str(&format!("{}{{{}}} {}\n", name, set, value));
+            }
+        }
+    }
+    out
+}
+
+/// The current rollup, ready to serve.
+pub async fn render(db: &D1Database) -> Result<String, AppError> {
+    let rows = db
+        .prepare(
+            "SELECT name, labels, value FROM metrics_counters
+             UNION ALL
+             SELECT name, labels, value FROM metrics_gauges",
+        )
+        .all()
+        .await?
+        .results::<Value>()?;
+    let samples: Vec<(String, String, f64)> = rows
+        .iter()
+        .map(|row| (text(row, "name"), text(row, "labels"), number(row, "value")))
+        .collect();
+    Ok(render_samples(&samples))
+}
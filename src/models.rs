@@ -35,12 +35,18 @@ pub struct StoredImage {
     pub expires_at: DateTime<Utc>,
 }
 
+/// A row of `refresh_tokens`. Tokens rotate on every use; all tokens issued
+/// from one sign-in share a `family_id`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RefreshToken {
     pub id: String,
+    pub app_id: String,
     pub user_id: String,
-    pub token: String,
+    pub family_id: String,
+    pub token_hash: String,
     pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -162,11 +168,18 @@ pub struct ErrorDetail {
     pub error_type: String,
     pub param: Option<String>,
     pub code: Option<String>,
+    /// The `X-Request-ID` of the failed request, to quote when reporting it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
+/// Payload of an access token. `sub` is the user id, `app` the tenant and
+/// `sid` the refresh-token family the token was issued from.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    pub app: String,
+    pub sid: String,
     pub exp: u64,
     pub iat: u64,
 }
@@ -175,6 +188,9 @@ pub struct Claims {
 pub struct OAuthTokenResponse {
     pub access_token: String,
     pub refresh_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires.
+    pub expires_in: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,437 @@
+use worker::{D1Database, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{Datelike, TimeZone, Utc};
+use uuid::Uuid;
+use crate::credits::{CreditTransaction, Payer};
+use crate::error::AppError;
+
+pub const ORG_ROLES: &[&str] = &["owner", "admin", "member"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Org {
+    pub id: String,
+    pub app_id: String,
+    pub name: String,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgMember {
+    pub user_id: String,
+    pub role: String,
+    pub monthly_spend_cap: Option<i32>,
+    pub joined_at: String,
+    /// Credits spent from the org wallet this calendar month, open holds included.
+    #[serde(default)]
+    pub spent_this_month: i32,
+}
+
+impl OrgMember {
+    pub fn can_manage(&self) -> bool {
+        self.role == "owner" || self.role == "admin"
+    }
+
+    /// What the member may still spend this month; None when uncapped. Negative
+    /// when a lowered cap is already overspent.
+    pub fn remaining_spend_cap(&self) -> Option<i32> {
+        self.monthly_spend_cap.map(|cap| cap - self.spent_this_month)
+    }
+}
+
+/// An org as seen by one of its members.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgMembership {
+    pub id: String,
+    pub name: String,
+    pub role: String,
+    pub balance: i32,
+    pub created_at: String,
+}
+
+/// Picks the wallet a metered call charges. Without `X-Org-ID` that's the
+/// caller's own; with it, the org's, provided the caller is a member.
+pub async fn resolve_payer(
+    req: &Request,
+    app_id: &str,
+    user_id: &str,
+    db: &D1Database,
+) -> Result<Payer, AppError> {
+    let org_id = req
+        .headers()
+        .get("X-Org-ID")
+        .ok()
+        .flatten()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let Some(org_id) = org_id else {
+        return Ok(Payer::user(user_id));
+    };
+    get_member(app_id, &org_id, user_id, db)
+        .await?
+        .ok_or_else(|| AppError::Forbidden("You are not a member of this organisation".to_string()))?;
+    Ok(Payer::org(&org_id, user_id))
+}
+
+/// Fails with 402 when charging `amount` to an org wallet would take the
+/// member past their monthly cap. Personal wallets have no cap. This is the
+/// up-front check that gives a precise error; the charge itself re-checks
+/// atomically with `within_spend_cap_sql`, so concurrent calls can't overshoot.
+pub async fn ensure_within_spend_cap(
+    app_id: &str,
+    payer: &Payer,
+    amount: u32,
+    db: &D1Database,
+) -> Result<(), AppError> {
+    check_spend_cap(remaining_spend_cap(app_id, payer, db).await?, amount)
+}
+
+/// Fails with 402 when `amount` exceeds what's `remaining` of a cap.
+fn check_spend_cap(remaining: Option<i32>, amount: u32) -> Result<(), AppError> {
+    match remaining {
+        Some(remaining) if remaining < amount as i32 => Err(AppError::PaymentRequired(format!(
+            "Monthly organisation spending cap reached. Need {} credits, {} left this month",
+            amount,
+            remaining.max(0)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// What the member may still spend from the org wallet this month; None when
+/// there is no cap (or the payer is a personal wallet).
+pub async fn remaining_spend_cap(app_id: &str, payer: &Payer, db: &D1Database) -> Result<Option<i32>, AppError> {
+    let (Some(org_id), Some(member_id)) = (payer.org_id(), payer.member_id.as_deref()) else {
+        return Ok(None);
+    };
+    Ok(get_member(app_id, org_id, member_id, db)
+        .await?
+        .and_then(|m| m.remaining_spend_cap()))
+}
+
+/// SQL condition that holds unless charging the amount would take the payer's
+/// member past their monthly cap, for the WHERE clause of the statement that
+/// makes a charge. Binds `spend_cap_params` at positions `first`..`first + 4`.
+/// True for personal wallets and uncapped members.
+pub fn within_spend_cap_sql(first: usize) -> String {
+    format!(
+        "NOT EXISTS (SELECT 1 FROM org_members m
+                     WHERE m.app_id = ?{0} AND m.org_id = ?{1} AND m.user_id = ?{2}
+                       AND m.monthly_spend_cap IS NOT NULL AND m.monthly_spend_cap - ({4}) < ?{3})",
+        first,
+        first + 1,
+        first + 2,
+        first + 3,
+        spent_since_sql(&format!("?{}", first + 4))
+    )
+}
+
+/// Parameters for `within_spend_cap_sql`, in order.
+pub fn spend_cap_params(app_id: &str, payer: &Payer, amount: u32) -> Vec<worker::wasm_bindgen::JsValue> {
+    vec![
+        app_id.into(),
+        payer.wallet_id.clone().into(),
+        payer
+            .member_id
+            .clone()
+            .map(|m| m.into())
+            .unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        amount.into(),
+        month_start().into(),
+    ]
+}
+
+fn month_start() -> String {
+    let now = Utc::now();
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+        .to_rfc3339()
+}
+
+/// Spend minus refunds charged to the org wallet by member `m` since the bound
+/// parameter, plus what the member still has escrowed in open holds.
+fn spent_since_sql(since_param: &str) -> String {
+    format!(
+        "(SELECT -COALESCE(SUM(t.amount), 0) FROM credit_transactions t
+          WHERE t.app_id = m.app_id AND t.user_id = m.org_id AND t.member_id = m.user_id
+            AND t.type IN ('spend', 'refund') AND t.created_at >= {0})
+         + (SELECT COALESCE(SUM(h.amount), 0) FROM credit_holds h
+            WHERE h.app_id = m.app_id AND h.user_id = m.org_id AND h.member_id = m.user_id AND h.status = 'held')",
+        since_param
+    )
+}
+
+pub async fn get_member(app_id: &str, org_id: &str, user_id: &str, db: &D1Database) -> Result<Option<OrgMember>, AppError> {
+    let row = db
+        .prepare(format!(
+            "SELECT m.user_id, m.role, m.monthly_spend_cap, m.joined_at, {} AS spent_this_month
+             FROM org_members m WHERE m.app_id = ?1 AND m.org_id = ?2 AND m.user_id = ?3",
+            spent_since_sql("?4")
+        ))
+        .bind(&[app_id.into(), org_id.into(), user_id.into(), month_start().into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<OrgMember>(r).ok()))
+}
+
+pub async fn list_members(app_id: &str, org_id: &str, db: &D1Database) -> Result<Vec<OrgMember>, AppError> {
+    let rows = db
+        .prepare(format!(
+            "SELECT m.user_id, m.role, m.monthly_spend_cap, m.joined_at, {} AS spent_this_month
+             FROM org_members m WHERE m.app_id = ?1 AND m.org_id = ?2 ORDER BY m.joined_at",
+            spent_since_sql("?3")
+        ))
+        .bind(&[app_id.into(), org_id.into(), month_start().into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<OrgMember>(r).ok())
+        .collect())
+}
+
+/// Caller must belong to the org. Non-members get a 404 so org ids can't be probed.
+pub async fn require_member(app_id: &str, org_id: &str, user_id: &str, db: &D1Database) -> Result<OrgMember, AppError> {
+    get_member(app_id, org_id, user_id, db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Organisation not found".to_string()))
+}
+
+/// Caller must be an owner or admin of the org.
+pub async fn require_manager(app_id: &str, org_id: &str, user_id: &str, db: &D1Database) -> Result<OrgMember, AppError> {
+    let member = require_member(app_id, org_id, user_id, db).await?;
+    if !member.can_manage() {
+        return Err(AppError::Forbidden("Organisation owner or admin access required".to_string()));
+    }
+    Ok(member)
+}
+
+/// Creates the org, its (empty) wallet and the creator's owner membership in
+/// one batch.
+pub async fn create_org(app_id: &str, user_id: &str, name: &str, db: &D1Database) -> Result<Org, AppError> {
+    let org = Org {
+        id: format!("org_{}", Uuid::new_v4().simple()),
+        app_id: app_id.to_string(),
+        name: name.to_string(),
+        created_by: user_id.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    db.batch(vec![
+        db.prepare("INSERT INTO orgs (id, app_id, name, created_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .bind(&[
+                org.id.clone().into(),
+                app_id.into(),
+                name.into(),
+                user_id.into(),
+                org.created_at.clone().into(),
+            ])?,
+        db.prepare(
+            "INSERT INTO org_members (org_id, app_id, user_id, role, joined_at) VALUES (?1, ?2, ?3, 'owner', ?4)",
+        )
+        .bind(&[org.id.clone().into(), app_id.into(), user_id.into(), org.created_at.clone().into()])?,
+        db.prepare(
+            "INSERT INTO user_credits (app_id, user_id, balance, lifetime_purchased, lifetime_spent, created_at, updated_at)
+             VALUES (?1, ?2, 0, 0, 0, ?3, ?3)",
+        )
+        .bind(&[app_id.into(), org.id.clone().into(), org.created_at.clone().into()])?,
+    ])
+    .await?;
+    worker::console_log!("Org {} created in {} by {}", org.id, app_id, user_id);
+    Ok(org)
+}
+
+pub async fn get_org(app_id: &str, org_id: &str, db: &D1Database) -> Result<Option<Org>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM orgs WHERE app_id = ?1 AND id = ?2")
+        .bind(&[app_id.into(), org_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<Org>(r).ok()))
+}
+
+pub async fn list_user_orgs(app_id: &str, user_id: &str, db: &D1Database) -> Result<Vec<OrgMembership>, AppError> {
+    let rows = db
+        .prepare(
+            "SELECT o.id, o.name, m.role, COALESCE(uc.balance, 0) AS balance, o.created_at
+             FROM org_members m
+             JOIN orgs o ON o.id = m.org_id
+             LEFT JOIN user_credits uc ON uc.app_id = o.app_id AND uc.user_id = o.id
+             WHERE m.app_id = ?1 AND m.user_id = ?2
+             ORDER BY o.created_at",
+        )
+        .bind(&[app_id.into(), user_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<OrgMembership>(r).ok())
+        .collect())
+}
+
+pub async fn add_member(
+    app_id: &str,
+    org_id: &str,
+    user_id: &str,
+    role: &str,
+    monthly_spend_cap: Option<i32>,
+    db: &D1Database,
+) -> Result<(), AppError> {
+    let result = db
+        .prepare(
+            "INSERT INTO org_members (org_id, app_id, user_id, role, monthly_spend_cap, joined_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6) ON CONFLICT(org_id, user_id) DO NOTHING",
+        )
+        .bind(&[
+            org_id.into(),
+            app_id.into(),
+            user_id.into(),
+            role.into(),
+            monthly_spend_cap.map(|c| c.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            Utc::now().to_rfc3339().into(),
+        ])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::Conflict("User is already a member of this organisation".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn update_member(
+    app_id: &str,
+    org_id: &str,
+    user_id: &str,
+    role: &str,
+    monthly_spend_cap: Option<i32>,
+    db: &D1Database,
+) -> Result<(), AppError> {
+    db.prepare(
+        "UPDATE org_members SET role = ?1, monthly_spend_cap = ?2
+         WHERE app_id = ?3 AND org_id = ?4 AND user_id = ?5",
+    )
+    .bind(&[
+        role.into(),
+        monthly_spend_cap.map(|c| c.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        app_id.into(),
+        org_id.into(),
+        user_id.into(),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_member(app_id: &str, org_id: &str, user_id: &str, db: &D1Database) -> Result<(), AppError> {
+    db.prepare("DELETE FROM org_members WHERE app_id = ?1 AND org_id = ?2 AND user_id = ?3")
+        .bind(&[app_id.into(), org_id.into(), user_id.into()])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+pub async fn count_owners(app_id: &str, org_id: &str, db: &D1Database) -> Result<i32, AppError> {
+    let row = db
+        .prepare("SELECT COUNT(*) AS n FROM org_members WHERE app_id = ?1 AND org_id = ?2 AND role = 'owner'")
+        .bind(&[app_id.into(), org_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| r.get("n").and_then(|v| v.as_i64())).unwrap_or(0) as i32)
+}
+
+/// The org wallet's ledger, newest first, optionally narrowed to one member.
+/// Returns the page and the total row count.
+pub async fn list_org_transactions(
+    app_id: &str,
+    org_id: &str,
+    member_id: Option<&str>,
+    limit: i32,
+    offset: i32,
+    db: &D1Database,
+) -> Result<(Vec<CreditTransaction>, i32), AppError> {
+    let member = member_id.map(|m| m.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    let filter = "app_id = ?1 AND user_id = ?2 AND (?3 IS NULL OR member_id = ?3)";
+    let total = db
+        .prepare(format!("SELECT COUNT(*) AS total FROM credit_transactions WHERE {}", filter))
+        .bind(&[app_id.into(), org_id.into(), member.clone()])?
+        .first::<Value>(None)
+        .await?
+        .and_then(|r| r.get("total").and_then(|v| v.as_i64()))
+        .unwrap_or(0) as i32;
+    let rows = db
+        .prepare(format!(
+            "SELECT * FROM credit_transactions WHERE {} ORDER BY created_at DESC LIMIT ?4 OFFSET ?5",
+            filter
+        ))
+        .bind(&[app_id.into(), org_id.into(), member, limit.into(), offset.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    let transactions = rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<CreditTransaction>(r).ok())
+        .collect();
+    Ok((transactions, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(cap: Option<i32>, spent: i32) -> OrgMember {
+        OrgMember {
+            user_id: "u_1".to_string(),
+            role: "member".to_string(),
+            monthly_spend_cap: cap,
+            joined_at: "2026-01-01T00:00:00+00:00".to_string(),
+            spent_this_month: spent,
+        }
+    }
+
+    #[test]
+    fn test_remaining_spend_cap() {
+        assert_eq!(member(None, 500).remaining_spend_cap(), None);
+        assert_eq!(member(Some(100), 30).remaining_spend_cap(), Some(70));
+        // A cap lowered below this month's spend leaves a negative remainder.
+        assert_eq!(member(Some(100), 130).remaining_spend_cap(), Some(-30));
+    }
+
+    #[test]
+    fn test_check_spend_cap() {
+        assert!(check_spend_cap(None, 1_000).is_ok());
+        assert!(check_spend_cap(Some(70), 70).is_ok());
+        match check_spend_cap(Some(70), 71) {
+            Err(AppError::PaymentRequired(msg)) => assert!(msg.contains("Need 71 credits, 70 left"), "{}", msg),
+            other => panic!("expected PaymentRequired, got {:?}", other),
+        }
+        match check_spend_cap(member(Some(100), 130).remaining_spend_cap(), 1) {
+            Err(AppError::PaymentRequired(msg)) => assert!(msg.contains("0 left"), "{}", msg),
+            other => panic!("expected PaymentRequired, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_within_spend_cap_sql_placeholders() {
+        let sql = within_spend_cap_sql(10);
+        for n in 10..=14 {
+            assert!(sql.contains(&format!("?{}", n)), "missing ?{}", n);
+        }
+        assert!(!sql.contains("?15"));
+        // The cap is compared against the amount (?13) after the month's spend
+        // since ?14 is taken off.
+        assert!(sql.contains("m.monthly_spend_cap - ((SELECT"));
+        assert!(sql.contains("< ?13"));
+        assert!(sql.contains("t.created_at >= ?14"));
+    }
+
+    #[test]
+    fn test_month_start() {
+        let start = chrono::DateTime::parse_from_rfc3339(&month_start()).unwrap().with_timezone(&Utc);
+        let now = Utc::now();
+        assert_eq!((start.year(), start.month(), start.day()), (now.year(), now.month(), 1));
+        assert_eq!(start.time(), chrono::NaiveTime::MIN);
+    }
+}
@@ -0,0 +1,198 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromoCode {
+    pub app_id: String,
+    pub code: String,
+    pub credits: i32,
+    pub max_redemptions: Option<i32>,
+    pub per_user_limit: i32,
+    pub redemption_count: i32,
+    pub valid_from: Option<String>,
+    pub valid_until: Option<String>,
+    pub pack_discount_percent: Option<i32>,
+    pub discount_pack_id: Option<String>,
+    pub credit_ttl_days: Option<i32>,
+    pub enabled: i32,
+    pub created_at: String,
+}
+
+impl PromoCode {
+    /// Price of a pack after this code's discount, or None if the code carries
+    /// no discount for `pack_id`.
+    pub fn discounted_price_cents(&self, pack_id: &str, price_usd_cents: i32) -> Option<i32> {
+        let percent = self.pack_discount_percent?;
+        if let Some(only) = &self.discount_pack_id {
+            if only != pack_id {
+                return None;
+            }
+        }
+        Some((price_usd_cents * (100 - percent) / 100).max(1))
+    }
+
+    /// Expiry for credits granted by this code; falls back to the tenant default.
+    pub fn credit_expiry(&self, app_row: Option<&serde_json::Value>) -> Option<chrono::DateTime<Utc>> {
+        match self.credit_ttl_days {
+            Some(days) if days > 0 => Some(Utc::now() + Duration::days(days as i64)),
+            _ => crate::credits::promo_expiry(app_row),
+        }
+    }
+}
+
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_ascii_uppercase()
+}
+
+pub async fn get_promo_code(app_id: &str, code: &str, db: &D1Database) -> Result<Option<PromoCode>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM promo_codes WHERE app_id = ?1 AND code = ?2")
+        .bind(&[app_id.into(), normalize_code(code).into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<PromoCode>(r).ok()))
+}
+
+/// Looks up a code that can be redeemed right now. Unknown, disabled and
+/// out-of-window codes all read the same so codes can't be probed for state.
+pub async fn find_redeemable(app_id: &str, code: &str, db: &D1Database) -> Result<PromoCode, AppError> {
+    let invalid = || AppError::BadRequest("Invalid or expired promo code".to_string());
+    let promo = get_promo_code(app_id, code, db).await?.ok_or_else(invalid)?;
+
+    let now = Utc::now().to_rfc3339();
+    let started = promo.valid_from.as_deref().map(|f| f <= now.as_str()).unwrap_or(true);
+    let ended = promo.valid_until.as_deref().map(|u| u < now.as_str()).unwrap_or(false);
+    if promo.enabled == 0 || !started || ended {
+        return Err(invalid());
+    }
+    Ok(promo)
+}
+
+/// Refuses a user who has used up their discount redemptions of `promo`, or a
+/// code that has reached its global cap, without claiming anything. For
+/// checkout, where the code is only claimed once the purchase completes.
+pub async fn ensure_redeemable_by(promo: &PromoCode, user_id: &str, db: &D1Database) -> Result<(), AppError> {
+    let used = db
+        .prepare(
+            "SELECT COUNT(*) AS n FROM promo_redemptions
+             WHERE app_id = ?1 AND code = ?2 AND user_id = ?3 AND purchase_id IS NOT NULL",
+        )
+        .bind(&[promo.app_id.clone().into(), promo.code.clone().into(), user_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("n").and_then(|n| n.as_i64()))
+        .unwrap_or(0);
+    if used >= promo.per_user_limit.max(1) as i64 {
+        return Err(AppError::Conflict("Promo code already redeemed".to_string()));
+    }
+    if promo.max_redemptions.is_some_and(|max| promo.redemption_count >= max) {
+        return Err(AppError::Conflict("Promo code has reached its redemption limit".to_string()));
+    }
+    Ok(())
+}
+
+/// Records one redemption of `promo` by `user_id`: a credit grant when
+/// `purchase_id` is None, otherwise a pack discount applied to that purchase
+/// (at most once per purchase). `per_user_limit` applies to each kind on its
+/// own, so redeeming a code's credits doesn't use up its discount. Both caps
+/// are enforced by conditional writes — the insert only lands while the user
+/// is under `per_user_limit` for that kind, and the increment only while the
+/// code is under `max_redemptions` — so concurrent redeemers can never exceed
+/// either. Returns the redemption id, which
+/// `release_redemption` takes if the grant that follows fails.
+pub async fn claim_redemption(
+    promo: &PromoCode,
+    user_id: &str,
+    purchase_id: Option<&str>,
+    db: &D1Database,
+) -> Result<String, AppError> {
+    let redemption_id = Uuid::new_v4().to_string();
+    let credits_granted = if purchase_id.is_some() { 0 } else { promo.credits.max(0) };
+    let insert = db
+        .prepare(
+            "INSERT INTO promo_redemptions (id, app_id, code, user_id, credits_granted, purchase_id, created_at)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7
+             WHERE (SELECT COUNT(*) FROM promo_redemptions
+                    WHERE app_id = ?2 AND code = ?3 AND user_id = ?4 AND (purchase_id IS NULL) = (?6 IS NULL)) < ?8
+               AND (?6 IS NULL OR NOT EXISTS (SELECT 1 FROM promo_redemptions WHERE app_id = ?2 AND purchase_id = ?6))",
+        )
+        .bind(&[
+            redemption_id.clone().into(),
+            promo.app_id.clone().into(),
+            promo.code.clone().into(),
+            user_id.into(),
+            credits_granted.into(),
+            purchase_id.map(|p| p.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            Utc::now().to_rfc3339().into(),
+            promo.per_user_limit.max(1).into(),
+        ])?
+        .run()
+        .await?;
+    let inserted = insert.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0;
+    if !inserted {
+        return Err(AppError::Conflict("Promo code already redeemed".to_string()));
+    }
+
+    let claim = db
+        .prepare(
+            "UPDATE promo_codes SET redemption_count = redemption_count + 1
+             WHERE app_id = ?1 AND code = ?2 AND enabled = 1
+               AND (max_redemptions IS NULL OR redemption_count < max_redemptions)",
+        )
+        .bind(&[promo.app_id.clone().into(), promo.code.clone().into()])?
+        .run()
+        .await;
+    let won = match &claim {
+        Ok(result) => result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) > 0,
+        Err(_) => false,
+    };
+    if !won {
+        delete_redemption(&redemption_id, db).await;
+        claim?;
+        return Err(AppError::Conflict("Promo code has reached its redemption limit".to_string()));
+    }
+    Ok(redemption_id)
+}
+
+/// Gives back a redemption whose grant failed, so the code isn't used up.
+pub async fn release_redemption(promo: &PromoCode, redemption_id: &str, db: &D1Database) -> Result<(), AppError> {
+    db.batch(vec![
+        db.prepare("DELETE FROM promo_redemptions WHERE id = ?1")
+            .bind(&[redemption_id.into()])?,
+        db.prepare(
+            "UPDATE promo_codes SET redemption_count = MAX(redemption_count - 1, 0) WHERE app_id = ?1 AND code = ?2",
+        )
+        .bind(&[promo.app_id.clone().into(), promo.code.clone().into()])?,
+    ])
+    .await?;
+    Ok(())
+}
+
+/// Claims the discount code a completed purchase was checked out with. Payment
+/// has already gone through at the discounted price, so the code is looked up
+/// regardless of whether it is still within its window.
+pub async fn claim_for_purchase(
+    app_id: &str,
+    code: &str,
+    user_id: &str,
+    purchase_id: &str,
+    db: &D1Database,
+) -> Result<(), AppError> {
+    let promo = get_promo_code(app_id, code, db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Promo code {} not found", code)))?;
+    claim_redemption(&promo, user_id, Some(purchase_id), db).await?;
+    Ok(())
+}
+
+async fn delete_redemption(redemption_id: &str, db: &D1Database) {
+    if let Ok(stmt) = db
+        .prepare("DELETE FROM promo_redemptions WHERE id = ?1")
+        .bind(&[redemption_id.into()])
+    {
+        let _ = stmt.run().await;
+    }
+}
@@ -0,0 +1,306 @@
+use worker::{D1Database, D1PreparedStatement, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::Utc;
+use uuid::Uuid;
+use crate::error::AppError;
+
+/// The daily cron trigger (wrangler.toml) that runs the reconciliation; the
+/// half-hourly sweeps share the scheduled handler.
+pub const RECONCILE_CRON: &str = "0 4 * * *";
+
+/// Findings kept per check per run. A systemic bug can break every wallet at
+/// once; the first page is enough to act on and keeps the run inside the
+/// scheduled handler's limits.
+const MAX_FINDINGS_PER_CHECK: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconciliationRun {
+    pub id: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub status: String,
+    pub wallets_checked: i64,
+    pub transactions_checked: i64,
+    pub discrepancies: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerDiscrepancy {
+    pub id: String,
+    pub app_id: String,
+    pub user_id: String,
+    pub kind: String,
+    pub expected: i64,
+    pub actual: i64,
+    pub transaction_id: Option<String>,
+    pub detected_at: String,
+    pub last_seen_at: String,
+    pub resolved_at: Option<String>,
+    pub resolution_note: Option<String>,
+}
+
+struct Finding {
+    app_id: String,
+    user_id: String,
+    kind: &'static str,
+    expected: i64,
+    actual: i64,
+    transaction_id: Option<String>,
+}
+
+impl Finding {
+    fn fingerprint(&self) -> String {
+        match &self.transaction_id {
+            Some(tx) => format!("{}:{}:{}:{}", self.kind, self.app_id, self.user_id, tx),
+            None => format!("{}:{}:{}", self.kind, self.app_id, self.user_id),
+        }
+    }
+}
+
+/// Compares every wallet in every tenant against its ledger and records what
+/// doesn't add up. Wallet writes that aren't batched with their ledger row can
+/// be caught mid-flight; such a finding goes away on the next run, which
+/// resolves anything it no longer sees.
+pub async fn reconcile_ledger(db: &D1Database) -> Result<ReconciliationRun> {
+    let run_id = Uuid::new_v4().to_string();
+    let started_at = Utc::now().to_rfc3339();
+    db.prepare("INSERT INTO ledger_reconciliation_runs (id, started_at, status) VALUES (?1, ?2, 'running')")
+        .bind(&[run_id.clone().into(), started_at.clone().into()])?
+        .run()
+        .await?;
+
+    match check_ledger(&run_id, db).await {
+        Ok((wallets_checked, transactions_checked, discrepancies)) => {
+            let finished_at = Utc::now().to_rfc3339();
+            db.prepare(
+                "UPDATE ledger_reconciliation_runs
+                 SET status = 'completed', finished_at = ?1, wallets_checked = ?2, transactions_checked = ?3, discrepancies = ?4
+                 WHERE id = ?5",
+            )
+            .bind(&[
+                finished_at.clone().into(),
+                (wallets_checked as i32).into(),
+                (transactions_checked as i32).into(),
+                (discrepancies as i32).into(),
+                run_id.clone().into(),
+            ])?
+            .run()
+            .await?;
+            Ok(ReconciliationRun {
+                id: run_id,
+                started_at,
+                finished_at: Some(finished_at),
+                status: "completed".to_string(),
+                wallets_checked,
+                transactions_checked,
+                discrepancies,
+            })
+        }
+        Err(e) => {
+            let _ = db
+                .prepare("UPDATE ledger_reconciliation_runs SET status = 'failed', finished_at = ?1, error = ?2 WHERE id = ?3")
+                .bind(&[Utc::now().to_rfc3339().into(), e.to_string().into(), run_id.into()])?
+                .run()
+                .await;
+            Err(e)
+        }
+    }
+}
+
+/// Returns (wallets checked, ledger entries checked, open discrepancies seen).
+async fn check_ledger(run_id: &str, db: &D1Database) -> Result<(i64, i64, i64)> {
+    let count = |table: &'static str| async move {
+        db.prepare(format!("SELECT COUNT(*) AS n FROM {}", table))
+            .first::<Value>(None)
+            .await
+            .map(|r| r.and_then(|v| v.get("n").and_then(|n| n.as_i64())).unwrap_or(0))
+    };
+    let wallets_checked = count("user_credits").await?;
+    let transactions_checked = count("credit_transactions").await?;
+
+    let mut findings = Vec::new();
+    let mut truncated = false;
+
+    let wallets = db
+        .prepare(format!(
+            "SELECT uc.app_id, uc.user_id, uc.balance, uc.lifetime_purchased, uc.lifetime_spent,
+                    COALESCE(l.balance, 0) AS ledger_balance,
+                    COALESCE(l.purchased, 0) AS ledger_purchased,
+                    COALESCE(l.spent, 0) AS ledger_spent
+             FROM user_credits uc
+             LEFT JOIN (
+                 SELECT app_id, user_id,
+                        SUM(amount) AS balance,
+                        MAX(SUM(CASE WHEN type IN ('purchase', 'refund_clawback') THEN amount ELSE 0 END), 0) AS purchased,
+                        -SUM(CASE WHEN type = 'spend' THEN amount ELSE 0 END) AS spent
+                 FROM credit_transactions GROUP BY app_id, user_id
+             ) l ON l.app_id = uc.app_id AND l.user_id = uc.user_id
+             WHERE uc.balance != COALESCE(l.balance, 0)
+                OR uc.lifetime_purchased != COALESCE(l.purchased, 0)
+                OR uc.lifetime_spent != COALESCE(l.spent, 0)
+             LIMIT {}",
+            MAX_FINDINGS_PER_CHECK
+        ))
+        .all()
+        .await?
+        .results::<Value>()?;
+    truncated |= wallets.len() as i64 >= MAX_FINDINGS_PER_CHECK;
+    for row in &wallets {
+        let text = |key: &str| row.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let int = |key: &str| row.get(key).and_then(|v| v.as_i64()).unwrap_or(0);
+        for (kind, expected, actual) in [
+            ("balance", int("ledger_balance"), int("balance")),
+            ("lifetime_purchased", int("ledger_purchased"), int("lifetime_purchased")),
+            ("lifetime_spent", int("ledger_spent"), int("lifetime_spent")),
+        ] {
+            if expected != actual {
+                findings.push(Finding {
+                    app_id: text("app_id"),
+                    user_id: text("user_id"),
+                    kind,
+                    expected,
+                    actual,
+                    transaction_id: None,
+                });
+            }
+        }
+    }
+
+    // rowid breaks created_at ties in insertion order.
+    let breaks = db
+        .prepare(format!(
+            "SELECT id, app_id, user_id, balance_after, prev_after + amount AS expected FROM (
+                 SELECT id, app_id, user_id, amount, balance_after,
+                        LAG(balance_after, 1, 0) OVER (PARTITION BY app_id, user_id ORDER BY created_at, rowid) AS prev_after
+                 FROM credit_transactions
+             ) WHERE balance_after != prev_after + amount
+             LIMIT {}",
+            MAX_FINDINGS_PER_CHECK
+        ))
+        .all()
+        .await?
+        .results::<Value>()?;
+    truncated |= breaks.len() as i64 >= MAX_FINDINGS_PER_CHECK;
+    for row in &breaks {
+        let text = |key: &str| row.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let int = |key: &str| row.get(key).and_then(|v| v.as_i64()).unwrap_or(0);
+        findings.push(Finding {
+            app_id: text("app_id"),
+            user_id: text("user_id"),
+            kind: "balance_chain",
+            expected: int("expected"),
+            actual: int("balance_after"),
+            transaction_id: Some(text("id")),
+        });
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let mut statements = findings
+        .iter()
+        .map(|f| record_statement(f, run_id, &now, db))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .peekable();
+    while statements.peek().is_some() {
+        db.batch(statements.by_ref().take(50).collect()).await?;
+    }
+
+    // With a truncated run we can't tell "fixed" from "not looked at".
+    if !truncated {
+        db.prepare(
+            "UPDATE ledger_discrepancies SET resolved_at = ?1, resolution_note = 'No longer detected'
+             WHERE resolved_at IS NULL AND last_run_id != ?2",
+        )
+        .bind(&[now.into(), run_id.into()])?
+        .run()
+        .await?;
+    }
+
+    if !findings.is_empty() {
+        worker::console_error!("Ledger reconciliation found {} discrepancies", findings.len());
+    }
+    Ok((wallets_checked, transactions_checked, findings.len() as i64))
+}
+
+fn record_statement(f: &Finding, run_id: &str, now: &str, db: &D1Database) -> Result<D1PreparedStatement> {
+    db.prepare(
+        "INSERT INTO ledger_discrepancies
+             (id, fingerprint, app_id, user_id, kind, expected, actual, transaction_id,
+              first_run_id, last_run_id, detected_at, last_seen_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, ?10, ?10)
+         ON CONFLICT(fingerprint) WHERE resolved_at IS NULL DO UPDATE SET
+             expected = excluded.expected, actual = excluded.actual,
+             last_run_id = excluded.last_run_id, last_seen_at = excluded.last_seen_at",
+    )
+    .bind(&[
+        Uuid::new_v4().to_string().into(),
+        f.fingerprint().into(),
+        f.app_id.clone().into(),
+        f.user_id.clone().into(),
+        f.kind.into(),
+        (f.expected as i32).into(),
+        (f.actual as i32).into(),
+        f.transaction_id.clone().map(|t| t.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+        run_id.into(),
+        now.into(),
+    ])
+}
+
+/// The app's discrepancies, newest first. `status` is "open", "resolved" or "all".
+pub async fn list_discrepancies(
+    app_id: &str,
+    status: &str,
+    limit: i32,
+    offset: i32,
+    db: &D1Database,
+) -> std::result::Result<Vec<LedgerDiscrepancy>, AppError> {
+    let filter = match status {
+        "open" => "AND resolved_at IS NULL",
+        "resolved" => "AND resolved_at IS NOT NULL",
+        "all" => "",
+        other => return Err(AppError::BadRequest(format!("Unknown status '{}'", other))),
+    };
+    let rows = db
+        .prepare(format!(
+            "SELECT * FROM ledger_discrepancies WHERE app_id = ?1 {} ORDER BY detected_at DESC LIMIT ?2 OFFSET ?3",
+            filter
+        ))
+        .bind(&[app_id.into(), limit.into(), offset.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<LedgerDiscrepancy>(r).ok())
+        .collect())
+}
+
+/// Marks an open discrepancy as handled, e.g. after a manual correction.
+pub async fn resolve_discrepancy(
+    app_id: &str,
+    id: &str,
+    note: &str,
+    db: &D1Database,
+) -> std::result::Result<(), AppError> {
+    let result = db
+        .prepare(
+            "UPDATE ledger_discrepancies SET resolved_at = ?1, resolution_note = ?2
+             WHERE id = ?3 AND app_id = ?4 AND resolved_at IS NULL",
+        )
+        .bind(&[Utc::now().to_rfc3339().into(), note.into(), id.into(), app_id.into()])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::NotFound("No open discrepancy with this id".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn latest_run(db: &D1Database) -> std::result::Result<Option<ReconciliationRun>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM ledger_reconciliation_runs ORDER BY started_at DESC LIMIT 1")
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<ReconciliationRun>(r).ok()))
+}
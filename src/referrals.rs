@@ -0,0 +1,408 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use uuid::Uuid;
+use crate::credits::{add_promo_credits, promo_expiry, PromoGrant};
+use crate::error::AppError;
+
+#[derive(Debug, Clone)]
+pub struct ReferralConfig {
+    pub enabled: bool,
+    pub referrer_credits: u32,
+    pub referee_credits: u32,
+    /// The `apps` row the config came from, for `promo_expiry`.
+    app_row: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferralStats {
+    pub code: String,
+    pub enabled: bool,
+    pub referrer_credits: u32,
+    pub referee_credits: u32,
+    pub referred: i64,
+    pub pending: i64,
+    pub rewarded: i64,
+    pub credits_earned: i64,
+}
+
+pub async fn get_referral_config(app_id: &str, db: &D1Database) -> Result<ReferralConfig, AppError> {
+    let row = db
+        .prepare(
+            "SELECT referral_enabled, referral_referrer_credits, referral_referee_credits, promo_credit_ttl_days
+             FROM apps WHERE app_id = ?1",
+        )
+        .bind(&[app_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    Ok(parse_config(row))
+}
+
+/// A missing app row reads as disabled with no rewards; negative rewards as 0.
+fn parse_config(row: Option<serde_json::Value>) -> ReferralConfig {
+    let int = |key: &str| row.as_ref().and_then(|r| r.get(key)).and_then(|v| v.as_i64()).unwrap_or(0);
+    ReferralConfig {
+        enabled: int("referral_enabled") == 1,
+        referrer_credits: int("referral_referrer_credits").max(0) as u32,
+        referee_credits: int("referral_referee_credits").max(0) as u32,
+        app_row: row,
+    }
+}
+
+/// Returns the user's referral code, creating one on first use. Codes are 8
+/// upper-case hex characters; a collision with another user's code just retries.
+pub async fn get_or_create_code(app_id: &str, user_id: &str, db: &D1Database) -> Result<String, AppError> {
+    for _ in 0..3 {
+        if let Some(code) = find_code(app_id, user_id, db).await? {
+            return Ok(code);
+        }
+        let candidate = Uuid::new_v4().simple().to_string()[..8].to_ascii_uppercase();
+        db.prepare(
+            "INSERT INTO referral_codes (app_id, user_id, code, created_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(&[app_id.into(), user_id.into(), candidate.into(), Utc::now().to_rfc3339().into()])?
+        .run()
+        .await?;
+    }
+    find_code(app_id, user_id, db)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Could not allocate a referral code".to_string()))
+}
+
+async fn find_code(app_id: &str, user_id: &str, db: &D1Database) -> Result<Option<String>, AppError> {
+    Ok(db
+        .prepare("SELECT code FROM referral_codes WHERE app_id = ?1 AND user_id = ?2")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("code").and_then(|c| c.as_str()).map(|s| s.to_string())))
+}
+
+/// Records that `referee_id` signed up with `code`. Only called for freshly
+/// created accounts; a user can be referred at most once per app.
+pub async fn attach_referral(app_id: &str, referee_id: &str, code: &str, db: &D1Database) -> Result<(), AppError> {
+    let config = get_referral_config(app_id, db).await?;
+    if !config.enabled {
+        return Err(AppError::BadRequest("Referrals are not enabled for this app".to_string()));
+    }
+
+    let code = crate::promo_codes::normalize_code(code);
+    let referrer_id = db
+        .prepare("SELECT user_id FROM referral_codes WHERE app_id = ?1 AND code = ?2")
+        .bind(&[app_id.into(), code.clone().into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|v| v.get("user_id").and_then(|u| u.as_str()).map(|s| s.to_string()))
+        .ok_or_else(|| AppError::BadRequest("Unknown referral code".to_string()))?;
+    if referrer_id == referee_id {
+        return Err(AppError::BadRequest("Cannot use your own referral code".to_string()));
+    }
+
+    db.prepare(
+        "INSERT INTO referrals (id, app_id, referrer_id, referee_id, code, status, created_at)
+         VALUES (?, ?, ?, ?, ?, 'pending', ?)
+         ON CONFLICT(app_id, referee_id) DO NOTHING",
+    )
+    .bind(&[
+        Uuid::new_v4().to_string().into(),
+        app_id.into(),
+        referrer_id.into(),
+        referee_id.into(),
+        code.into(),
+        Utc::now().to_rfc3339().into(),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+/// Signup hook: attaches `code` if one was supplied. A bad or unusable code is
+/// logged and ignored; it never fails account creation.
+pub async fn attach_on_signup(app_id: &str, user_id: &str, code: Option<&str>, db: &D1Database) {
+    let Some(code) = code.map(str::trim).filter(|c| !c.is_empty()) else {
+        return;
+    };
+    if let Err(e) = attach_referral(app_id, user_id, code, db).await {
+        worker::console_log!("Ignoring referral code {} for {}: {:?}", code, user_id, e);
+    }
+}
+
+/// Pays out a pending referral once the referee completes a purchase. The
+/// conditional status flip makes this safe to call on every completed purchase:
+/// only the first call that finds the referral pending grants anything.
+pub async fn reward_on_purchase(
+    app_id: &str,
+    referee_id: &str,
+    purchase_id: &str,
+    db: &D1Database,
+) -> Result<(), AppError> {
+    let config = get_referral_config(app_id, db).await?;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let claim = db
+        .prepare(
+            "UPDATE referrals SET status = 'rewarded', referrer_credits = ?1, referee_credits = ?2,
+                                  purchase_id = ?3, rewarded_at = ?4
+             WHERE app_id = ?5 AND referee_id = ?6 AND status = 'pending'",
+        )
+        .bind(&[
+            config.referrer_credits.into(),
+            config.referee_credits.into(),
+            purchase_id.into(),
+            Utc::now().to_rfc3339().into(),
+            app_id.into(),
+            referee_id.into(),
+        ])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Ok(());
+    }
+
+    let referral = db
+        .prepare("SELECT id, referrer_id FROM referrals WHERE app_id = ?1 AND referee_id = ?2")
+        .bind(&[app_id.into(), referee_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Referral vanished after claim".to_string()))?;
+    let referral_id = referral.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let referrer_id = referral.get("referrer_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+
+    let expires_at = promo_expiry(config.app_row.as_ref());
+    if config.referrer_credits > 0 {
+        add_promo_credits(
+            &PromoGrant {
+                app_id,
+                user_id: &referrer_id,
+                amount: config.referrer_credits,
+                source: "bonus",
+                description: "Referral reward",
+                reference_id: Some(&referral_id),
+                expires_at,
+            },
+            db,
+        )
+        .await?;
+    }
+    if config.referee_credits > 0 {
+        add_promo_credits(
+            &PromoGrant {
+                app_id,
+                user_id: referee_id,
+                amount: config.referee_credits,
+                source: "bonus",
+                description: "Referral welcome reward",
+                reference_id: Some(&referral_id),
+                expires_at,
+            },
+            db,
+        )
+        .await?;
+    }
+
+    worker::console_log!("Rewarded referral {} ({} -> {})", referral_id, referrer_id, referee_id);
+    Ok(())
+}
+
+/// Takes back both rewards of the referral paid out on `purchase_id`, which
+/// has just been refunded or disputed. Each side's reward bucket is emptied and
+/// the full reward debited with a 'refund_clawback' entry (the balance may go
+/// negative, as for the purchase itself). The referral moves to 'reversed', so
+/// a later purchase can't earn it again. Returns false if nothing was rewarded
+/// for that purchase.
+pub async fn claw_back_on_refund(purchase_id: &str, reason: &str, db: &D1Database) -> Result<bool, AppError> {
+    let Some(referral) = flip_status(purchase_id, "rewarded", "reversed", db).await? else {
+        return Ok(false);
+    };
+    let now = Utc::now().to_rfc3339();
+    let mut statements = Vec::new();
+    for (user_id, amount) in referral.rewards() {
+        statements.push(
+            db.prepare(
+                "UPDATE credit_buckets SET remaining = 0
+                 WHERE app_id = ?1 AND user_id = ?2 AND reference_id = ?3 AND remaining > 0",
+            )
+            .bind(&[referral.app_id.clone().into(), user_id.into(), referral.id.clone().into()])?,
+        );
+        statements.push(
+            db.prepare(
+                "UPDATE user_credits SET balance = balance - ?1, updated_at = ?2 WHERE app_id = ?3 AND user_id = ?4",
+            )
+            .bind(&[amount.into(), now.clone().into(), referral.app_id.clone().into(), user_id.into()])?,
+        );
+        statements.push(
+            db.prepare(
+                "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+                 VALUES (?1, ?2, ?3, 'refund_clawback', ?4, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?5, ?6, ?7, ?8)",
+            )
+            .bind(&[
+                Uuid::new_v4().to_string().into(),
+                referral.app_id.clone().into(),
+                user_id.into(),
+                (-amount).into(),
+                format!("Referral reward clawback ({})", reason).into(),
+                referral.id.clone().into(),
+                now.clone().into(),
+                crate::request_id::value(),
+            ])?,
+        );
+    }
+    if !statements.is_empty() {
+        if let Err(e) = db.batch(statements).await {
+            flip_status(purchase_id, "reversed", "rewarded", db).await?;
+            return Err(e.into());
+        }
+    }
+    worker::console_log!("Reversed referral {} after purchase {} was refunded", referral.id, purchase_id);
+    Ok(true)
+}
+
+/// Re-grants a referral reversed by `claw_back_on_refund` once the merchant
+/// wins the dispute on `purchase_id`.
+pub async fn restore_on_purchase(purchase_id: &str, db: &D1Database) -> Result<bool, AppError> {
+    let Some(referral) = flip_status(purchase_id, "reversed", "rewarded", db).await? else {
+        return Ok(false);
+    };
+    let config = get_referral_config(&referral.app_id, db).await?;
+    let expires_at = promo_expiry(config.app_row.as_ref());
+    for (user_id, amount) in referral.rewards() {
+        add_promo_credits(
+            &PromoGrant {
+                app_id: &referral.app_id,
+                user_id,
+                amount: amount as u32,
+                source: "bonus",
+                description: "Referral reward restored",
+                reference_id: Some(&referral.id),
+                expires_at,
+            },
+            db,
+        )
+        .await?;
+    }
+    Ok(true)
+}
+
+/// A referral paid out on a purchase, as needed to reverse or restore it.
+struct PaidReferral {
+    id: String,
+    app_id: String,
+    referrer_id: String,
+    referee_id: String,
+    referrer_credits: i32,
+    referee_credits: i32,
+}
+
+impl PaidReferral {
+    /// (user, credits) for each side that was actually granted something.
+    fn rewards(&self) -> Vec<(&str, i32)> {
+        [(self.referrer_id.as_str(), self.referrer_credits), (self.referee_id.as_str(), self.referee_credits)]
+            .into_iter()
+            .filter(|(_, amount)| *amount > 0)
+            .collect()
+    }
+}
+
+/// Moves the referral rewarded on `purchase_id` from one status to another,
+/// exactly once. Returns it to the caller that won.
+async fn flip_status(purchase_id: &str, from: &str, to: &str, db: &D1Database) -> Result<Option<PaidReferral>, AppError> {
+    let claim = db
+        .prepare("UPDATE referrals SET status = ?1, reversed_at = ?2 WHERE purchase_id = ?3 AND status = ?4")
+        .bind(&[
+            to.into(),
+            if to == "reversed" { Utc::now().to_rfc3339().into() } else { worker::wasm_bindgen::JsValue::NULL },
+            purchase_id.into(),
+            from.into(),
+        ])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+    let row = db
+        .prepare(
+            "SELECT id, app_id, referrer_id, referee_id, referrer_credits, referee_credits
+             FROM referrals WHERE purchase_id = ?1",
+        )
+        .bind(&[purchase_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Referral vanished after claim".to_string()))?;
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let int = |key: &str| row.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+    Ok(Some(PaidReferral {
+        id: text("id"),
+        app_id: text("app_id"),
+        referrer_id: text("referrer_id"),
+        referee_id: text("referee_id"),
+        referrer_credits: int("referrer_credits"),
+        referee_credits: int("referee_credits"),
+    }))
+}
+
+pub async fn get_referral_stats(app_id: &str, user_id: &str, db: &D1Database) -> Result<ReferralStats, AppError> {
+    let config = get_referral_config(app_id, db).await?;
+    let code = get_or_create_code(app_id, user_id, db).await?;
+
+    let row = db
+        .prepare(
+            "SELECT COUNT(*) AS referred,
+                    COALESCE(SUM(CASE WHEN status = 'pending' THEN 1 ELSE 0 END), 0) AS pending,
+                    COALESCE(SUM(CASE WHEN status = 'rewarded' THEN 1 ELSE 0 END), 0) AS rewarded,
+                    COALESCE(SUM(CASE WHEN status = 'rewarded' THEN referrer_credits ELSE 0 END), 0) AS credits_earned
+             FROM referrals WHERE app_id = ?1 AND referrer_id = ?2",
+        )
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    let int = |key: &str| row.as_ref().and_then(|r| r.get(key)).and_then(|v| v.as_i64()).unwrap_or(0);
+
+    Ok(ReferralStats {
+        code,
+        enabled: config.enabled,
+        referrer_credits: config.referrer_credits,
+        referee_credits: config.referee_credits,
+        referred: int("referred"),
+        pending: int("pending"),
+        rewarded: int("rewarded"),
+        credits_earned: int("credits_earned"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(Some(serde_json::json!({
+            "referral_enabled": 1,
+            "referral_referrer_credits": 50,
+            "referral_referee_credits": -5,
+            "promo_credit_ttl_days": null,
+        })));
+        assert!(config.enabled);
+        assert_eq!(config.referrer_credits, 50);
+        assert_eq!(config.referee_credits, 0);
+
+        let missing = parse_config(None);
+        assert!(!missing.enabled);
+        assert_eq!(missing.referrer_credits, 0);
+    }
+
+    #[test]
+    fn test_rewards_skip_empty_sides() {
+        let referral = PaidReferral {
+            id: "r1".to_string(),
+            app_id: "pixie".to_string(),
+            referrer_id: "alice".to_string(),
+            referee_id: "bob".to_string(),
+            referrer_credits: 50,
+            referee_credits: 0,
+        };
+        assert_eq!(referral.rewards(), vec![("alice", 50)]);
+    }
+}
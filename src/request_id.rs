@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use worker::Request;
+use worker::wasm_bindgen::JsValue;
+use uuid::Uuid;
+
+/// Header a caller may set to choose the id, and that every response echoes.
+pub const HEADER: &str = "X-Request-ID";
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The caller's `X-Request-ID` if it is a sane token (at most 128 of
+/// `A-Z a-z 0-9 . _ : -`), otherwise a fresh UUID.
+pub fn from_request(req: &Request) -> String {
+    req.headers()
+        .get(HEADER)
+        .ok()
+        .flatten()
+        .map(|id| id.trim().to_string())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | ':' | '-'))
+        })
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// The id of the request being handled, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// `current()` ready to bind, NULL outside a request (cron runs).
+pub fn value() -> JsValue {
+    current().map(JsValue::from).unwrap_or(JsValue::NULL)
+}
+
+/// Runs `future` with `id` as the current request id. One isolate can
+/// interleave several requests, so the id is swapped in for each poll of
+/// this request's future and restored afterwards, rather than set once.
+pub fn scope<F: Future>(id: String, future: F) -> Scoped<F> {
+    Scoped { id, future: Box::pin(future) }
+}
+
+pub struct Scoped<F: Future> {
+    id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let previous = CURRENT.with(|c| c.replace(Some(this.id.clone())));
+        let result = this.future.as_mut().poll(cx);
+        CURRENT.with(|c| *c.borrow_mut() = previous);
+        result
+    }
+}
@@ -0,0 +1,197 @@
+use worker::{D1Database, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use crate::error::AppError;
+
+/// Where a sign-in came from. Clients describe themselves with
+/// `X-Device-Name` and `X-Device-Platform`; the country is Cloudflare's
+/// `CF-IPCountry`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip_country: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn from_request(req: &Request) -> Self {
+        let header = |name: &str, max: usize| {
+            req.headers()
+                .get(name)
+                .ok()
+                .flatten()
+                .map(|v| v.trim().chars().take(max).collect::<String>())
+                .filter(|v| !v.is_empty())
+        };
+        DeviceInfo {
+            device_name: header("X-Device-Name", 100),
+            platform: header("X-Device-Platform", 32),
+            ip_country: header("CF-IPCountry", 2).filter(|c| c != "XX"),
+        }
+    }
+}
+
+/// A sign-in as shown to its user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub provider: String,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip_country: Option<String>,
+    pub created_at: String,
+    pub last_seen_at: String,
+}
+
+fn parse_session(row: &Value) -> Option<Session> {
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    Some(Session {
+        id: text("id")?,
+        provider: text("provider").unwrap_or_default(),
+        device_name: text("device_name"),
+        platform: text("platform"),
+        ip_country: text("ip_country"),
+        created_at: text("created_at").unwrap_or_default(),
+        last_seen_at: text("last_seen_at").unwrap_or_default(),
+    })
+}
+
+/// Records a sign-in and returns the new session's id. Suspended and banned
+/// users can't sign in.
+pub async fn start_session(
+    app_id: &str,
+    user_id: &str,
+    provider: &str,
+    device: &DeviceInfo,
+    db: &D1Database,
+) -> Result<String, AppError> {
+    crate::user_status::ensure_can_sign_in(app_id, user_id, db).await?;
+    let id = Uuid::new_v4().to_string();
+    let now = Utc::now().to_rfc3339();
+    let opt = |v: &Option<String>| v.clone().map(|s| s.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    db.prepare(
+        "INSERT INTO sessions (id, app_id, user_id, provider, device_name, platform, ip_country, created_at, last_seen_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)",
+    )
+    .bind(&[
+        id.clone().into(),
+        app_id.into(),
+        user_id.into(),
+        provider.into(),
+        opt(&device.device_name),
+        opt(&device.platform),
+        opt(&device.ip_country),
+        now.into(),
+    ])?
+    .run()
+    .await?;
+    Ok(id)
+}
+
+/// Active sessions, most recently used first.
+pub async fn list_sessions(app_id: &str, user_id: &str, db: &D1Database) -> Result<Vec<Session>, AppError> {
+    let rows = db
+        .prepare(
+            "SELECT * FROM sessions WHERE app_id = ?1 AND user_id = ?2 AND revoked_at IS NULL
+             ORDER BY last_seen_at DESC",
+        )
+        .bind(&[app_id.into(), user_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows.iter().filter_map(parse_session).collect())
+}
+
+/// Updates last_seen_at, at most once a minute per session.
+pub async fn touch(session_id: &str, db: &D1Database) {
+    let now = Utc::now();
+    let stale = (now - Duration::minutes(1)).to_rfc3339();
+    if let Ok(stmt) = db
+        .prepare("UPDATE sessions SET last_seen_at = ?1 WHERE id = ?2 AND last_seen_at < ?3")
+        .bind(&[now.to_rfc3339().into(), session_id.into(), stale.into()])
+    {
+        let _ = stmt.run().await;
+    }
+}
+
+/// Revokes one session together with its refresh tokens and login key.
+/// Ids are only matched within `sessions`, so the filter on the user there
+/// also guards the other two tables.
+pub async fn revoke_session(app_id: &str, user_id: &str, session_id: &str, db: &D1Database) -> Result<(), AppError> {
+    let now = Utc::now().to_rfc3339();
+    let result = db
+        .prepare(
+            "UPDATE sessions SET revoked_at = ?1
+             WHERE app_id = ?2 AND user_id = ?3 AND id = ?4 AND revoked_at IS NULL",
+        )
+        .bind(&[now.clone().into(), app_id.into(), user_id.into(), session_id.into()])?
+        .run()
+        .await?;
+    if result.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::NotFound("No active session with this id".to_string()));
+    }
+    revoke_credentials(&[session_id.to_string()], &now, db).await
+}
+
+/// Revokes a session without checking its owner, for callers that found it
+/// through one of its own credentials.
+pub async fn revoke_by_id(session_id: &str, db: &D1Database) -> Result<(), AppError> {
+    revoke_credentials(&[session_id.to_string()], &Utc::now().to_rfc3339(), db).await
+}
+
+/// Signs a user out everywhere: revokes every session except `keep` and,
+/// with `retire_account_key`, the account key too. Keys created through
+/// /v1/keys are left alone. Returns how many sessions were revoked.
+pub async fn revoke_all_sessions(
+    app_id: &str,
+    user_id: &str,
+    keep: Option<&str>,
+    retire_account_key: bool,
+    db: &D1Database,
+) -> Result<usize, AppError> {
+    let ids: Vec<String> = db
+        .prepare("SELECT id FROM sessions WHERE app_id = ?1 AND user_id = ?2 AND revoked_at IS NULL AND id != ?3")
+        .bind(&[app_id.into(), user_id.into(), keep.unwrap_or("").into()])?
+        .all()
+        .await?
+        .results::<Value>()?
+        .iter()
+        .filter_map(|r| r.get("id").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .collect();
+
+    let now = Utc::now().to_rfc3339();
+    revoke_credentials(&ids, &now, db).await?;
+
+    if retire_account_key {
+        db.prepare("UPDATE users SET api_key = NULL, api_key_prefix = NULL, api_key_hash = NULL WHERE app_id = ?1 AND id = ?2")
+            .bind(&[app_id.into(), user_id.into()])?
+            .run()
+            .await?;
+    }
+    Ok(ids.len())
+}
+
+/// Revokes the given sessions with their refresh tokens and login keys.
+async fn revoke_credentials(session_ids: &[String], now: &str, db: &D1Database) -> Result<(), AppError> {
+    let mut statements = Vec::new();
+    for id in session_ids {
+        statements.push(
+            db.prepare("UPDATE sessions SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL")
+                .bind(&[now.into(), id.clone().into()])?,
+        );
+        statements.push(
+            db.prepare("UPDATE refresh_tokens SET revoked_at = ?1 WHERE family_id = ?2 AND revoked_at IS NULL")
+                .bind(&[now.into(), id.clone().into()])?,
+        );
+        statements.push(
+            db.prepare("UPDATE api_keys SET revoked_at = ?1 WHERE session_id = ?2 AND revoked_at IS NULL")
+                .bind(&[now.into(), id.clone().into()])?,
+        );
+    }
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+    Ok(())
+}
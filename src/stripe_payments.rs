@@ -67,16 +67,29 @@ pub struct CreateCheckoutSessionRequest {
     pub mode: String,
     pub line_items: Vec<LineItem>,
     pub metadata: HashMap<String, String>,
+    /// Copied onto the created subscription (mode "subscription" only), so its
+    /// invoices and lifecycle events can be attributed to an app and user.
+    pub subscription_metadata: HashMap<String, String>,
     pub customer_email: Option<String>,
     pub expires_at: Option<i64>,
 }
 
+/// Either a pre-created Stripe `price`, or inline `price_data` for a one-off
+/// amount (e.g. a promo-code discounted pack).
 #[derive(Debug, Serialize)]
 pub struct LineItem {
-    pub price: String,
+    pub price: Option<String>,
+    pub price_data: Option<LineItemPriceData>,
     pub quantity: u32,
 }
 
+#[derive(Debug, Serialize)]
+pub struct LineItemPriceData {
+    pub currency: String,
+    pub unit_amount: i64,
+    pub product_name: String,
+}
+
 // Stripe webhook event types
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
@@ -113,7 +126,14 @@ fn to_form_encoded(params: &CreateCheckoutSessionRequest) -> String {
     
     // Add line items
     for (idx, item) in params.line_items.iter().enumerate() {
-        parts.push((format!("line_items[{}][price]", idx), item.price.clone()));
+        if let Some(price) = &item.price {
+            parts.push((format!("line_items[{}][price]", idx), price.clone()));
+        }
+        if let Some(data) = &item.price_data {
+            parts.push((format!("line_items[{}][price_data][currency]", idx), data.currency.clone()));
+            parts.push((format!("line_items[{}][price_data][unit_amount]", idx), data.unit_amount.to_string()));
+            parts.push((format!("line_items[{}][price_data][product_data][name]", idx), data.product_name.clone()));
+        }
         parts.push((format!("line_items[{}][quantity]", idx), item.quantity.to_string()));
     }
     
@@ -121,6 +141,9 @@ fn to_form_encoded(params: &CreateCheckoutSessionRequest) -> String {
     for (key, value) in &params.metadata {
         parts.push((format!("metadata[{}]", key), value.clone()));
     }
+    for (key, value) in &params.subscription_metadata {
+        parts.push((format!("subscription_data[metadata][{}]", key), value.clone()));
+    }
     
     // Add optional fields
     if let Some(email) = &params.customer_email {
@@ -136,13 +159,35 @@ fn to_form_encoded(params: &CreateCheckoutSessionRequest) -> String {
         .finish()
 }
 
+/// Maps a failed Stripe API response to a user-facing error.
+fn stripe_api_error(status: u16, error_text: &str) -> worker::Error {
+    if let Ok(stripe_error) = serde_json::from_str::<StripeError>(error_text) {
+        let user_message = match stripe_error.error.code.as_deref() {
+            Some("api_key_expired") => "Payment configuration error. Please contact support.".to_string(),
+            Some("insufficient_funds") => "Insufficient funds. Please try a different payment method.".to_string(),
+            Some("card_declined") => "Card declined. Please check your card details or try a different card.".to_string(),
+            Some("expired_card") => "Card expired. Please use a different card.".to_string(),
+            Some("incorrect_cvc") => "Incorrect security code. Please check your card details.".to_string(),
+            Some("processing_error") => "Payment processing error. Please try again.".to_string(),
+            Some("rate_limit") => "Too many payment attempts. Please wait a moment and try again.".to_string(),
+            _ => stripe_error.error.message.clone(),
+        };
+        return AppError::BadRequest(user_message).into();
+    }
+
+    match status {
+        500..=599 => AppError::InternalError("Payment service temporarily unavailable".to_string()).into(),
+        _ => AppError::BadRequest("Payment service error. Please try again.".to_string()).into(),
+    }
+}
+
 pub async fn create_checkout_session(
     env: &Env,
     purchase_id: &str,
     pack_id: &str,
     pack_name: &str,
     credits: u32,
-    price_stripe_id: &str,
+    line_item: LineItem,
     success_url: &str,
     cancel_url: &str,
     customer_email: Option<&str>,
@@ -159,11 +204,9 @@ pub async fn create_checkout_session(
         success_url: success_url.to_string(),
         cancel_url: cancel_url.to_string(),
         mode: "payment".to_string(),
-        line_items: vec![LineItem {
-            price: price_stripe_id.to_string(),
-            quantity: 1,
-        }],
+        line_items: vec![line_item],
         metadata,
+        subscription_metadata: HashMap::new(),
         customer_email: customer_email.map(|s| s.to_string()),
         expires_at: Some(chrono::Utc::now().timestamp() + 1800), // 30 minutes
     };
@@ -183,24 +226,7 @@ pub async fn create_checkout_session(
     
     if response.status_code() < 200 || response.status_code() >= 300 {
         let error_text = response.text().await?;
-        if let Ok(stripe_error) = serde_json::from_str::<StripeError>(&error_text) {
-            let user_message = match stripe_error.error.code.as_deref() {
-                Some("api_key_expired") => "Payment configuration error. Please contact support.".to_string(),
-                Some("insufficient_funds") => "Insufficient funds. Please try a different payment method.".to_string(),
-                Some("card_declined") => "Card declined. Please check your card details or try a different card.".to_string(),
-                Some("expired_card") => "Card expired. Please use a different card.".to_string(),
-                Some("incorrect_cvc") => "Incorrect security code. Please check your card details.".to_string(),
-                Some("processing_error") => "Payment processing error. Please try again.".to_string(),
-                Some("rate_limit") => "Too many payment attempts. Please wait a moment and try again.".to_string(),
-                _ => stripe_error.error.message.clone(),
-            };
-            return Err(AppError::BadRequest(user_message).into());
-        }
-        
-        return match response.status_code() {
-            500..=599 => Err(AppError::InternalError("Payment service temporarily unavailable".to_string()).into()),
-            _ => Err(AppError::BadRequest("Payment service error. Please try again.".to_string()).into()),
-        };
+        return Err(stripe_api_error(response.status_code(), &error_text));
     }
     
     let session: StripeCheckoutSession = response.json().await?;
@@ -223,30 +249,140 @@ pub async fn get_checkout_session(env: &Env, session_id: &str) -> Result<StripeC
     
     if response.status_code() < 200 || response.status_code() >= 300 {
         let error_text = response.text().await?;
-        if let Ok(stripe_error) = serde_json::from_str::<StripeError>(&error_text) {
-            let user_message = match stripe_error.error.code.as_deref() {
-                Some("api_key_expired") => "Payment configuration error. Please contact support.".to_string(),
-                Some("insufficient_funds") => "Insufficient funds. Please try a different payment method.".to_string(),
-                Some("card_declined") => "Card declined. Please check your card details or try a different card.".to_string(),
-                Some("expired_card") => "Card expired. Please use a different card.".to_string(),
-                Some("incorrect_cvc") => "Incorrect security code. Please check your card details.".to_string(),
-                Some("processing_error") => "Payment processing error. Please try again.".to_string(),
-                Some("rate_limit") => "Too many payment attempts. Please wait a moment and try again.".to_string(),
-                _ => stripe_error.error.message.clone(),
-            };
-            return Err(AppError::BadRequest(user_message).into());
-        }
-        
-        return match response.status_code() {
-            500..=599 => Err(AppError::InternalError("Payment service temporarily unavailable".to_string()).into()),
-            _ => Err(AppError::BadRequest("Payment service error. Please try again.".to_string()).into()),
-        };
+        return Err(stripe_api_error(response.status_code(), &error_text));
     }
     
     let session: StripeCheckoutSession = response.json().await?;
     Ok(session)
 }
 
+/// Checkout session for a recurring plan. The app, user and plan ride along as
+/// subscription metadata; `invoice.paid` uses them to grant the allowance.
+pub async fn create_subscription_checkout_session(
+    env: &Env,
+    stripe_price_id: &str,
+    metadata: HashMap<String, String>,
+    success_url: &str,
+    cancel_url: &str,
+    customer_email: Option<&str>,
+) -> Result<StripeCheckoutSession> {
+    let api_key = env.secret("STRIPE_SECRET_KEY")?.to_string();
+
+    let request_data = CreateCheckoutSessionRequest {
+        success_url: success_url.to_string(),
+        cancel_url: cancel_url.to_string(),
+        mode: "subscription".to_string(),
+        line_items: vec![LineItem {
+            price: Some(stripe_price_id.to_string()),
+            price_data: None,
+            quantity: 1,
+        }],
+        metadata: metadata.clone(),
+        subscription_metadata: metadata,
+        customer_email: customer_email.map(|s| s.to_string()),
+        expires_at: Some(chrono::Utc::now().timestamp() + 1800), // 30 minutes
+    };
+
+    let headers = create_stripe_headers(&api_key)?;
+    let body = to_form_encoded(&request_data);
+
+    let request = Request::new_with_init(
+        "https://api.stripe.com/v1/checkout/sessions",
+        worker::RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.into()))
+    )?;
+
+    let mut response = Fetch::Request(request).send().await?;
+
+    if response.status_code() < 200 || response.status_code() >= 300 {
+        let error_text = response.text().await?;
+        return Err(stripe_api_error(response.status_code(), &error_text));
+    }
+
+    let session: StripeCheckoutSession = response.json().await?;
+    Ok(session)
+}
+
+/// Schedules a subscription to end at the close of its current period. Stripe
+/// follows up with `customer.subscription.updated` (and `.deleted` at the end).
+pub async fn cancel_subscription_at_period_end(env: &Env, subscription_id: &str) -> Result<()> {
+    let api_key = env.secret("STRIPE_SECRET_KEY")?.to_string();
+
+    let headers = create_stripe_headers(&api_key)?;
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("cancel_at_period_end", "true")
+        .finish();
+
+    let request = Request::new_with_init(
+        &format!("https://api.stripe.com/v1/subscriptions/{}", subscription_id),
+        worker::RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.into()))
+    )?;
+
+    let mut response = Fetch::Request(request).send().await?;
+
+    if response.status_code() < 200 || response.status_code() >= 300 {
+        let error_text = response.text().await?;
+        return Err(stripe_api_error(response.status_code(), &error_text));
+    }
+    Ok(())
+}
+
+/// Maps a Stripe payment intent back to the `payment_id` we stored for it: the
+/// Checkout Session id for pack purchases, or the invoice id for subscription
+/// renewals. Refund and dispute events only carry the charge/payment intent.
+pub async fn resolve_payment_reference(env: &Env, payment_intent: &str) -> Result<Option<String>> {
+    let api_key = env.secret("STRIPE_SECRET_KEY")?.to_string();
+
+    let sessions_url = format!(
+        "https://api.stripe.com/v1/checkout/sessions?payment_intent={}&limit=1",
+        payment_intent
+    );
+    let request = Request::new_with_init(
+        &sessions_url,
+        worker::RequestInit::new()
+            .with_method(Method::Get)
+            .with_headers(create_stripe_headers(&api_key)?)
+    )?;
+    let mut response = Fetch::Request(request).send().await?;
+    if response.status_code() < 200 || response.status_code() >= 300 {
+        let error_text = response.text().await?;
+        return Err(stripe_api_error(response.status_code(), &error_text));
+    }
+    let sessions: serde_json::Value = response.json().await?;
+    if let Some(id) = sessions.pointer("/data/0/id").and_then(|v| v.as_str()) {
+        return Ok(Some(id.to_string()));
+    }
+
+    let request = Request::new_with_init(
+        &format!("https://api.stripe.com/v1/payment_intents/{}", payment_intent),
+        worker::RequestInit::new()
+            .with_method(Method::Get)
+            .with_headers(create_stripe_headers(&api_key)?)
+    )?;
+    let mut response = Fetch::Request(request).send().await?;
+    if response.status_code() < 200 || response.status_code() >= 300 {
+        let error_text = response.text().await?;
+        return Err(stripe_api_error(response.status_code(), &error_text));
+    }
+    let intent: serde_json::Value = response.json().await?;
+    Ok(intent.get("invoice").and_then(|v| v.as_str()).map(|s| s.to_string()))
+}
+
+/// Whether a refunded charge was refunded in full: `refunded`, or a refunded
+/// amount that covers the charge. Partial refunds keep the credits.
+pub fn is_full_refund(charge: &serde_json::Value) -> bool {
+    if charge.get("refunded").and_then(|r| r.as_bool()) == Some(true) {
+        return true;
+    }
+    let cents = |key: &str| charge.get(key).and_then(|v| v.as_i64()).unwrap_or(0);
+    cents("amount") > 0 && cents("amount_refunded") >= cents("amount")
+}
+
 pub fn verify_webhook_signature(env: &Env, signature: &str, body: &str, timestamp: &str) -> Result<bool> {
     let webhook_secret = env.secret("STRIPE_WEBHOOK_SECRET")?.to_string();
     
@@ -320,4 +456,18 @@ pub fn get_stripe_price_id(env: &Env, pack_id: &str) -> Option<String> {
     }
     
     result
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_full_refund() {
+        assert!(is_full_refund(&json!({ "refunded": true, "amount": 999, "amount_refunded": 999 })));
+        assert!(is_full_refund(&json!({ "amount": 999, "amount_refunded": 999 })));
+        assert!(!is_full_refund(&json!({ "refunded": false, "amount": 999, "amount_refunded": 500 })));
+        assert!(!is_full_refund(&json!({ "amount": 0, "amount_refunded": 0 })));
+        assert!(!is_full_refund(&json!({})));
+    }
+}
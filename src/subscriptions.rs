@@ -0,0 +1,298 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+use crate::credits::{find_purchase_by_payment, grant_subscription_allowance, AllowanceGrant};
+use crate::error::AppError;
+
+/// Statuses in which a subscription is still billing (and granting allowance).
+const LIVE_STATUSES: &str = "'active', 'trialing', 'past_due'";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionPlan {
+    pub plan_id: String,
+    pub name: String,
+    pub description: String,
+    #[serde(skip_serializing)]
+    pub stripe_price_id: String,
+    pub price_usd_cents: i32,
+    pub monthly_credits: i32,
+    pub rollover_cap: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub app_id: String,
+    pub user_id: String,
+    pub plan_id: String,
+    pub stripe_subscription_id: String,
+    pub status: String,
+    pub current_period_start: Option<String>,
+    pub current_period_end: Option<String>,
+    pub cancel_at_period_end: i32,
+    pub created_at: String,
+}
+
+impl Subscription {
+    pub fn is_live(&self) -> bool {
+        matches!(self.status.as_str(), "active" | "trialing" | "past_due")
+    }
+}
+
+pub async fn get_plans(app_id: &str, db: &D1Database) -> Result<Vec<SubscriptionPlan>, AppError> {
+    let rows = db
+        .prepare("SELECT * FROM subscription_plans WHERE app_id = ?1 AND enabled = 1 ORDER BY sort_order")
+        .bind(&[app_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<SubscriptionPlan>(r).ok())
+        .collect())
+}
+
+pub async fn get_plan(app_id: &str, plan_id: &str, db: &D1Database) -> Result<Option<SubscriptionPlan>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM subscription_plans WHERE app_id = ?1 AND plan_id = ?2")
+        .bind(&[app_id.into(), plan_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<SubscriptionPlan>(r).ok()))
+}
+
+/// The user's live subscription if any, otherwise their most recent one.
+pub async fn get_current_subscription(
+    app_id: &str,
+    user_id: &str,
+    db: &D1Database,
+) -> Result<Option<Subscription>, AppError> {
+    let row = db
+        .prepare(format!(
+            "SELECT * FROM subscriptions WHERE app_id = ?1 AND user_id = ?2
+             ORDER BY status IN ({}) DESC, updated_at DESC LIMIT 1",
+            LIVE_STATUSES
+        ))
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<Subscription>(r).ok()))
+}
+
+async fn get_by_stripe_id(stripe_subscription_id: &str, db: &D1Database) -> Result<Option<Subscription>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM subscriptions WHERE stripe_subscription_id = ?1")
+        .bind(&[stripe_subscription_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<Subscription>(r).ok()))
+}
+
+fn unix_to_datetime(v: Option<&Value>) -> Option<DateTime<Utc>> {
+    v.and_then(|t| t.as_i64()).and_then(|t| DateTime::from_timestamp(t, 0))
+}
+
+fn str_field<'a>(v: &'a Value, key: &str) -> Option<&'a str> {
+    v.get(key).and_then(|s| s.as_str()).filter(|s| !s.is_empty())
+}
+
+struct StripeSubscriptionState<'a> {
+    stripe_subscription_id: &'a str,
+    customer: Option<&'a str>,
+    /// None leaves the stored status alone (a new row starts 'active').
+    status: Option<&'a str>,
+    period_start: Option<DateTime<Utc>>,
+    period_end: Option<DateTime<Utc>>,
+    cancel_at_period_end: Option<bool>,
+    plan_id: Option<String>,
+}
+
+/// Creates the local row from the metadata set at checkout, or refreshes an
+/// existing one. Stripe doesn't order webhooks, so whichever of `invoice.paid`
+/// and `customer.subscription.*` arrives first creates it.
+async fn upsert_subscription(
+    state: &StripeSubscriptionState<'_>,
+    metadata: Option<&Value>,
+    db: &D1Database,
+) -> Result<Subscription, AppError> {
+    let now = Utc::now().to_rfc3339();
+    let null = || worker::wasm_bindgen::JsValue::NULL;
+    let ts = |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339().into()).unwrap_or_else(null);
+
+    if get_by_stripe_id(state.stripe_subscription_id, db).await?.is_none() {
+        let meta = |key: &str| metadata.and_then(|m| str_field(m, key)).map(|s| s.to_string());
+        let (Some(app_id), Some(user_id)) = (meta("app_id"), meta("user_id")) else {
+            return Err(AppError::BadRequest(format!(
+                "Subscription {} has no app_id/user_id metadata",
+                state.stripe_subscription_id
+            )));
+        };
+        let plan_id = state
+            .plan_id
+            .clone()
+            .or_else(|| meta("plan_id"))
+            .ok_or_else(|| AppError::BadRequest("Subscription has no plan".to_string()))?;
+        db.prepare(
+            "INSERT INTO subscriptions (id, app_id, user_id, plan_id, stripe_subscription_id, stripe_customer_id, status,
+                                        current_period_start, current_period_end, cancel_at_period_end, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(stripe_subscription_id) DO NOTHING",
+        )
+        .bind(&[
+            Uuid::new_v4().to_string().into(),
+            app_id.into(),
+            user_id.into(),
+            plan_id.into(),
+            state.stripe_subscription_id.into(),
+            state.customer.map(|c| c.into()).unwrap_or_else(null),
+            state.status.unwrap_or("active").into(),
+            ts(state.period_start),
+            ts(state.period_end),
+            ((state.cancel_at_period_end == Some(true)) as i32).into(),
+            now.clone().into(),
+            now.clone().into(),
+        ])?
+        .run()
+        .await?;
+    }
+
+    db.prepare(
+        "UPDATE subscriptions SET status = COALESCE(?1, status),
+                                  plan_id = COALESCE(?2, plan_id),
+                                  stripe_customer_id = COALESCE(?3, stripe_customer_id),
+                                  current_period_start = COALESCE(?4, current_period_start),
+                                  current_period_end = COALESCE(?5, current_period_end),
+                                  cancel_at_period_end = COALESCE(?6, cancel_at_period_end),
+                                  updated_at = ?7
+         WHERE stripe_subscription_id = ?8",
+    )
+    .bind(&[
+        state.status.map(|s| s.into()).unwrap_or_else(null),
+        state.plan_id.clone().map(|p| p.into()).unwrap_or_else(null),
+        state.customer.map(|c| c.into()).unwrap_or_else(null),
+        ts(state.period_start),
+        ts(state.period_end),
+        state.cancel_at_period_end.map(|c| (c as i32).into()).unwrap_or_else(null),
+        now.into(),
+        state.stripe_subscription_id.into(),
+    ])?
+    .run()
+    .await?;
+
+    get_by_stripe_id(state.stripe_subscription_id, db)
+        .await?
+        .ok_or_else(|| AppError::InternalError("Subscription vanished after upsert".to_string()))
+}
+
+/// Maps a Stripe price back to the tenant's plan, so upgrades and downgrades
+/// made in the Stripe portal switch the allowance too.
+async fn plan_for_price(app_id: Option<&str>, price_id: Option<&str>, db: &D1Database) -> Result<Option<String>, AppError> {
+    let (Some(app_id), Some(price_id)) = (app_id, price_id) else {
+        return Ok(None);
+    };
+    Ok(db
+        .prepare("SELECT plan_id FROM subscription_plans WHERE app_id = ?1 AND stripe_price_id = ?2")
+        .bind(&[app_id.into(), price_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .and_then(|v| v.get("plan_id").and_then(|p| p.as_str()).map(|s| s.to_string())))
+}
+
+/// `customer.subscription.updated` / `customer.subscription.deleted`: mirror the
+/// status, period, pending cancellation and (on plan changes) the plan.
+pub async fn sync_subscription(subscription: &Value, db: &D1Database) -> Result<(), AppError> {
+    let stripe_subscription_id = str_field(subscription, "id")
+        .ok_or_else(|| AppError::BadRequest("Subscription event without id".to_string()))?;
+    let metadata = subscription.get("metadata");
+
+    let app_id = match get_by_stripe_id(stripe_subscription_id, db).await? {
+        Some(existing) => Some(existing.app_id),
+        None => metadata.and_then(|m| str_field(m, "app_id")).map(|s| s.to_string()),
+    };
+    let price_id = subscription
+        .pointer("/items/data/0/price/id")
+        .and_then(|p| p.as_str());
+    let plan_id = plan_for_price(app_id.as_deref(), price_id, db).await?;
+
+    let state = StripeSubscriptionState {
+        stripe_subscription_id,
+        customer: str_field(subscription, "customer"),
+        status: str_field(subscription, "status"),
+        period_start: unix_to_datetime(subscription.get("current_period_start")),
+        period_end: unix_to_datetime(subscription.get("current_period_end")),
+        cancel_at_period_end: subscription.get("cancel_at_period_end").and_then(|c| c.as_bool()),
+        plan_id,
+    };
+    let sub = upsert_subscription(&state, metadata, db).await?;
+    worker::console_log!("Subscription {} for {} is now {}", stripe_subscription_id, sub.user_id, sub.status);
+    Ok(())
+}
+
+/// `invoice.paid` for a subscription invoice: roll over what's left of the last
+/// period's allowance (up to the plan's cap) and grant the new period's
+/// allowance as a completed purchase of the plan, together. Both are keyed on
+/// the invoice id, so redelivery of any invoice is a no-op. Status is left to the
+/// `customer.subscription.*` events.
+pub async fn handle_invoice_paid(invoice: &Value, db: &D1Database) -> Result<(), AppError> {
+    let Some(stripe_subscription_id) = str_field(invoice, "subscription") else {
+        return Ok(());
+    };
+    let invoice_id = str_field(invoice, "id")
+        .ok_or_else(|| AppError::BadRequest("Invoice event without id".to_string()))?;
+    let period = invoice.pointer("/lines/data/0/period");
+    let period_start = unix_to_datetime(period.and_then(|p| p.get("start")));
+    let period_end = unix_to_datetime(period.and_then(|p| p.get("end")))
+        .unwrap_or_else(|| Utc::now() + Duration::days(31));
+
+    let state = StripeSubscriptionState {
+        stripe_subscription_id,
+        customer: str_field(invoice, "customer"),
+        status: None,
+        period_start,
+        period_end: Some(period_end),
+        cancel_at_period_end: None,
+        plan_id: None,
+    };
+    let sub = upsert_subscription(&state, invoice.pointer("/subscription_details/metadata"), db).await?;
+    let plan = get_plan(&sub.app_id, &sub.plan_id, db)
+        .await?
+        .ok_or_else(|| AppError::InternalError(format!("Unknown subscription plan {}", sub.plan_id)))?;
+
+    if find_purchase_by_payment("stripe", invoice_id, db).await?.is_some() {
+        worker::console_log!("Invoice {} already granted, skipping", invoice_id);
+        return Ok(());
+    }
+
+    let amount_paid = invoice.get("amount_paid").and_then(|a| a.as_i64()).unwrap_or(0).max(0) as u32;
+    let grant = AllowanceGrant {
+        app_id: &sub.app_id,
+        user_id: &sub.user_id,
+        plan_id: &plan.plan_id,
+        amount: plan.monthly_credits.max(0) as u32,
+        amount_paid_cents: amount_paid,
+        description: &format!("Subscription: {} monthly allowance", plan.name),
+        invoice_id,
+        subscription_id: stripe_subscription_id,
+        period_end,
+        rollover_cap: plan.rollover_cap,
+    };
+    let Some((purchase_id, carried)) = grant_subscription_allowance(&grant, db).await? else {
+        worker::console_log!("Invoice {} already granted, skipping", invoice_id);
+        return Ok(());
+    };
+
+    if let Err(e) = crate::referrals::reward_on_purchase(&sub.app_id, &sub.user_id, &purchase_id, db).await {
+        worker::console_error!("Referral reward for purchase {} failed: {:?}", purchase_id, e);
+    }
+
+    worker::console_log!(
+        "Granted {} credits ({} rolled over) for invoice {} to {}",
+        plan.monthly_credits,
+        carried,
+        invoice_id,
+        sub.user_id
+    );
+    Ok(())
+}
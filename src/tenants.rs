@@ -0,0 +1,663 @@
+use worker::D1Database;
+use worker::wasm_bindgen::JsValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use chrono::Utc;
+use crate::error::AppError;
+
+/// The `apps` row of a tenant, minus its id. Missing fields take the column
+/// defaults, so a config is always a complete replacement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppSettings {
+    pub name: String,
+    pub enabled: bool,
+    pub rc_project_id: Option<String>,
+    pub rc_product_prefix: Option<String>,
+    pub apple_team_id: Option<String>,
+    pub apple_app_bundle_id: Option<String>,
+    pub enabled_capabilities: Vec<String>,
+    pub new_user_free_credits: i32,
+    pub premium_entitlement: Option<String>,
+    pub default_chat_model: Option<String>,
+    pub promo_credit_ttl_days: Option<i32>,
+    pub referral_enabled: bool,
+    pub referral_referrer_credits: i32,
+    pub referral_referee_credits: i32,
+    pub refund_policy: String,
+    pub transfers_enabled: bool,
+    pub transfer_daily_limit: Option<i32>,
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        AppSettings {
+            name: String::new(),
+            enabled: true,
+            rc_project_id: None,
+            rc_product_prefix: None,
+            apple_team_id: None,
+            apple_app_bundle_id: None,
+            enabled_capabilities: vec!["image.generate".to_string()],
+            new_user_free_credits: 6,
+            premium_entitlement: None,
+            default_chat_model: None,
+            promo_credit_ttl_days: None,
+            referral_enabled: false,
+            referral_referrer_credits: 0,
+            referral_referee_credits: 0,
+            refund_policy: "negative_balance".to_string(),
+            transfers_enabled: false,
+            transfer_daily_limit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackConfig {
+    pub pack_id: String,
+    pub name: String,
+    pub credits: i32,
+    #[serde(default)]
+    pub bonus_credits: i32,
+    pub price_usd_cents: i32,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CapabilityCostConfig {
+    pub capability: String,
+    #[serde(default)]
+    pub flat_credits: Option<i32>,
+    #[serde(default)]
+    pub credit_multiplier: Option<f64>,
+}
+
+/// Everything that makes up a tenant. Packs are listed in display order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub app: AppSettings,
+    #[serde(default)]
+    pub credit_packs: Vec<PackConfig>,
+    #[serde(default)]
+    pub capability_costs: Vec<CapabilityCostConfig>,
+}
+
+/// One difference between two configs. `path` is dotted, e.g.
+/// `app.new_user_free_credits` or `credit_packs.small.price_usd_cents`; an
+/// added or removed pack or cost has a null `before` or `after`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub path: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// A stored config version. `config` is left out of history listings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigVersion {
+    pub app_id: String,
+    pub version: i64,
+    pub actor_user_id: String,
+    pub reason: String,
+    pub changes: Vec<ConfigChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<TenantConfig>,
+    pub created_at: String,
+}
+
+fn is_slug(s: &str, max: usize) -> bool {
+    !s.is_empty()
+        && s.len() <= max
+        && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Capabilities are dotted lowercase names such as `image.generate`.
+fn is_capability(s: &str) -> bool {
+    s.len() <= 64 && s.split('.').count() >= 2 && s.split('.').all(|part| is_slug(part, 64))
+}
+
+pub fn validate_app_id(app_id: &str) -> Result<(), AppError> {
+    if is_slug(app_id, 32) && app_id.chars().next().is_some_and(|c| c.is_ascii_lowercase()) {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(
+        "app_id must be 1-32 characters of a-z, 0-9, '-' or '_', starting with a letter".to_string(),
+    ))
+}
+
+impl TenantConfig {
+    /// Checks the whole config and reports every problem at once.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let mut problems: Vec<String> = Vec::new();
+        let app = &self.app;
+
+        if app.name.trim().is_empty() {
+            problems.push("app.name is required".to_string());
+        }
+        if app.enabled_capabilities.is_empty() {
+            problems.push("app.enabled_capabilities must name at least one capability".to_string());
+        }
+        for capability in &app.enabled_capabilities {
+            if !is_capability(capability) {
+                problems.push(format!("app.enabled_capabilities: '{}' is not a capability name", capability));
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        if app.enabled_capabilities.iter().any(|c| !seen.insert(c)) {
+            problems.push("app.enabled_capabilities has duplicates".to_string());
+        }
+        for (field, value) in [
+            ("new_user_free_credits", app.new_user_free_credits),
+            ("referral_referrer_credits", app.referral_referrer_credits),
+            ("referral_referee_credits", app.referral_referee_credits),
+        ] {
+            if value < 0 {
+                problems.push(format!("app.{} must not be negative", field));
+            }
+        }
+        if app.promo_credit_ttl_days.is_some_and(|d| d <= 0) {
+            problems.push("app.promo_credit_ttl_days must be positive or null".to_string());
+        }
+        if app.transfer_daily_limit.is_some_and(|l| l <= 0) {
+            problems.push("app.transfer_daily_limit must be positive or null".to_string());
+        }
+        if !["negative_balance", "freeze"].contains(&app.refund_policy.as_str()) {
+            problems.push("app.refund_policy must be 'negative_balance' or 'freeze'".to_string());
+        }
+        if app.referral_enabled && app.referral_referrer_credits == 0 && app.referral_referee_credits == 0 {
+            problems.push("app.referral_enabled needs referrer or referee credits".to_string());
+        }
+        if app.default_chat_model.as_deref().is_some_and(|m| m.trim().is_empty()) {
+            problems.push("app.default_chat_model must be null or a model id".to_string());
+        }
+        if app.rc_project_id.is_some() && app.rc_product_prefix.is_none() {
+            problems.push("app.rc_product_prefix is required with rc_project_id".to_string());
+        }
+
+        let mut pack_ids = std::collections::HashSet::new();
+        for pack in &self.credit_packs {
+            let at = format!("credit_packs.{}", pack.pack_id);
+            if !is_slug(&pack.pack_id, 32) {
+                problems.push(format!("{}: pack_id must be 1-32 characters of a-z, 0-9, '-' or '_'", at));
+            }
+            if !pack_ids.insert(pack.pack_id.as_str()) {
+                problems.push(format!("{}: duplicate pack_id", at));
+            }
+            if pack.name.trim().is_empty() {
+                problems.push(format!("{}: name is required", at));
+            }
+            if pack.credits <= 0 {
+                problems.push(format!("{}: credits must be positive", at));
+            }
+            if pack.bonus_credits < 0 {
+                problems.push(format!("{}: bonus_credits must not be negative", at));
+            }
+            if pack.price_usd_cents <= 0 {
+                problems.push(format!("{}: price_usd_cents must be positive", at));
+            }
+        }
+
+        let mut costed = std::collections::HashSet::new();
+        for cost in &self.capability_costs {
+            let at = format!("capability_costs.{}", cost.capability);
+            if !app.enabled_capabilities.contains(&cost.capability) {
+                problems.push(format!("{}: capability is not in app.enabled_capabilities", at));
+            }
+            if !costed.insert(cost.capability.as_str()) {
+                problems.push(format!("{}: duplicate capability", at));
+            }
+            match (cost.flat_credits, cost.credit_multiplier) {
+                (None, None) => problems.push(format!("{}: set flat_credits or credit_multiplier", at)),
+                (Some(flat), _) if flat < 0 => problems.push(format!("{}: flat_credits must not be negative", at)),
+                (_, Some(m)) if m <= 0.0 || !m.is_finite() => {
+                    problems.push(format!("{}: credit_multiplier must be positive", at))
+                }
+                _ => {}
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::BadRequest(format!("Invalid app config: {}", problems.join("; "))))
+        }
+    }
+}
+
+fn fields(value: &impl Serialize) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+/// Field-by-field changes from `before` to `after`; with no `before`, every
+/// field of `after` counts as added.
+fn diff_fields(prefix: &str, before: &Map<String, Value>, after: &Map<String, Value>, changes: &mut Vec<ConfigChange>) {
+    for (key, new) in after {
+        let old = before.get(key).cloned().unwrap_or(Value::Null);
+        if old != *new {
+            changes.push(ConfigChange { path: format!("{}.{}", prefix, key), before: old, after: new.clone() });
+        }
+    }
+}
+
+/// Keyed list diff: entries matched by `key`, changed ones compared field by
+/// field, added and removed ones reported whole.
+fn diff_list<T: Serialize>(
+    prefix: &str,
+    before: &[T],
+    after: &[T],
+    key: impl Fn(&T) -> &str,
+    changes: &mut Vec<ConfigChange>,
+) {
+    for new in after {
+        let at = format!("{}.{}", prefix, key(new));
+        match before.iter().find(|old| key(old) == key(new)) {
+            Some(old) => diff_fields(&at, &fields(old), &fields(new), changes),
+            None => changes.push(ConfigChange { path: at, before: Value::Null, after: Value::Object(fields(new)) }),
+        }
+    }
+    for old in before {
+        if !after.iter().any(|new| key(new) == key(old)) {
+            changes.push(ConfigChange {
+                path: format!("{}.{}", prefix, key(old)),
+                before: Value::Object(fields(old)),
+                after: Value::Null,
+            });
+        }
+    }
+}
+
+/// What applying `after` would change. `before` is None for a new app.
+pub fn diff(before: Option<&TenantConfig>, after: &TenantConfig) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    let empty = TenantConfig { app: AppSettings::default(), credit_packs: Vec::new(), capability_costs: Vec::new() };
+    let old = before.unwrap_or(&empty);
+    let old_app = if before.is_some() { fields(&old.app) } else { Map::new() };
+    diff_fields("app", &old_app, &fields(&after.app), &mut changes);
+
+    diff_list("credit_packs", &old.credit_packs, &after.credit_packs, |p| p.pack_id.as_str(), &mut changes);
+    let order = |packs: &[PackConfig]| packs.iter().map(|p| p.pack_id.clone()).collect::<Vec<_>>();
+    let (old_order, new_order) = (order(&old.credit_packs), order(&after.credit_packs));
+    let kept: Vec<&String> = new_order.iter().filter(|id| old_order.contains(*id)).collect();
+    let was: Vec<&String> = old_order.iter().filter(|id| new_order.contains(*id)).collect();
+    if kept != was {
+        changes.push(ConfigChange { path: "credit_packs.order".to_string(), before: json!(old_order), after: json!(new_order) });
+    }
+
+    diff_list(
+        "capability_costs",
+        &old.capability_costs,
+        &after.capability_costs,
+        |c| c.capability.as_str(),
+        &mut changes,
+    );
+    changes
+}
+
+/// The live config of an app, or None if it doesn't exist.
+pub async fn load_config(app_id: &str, db: &D1Database) -> Result<Option<TenantConfig>, AppError> {
+    let Some(row) = db
+        .prepare("SELECT * FROM apps WHERE app_id = ?1")
+        .bind(&[app_id.into()])?
+        .first::<Value>(None)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let int = |key: &str| row.get(key).and_then(|v| v.as_i64()).map(|n| n as i32);
+    let defaults = AppSettings::default();
+    let app = AppSettings {
+        name: text("name").unwrap_or_default(),
+        enabled: int("enabled").unwrap_or(1) != 0,
+        rc_project_id: text("rc_project_id"),
+        rc_product_prefix: text("rc_product_prefix"),
+        apple_team_id: text("apple_team_id"),
+        apple_app_bundle_id: text("apple_app_bundle_id"),
+        enabled_capabilities: text("enabled_capabilities")
+            .unwrap_or_default()
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect(),
+        new_user_free_credits: int("new_user_free_credits").unwrap_or(defaults.new_user_free_credits),
+        premium_entitlement: text("premium_entitlement"),
+        default_chat_model: text("default_chat_model"),
+        promo_credit_ttl_days: int("promo_credit_ttl_days"),
+        referral_enabled: int("referral_enabled").unwrap_or(0) != 0,
+        referral_referrer_credits: int("referral_referrer_credits").unwrap_or(0),
+        referral_referee_credits: int("referral_referee_credits").unwrap_or(0),
+        refund_policy: text("refund_policy").unwrap_or(defaults.refund_policy),
+        transfers_enabled: int("transfers_enabled").unwrap_or(0) != 0,
+        transfer_daily_limit: int("transfer_daily_limit"),
+    };
+
+    let credit_packs = db
+        .prepare(
+            "SELECT pack_id, name, credits, bonus_credits, price_usd_cents, description
+             FROM credit_packs WHERE app_id = ?1 ORDER BY sort_order, pack_id",
+        )
+        .bind(&[app_id.into()])?
+        .all()
+        .await?
+        .results::<PackConfig>()?;
+    let capability_costs = db
+        .prepare("SELECT capability, flat_credits, credit_multiplier FROM capability_costs WHERE app_id = ?1 ORDER BY capability")
+        .bind(&[app_id.into()])?
+        .all()
+        .await?
+        .results::<CapabilityCostConfig>()?;
+
+    Ok(Some(TenantConfig { app, credit_packs, capability_costs }))
+}
+
+/// The latest stored version, or 0 for an app with no history.
+pub async fn current_version(app_id: &str, db: &D1Database) -> Result<i64, AppError> {
+    Ok(db
+        .prepare("SELECT MAX(version) AS v FROM app_config_versions WHERE app_id = ?1")
+        .bind(&[app_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .and_then(|r| r.get("v").and_then(|v| v.as_i64()))
+        .unwrap_or(0))
+}
+
+/// A config change for `apply` to write.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigUpdate<'a> {
+    pub app_id: &'a str,
+    pub config: &'a TenantConfig,
+    /// The diff from the current config, stored with the version.
+    pub changes: &'a [ConfigChange],
+    pub version: i64,
+    /// Whether the app is new.
+    pub create: bool,
+    pub actor_user_id: &'a str,
+    pub reason: &'a str,
+}
+
+/// Writes the update's config as its version of the app, creating the app if
+/// `create`. Everything goes in one batch headed by the version row, so a
+/// concurrent change that took the same version number fails it whole.
+pub async fn apply(update: &ConfigUpdate<'_>, db: &D1Database) -> Result<(), AppError> {
+    let ConfigUpdate { app_id, config, changes, version, create, actor_user_id, reason } = *update;
+    let now = Utc::now().to_rfc3339();
+    let opt_text = |v: &Option<String>| v.clone().map(JsValue::from).unwrap_or(JsValue::NULL);
+    let opt_int = |v: Option<i32>| v.map(JsValue::from).unwrap_or(JsValue::NULL);
+    let flag = |b: bool| JsValue::from(if b { 1 } else { 0 });
+    let app = &config.app;
+
+    let mut statements = vec![db
+        .prepare(
+            "INSERT INTO app_config_versions (app_id, version, config, diff, actor_user_id, reason, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(&[
+            app_id.into(),
+            (version as i32).into(),
+            encode(config)?.into(),
+            encode(&changes)?.into(),
+            actor_user_id.into(),
+            reason.into(),
+            now.clone().into(),
+        ])?];
+
+    let columns = [
+        ("name", JsValue::from(app.name.trim())),
+        ("enabled", flag(app.enabled)),
+        ("rc_project_id", opt_text(&app.rc_project_id)),
+        ("rc_product_prefix", opt_text(&app.rc_product_prefix)),
+        ("apple_team_id", opt_text(&app.apple_team_id)),
+        ("apple_app_bundle_id", opt_text(&app.apple_app_bundle_id)),
+        ("enabled_capabilities", JsValue::from(app.enabled_capabilities.join(","))),
+        ("new_user_free_credits", JsValue::from(app.new_user_free_credits)),
+        ("premium_entitlement", opt_text(&app.premium_entitlement)),
+        ("default_chat_model", opt_text(&app.default_chat_model)),
+        ("promo_credit_ttl_days", opt_int(app.promo_credit_ttl_days)),
+        ("referral_enabled", flag(app.referral_enabled)),
+        ("referral_referrer_credits", JsValue::from(app.referral_referrer_credits)),
+        ("referral_referee_credits", JsValue::from(app.referral_referee_credits)),
+        ("refund_policy", JsValue::from(app.refund_policy.as_str())),
+        ("transfers_enabled", flag(app.transfers_enabled)),
+        ("transfer_daily_limit", opt_int(app.transfer_daily_limit)),
+    ];
+    let mut values: Vec<JsValue> = columns.iter().map(|(_, v)| v.clone()).collect();
+    values.push(app_id.into());
+    let sql = if create {
+        let names: Vec<&str> = columns.iter().map(|(n, _)| *n).collect();
+        let slots: Vec<String> = (1..=values.len()).map(|i| format!("?{}", i)).collect();
+        format!("INSERT INTO apps ({}, app_id) VALUES ({})", names.join(", "), slots.join(", "))
+    } else {
+        let sets: Vec<String> = columns.iter().enumerate().map(|(i, (n, _))| format!("{} = ?{}", n, i + 1)).collect();
+        format!("UPDATE apps SET {} WHERE app_id = ?{}", sets.join(", "), values.len())
+    };
+    statements.push(db.prepare(sql).bind(&values)?);
+
+    statements.push(db.prepare("DELETE FROM credit_packs WHERE app_id = ?1").bind(&[app_id.into()])?);
+    for (order, pack) in config.credit_packs.iter().enumerate() {
+        statements.push(
+            db.prepare(
+                "INSERT INTO credit_packs (app_id, pack_id, name, credits, bonus_credits, price_usd_cents, description, sort_order)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )
+            .bind(&[
+                app_id.into(),
+                pack.pack_id.clone().into(),
+                pack.name.trim().into(),
+                pack.credits.into(),
+                pack.bonus_credits.into(),
+                pack.price_usd_cents.into(),
+                pack.description.clone().into(),
+                (order as i32).into(),
+            ])?,
+        );
+    }
+
+    statements.push(db.prepare("DELETE FROM capability_costs WHERE app_id = ?1").bind(&[app_id.into()])?);
+    for cost in &config.capability_costs {
+        statements.push(
+            db.prepare(
+                "INSERT INTO capability_costs (app_id, capability, flat_credits, credit_multiplier) VALUES (?1, ?2, ?3, ?4)",
+            )
+            .bind(&[
+                app_id.into(),
+                cost.capability.clone().into(),
+                opt_int(cost.flat_credits),
+                cost.credit_multiplier.map(JsValue::from).unwrap_or(JsValue::NULL),
+            ])?,
+        );
+    }
+
+    db.batch(statements).await.map_err(|e| {
+        if e.to_string().contains("UNIQUE") {
+            AppError::Conflict("The app's config changed while this one was applied; reload and retry".to_string())
+        } else {
+            AppError::from(e)
+        }
+    })?;
+    crate::apps::invalidate_app_config(app_id).await;
+    Ok(())
+}
+
+/// Serializes a config or diff for storage.
+fn encode(value: &impl Serialize) -> Result<String, AppError> {
+    serde_json::to_string(value).map_err(|e| AppError::InternalError(format!("Failed to encode config: {}", e)))
+}
+
+fn parse_version(row: &Value, with_config: bool) -> Option<ConfigVersion> {
+    let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    Some(ConfigVersion {
+        app_id: text("app_id")?,
+        version: row.get("version").and_then(|v| v.as_i64())?,
+        actor_user_id: text("actor_user_id").unwrap_or_default(),
+        reason: text("reason").unwrap_or_default(),
+        changes: text("diff").and_then(|d| serde_json::from_str(&d).ok()).unwrap_or_default(),
+        config: if with_config { text("config").and_then(|c| serde_json::from_str(&c).ok()) } else { None },
+        created_at: text("created_at").unwrap_or_default(),
+    })
+}
+
+/// Config history, newest first.
+pub async fn list_versions(app_id: &str, db: &D1Database) -> Result<Vec<ConfigVersion>, AppError> {
+    let rows = db
+        .prepare(
+            "SELECT app_id, version, diff, actor_user_id, reason, created_at
+             FROM app_config_versions WHERE app_id = ?1 ORDER BY version DESC LIMIT 100",
+        )
+        .bind(&[app_id.into()])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows.iter().filter_map(|r| parse_version(r, false)).collect())
+}
+
+pub async fn get_version(app_id: &str, version: i64, db: &D1Database) -> Result<Option<ConfigVersion>, AppError> {
+    let row = db
+        .prepare("SELECT * FROM app_config_versions WHERE app_id = ?1 AND version = ?2")
+        .bind(&[app_id.into(), (version as i32).into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| parse_version(&r, true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TenantConfig {
+        TenantConfig {
+            app: AppSettings { name: "Test".to_string(), ..AppSettings::default() },
+            credit_packs: vec![PackConfig {
+                pack_id: "small".to_string(),
+                name: "Small".to_string(),
+                credits: 100,
+                bonus_credits: 0,
+                price_usd_cents: 499,
+                description: String::new(),
+            }],
+            capability_costs: vec![CapabilityCostConfig {
+                capability: "image.generate".to_string(),
+                flat_credits: Some(4),
+                credit_multiplier: None,
+            }],
+        }
+    }
+
+    fn problems(config: &TenantConfig) -> String {
+        match config.validate() {
+            Err(AppError::BadRequest(msg)) => msg,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_default_config() {
+        assert!(config().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut bad = config();
+        bad.app.name = " ".to_string();
+        bad.app.enabled_capabilities.push("Image Generate".to_string());
+        bad.app.new_user_free_credits = -1;
+        bad.app.refund_policy = "refund".to_string();
+        bad.credit_packs[0].price_usd_cents = 0;
+        bad.capability_costs[0].flat_credits = None;
+
+        let msg = problems(&bad);
+        for expected in [
+            "app.name is required",
+            "'Image Generate' is not a capability name",
+            "app.new_user_free_credits must not be negative",
+            "app.refund_policy must be",
+            "credit_packs.small: price_usd_cents must be positive",
+            "capability_costs.image.generate: set flat_credits or credit_multiplier",
+        ] {
+            assert!(msg.contains(expected), "missing '{}' in: {}", expected, msg);
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicates_and_uncapped_costs() {
+        let mut bad = config();
+        bad.credit_packs.push(bad.credit_packs[0].clone());
+        bad.capability_costs.push(CapabilityCostConfig {
+            capability: "chat.completion".to_string(),
+            flat_credits: None,
+            credit_multiplier: Some(1.5),
+        });
+        let msg = problems(&bad);
+        assert!(msg.contains("credit_packs.small: duplicate pack_id"));
+        assert!(msg.contains("capability_costs.chat.completion: capability is not in app.enabled_capabilities"));
+    }
+
+    #[test]
+    fn test_validate_app_id() {
+        assert!(validate_app_id("pixie").is_ok());
+        assert!(validate_app_id("dream-eater_2").is_ok());
+        assert!(validate_app_id("2pixie").is_err());
+        assert!(validate_app_id("Pixie").is_err());
+        assert!(validate_app_id("").is_err());
+    }
+
+    #[test]
+    fn test_diff_of_identical_configs_is_empty() {
+        assert!(diff(Some(&config()), &config()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_reports_changed_added_and_removed_entries() {
+        let before = config();
+        let mut after = config();
+        after.app.new_user_free_credits = 10;
+        after.credit_packs[0].price_usd_cents = 599;
+        after.credit_packs.push(PackConfig {
+            pack_id: "large".to_string(),
+            name: "Large".to_string(),
+            credits: 1000,
+            bonus_credits: 100,
+            price_usd_cents: 2999,
+            description: String::new(),
+        });
+        after.capability_costs.clear();
+
+        let changes = diff(Some(&before), &after);
+        let find = |path: &str| changes.iter().find(|c| c.path == path);
+        let free = find("app.new_user_free_credits").expect("app field change");
+        assert_eq!((free.before.clone(), free.after.clone()), (json!(6), json!(10)));
+        let price = find("credit_packs.small.price_usd_cents").expect("pack field change");
+        assert_eq!((price.before.clone(), price.after.clone()), (json!(499), json!(599)));
+        assert!(find("credit_packs.large").is_some_and(|c| c.before.is_null() && c.after.is_object()));
+        assert!(find("capability_costs.image.generate").is_some_and(|c| c.before.is_object() && c.after.is_null()));
+        assert!(find("credit_packs.order").is_none());
+        assert_eq!(changes.len(), 4);
+    }
+
+    #[test]
+    fn test_diff_reports_reordered_packs() {
+        let mut before = config();
+        before.credit_packs.push(PackConfig { pack_id: "large".to_string(), ..before.credit_packs[0].clone() });
+        let mut after = before.clone();
+        after.credit_packs.reverse();
+        let changes = diff(Some(&before), &after);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "credit_packs.order");
+        assert_eq!(changes[0].after, json!(["large", "small"]));
+    }
+
+    #[test]
+    fn test_diff_of_new_app_lists_every_field() {
+        let changes = diff(None, &config());
+        assert!(changes.iter().any(|c| c.path == "app.name" && c.before.is_null()));
+        assert!(changes.iter().any(|c| c.path == "app.enabled" && c.after == json!(true)));
+        assert!(changes.iter().any(|c| c.path == "credit_packs.small"));
+    }
+}
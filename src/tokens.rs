@@ -0,0 +1,255 @@
+use worker::{D1Database, Env, Request};
+use chrono::{Duration, Utc};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::api_keys;
+use crate::sessions::{self, DeviceInfo};
+use crate::error::AppError;
+use crate::models::{Claims, OAuthTokenResponse, RefreshToken};
+
+pub const ACCESS_TOKEN_TTL_SECS: u64 = 15 * 60;
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 60;
+
+/// Worker secret the access tokens are signed with (HS256).
+const SIGNING_SECRET: &str = "JWT_SECRET";
+
+/// Whether a sign-in asked for an access/refresh token pair
+/// (`X-Auth-Mode: tokens`). Clients that don't ask keep getting an API key.
+pub fn wants_tokens(req: &Request) -> bool {
+    req.headers()
+        .get("X-Auth-Mode")
+        .ok()
+        .flatten()
+        .map(|v| v.eq_ignore_ascii_case("tokens"))
+        .unwrap_or(false)
+}
+
+/// What a sign-in hands back: either an API key or a token pair, plus the
+/// session they belong to.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignInCredentials {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(flatten)]
+    pub tokens: Option<OAuthTokenResponse>,
+}
+
+/// Finishes a sign-in for `user_id`: records a session and issues either a
+/// token pair (its refresh-token family is the session) or a login key.
+pub async fn sign_in(
+    token_mode: bool,
+    env: &Env,
+    app_id: &str,
+    user_id: &str,
+    provider: &str,
+    device: &DeviceInfo,
+    db: &D1Database,
+) -> Result<SignInCredentials, AppError> {
+    if token_mode && signing_secret(env).is_none() {
+        return Err(AppError::InternalError("Access tokens are not configured".to_string()));
+    }
+    let session_id = sessions::start_session(app_id, user_id, provider, device, db).await?;
+    if token_mode {
+        let tokens = issue_in_family(env, app_id, user_id, &session_id, db).await?;
+        return Ok(SignInCredentials { session_id, api_key: None, tokens: Some(tokens) });
+    }
+    let api_key = api_keys::issue_login_key(app_id, user_id, provider, &session_id, db).await?;
+    Ok(SignInCredentials { session_id, api_key: Some(api_key), tokens: None })
+}
+
+fn signing_secret(env: &Env) -> Option<String> {
+    env.secret(SIGNING_SECRET)
+        .ok()
+        .map(|s| s.to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn new_mac(secret: &str) -> Result<Hmac<Sha256>, AppError> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| AppError::InternalError("Invalid access token secret".to_string()))
+}
+
+fn sign(secret: &str, claims: &Claims) -> Result<String, AppError> {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = serde_json::to_vec(claims)
+        .map_err(|e| AppError::InternalError(format!("Failed to encode claims: {}", e)))?;
+    let signing_input = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(payload));
+    let mut mac = new_mac(secret)?;
+    mac.update(signing_input.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{}.{}", signing_input, signature))
+}
+
+/// Whether a bearer credential is an access token rather than an API key.
+pub fn is_access_token(bearer: &str) -> bool {
+    bearer.starts_with("eyJ") && bearer.split('.').count() == 3
+}
+
+/// Checks an access token's signature, expiry and tenant. Whether its
+/// session has been revoked is left to `auth::authenticate`.
+pub fn verify_access_token(env: &Env, app_id: &str, token: &str) -> Result<Claims, AppError> {
+    let secret = signing_secret(env).ok_or_else(|| AppError::Unauthorized("Invalid access token".to_string()))?;
+    verify(&secret, app_id, token)
+}
+
+fn verify(secret: &str, app_id: &str, token: &str) -> Result<Claims, AppError> {
+    let invalid = || AppError::Unauthorized("Invalid access token".to_string());
+    let (signing_input, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    let mut mac = new_mac(secret)?;
+    mac.update(signing_input.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| invalid())?;
+
+    let (_, payload) = signing_input.split_once('.').ok_or_else(invalid)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    if claims.exp <= Utc::now().timestamp() as u64 {
+        return Err(AppError::Unauthorized("Access token has expired".to_string()));
+    }
+    if claims.app != app_id {
+        return Err(invalid());
+    }
+    Ok(claims)
+}
+
+async fn issue_in_family(
+    env: &Env,
+    app_id: &str,
+    user_id: &str,
+    family_id: &str,
+    db: &D1Database,
+) -> Result<OAuthTokenResponse, AppError> {
+    let secret = signing_secret(env)
+        .ok_or_else(|| AppError::InternalError("Access tokens are not configured".to_string()))?;
+    let now = Utc::now();
+    let refresh_token = format!("pixie_rt_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    db.prepare(
+        "INSERT INTO refresh_tokens (id, app_id, user_id, family_id, token_hash, expires_at, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )
+    .bind(&[
+        Uuid::new_v4().to_string().into(),
+        app_id.into(),
+        user_id.into(),
+        family_id.into(),
+        api_keys::hash_key(&refresh_token).into(),
+        (now + Duration::days(REFRESH_TOKEN_TTL_DAYS)).to_rfc3339().into(),
+        now.to_rfc3339().into(),
+    ])?
+    .run()
+    .await?;
+
+    let iat = now.timestamp() as u64;
+    let claims = Claims {
+        sub: user_id.to_string(),
+        app: app_id.to_string(),
+        sid: family_id.to_string(),
+        iat,
+        exp: iat + ACCESS_TOKEN_TTL_SECS,
+    };
+    Ok(OAuthTokenResponse {
+        access_token: sign(&secret, &claims)?,
+        refresh_token,
+        token_type: "Bearer".to_string(),
+        expires_in: ACCESS_TOKEN_TTL_SECS,
+    })
+}
+
+/// Trades a refresh token for a new pair and marks it used. A token that
+/// was already used means someone else holds a copy, so the whole session is
+/// revoked and both holders have to sign in again.
+pub async fn refresh(env: &Env, app_id: &str, refresh_token: &str, db: &D1Database) -> Result<OAuthTokenResponse, AppError> {
+    let invalid = || AppError::Unauthorized("Invalid refresh token".to_string());
+    let token = db
+        .prepare("SELECT * FROM refresh_tokens WHERE app_id = ?1 AND token_hash = ?2")
+        .bind(&[app_id.into(), api_keys::hash_key(refresh_token).into()])?
+        .first::<RefreshToken>(None)
+        .await?
+        .ok_or_else(invalid)?;
+
+    if token.revoked_at.is_some() {
+        return Err(invalid());
+    }
+    let reused = || AppError::Unauthorized("Refresh token was already used; sign in again".to_string());
+    if token.used_at.is_some() {
+        sessions::revoke_by_id(&token.family_id, db).await?;
+        return Err(reused());
+    }
+    let now = Utc::now();
+    if token.expires_at <= now {
+        return Err(AppError::Unauthorized("Refresh token has expired".to_string()));
+    }
+
+    // Claim it; losing the race to a concurrent refresh counts as reuse.
+    let claimed = db
+        .prepare("UPDATE refresh_tokens SET used_at = ?1 WHERE id = ?2 AND used_at IS NULL AND revoked_at IS NULL")
+        .bind(&[now.to_rfc3339().into(), token.id.clone().into()])?
+        .run()
+        .await?;
+    if claimed.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        sessions::revoke_by_id(&token.family_id, db).await?;
+        return Err(reused());
+    }
+
+    issue_in_family(env, &token.app_id, &token.user_id, &token.family_id, db).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    fn claims(app: &str, exp_in: i64) -> Claims {
+        let now = Utc::now().timestamp();
+        Claims {
+            sub: "user-1".to_string(),
+            app: app.to_string(),
+            sid: "session-1".to_string(),
+            exp: (now + exp_in) as u64,
+            iat: now as u64,
+        }
+    }
+
+    #[test]
+    fn test_sign_verify_round_trip() {
+        let token = sign(SECRET, &claims("pixie", 600)).unwrap();
+        assert!(is_access_token(&token));
+        let verified = verify(SECRET, "pixie", &token).unwrap();
+        assert_eq!(verified.sub, "user-1");
+        assert_eq!(verified.sid, "session-1");
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_token() {
+        let token = sign(SECRET, &claims("pixie", 600)).unwrap();
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let mut forged = claims("pixie", 600);
+        forged.sub = "user-2".to_string();
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let tampered = format!("{}.{}.{}", header, payload, signature);
+        assert!(matches!(verify(SECRET, "pixie", &tampered), Err(AppError::Unauthorized(_))));
+        assert!(matches!(verify("other-secret", "pixie", &token), Err(AppError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn test_verify_rejects_expired_token() {
+        let token = sign(SECRET, &claims("pixie", -1)).unwrap();
+        match verify(SECRET, "pixie", &token) {
+            Err(AppError::Unauthorized(msg)) => assert!(msg.contains("expired")),
+            other => panic!("expected expiry error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects_other_app() {
+        let token = sign(SECRET, &claims("dreameater", 600)).unwrap();
+        assert!(matches!(verify(SECRET, "pixie", &token), Err(AppError::Unauthorized(_))));
+    }
+}
@@ -0,0 +1,428 @@
+use worker::D1Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use crate::error::AppError;
+
+/// Credits a sender could transfer right now: balance minus escrowed holds and
+/// minus every promotional bucket still holding credits. Expired-but-not-yet-
+/// lapsed buckets count as promotional too, so the cron can't race a transfer.
+const TRANSFERABLE_SQL: &str =
+    "balance - held_credits - COALESCE((SELECT SUM(remaining) FROM credit_buckets b
+                                        WHERE b.app_id = user_credits.app_id AND b.user_id = user_credits.user_id
+                                          AND b.remaining > 0), 0)";
+
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    pub enabled: bool,
+    /// Max credits one sender may send in a rolling 24 hours; None = no cap.
+    pub daily_limit: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditTransfer {
+    pub id: String,
+    pub sender_id: String,
+    pub recipient_id: String,
+    pub amount: i32,
+    pub note: Option<String>,
+    pub created_at: String,
+}
+
+pub async fn get_transfer_config(app_id: &str, db: &D1Database) -> Result<TransferConfig, AppError> {
+    let row = db
+        .prepare("SELECT transfers_enabled, transfer_daily_limit FROM apps WHERE app_id = ?1")
+        .bind(&[app_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(TransferConfig {
+        enabled: row.as_ref().and_then(|r| r.get("transfers_enabled")).and_then(|v| v.as_i64()) == Some(1),
+        daily_limit: row
+            .as_ref()
+            .and_then(|r| r.get("transfer_daily_limit"))
+            .and_then(|v| v.as_i64())
+            .map(|v| v.max(0) as i32),
+    })
+}
+
+/// Finds a user of the app by id or (case-insensitive) email. Only accounts
+/// with a wallet qualify.
+pub async fn resolve_user(
+    app_id: &str,
+    user_id: Option<&str>,
+    email: Option<&str>,
+    db: &D1Database,
+) -> Result<String, AppError> {
+    let user_id = user_id.map(str::trim).filter(|s| !s.is_empty());
+    let email = email.map(str::trim).filter(|s| !s.is_empty());
+    let rows = match (user_id, email) {
+        (Some(id), _) => db
+            .prepare(
+                "SELECT u.id FROM users u JOIN user_credits uc ON uc.app_id = u.app_id AND uc.user_id = u.id
+                 WHERE u.app_id = ?1 AND u.id = ?2",
+            )
+            .bind(&[app_id.into(), id.into()])?,
+        (None, Some(email)) => db
+            .prepare(
+                "SELECT u.id FROM users u JOIN user_credits uc ON uc.app_id = u.app_id AND uc.user_id = u.id
+                 WHERE u.app_id = ?1 AND LOWER(u.email) = LOWER(?2) LIMIT 2",
+            )
+            .bind(&[app_id.into(), email.into()])?,
+        (None, None) => return Err(AppError::BadRequest("Provide a recipient user_id or email".to_string())),
+    }
+    .all()
+    .await?
+    .results::<Value>()?;
+
+    let ids: Vec<String> = rows
+        .iter()
+        .filter_map(|r| r.get("id").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .collect();
+    match ids.as_slice() {
+        [id] => Ok(id.clone()),
+        [] => Err(AppError::NotFound("No user with that id or email in this app".to_string())),
+        _ => Err(AppError::BadRequest(
+            "More than one account uses that email; send to the user id instead".to_string(),
+        )),
+    }
+}
+
+/// Values bound per IN list by `resolve_users`; D1 caps a statement at 100.
+const RESOLVE_CHUNK: usize = 90;
+
+/// What `resolve_users` found: the user ids that exist (with a wallet), and
+/// for each lower-cased email the ids of the accounts using it.
+#[derive(Debug, Default)]
+pub struct ResolvedUsers {
+    pub ids: std::collections::HashSet<String>,
+    pub emails: std::collections::HashMap<String, Vec<String>>,
+}
+
+/// `resolve_user` for many recipients at once, in a query per chunk of ids or
+/// emails instead of one per recipient.
+pub async fn resolve_users(
+    app_id: &str,
+    user_ids: &[&str],
+    emails: &[&str],
+    db: &D1Database,
+) -> Result<ResolvedUsers, AppError> {
+    let mut resolved = ResolvedUsers::default();
+    let lowered: Vec<String> = emails.iter().map(|e| e.trim().to_lowercase()).collect();
+    let lookups = [
+        ("u.id", user_ids.iter().map(|id| id.trim().to_string()).collect::<Vec<_>>()),
+        ("LOWER(u.email)", lowered),
+    ];
+    for (column, values) in lookups {
+        for chunk in values.chunks(RESOLVE_CHUNK) {
+            let sql = format!(
+                "SELECT u.id, LOWER(u.email) AS email FROM users u
+                 JOIN user_credits uc ON uc.app_id = u.app_id AND uc.user_id = u.id
+                 WHERE u.app_id = ? AND {} IN ({})",
+                column,
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut params: Vec<worker::wasm_bindgen::JsValue> = vec![app_id.into()];
+            params.extend(chunk.iter().map(|v| v.clone().into()));
+            let rows = db.prepare(sql).bind(&params)?.all().await?.results::<Value>()?;
+            for row in rows {
+                let Some(id) = row.get("id").and_then(|v| v.as_str()) else {
+                    continue;
+                };
+                if column == "u.id" {
+                    resolved.ids.insert(id.to_string());
+                } else if let Some(email) = row.get("email").and_then(|v| v.as_str()) {
+                    resolved.emails.entry(email.to_string()).or_default().push(id.to_string());
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+pub async fn get_transferable_balance(app_id: &str, user_id: &str, db: &D1Database) -> Result<i32, AppError> {
+    let row = db
+        .prepare(format!(
+            "SELECT {} AS transferable FROM user_credits WHERE app_id = ?1 AND user_id = ?2",
+            TRANSFERABLE_SQL
+        ))
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row
+        .and_then(|r| r.get("transferable").and_then(|v| v.as_i64()))
+        .unwrap_or(0)
+        .max(0) as i32)
+}
+
+/// Credits `sender_id` has sent in the last 24 hours.
+pub async fn sent_last_24h(app_id: &str, sender_id: &str, db: &D1Database) -> Result<i32, AppError> {
+    let since = (Utc::now() - Duration::hours(24)).to_rfc3339();
+    let row = db
+        .prepare(
+            "SELECT COALESCE(SUM(amount), 0) AS sent FROM credit_transfers
+             WHERE app_id = ?1 AND sender_id = ?2 AND created_at >= ?3",
+        )
+        .bind(&[app_id.into(), sender_id.into(), since.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| r.get("sent").and_then(|v| v.as_i64())).unwrap_or(0) as i32)
+}
+
+/// A user-to-user transfer, subject to the app's opt-in and daily limit. The
+/// recipient is named by id or email. A recipient that can't be found, or an
+/// email shared by several accounts, gets the same error so the endpoint
+/// can't be used to probe which accounts exist, and the sender's ledger names
+/// the recipient the way the sender did rather than by internal id.
+pub async fn transfer_credits(
+    app_id: &str,
+    sender_id: &str,
+    recipient_user_id: Option<&str>,
+    recipient_email: Option<&str>,
+    amount: u32,
+    note: Option<&str>,
+    db: &D1Database,
+) -> Result<CreditTransfer, AppError> {
+    let config = get_transfer_config(app_id, db).await?;
+    if !config.enabled {
+        return Err(AppError::Forbidden("Credit transfers are not enabled for this app".to_string()));
+    }
+    let recipient_id = match resolve_user(app_id, recipient_user_id, recipient_email, db).await {
+        Ok(id) => id,
+        Err(AppError::NotFound(_)) | Err(AppError::BadRequest(_))
+            if recipient_user_id.is_some() || recipient_email.is_some() =>
+        {
+            return Err(AppError::BadRequest("Can't transfer credits to that recipient".to_string()));
+        }
+        Err(e) => return Err(e),
+    };
+    let label = recipient_user_id
+        .or(recipient_email)
+        .map(str::trim)
+        .unwrap_or_default();
+    let transfer = Transfer {
+        app_id,
+        sender_id,
+        recipient_id: &recipient_id,
+        recipient_label: label,
+        amount,
+        note,
+        daily_limit: config.daily_limit,
+    };
+    move_credits(&transfer, db).await
+}
+
+/// A transfer for `move_credits` to apply.
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer<'a> {
+    pub app_id: &'a str,
+    pub sender_id: &'a str,
+    pub recipient_id: &'a str,
+    /// Names the recipient in the sender's ledger entry.
+    pub recipient_label: &'a str,
+    pub amount: u32,
+    pub note: Option<&'a str>,
+    /// Sender's rolling 24-hour cap; None = no cap.
+    pub daily_limit: Option<i32>,
+}
+
+/// Moves `amount` paid credits from the sender to the recipient in one D1
+/// batch. The first statement records the transfer only if the sender still
+/// has the credits (and `daily_limit` allows it) at that instant; every other
+/// statement is conditioned on that row existing, so either the whole transfer
+/// applies or none of it does. Recipients may be org wallets (orgs.rs).
+pub async fn move_credits(transfer: &Transfer<'_>, db: &D1Database) -> Result<CreditTransfer, AppError> {
+    let Transfer { app_id, sender_id, recipient_id, recipient_label, amount, note, daily_limit } = *transfer;
+    if sender_id == recipient_id {
+        return Err(AppError::BadRequest("You can't transfer credits to yourself".to_string()));
+    }
+    if amount == 0 {
+        return Err(AppError::BadRequest("amount must be positive".to_string()));
+    }
+    crate::credits::ensure_wallet_active(app_id, sender_id, db).await?;
+
+    // Checked up front for a precise error; the batch re-checks atomically.
+    let transferable = get_transferable_balance(app_id, sender_id, db).await?;
+    if transferable < amount as i32 {
+        return Err(AppError::PaymentRequired(format!(
+            "Only {} credits can be transferred; promotional credits and credits reserved for in-flight requests can't be sent",
+            transferable
+        )));
+    }
+    if let Some(limit) = daily_limit {
+        let sent = sent_last_24h(app_id, sender_id, db).await?;
+        if let Some(error) = daily_limit_error(limit, sent, amount) {
+            return Err(error);
+        }
+    }
+
+    let transfer_id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let created_at = now.to_rfc3339();
+    let since = (now - Duration::hours(24)).to_rfc3339();
+    let note = clean_note(note);
+    let limit = daily_limit
+        .map(|l| l.into())
+        .unwrap_or(worker::wasm_bindgen::JsValue::NULL);
+    let recorded = "EXISTS (SELECT 1 FROM credit_transfers WHERE id = ?1)";
+    let (sent_description, received_description) = ledger_descriptions(recipient_label, note);
+    let entry = |user_id, kind, amount, description| LedgerEntry {
+        transfer_id: &transfer_id,
+        app_id,
+        user_id,
+        kind,
+        amount,
+        description,
+        created_at: &created_at,
+    };
+
+    let statements = vec![
+        db.prepare(format!(
+            "INSERT INTO credit_transfers (id, app_id, sender_id, recipient_id, amount, note, created_at)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7
+             WHERE EXISTS (SELECT 1 FROM user_credits WHERE app_id = ?2 AND user_id = ?3
+                             AND frozen_at IS NULL AND {} >= ?5)
+               AND (?8 IS NULL OR ?5 + (SELECT COALESCE(SUM(amount), 0) FROM credit_transfers
+                                        WHERE app_id = ?2 AND sender_id = ?3 AND created_at >= ?9) <= ?8)",
+            TRANSFERABLE_SQL
+        ))
+        .bind(&[
+            transfer_id.clone().into(),
+            app_id.into(),
+            sender_id.into(),
+            recipient_id.into(),
+            amount.into(),
+            note.map(|n| n.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            created_at.clone().into(),
+            limit,
+            since.into(),
+        ])?,
+        db.prepare(format!(
+            "UPDATE user_credits SET balance = balance - ?2, updated_at = ?3 WHERE app_id = ?4 AND user_id = ?5 AND {}",
+            recorded
+        ))
+        .bind(&[transfer_id.clone().into(), amount.into(), created_at.clone().into(), app_id.into(), sender_id.into()])?,
+        db.prepare(format!(
+            "UPDATE user_credits SET balance = balance + ?2, updated_at = ?3 WHERE app_id = ?4 AND user_id = ?5 AND {}",
+            recorded
+        ))
+        .bind(&[transfer_id.clone().into(), amount.into(), created_at.clone().into(), app_id.into(), recipient_id.into()])?,
+        ledger_entry(&entry(sender_id, "transfer_out", -(amount as i32), &sent_description), db)?,
+        ledger_entry(&entry(recipient_id, "transfer_in", amount as i32, &received_description), db)?,
+    ];
+    db.batch(statements).await?;
+
+    let row = db
+        .prepare("SELECT * FROM credit_transfers WHERE id = ?1")
+        .bind(&[transfer_id.clone().into()])?
+        .first::<Value>(None)
+        .await?;
+    let transfer = row
+        .and_then(|r| serde_json::from_value::<CreditTransfer>(r).ok())
+        .ok_or_else(|| {
+            AppError::Conflict("Transfer not completed: your transferable balance or daily limit changed".to_string())
+        })?;
+
+    worker::console_log!("Transfer {}: {} credits {} -> {} in {}", transfer_id, amount, sender_id, recipient_id, app_id);
+    Ok(transfer)
+}
+
+/// Trims a transfer note; a blank one is no note.
+fn clean_note(note: Option<&str>) -> Option<&str> {
+    note.map(str::trim).filter(|n| !n.is_empty())
+}
+
+/// The error for sending `amount` on top of `sent` against a daily `limit`,
+/// or None when it fits.
+fn daily_limit_error(limit: i32, sent: i32, amount: u32) -> Option<AppError> {
+    if sent + amount as i32 <= limit {
+        return None;
+    }
+    Some(AppError::BadRequest(format!(
+        "Daily transfer limit is {} credits; {} more can be sent in the next 24 hours",
+        limit,
+        (limit - sent).max(0)
+    )))
+}
+
+/// Ledger descriptions for the sender and the recipient. The sender sees the
+/// recipient the way they named them; the recipient's entry doesn't name the
+/// sender at all, since the only handle we have for them is an internal id.
+fn ledger_descriptions(recipient_label: &str, note: Option<&str>) -> (String, String) {
+    let with_note = |base: String| match note {
+        Some(n) => format!("{} ({})", base, n),
+        None => base,
+    };
+    (
+        with_note(format!("Transfer to {}", recipient_label)),
+        with_note("Transfer received".to_string()),
+    )
+}
+
+/// One side of a transfer in `credit_transactions`.
+struct LedgerEntry<'a> {
+    transfer_id: &'a str,
+    app_id: &'a str,
+    user_id: &'a str,
+    kind: &'a str,
+    amount: i32,
+    description: &'a str,
+    created_at: &'a str,
+}
+
+fn ledger_entry(entry: &LedgerEntry<'_>, db: &D1Database) -> worker::Result<worker::D1PreparedStatement> {
+    db.prepare(
+        "INSERT INTO credit_transactions (id, app_id, user_id, type, amount, balance_after, description, reference_id, created_at, request_id)
+         SELECT ?1, ?2, ?3, ?4, ?5, (SELECT balance FROM user_credits WHERE app_id = ?2 AND user_id = ?3), ?6, ?7, ?8, ?9
+         WHERE EXISTS (SELECT 1 FROM credit_transfers WHERE id = ?7)",
+    )
+    .bind(&[
+        Uuid::new_v4().to_string().into(),
+        entry.app_id.into(),
+        entry.user_id.into(),
+        entry.kind.into(),
+        entry.amount.into(),
+        entry.description.into(),
+        entry.transfer_id.into(),
+        entry.created_at.into(),
+        crate::request_id::value(),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_note() {
+        assert_eq!(clean_note(Some("  lunch ")), Some("lunch"));
+        assert_eq!(clean_note(Some("   ")), None);
+        assert_eq!(clean_note(None), None);
+    }
+
+    #[test]
+    fn test_daily_limit_error() {
+        assert!(daily_limit_error(100, 60, 40).is_none());
+        assert!(daily_limit_error(0, 0, 0).is_none());
+        match daily_limit_error(100, 60, 41) {
+            Some(AppError::BadRequest(msg)) => assert!(msg.contains("40 more"), "{}", msg),
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+        // Already past a limit that was lowered since: nothing more, not a negative.
+        match daily_limit_error(50, 80, 1) {
+            Some(AppError::BadRequest(msg)) => assert!(msg.contains("0 more"), "{}", msg),
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ledger_descriptions() {
+        let (sent, received) = ledger_descriptions("friend@example.com", None);
+        assert_eq!(sent, "Transfer to friend@example.com");
+        assert_eq!(received, "Transfer received");
+
+        let (sent, received) = ledger_descriptions("u_2", Some("lunch"));
+        assert_eq!(sent, "Transfer to u_2 (lunch)");
+        assert_eq!(received, "Transfer received (lunch)");
+    }
+}
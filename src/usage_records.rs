@@ -0,0 +1,117 @@
+use worker::D1Database;
+use worker::wasm_bindgen::JsValue;
+use chrono::Utc;
+use uuid::Uuid;
+use crate::models::UsageRecord;
+
+/// Writes a `usage_records` row for a metered call. Best effort: the call has
+/// already been served (and charged), so a failed write is only logged.
+pub async fn record(
+    app_id: &str,
+    capability: &str,
+    provider: &str,
+    usage: &UsageRecord,
+    credits_charged: u32,
+    db: &D1Database,
+) {
+    let stmt = db
+        .prepare(
+            "INSERT INTO usage_records (id, app_id, user_id, capability, request_type, provider, model, prompt, image_size,
+             image_quality, image_count, input_images_count, total_tokens, input_tokens, output_tokens, text_tokens,
+             image_tokens, r2_keys, response_time_ms, simplified_cost, credits_charged, error, created_at, request_id)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            usage.id.clone().into(),
+            app_id.into(),
+            usage.user_id.clone().into(),
+            capability.into(),
+            usage.request_type.clone().into(),
+            provider.into(),
+            usage.model.clone().into(),
+            usage.prompt.clone().into(),
+            usage.image_size.clone().into(),
+            usage.image_quality.clone().into(),
+            usage.image_count.into(),
+            usage.input_images_count.map(|n| n.into()).unwrap_or(JsValue::NULL),
+            usage.total_tokens.into(),
+            usage.input_tokens.into(),
+            usage.output_tokens.into(),
+            usage.text_tokens.into(),
+            usage.image_tokens.into(),
+            serde_json::to_string(&usage.r2_keys).unwrap_or_default().into(),
+            usage.response_time_ms.into(),
+            (provider == "gemini").into(),
+            credits_charged.into(),
+            usage.error.clone().map(JsValue::from).unwrap_or(JsValue::NULL),
+            usage.created_at.to_rfc3339().into(),
+            crate::request_id::value(),
+        ]);
+    let result = match stmt {
+        Ok(stmt) => stmt.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        crate::log_error!("Failed to record usage", serde_json::json!({
+            "error": e.to_string(),
+            "app_id": app_id,
+            "user_id": usage.user_id,
+            "capability": capability,
+        }));
+    }
+}
+
+/// A call the provider failed, for error rates. Nothing was charged.
+#[allow(clippy::too_many_arguments)]
+pub async fn record_failure(
+    app_id: &str,
+    user_id: &str,
+    capability: &str,
+    request_type: &str,
+    provider: &str,
+    model: &str,
+    error: &str,
+    response_time_ms: u32,
+    db: &D1Database,
+) {
+    let usage = UsageRecord {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        request_type: request_type.to_string(),
+        model: model.to_string(),
+        prompt: String::new(),
+        image_size: String::new(),
+        image_quality: String::new(),
+        image_count: 0,
+        input_images_count: None,
+        total_tokens: 0,
+        input_tokens: 0,
+        output_tokens: 0,
+        text_tokens: 0,
+        image_tokens: 0,
+        r2_keys: Vec::new(),
+        response_time_ms,
+        error: Some(error.chars().take(500).collect()),
+        created_at: Utc::now(),
+    };
+    record(app_id, capability, provider, &usage, 0, db).await;
+}
+
+/// Corrects the credits on a row written before the final charge was known,
+/// as for a realtime session recorded at start and billed at settle.
+pub async fn set_credits_charged(app_id: &str, id: &str, credits_charged: i32, db: &D1Database) {
+    let stmt = db
+        .prepare("UPDATE usage_records SET credits_charged = ?1 WHERE id = ?2 AND app_id = ?3")
+        .bind(&[credits_charged.into(), id.into(), app_id.into()]);
+    let result = match stmt {
+        Ok(stmt) => stmt.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        crate::log_error!("Failed to update usage credits", serde_json::json!({
+            "error": e.to_string(),
+            "app_id": app_id,
+            "usage_id": id,
+        }));
+    }
+}
@@ -0,0 +1,170 @@
+use worker::D1Database;
+use worker::wasm_bindgen::JsValue;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use crate::error::AppError;
+use crate::sessions;
+
+pub const STATUSES: &[&str] = &["active", "suspended", "banned"];
+
+/// A user's standing as stored on `users`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserStatus {
+    pub status: String,
+    pub reason: Option<String>,
+    pub expires_at: Option<String>,
+    pub changed_at: Option<String>,
+    pub changed_by: Option<String>,
+}
+
+impl UserStatus {
+    /// Reads the status columns off a `users` row.
+    pub fn from_row(row: &Value) -> Self {
+        let text = |key: &str| row.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        UserStatus {
+            status: text("status").unwrap_or_else(|| "active".to_string()),
+            reason: text("status_reason"),
+            expires_at: text("status_expires_at"),
+            changed_at: text("status_changed_at"),
+            changed_by: text("status_changed_by"),
+        }
+    }
+
+    /// The status in force now: a suspension past its expiry is over.
+    pub fn effective(&self) -> &str {
+        let expired = self
+            .expires_at
+            .as_deref()
+            .and_then(|e| DateTime::parse_from_rfc3339(e).ok())
+            .map(|e| e.with_timezone(&Utc) <= Utc::now())
+            .unwrap_or(false);
+        if self.status == "suspended" && expired {
+            "active"
+        } else {
+            &self.status
+        }
+    }
+
+    /// Refuses a suspended or banned user, saying why and for how long.
+    pub fn check(&self) -> Result<(), AppError> {
+        let reason = self.reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default();
+        match self.effective() {
+            "suspended" => Err(AppError::Forbidden(match &self.expires_at {
+                Some(until) => format!("This account is suspended until {}{}", until, reason),
+                None => format!("This account is suspended{}", reason),
+            })),
+            "banned" => Err(AppError::Forbidden(format!("This account is banned{}", reason))),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub async fn get_status(app_id: &str, user_id: &str, db: &D1Database) -> Result<Option<UserStatus>, AppError> {
+    let row = db
+        .prepare(
+            "SELECT status, status_reason, status_expires_at, status_changed_at, status_changed_by
+             FROM users WHERE app_id = ?1 AND id = ?2",
+        )
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.as_ref().map(UserStatus::from_row))
+}
+
+/// Refuses a sign-in for a suspended or banned user.
+pub async fn ensure_can_sign_in(app_id: &str, user_id: &str, db: &D1Database) -> Result<(), AppError> {
+    match get_status(app_id, user_id, db).await? {
+        Some(status) => status.check(),
+        None => Ok(()),
+    }
+}
+
+/// Refuses a device that a banned user registered from.
+pub async fn ensure_device_allowed(app_id: &str, device_id: &str, db: &D1Database) -> Result<(), AppError> {
+    let banned = db
+        .prepare("SELECT 1 FROM banned_devices WHERE app_id = ?1 AND device_id = ?2")
+        .bind(&[app_id.into(), device_id.into()])?
+        .first::<Value>(None)
+        .await?;
+    if banned.is_some() {
+        return Err(AppError::Forbidden("This device can't be used to register".to_string()));
+    }
+    Ok(())
+}
+
+/// Sets a user's status. Banning also revokes their sign-ins and blocks the
+/// device they signed up from; any other status lifts those device blocks.
+pub async fn set_status(
+    app_id: &str,
+    user_id: &str,
+    status: &str,
+    reason: &str,
+    expires_at: Option<&str>,
+    changed_by: &str,
+    db: &D1Database,
+) -> Result<UserStatus, AppError> {
+    if !STATUSES.contains(&status) {
+        return Err(AppError::BadRequest(format!("status must be one of: {}", STATUSES.join(", "))));
+    }
+    if expires_at.is_some() && status != "suspended" {
+        return Err(AppError::BadRequest("Only a suspension can have an expiry".to_string()));
+    }
+    let user = db
+        .prepare("SELECT signup_device_id FROM users WHERE app_id = ?1 AND id = ?2")
+        .bind(&[app_id.into(), user_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found in this app".to_string()))?;
+    let device_id = user.get("signup_device_id").and_then(|v| v.as_str()).map(|s| s.to_string());
+
+    let now = Utc::now().to_rfc3339();
+    let active = status == "active";
+    let opt = |v: Option<&str>| v.map(JsValue::from).unwrap_or(JsValue::NULL);
+    let mut statements = vec![db
+        .prepare(
+            "UPDATE users SET status = ?1, status_reason = ?2, status_expires_at = ?3, status_changed_at = ?4,
+                              status_changed_by = ?5, updated_at = ?4
+             WHERE app_id = ?6 AND id = ?7",
+        )
+        .bind(&[
+            status.into(),
+            opt((!active).then_some(reason)),
+            opt(expires_at),
+            now.clone().into(),
+            changed_by.into(),
+            app_id.into(),
+            user_id.into(),
+        ])?];
+    if status == "banned" {
+        if let Some(device_id) = &device_id {
+            statements.push(
+                db.prepare(
+                    "INSERT OR REPLACE INTO banned_devices (app_id, device_id, user_id, reason, banned_by, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )
+                .bind(&[
+                    app_id.into(),
+                    device_id.clone().into(),
+                    user_id.into(),
+                    reason.into(),
+                    changed_by.into(),
+                    now.clone().into(),
+                ])?,
+            );
+        }
+    } else {
+        statements.push(
+            db.prepare("DELETE FROM banned_devices WHERE app_id = ?1 AND user_id = ?2")
+                .bind(&[app_id.into(), user_id.into()])?,
+        );
+    }
+    db.batch(statements).await?;
+
+    if status == "banned" {
+        sessions::revoke_all_sessions(app_id, user_id, None, false, db).await?;
+    }
+    get_status(app_id, user_id, db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found in this app".to_string()))
+}
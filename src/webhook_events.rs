@@ -0,0 +1,234 @@
+use worker::{D1Database, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub provider: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub app_id: Option<String>,
+    pub status: String,
+    pub error: Option<String>,
+    pub attempts: i64,
+    pub received_at: String,
+    pub processed_at: Option<String>,
+    /// Raw body as received; only returned when fetching a single event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+}
+
+const LIST_COLUMNS: &str =
+    "id, provider, event_id, event_type, app_id, status, error, attempts, received_at, processed_at";
+
+/// How long an attempt may sit in 'processing' before it is presumed dead (the
+/// worker was evicted or timed out mid-event) and the event can be claimed again.
+const PROCESSING_TIMEOUT_MINUTES: i64 = 10;
+
+fn stale_before() -> String {
+    (Utc::now() - Duration::minutes(PROCESSING_TIMEOUT_MINUTES)).to_rfc3339()
+}
+
+/// Stores a verified webhook and claims it for processing. Returns the row id
+/// to pass to `complete`, or None when the event was already processed (or is
+/// being processed by a concurrent delivery) and should just be acknowledged.
+/// A redelivery of a failed event, or of one whose attempt has been stuck in
+/// 'processing' past the timeout, is claimed again, so provider retries work
+/// like a replay.
+pub async fn begin(
+    provider: &str,
+    event_id: &str,
+    event_type: &str,
+    app_id: Option<&str>,
+    payload: &str,
+    db: &D1Database,
+) -> Result<Option<String>, AppError> {
+    let claim = db
+        .prepare(
+            "INSERT INTO webhook_events (id, provider, event_id, event_type, app_id, payload, status, attempts, received_at, claimed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'processing', 1, ?7, ?7)
+             ON CONFLICT(provider, event_id) DO UPDATE SET
+                 status = 'processing', attempts = webhook_events.attempts + 1, error = NULL, claimed_at = ?7
+             WHERE webhook_events.status = 'failed'
+                OR (webhook_events.status = 'processing' AND webhook_events.claimed_at < ?8)",
+        )
+        .bind(&[
+            Uuid::new_v4().to_string().into(),
+            provider.into(),
+            event_id.into(),
+            event_type.into(),
+            app_id.map(|a| a.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            payload.into(),
+            Utc::now().to_rfc3339().into(),
+            stale_before().into(),
+        ])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        worker::console_log!("Duplicate {} webhook {}, skipping", provider, event_id);
+        return Ok(None);
+    }
+
+    let id = db
+        .prepare("SELECT id FROM webhook_events WHERE provider = ?1 AND event_id = ?2")
+        .bind(&[provider.into(), event_id.into()])?
+        .first::<Value>(None)
+        .await?
+        .and_then(|v| v.get("id").and_then(|i| i.as_str()).map(|s| s.to_string()))
+        .ok_or_else(|| AppError::InternalError("Webhook event vanished after claim".to_string()))?;
+    Ok(Some(id))
+}
+
+/// Records how processing went and turns the outcome into the webhook response.
+/// Failures answer with the error so the provider retries the delivery.
+pub async fn complete(
+    id: &str,
+    outcome: Result<(), AppError>,
+    db: &D1Database,
+) -> worker::Result<Response> {
+    let (status, error) = match &outcome {
+        Ok(()) => ("processed", None),
+        Err(e) => ("failed", Some(e.message().to_string())),
+    };
+    db.prepare("UPDATE webhook_events SET status = ?1, error = ?2, processed_at = ?3 WHERE id = ?4")
+        .bind(&[
+            status.into(),
+            error.map(|e| e.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            Utc::now().to_rfc3339().into(),
+            id.into(),
+        ])?
+        .run()
+        .await?;
+
+    match outcome {
+        Ok(()) => Response::ok("OK"),
+        Err(e) => {
+            worker::console_error!("Webhook event {} failed: {:?}", id, e);
+            e.to_response()
+        }
+    }
+}
+
+/// Claims a failed event for a manual replay, or one whose attempt has been
+/// stuck in 'processing' past the timeout. `include_unassigned` also reaches
+/// events that couldn't be tied to an app on receipt (platform admins only).
+pub async fn claim_replay(
+    app_id: &str,
+    include_unassigned: bool,
+    id: &str,
+    db: &D1Database,
+) -> Result<WebhookEvent, AppError> {
+    let event = get_event(app_id, include_unassigned, id, db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Webhook event not found".to_string()))?;
+
+    let claim = db
+        .prepare(
+            "UPDATE webhook_events SET status = 'processing', attempts = attempts + 1, error = NULL, claimed_at = ?2
+             WHERE id = ?1 AND (status = 'failed' OR (status = 'processing' AND claimed_at < ?3))",
+        )
+        .bind(&[id.into(), Utc::now().to_rfc3339().into(), stale_before().into()])?
+        .run()
+        .await?;
+    if claim.meta().ok().flatten().and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return Err(AppError::Conflict(format!(
+            "Only failed or stuck events can be replayed (event is {})",
+            event.status
+        )));
+    }
+    Ok(event)
+}
+
+/// Fetches one event of `app_id`, payload included. With `include_unassigned`,
+/// events that were never tied to an app are found too.
+pub async fn get_event(
+    app_id: &str,
+    include_unassigned: bool,
+    id: &str,
+    db: &D1Database,
+) -> Result<Option<WebhookEvent>, AppError> {
+    let row = db
+        .prepare(format!(
+            "SELECT {}, payload FROM webhook_events
+             WHERE id = ?1 AND (app_id = ?2 OR (?3 = 1 AND app_id IS NULL))",
+            LIST_COLUMNS
+        ))
+        .bind(&[id.into(), app_id.into(), (include_unassigned as i32).into()])?
+        .first::<Value>(None)
+        .await?;
+    Ok(row.and_then(|r| serde_json::from_value::<WebhookEvent>(r).ok()))
+}
+
+/// Newest first. `status` and `provider` filter when given; with
+/// `include_unassigned`, events that were never tied to an app are listed too.
+pub async fn list_events(
+    app_id: &str,
+    include_unassigned: bool,
+    status: Option<&str>,
+    provider: Option<&str>,
+    limit: i32,
+    offset: i32,
+    db: &D1Database,
+) -> Result<Vec<WebhookEvent>, AppError> {
+    let rows = db
+        .prepare(format!(
+            "SELECT {} FROM webhook_events
+             WHERE (app_id = ?1 OR (?6 = 1 AND app_id IS NULL))
+               AND (?2 IS NULL OR status = ?2) AND (?3 IS NULL OR provider = ?3)
+             ORDER BY received_at DESC LIMIT ?4 OFFSET ?5",
+            LIST_COLUMNS
+        ))
+        .bind(&[
+            app_id.into(),
+            status.map(|s| s.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            provider.map(|p| p.into()).unwrap_or(worker::wasm_bindgen::JsValue::NULL),
+            limit.into(),
+            offset.into(),
+            (include_unassigned as i32).into(),
+        ])?
+        .all()
+        .await?
+        .results::<Value>()?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| serde_json::from_value::<WebhookEvent>(r).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_row_round_trip() {
+        let row = serde_json::json!({
+            "id": "w1",
+            "provider": "stripe",
+            "event_id": "evt_1",
+            "event_type": "charge.refunded",
+            "app_id": null,
+            "status": "failed",
+            "error": "Purchase not found",
+            "attempts": 2,
+            "received_at": "2026-01-01T00:00:00+00:00",
+            "processed_at": null,
+        });
+        let event: WebhookEvent = serde_json::from_value(row).unwrap();
+        assert_eq!(event.app_id, None);
+        assert_eq!(event.payload, None);
+        // Listed events leave the payload out entirely.
+        assert!(serde_json::to_value(&event).unwrap().get("payload").is_none());
+    }
+
+    #[test]
+    fn test_stale_before() {
+        let cutoff = chrono::DateTime::parse_from_rfc3339(&stale_before()).unwrap();
+        let age = Utc::now().signed_duration_since(cutoff);
+        assert!(age >= Duration::minutes(PROCESSING_TIMEOUT_MINUTES));
+        assert!(age < Duration::minutes(PROCESSING_TIMEOUT_MINUTES + 1));
+    }
+}